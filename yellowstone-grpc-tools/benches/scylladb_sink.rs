@@ -0,0 +1,426 @@
+//! Measures the logging throughput and flush latency of `ScyllaSink` under synthetic workloads, for each batching
+//! strategy.
+//!
+//! Runs against a real cluster with `solana.cql` applied and the benchmark producer registered in `producer_info`:
+//!
+//! ```text
+//! cargo bench -p yellowstone-grpc-tools --features bench --bench scylladb_sink
+//! ```
+//!
+//! With `BENCH_TARGET=memory`, the events are logged to an `InMemorySink` instead and no cluster is needed. This
+//! measures the cost of logging the events, a baseline for the figures of a cluster, and checks that the benchmark
+//! still runs. Batching strategies do not apply to it, each workload is measured once.
+//!
+//! Settings are read from the environment:
+//! - `BENCH_TARGET`: `scylladb` (the default) or `memory`.
+//! - `BENCH_SCYLLADB_HOSTNAME`, `BENCH_SCYLLADB_USERNAME`, `BENCH_SCYLLADB_PASSWORD`: defaults to
+//!   `localhost:9042` and `cassandra`.
+//! - `BENCH_KEYSPACE` (`solana`) and `BENCH_PRODUCER_ID` (`0`).
+//! - `BENCH_WORKLOADS` and `BENCH_STRATEGIES`: comma separated names of the workload presets and batching
+//!   strategies to run, every one of them if unset.
+//!
+//! Every workload preset and batching strategy is a benchmark group named `<preset>/<strategy>`, pass its name
+//! after `--` to run only some of them. Each iteration logs the events of a hundredth of a second at the rate of
+//! the preset: `log` measures logging them, the sink pushes back when it can not keep up, and `flush_all` how
+//! long the shards take to make them durable. Events are generated outside of the measured time.
+use {
+    criterion::{criterion_group, criterion_main, Criterion, Throughput},
+    rand::{rngs::StdRng, Rng, SeedableRng},
+    std::{
+        env,
+        time::{Duration, Instant},
+    },
+    tokio::runtime::Runtime,
+    yellowstone_grpc_tools::scylladb::{
+        config::ConfigGrpc2ScyllaDB,
+        event_sink::{EventSink, InMemorySink},
+        sink::{ScyllaSink, ScyllaSinkConfig},
+        types::{AccountUpdate, Transaction, TransactionMeta},
+    },
+};
+
+/// Share of events per size, in bytes of account data or of transaction log messages.
+type SizeDistribution = &'static [(f64, usize)];
+
+struct Workload {
+    name: &'static str,
+    /// Events logged per second, sets how many events an iteration logs.
+    rate: u64,
+    /// Share of transactions among the events, the rest are account updates.
+    transaction_ratio: f64,
+    account_data_sizes: SizeDistribution,
+    log_message_sizes: SizeDistribution,
+}
+
+/// Presets sized after the Solana mainnet traffic of a full geyser subscription.
+static WORKLOADS: [Workload; 3] = [
+    Workload {
+        name: "mainnet-account-updates",
+        rate: 20_000,
+        transaction_ratio: 0.0,
+        // Mostly token accounts, then program and large state accounts.
+        account_data_sizes: &[(0.7, 165), (0.25, 1_024), (0.05, 10_240)],
+        log_message_sizes: &[],
+    },
+    Workload {
+        name: "mainnet-transactions",
+        rate: 4_000,
+        transaction_ratio: 1.0,
+        account_data_sizes: &[],
+        // Votes log next to nothing, other transactions a few kilobytes.
+        log_message_sizes: &[(0.7, 100), (0.25, 2_048), (0.05, 8_192)],
+    },
+    Workload {
+        name: "mainnet-mixed",
+        rate: 24_000,
+        transaction_ratio: 0.17,
+        account_data_sizes: &[(0.7, 165), (0.25, 1_024), (0.05, 10_240)],
+        log_message_sizes: &[(0.7, 100), (0.25, 2_048), (0.05, 8_192)],
+    },
+];
+
+struct Strategy {
+    name: &'static str,
+    batch_size_kb_limit: usize,
+    linger: Duration,
+    min_batch_len: usize,
+    max_linger: Duration,
+}
+
+const STRATEGIES: [Strategy; 3] = [
+    Strategy {
+        name: "default",
+        batch_size_kb_limit: 131585,
+        linger: Duration::from_millis(10),
+        min_batch_len: 1,
+        max_linger: Duration::from_millis(10),
+    },
+    Strategy {
+        name: "low-latency",
+        batch_size_kb_limit: 131585,
+        linger: Duration::from_millis(1),
+        min_batch_len: 1,
+        max_linger: Duration::from_millis(1),
+    },
+    Strategy {
+        name: "large-batches",
+        batch_size_kb_limit: 131585,
+        linger: Duration::from_millis(50),
+        min_batch_len: 32,
+        max_linger: Duration::from_millis(200),
+    },
+];
+
+/// Stands in for the batching strategies when logging to an `InMemorySink`.
+const IN_MEMORY: Strategy = Strategy {
+    name: "in-memory",
+    batch_size_kb_limit: 0,
+    linger: Duration::ZERO,
+    min_batch_len: 0,
+    max_linger: Duration::ZERO,
+};
+
+/// Sink the events are logged to, see `BENCH_TARGET`.
+enum Target {
+    ScyllaDb(ScyllaSink),
+    Memory(InMemorySink),
+}
+
+impl Target {
+    async fn open(in_memory: bool, strategy: &Strategy) -> anyhow::Result<Self> {
+        if in_memory {
+            return Ok(Target::Memory(InMemorySink::new()));
+        }
+        let sink = ScyllaSink::new(
+            sink_config(strategy)?,
+            env_or("BENCH_SCYLLADB_HOSTNAME", "localhost:9042"),
+            env_or("BENCH_SCYLLADB_USERNAME", "cassandra"),
+            env_or("BENCH_SCYLLADB_PASSWORD", "cassandra"),
+        )
+        .await?;
+        Ok(Target::ScyllaDb(sink))
+    }
+
+    async fn log(&mut self, event: Event) -> anyhow::Result<()> {
+        match (self, event) {
+            (Target::ScyllaDb(sink), Event::AccountUpdate(update)) => {
+                Ok(sink.log_account_update(update).await?)
+            }
+            (Target::ScyllaDb(sink), Event::Transaction(tx)) => {
+                Ok(sink.log_transaction(tx).await?)
+            }
+            (Target::Memory(sink), Event::AccountUpdate(update)) => {
+                EventSink::log_account_update(sink, update).await
+            }
+            (Target::Memory(sink), Event::Transaction(tx)) => {
+                EventSink::log_transaction(sink, tx).await
+            }
+        }
+    }
+
+    /// Events logged to an `InMemorySink` are durable as soon as they are logged.
+    async fn flush_all(&mut self) -> anyhow::Result<()> {
+        match self {
+            Target::ScyllaDb(sink) => sink.flush_all().await,
+            Target::Memory(_) => Ok(()),
+        }
+    }
+
+    async fn shutdown(self) -> anyhow::Result<()> {
+        match self {
+            Target::ScyllaDb(sink) => sink.shutdown().await,
+            Target::Memory(sink) => EventSink::shutdown(sink).await,
+        }
+    }
+}
+
+/// Number of event batches logged per second, an iteration logs one batch.
+const TICKS_PER_SECOND: u64 = 100;
+
+#[allow(clippy::large_enum_variant)]
+enum Event {
+    AccountUpdate(AccountUpdate),
+    Transaction(Transaction),
+}
+
+/// Generates the events of a workload, in slots of 400ms as on mainnet.
+struct EventGenerator {
+    workload: &'static Workload,
+    rng: StdRng,
+    events_per_slot: u64,
+    events: u64,
+}
+
+impl EventGenerator {
+    fn new(workload: &'static Workload) -> Self {
+        EventGenerator {
+            workload,
+            rng: StdRng::seed_from_u64(42),
+            events_per_slot: (workload.rate * 2 / 5).max(1),
+            events: 0,
+        }
+    }
+
+    fn next_event(&mut self) -> Event {
+        let slot = (self.events / self.events_per_slot) as i64;
+        let position = (self.events % self.events_per_slot) as i64;
+        let event = if self.rng.gen::<f64>() < self.workload.transaction_ratio {
+            let logs_len = pick_size(&mut self.rng, self.workload.log_message_sizes);
+            Event::Transaction(transaction(&mut self.rng, slot, position, logs_len))
+        } else {
+            let data_len = pick_size(&mut self.rng, self.workload.account_data_sizes);
+            Event::AccountUpdate(account_update(
+                &mut self.rng,
+                slot,
+                self.events as i64,
+                data_len,
+            ))
+        };
+        self.events += 1;
+        event
+    }
+
+    fn next_batch(&mut self, len: u64) -> Vec<Event> {
+        (0..len).map(|_| self.next_event()).collect()
+    }
+}
+
+fn env_or(name: &str, default: &str) -> String {
+    env::var(name).unwrap_or_else(|_| default.to_owned())
+}
+
+/// Presets named in the comma separated list of the `name` variable, every preset if unset.
+fn env_presets<'a, T>(
+    name: &str,
+    presets: &'a [T],
+    preset_name: impl Fn(&T) -> &'static str,
+) -> Vec<&'a T> {
+    let Ok(selected) = env::var(name) else {
+        return presets.iter().collect();
+    };
+    selected
+        .split(',')
+        .map(str::trim)
+        .filter(|selected| !selected.is_empty())
+        .map(|selected| {
+            presets
+                .iter()
+                .find(|preset| preset_name(preset) == selected)
+                .unwrap_or_else(|| {
+                    let names = presets.iter().map(&preset_name).collect::<Vec<_>>();
+                    panic!("unknown preset {selected:?} in {name}, expected one of {names:?}")
+                })
+        })
+        .collect()
+}
+
+fn pick_size(rng: &mut StdRng, sizes: SizeDistribution) -> usize {
+    let mut draw = rng.gen::<f64>();
+    for (share, size) in sizes {
+        if draw < *share {
+            return *size;
+        }
+        draw -= share;
+    }
+    sizes.last().map(|(_, size)| *size).unwrap_or(0)
+}
+
+fn account_update(
+    rng: &mut StdRng,
+    slot: i64,
+    write_version: i64,
+    data_len: usize,
+) -> AccountUpdate {
+    AccountUpdate {
+        slot,
+        pubkey: rng.gen(),
+        lamports: rng.gen_range(0..1_000_000_000),
+        owner: rng.gen(),
+        write_version,
+        data: (0..data_len).map(|_| rng.gen()).collect(),
+        ..AccountUpdate::zero_account()
+    }
+}
+
+fn transaction(rng: &mut StdRng, slot: i64, tx_index: i64, logs_len: usize) -> Transaction {
+    let signature = (0..64).map(|_| rng.gen()).collect::<Vec<u8>>();
+    Transaction {
+        slot,
+        signature: signature.clone(),
+        signatures: vec![signature],
+        num_required_signatures: 1,
+        num_readonly_signed_accounts: 0,
+        num_readonly_unsigned_accounts: 1,
+        account_keys: (0..4).map(|_| rng.gen::<[u8; 32]>().to_vec()).collect(),
+        recent_blockhash: rng.gen::<[u8; 32]>().to_vec(),
+        instructions: vec![],
+        versioned: false,
+        address_table_lookups: vec![],
+        meta: TransactionMeta {
+            fee: 5_000,
+            log_messages: Some(vec!["x".repeat(logs_len)]),
+            ..TransactionMeta::default()
+        },
+        is_vote: logs_len < 1_024,
+        tx_index,
+        ingested_at: None,
+        truncated: false,
+        raw_proto: None,
+    }
+}
+
+fn sink_config(strategy: &Strategy) -> anyhow::Result<ScyllaSinkConfig> {
+    let config: ConfigGrpc2ScyllaDB = serde_json::from_value(serde_json::json!({
+        "endpoint": "",
+        "request": {},
+        "producer_id": env_or("BENCH_PRODUCER_ID", "0").parse::<u8>()?,
+        "keyspace": env_or("BENCH_KEYSPACE", "solana"),
+    }))?;
+    let mut config = config.get_scylladb_sink_config();
+    config.batch_size_kb_limit = strategy.batch_size_kb_limit;
+    config.linger = strategy.linger;
+    config.min_batch_len = strategy.min_batch_len;
+    config.max_linger = strategy.max_linger;
+    Ok(config)
+}
+
+/// Logs `iters` batches of events, measuring only the logging.
+async fn log_batches(
+    sink: &mut Target,
+    events: &mut EventGenerator,
+    batch_len: u64,
+    iters: u64,
+) -> anyhow::Result<Duration> {
+    let mut elapsed = Duration::ZERO;
+    for _ in 0..iters {
+        let batch = events.next_batch(batch_len);
+        let start = Instant::now();
+        for event in batch {
+            sink.log(event).await?;
+        }
+        elapsed += start.elapsed();
+    }
+    Ok(elapsed)
+}
+
+/// Logs `iters` batches of events, measuring only the `flush_all` following each batch.
+async fn flush_batches(
+    sink: &mut Target,
+    events: &mut EventGenerator,
+    batch_len: u64,
+    iters: u64,
+) -> anyhow::Result<Duration> {
+    let mut elapsed = Duration::ZERO;
+    for _ in 0..iters {
+        for event in events.next_batch(batch_len) {
+            sink.log(event).await?;
+        }
+        let start = Instant::now();
+        sink.flush_all().await?;
+        elapsed += start.elapsed();
+    }
+    Ok(elapsed)
+}
+
+fn bench_workload(
+    c: &mut Criterion,
+    runtime: &Runtime,
+    in_memory: bool,
+    workload: &'static Workload,
+    strategy: &Strategy,
+) {
+    let mut sink = runtime
+        .block_on(Target::open(in_memory, strategy))
+        .expect("failed to open the sink");
+    let mut events = EventGenerator::new(workload);
+    let batch_len = (workload.rate / TICKS_PER_SECOND).max(1);
+
+    let mut group = c.benchmark_group(format!("{}/{}", workload.name, strategy.name));
+    group.throughput(Throughput::Elements(batch_len));
+    group.bench_function("log", |b| {
+        b.iter_custom(|iters| {
+            runtime
+                .block_on(log_batches(&mut sink, &mut events, batch_len, iters))
+                .expect("failed to log the events")
+        })
+    });
+    // Leaves nothing buffered by `log` to the first `flush_all`.
+    runtime
+        .block_on(sink.flush_all())
+        .expect("failed to flush the events");
+    group.bench_function("flush_all", |b| {
+        b.iter_custom(|iters| {
+            runtime
+                .block_on(flush_batches(&mut sink, &mut events, batch_len, iters))
+                .expect("failed to flush the events")
+        })
+    });
+    group.finish();
+
+    runtime
+        .block_on(sink.shutdown())
+        .expect("failed to shut the sink down");
+}
+
+fn scylladb_sink(c: &mut Criterion) {
+    let in_memory = match env_or("BENCH_TARGET", "scylladb").as_str() {
+        "scylladb" => false,
+        "memory" => true,
+        target => panic!("unknown target {target:?}, expected scylladb or memory"),
+    };
+    let strategies = if in_memory {
+        vec![&IN_MEMORY]
+    } else {
+        env_presets("BENCH_STRATEGIES", &STRATEGIES, |strategy| strategy.name)
+    };
+    let workloads = env_presets("BENCH_WORKLOADS", &WORKLOADS, |workload| workload.name);
+    let runtime = Runtime::new().expect("failed to start the tokio runtime");
+    for workload in workloads {
+        for strategy in strategies.iter().copied() {
+            bench_workload(c, &runtime, in_memory, workload, strategy);
+        }
+    }
+}
+
+criterion_group!(benches, scylladb_sink);
+criterion_main!(benches);
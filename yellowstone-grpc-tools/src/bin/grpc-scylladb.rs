@@ -10,10 +10,8 @@ use {
     yellowstone_grpc_client::GeyserGrpcClient,
     yellowstone_grpc_proto::{
         prelude::subscribe_update::UpdateOneof,
-        yellowstone::log::{
-            yellowstone_log_server::{self, YellowstoneLog, YellowstoneLogServer},
-            EventSubscriptionPolicy,
-        },
+        prost::Message,
+        yellowstone::log::{yellowstone_log_server::YellowstoneLogServer, EventSubscriptionPolicy},
     },
     yellowstone_grpc_tools::{
         config::{load as config_load, GrpcRequestToProto},
@@ -27,8 +25,8 @@ use {
                 common::InitialOffsetPolicy,
                 grpc::{spawn_grpc_consumer, ScyllaYsLog, SpawnGrpcConsumerReq},
             },
-            sink::ScyllaSink,
-            types::Transaction,
+            sink::{list_producers, register_producer, ScyllaSink, ScyllaSinkError},
+            types::{AccountUpdate, BlockMeta, ShardId, Transaction},
         },
         setup_tracing,
     },
@@ -64,6 +62,26 @@ enum ArgsAction {
 
     #[command(name = "test")]
     Test,
+
+    /// List the registered producers and their number of shards
+    #[command(name = "list-producers")]
+    ListProducers,
+
+    /// Register a producer with its number of shards, a no-op if it is already registered
+    #[command(name = "register-producer")]
+    RegisterProducer {
+        /// Id of the producer
+        #[clap(long)]
+        producer_id: u8,
+
+        /// Number of shards of the producer
+        #[clap(long)]
+        num_shards: ShardId,
+
+        /// Number of partitions of the log each period is split into, must divide the period length
+        #[clap(long, default_value_t = 1)]
+        partitions_per_period: i16,
+    },
 }
 
 impl ArgsAction {
@@ -88,9 +106,91 @@ impl ArgsAction {
                 })?;
                 Self::test(config2, config.scylladb, shutdown).await
             }
+            ArgsAction::ListProducers => {
+                let config2 = config.grpc2scylladb.ok_or_else(|| {
+                    anyhow::anyhow!("`grpc2scylladb` section in config should be defined")
+                })?;
+                Self::list_producers(config2, config.scylladb).await
+            }
+            ArgsAction::RegisterProducer {
+                producer_id,
+                num_shards,
+                partitions_per_period,
+            } => {
+                let config2 = config.grpc2scylladb.ok_or_else(|| {
+                    anyhow::anyhow!("`grpc2scylladb` section in config should be defined")
+                })?;
+                Self::register_producer(
+                    config2,
+                    config.scylladb,
+                    producer_id,
+                    num_shards,
+                    partitions_per_period,
+                )
+                .await
+            }
         }
     }
 
+    async fn register_producer(
+        config: ConfigGrpc2ScyllaDB,
+        scylladb_conn_config: ScyllaDbConnectionInfo,
+        producer_id: u8,
+        num_shards: ShardId,
+        partitions_per_period: i16,
+    ) -> anyhow::Result<()> {
+        let session_builder = SessionBuilder::new()
+            .known_node(&scylladb_conn_config.hostname)
+            .compression(Some(Compression::Lz4))
+            .use_keyspace(config.keyspace.clone(), false);
+        let session: Session = scylladb_conn_config
+            .auth()
+            .apply(session_builder)
+            .build()
+            .await?;
+        let registered = register_producer(
+            Arc::new(session),
+            [producer_id],
+            num_shards,
+            partitions_per_period,
+            config.control_keyspace.as_deref(),
+        )
+        .await?;
+        if registered {
+            println!("producer {producer_id} registered with {num_shards} shards");
+        } else {
+            println!("producer {producer_id} is already registered with {num_shards} shards");
+        }
+        Ok(())
+    }
+
+    async fn list_producers(
+        config: ConfigGrpc2ScyllaDB,
+        scylladb_conn_config: ScyllaDbConnectionInfo,
+    ) -> anyhow::Result<()> {
+        let session_builder = SessionBuilder::new()
+            .known_node(&scylladb_conn_config.hostname)
+            .compression(Some(Compression::Lz4))
+            .use_keyspace(config.keyspace.clone(), false);
+        let session: Session = scylladb_conn_config
+            .auth()
+            .apply(session_builder)
+            .build()
+            .await?;
+        let producers =
+            list_producers(Arc::new(session), config.control_keyspace.as_deref()).await?;
+        println!("producer_id\tnum_shards\tpartitions_per_period");
+        for producer in producers {
+            println!(
+                "{}\t{}\t{}",
+                producer.producer_id[0],
+                producer.num_shards,
+                producer.partitions_per_period()
+            );
+        }
+        Ok(())
+    }
+
     async fn yellowstone_log_server(
         config: ConfigYellowstoneLogServer,
         scylladb_conn_config: ScyllaDbConnectionInfo,
@@ -98,16 +198,19 @@ impl ArgsAction {
     ) -> anyhow::Result<()> {
         let addr = config.listen.parse().unwrap();
 
-        let session: Session = SessionBuilder::new()
-            .known_node(scylladb_conn_config.hostname)
-            .user(scylladb_conn_config.username, scylladb_conn_config.password)
+        let session_builder = SessionBuilder::new()
+            .known_node(&scylladb_conn_config.hostname)
             .compression(Some(Compression::Lz4))
-            .use_keyspace(config.keyspace.clone(), false)
+            .use_keyspace(config.keyspace.clone(), false);
+        let session: Session = scylladb_conn_config
+            .auth()
+            .apply(session_builder)
             .build()
             .await?;
 
         let session = Arc::new(session);
-        let scylla_ys_log = ScyllaYsLog::new(session);
+        let scylla_ys_log =
+            ScyllaYsLog::new(session).with_control_keyspace(config.control_keyspace.clone());
         let ys_log_server = YellowstoneLogServer::new(scylla_ys_log);
 
         println!("YellowstoneLogServer listening on {}", addr);
@@ -129,11 +232,13 @@ impl ArgsAction {
         scylladb_conn_config: ScyllaDbConnectionInfo,
         mut shutdown: BoxFuture<'static, ()>,
     ) -> anyhow::Result<()> {
-        let session: Session = SessionBuilder::new()
-            .known_node(scylladb_conn_config.hostname)
-            .user(scylladb_conn_config.username, scylladb_conn_config.password)
+        let session_builder = SessionBuilder::new()
+            .known_node(&scylladb_conn_config.hostname)
             .compression(Some(Compression::Lz4))
-            .use_keyspace(config.keyspace.clone(), false)
+            .use_keyspace(config.keyspace.clone(), false);
+        let session: Session = scylladb_conn_config
+            .auth()
+            .apply(session_builder)
             .build()
             .await?;
         let session = Arc::new(session);
@@ -143,6 +248,7 @@ impl ArgsAction {
             tx_event_filter: None,
             buffer_capacity: None,
             offset_commit_interval: None,
+            control_keyspace: config.control_keyspace.clone(),
         };
         let mut rx = spawn_grpc_consumer(
             session,
@@ -182,6 +288,8 @@ impl ArgsAction {
         mut shutdown: BoxFuture<'static, ()>,
     ) -> anyhow::Result<()> {
         let sink_config = config.get_scylladb_sink_config();
+        // Geyser messages are encoded back as received, before their conversion, only if the sink stores them.
+        let store_raw_proto = sink_config.store_raw_proto;
         info!("sink configuration {:?}", sink_config);
 
         // Create gRPC client & subscribe
@@ -196,11 +304,10 @@ impl ArgsAction {
         let mut geyser = client.subscribe_once(config.request.to_proto()).await?;
         info!("Grpc subscription is successful .");
 
-        let mut sink = ScyllaSink::new(
+        let mut sink = ScyllaSink::new_with_auth(
             sink_config,
-            scylladb_conn_config.hostname,
-            scylladb_conn_config.username,
-            scylladb_conn_config.password,
+            &scylladb_conn_config.hostname,
+            scylladb_conn_config.auth(),
         )
         .await?;
 
@@ -226,7 +333,8 @@ impl ArgsAction {
 
                 let result = match message {
                     UpdateOneof::Account(msg) => {
-                        let acc_update = msg.clone().try_into();
+                        let raw_proto = store_raw_proto.then(|| msg.encode_to_vec());
+                        let acc_update = AccountUpdate::try_from(msg);
                         if acc_update.is_err() {
                             // Drop the message if invalid
                             warn!(
@@ -236,21 +344,51 @@ impl ArgsAction {
                             continue;
                         }
                         // If the sink is close, let it crash...
-                        sink.log_account_update(acc_update.unwrap()).await
+                        sink.log_account_update(AccountUpdate {
+                            raw_proto,
+                            ..acc_update.unwrap()
+                        })
+                        .await
                     }
                     UpdateOneof::Transaction(msg) => {
+                        let raw_proto = store_raw_proto.then(|| msg.encode_to_vec());
                         let tx: Result<Transaction, anyhow::Error> = msg.try_into();
                         if tx.is_err() {
                             warn!("failed to convert update tx: {:?}", tx.err().unwrap());
                             continue;
                         }
-                        sink.log_transaction(tx.unwrap()).await
+                        sink.log_transaction(Transaction {
+                            raw_proto,
+                            ..tx.unwrap()
+                        })
+                        .await
+                    }
+                    UpdateOneof::BlockMeta(msg) => {
+                        let raw_proto = store_raw_proto.then(|| msg.encode_to_vec());
+                        match BlockMeta::try_from(msg) {
+                            std::result::Result::Ok(block_meta) => {
+                                sink.log_block_meta(BlockMeta {
+                                    raw_proto,
+                                    ..block_meta
+                                })
+                                .await
+                            }
+                            Err(e) => {
+                                warn!("failed to convert block meta: {e:?}");
+                                continue;
+                            }
+                        }
                     }
                     _ => continue,
                 };
 
-                if result.is_err() {
-                    error!("errror detected in sink...");
+                if let Err(e) = result {
+                    if let ScyllaSinkError::EventTooLarge(_) | ScyllaSinkError::FieldTooLarge(_) = e
+                    {
+                        // The sink already reported it, the event is dropped.
+                        continue;
+                    }
+                    error!("errror detected in sink: {e}");
                     break;
                 }
             }
@@ -8,8 +8,18 @@ use crate::google_pubsub::prom::{
 use crate::kafka::prom::{KAFKA_DEDUP_TOTAL, KAFKA_RECV_TOTAL, KAFKA_SENT_TOTAL, KAFKA_STATS};
 #[cfg(feature = "scylla")]
 use crate::scylladb::prom::{
-    SCYLLADB_BATCHITEM_DELIVERED, SCYLLADB_BATCH_DELIVERED, SCYLLADB_BATCH_QUEUE,
-    SCYLLADB_BATCH_REQUEST_LAG, SCYLLADB_BATCH_SIZE, SCYLLADB_PEAK_BATCH_LINGER_SECONDS,
+    SCYLLADB_ACCOUNT_UPDATE_DEDUP, SCYLLADB_ACTIVE_SHARDS, SCYLLADB_BATCHITEM_DELIVERED,
+    SCYLLADB_BATCH_DELIVERED, SCYLLADB_BATCH_QUEUE, SCYLLADB_BATCH_REQUEST_LAG,
+    SCYLLADB_BATCH_SIZE, SCYLLADB_BUFFERED_BYTES, SCYLLADB_BUFFERED_BYTES_LIMIT,
+    SCYLLADB_BUFFER_FULL_DROP, SCYLLADB_CIRCUIT_BREAKER_STATE, SCYLLADB_COMPRESSION_INPUT_BYTES,
+    SCYLLADB_COMPRESSION_OUTPUT_BYTES, SCYLLADB_CONSISTENCY_DOWNGRADE, SCYLLADB_INGESTION_LATENCY,
+    SCYLLADB_LOCK_ACQUISITION, SCYLLADB_LOCK_ACQUISITION_SECONDS,
+    SCYLLADB_OLDEST_BUFFERED_EVENT_AGE, SCYLLADB_OVERSIZED_EVENT, SCYLLADB_OVERSIZED_FIELD,
+    SCYLLADB_PARTITION_WRITES, SCYLLADB_PEAK_BATCH_LINGER_SECONDS, SCYLLADB_PREPARE_FAILURE,
+    SCYLLADB_PRODUCER_LOCK_HELD, SCYLLADB_ROUTER_CURSOR, SCYLLADB_ROUTER_RESERVE_WAIT,
+    SCYLLADB_SHARD_DURABILITY_LAG, SCYLLADB_SHARD_REBALANCE, SCYLLADB_SINGLE_ITEM_BATCH_RATIO,
+    SCYLLADB_SLOT_REGRESSION, SCYLLADB_STATEMENT_FAILURE, SCYLLADB_STATEMENT_RETRY,
+    SCYLLADB_TRANSACTION_DEDUP,
 };
 use {
     crate::version::VERSION as VERSION_INFO,
@@ -70,6 +80,33 @@ pub fn run_server(address: SocketAddr) -> anyhow::Result<()> {
             register!(SCYLLADB_BATCH_SIZE);
             register!(SCYLLADB_BATCH_QUEUE);
             register!(SCYLLADB_BATCH_REQUEST_LAG);
+            register!(SCYLLADB_ACCOUNT_UPDATE_DEDUP);
+            register!(SCYLLADB_PREPARE_FAILURE);
+            register!(SCYLLADB_BUFFERED_BYTES);
+            register!(SCYLLADB_BUFFERED_BYTES_LIMIT);
+            register!(SCYLLADB_SHARD_DURABILITY_LAG);
+            register!(SCYLLADB_INGESTION_LATENCY);
+            register!(SCYLLADB_PARTITION_WRITES);
+            register!(SCYLLADB_OVERSIZED_EVENT);
+            register!(SCYLLADB_OVERSIZED_FIELD);
+            register!(SCYLLADB_ACTIVE_SHARDS);
+            register!(SCYLLADB_PRODUCER_LOCK_HELD);
+            register!(SCYLLADB_ROUTER_CURSOR);
+            register!(SCYLLADB_ROUTER_RESERVE_WAIT);
+            register!(SCYLLADB_SINGLE_ITEM_BATCH_RATIO);
+            register!(SCYLLADB_OLDEST_BUFFERED_EVENT_AGE);
+            register!(SCYLLADB_CIRCUIT_BREAKER_STATE);
+            register!(SCYLLADB_CONSISTENCY_DOWNGRADE);
+            register!(SCYLLADB_SHARD_REBALANCE);
+            register!(SCYLLADB_COMPRESSION_INPUT_BYTES);
+            register!(SCYLLADB_COMPRESSION_OUTPUT_BYTES);
+            register!(SCYLLADB_STATEMENT_RETRY);
+            register!(SCYLLADB_STATEMENT_FAILURE);
+            register!(SCYLLADB_LOCK_ACQUISITION_SECONDS);
+            register!(SCYLLADB_LOCK_ACQUISITION);
+            register!(SCYLLADB_TRANSACTION_DEDUP);
+            register!(SCYLLADB_BUFFER_FULL_DROP);
+            register!(SCYLLADB_SLOT_REGRESSION);
         }
 
         VERSION
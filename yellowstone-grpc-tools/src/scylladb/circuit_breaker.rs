@@ -0,0 +1,250 @@
+use {
+    super::{prom::scylladb_circuit_breaker_state_set, types::ProducerId},
+    serde::Deserialize,
+    serde_with::{serde_as, DurationMilliSeconds},
+    std::{collections::VecDeque, sync::Mutex, time::Duration},
+    tokio::time::Instant,
+    tracing::{info, warn},
+};
+
+/// Interval at which a shard checks whether the probe batch of a half-open breaker went through.
+const HALF_OPEN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+const fn default_circuit_breaker_window() -> usize {
+    20
+}
+
+const fn default_circuit_breaker_error_rate() -> f64 {
+    0.5
+}
+
+const fn default_circuit_breaker_open_duration() -> Duration {
+    Duration::from_secs(5)
+}
+
+/// Settings of the breaker tripped by a high batch error rate, see [`CircuitBreaker`].
+#[serde_as]
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize)]
+pub struct CircuitBreakerConfig {
+    /// Number of last batches the error rate is computed over, the breaker never trips before that many batches.
+    #[serde(default = "default_circuit_breaker_window")]
+    pub window: usize,
+    /// Ratio of failed batches among the last `window` ones tripping the breaker.
+    #[serde(default = "default_circuit_breaker_error_rate")]
+    pub error_rate: f64,
+    /// How long the breaker stays open in milliseconds before letting a probe batch through.
+    #[serde(default = "default_circuit_breaker_open_duration")]
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    pub open_duration: Duration,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            window: default_circuit_breaker_window(),
+            error_rate: default_circuit_breaker_error_rate(),
+            open_duration: default_circuit_breaker_open_duration(),
+        }
+    }
+}
+
+/// Carried by the errors of the `log_*` methods while the circuit breaker of the sink is open.
+///
+/// The cluster is failing most writes, the caller should shed load and try again later.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CircuitOpen {
+    pub producer_id: ProducerId,
+}
+
+impl std::fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "circuit breaker of producer {:?} is open, ScyllaDB is failing most writes",
+            self.producer_id
+        )
+    }
+}
+
+impl std::error::Error for CircuitOpen {}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum CircuitState {
+    Closed,
+    /// Every batch waits until `until`, then the first one goes through as a probe.
+    Open {
+        until: Instant,
+    },
+    /// A single probe batch is in flight, its outcome closes or reopens the breaker.
+    HalfOpen,
+}
+
+impl CircuitState {
+    /// Value of the `scylladb_circuit_breaker_state` gauge.
+    const fn metric_value(self) -> i64 {
+        match self {
+            CircuitState::Closed => 0,
+            CircuitState::HalfOpen => 1,
+            CircuitState::Open { .. } => 2,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CircuitBreakerState {
+    state: CircuitState,
+    /// Outcome of the last batches while closed, true for a failure.
+    outcomes: VecDeque<bool>,
+    failures: usize,
+}
+
+/// Breaker shared by the shards of a sink, tripped when too many of the last batches failed.
+///
+/// While open, shards hold their buffer instead of retrying against a failing cluster and the `log_*` methods
+/// fail fast with [`ScyllaSinkError::Backpressure`](super::sink::ScyllaSinkError::Backpressure). Once
+/// `open_duration` elapsed, a single batch is sent as a probe: the breaker closes if it succeeds and opens
+/// again otherwise.
+#[derive(Debug)]
+pub(crate) struct CircuitBreaker {
+    producer_id: ProducerId,
+    config: CircuitBreakerConfig,
+    inner: Mutex<CircuitBreakerState>,
+}
+
+impl CircuitBreaker {
+    pub(crate) fn new(producer_id: ProducerId, config: CircuitBreakerConfig) -> Self {
+        scylladb_circuit_breaker_state_set(producer_id, CircuitState::Closed.metric_value());
+        CircuitBreaker {
+            producer_id,
+            config,
+            inner: Mutex::new(CircuitBreakerState {
+                state: CircuitState::Closed,
+                outcomes: VecDeque::with_capacity(config.window),
+                failures: 0,
+            }),
+        }
+    }
+
+    /// Returns true while the breaker rejects new events, that is until the probe batch is allowed.
+    pub(crate) fn is_open(&self) -> bool {
+        let inner = self.inner.lock().expect("poisoned");
+        matches!(inner.state, CircuitState::Open { until } if Instant::now() < until)
+    }
+
+    /// Waits until a batch may be sent: right away while closed, or once it is the probe of a half-open breaker.
+    pub(crate) async fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut inner = self.inner.lock().expect("poisoned");
+                match inner.state {
+                    CircuitState::Closed => return,
+                    CircuitState::Open { until } if Instant::now() >= until => {
+                        info!(
+                            "circuit breaker of producer {:?} half-open, probing",
+                            self.producer_id
+                        );
+                        self.transition(&mut inner, CircuitState::HalfOpen);
+                        return;
+                    }
+                    CircuitState::Open { until } => until - Instant::now(),
+                    CircuitState::HalfOpen => HALF_OPEN_POLL_INTERVAL,
+                }
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Records the outcome of a batch sent after [`CircuitBreaker::acquire`].
+    pub(crate) fn record(&self, failed: bool) {
+        let mut inner = self.inner.lock().expect("poisoned");
+        match inner.state {
+            CircuitState::Closed => {
+                if inner.outcomes.len() >= self.config.window
+                    && inner.outcomes.pop_front() == Some(true)
+                {
+                    inner.failures -= 1;
+                }
+                inner.outcomes.push_back(failed);
+                inner.failures += failed as usize;
+                let window_full = inner.outcomes.len() >= self.config.window;
+                let error_rate = inner.failures as f64 / inner.outcomes.len() as f64;
+                if window_full && error_rate >= self.config.error_rate {
+                    warn!(
+                        "circuit breaker of producer {:?} tripped, {:.0}% of the last {} batches failed",
+                        self.producer_id,
+                        error_rate * 100.0,
+                        inner.outcomes.len()
+                    );
+                    self.open(&mut inner);
+                }
+            }
+            CircuitState::HalfOpen if failed => {
+                warn!(
+                    "circuit breaker of producer {:?} probe failed, reopening",
+                    self.producer_id
+                );
+                self.open(&mut inner);
+            }
+            CircuitState::HalfOpen => {
+                info!("circuit breaker of producer {:?} closed", self.producer_id);
+                inner.outcomes.clear();
+                inner.failures = 0;
+                self.transition(&mut inner, CircuitState::Closed);
+            }
+            // A batch sent before the breaker tripped.
+            CircuitState::Open { .. } => (),
+        }
+    }
+
+    fn open(&self, inner: &mut CircuitBreakerState) {
+        let until = Instant::now() + self.config.open_duration;
+        self.transition(inner, CircuitState::Open { until });
+    }
+
+    fn transition(&self, inner: &mut CircuitBreakerState, state: CircuitState) {
+        inner.state = state;
+        scylladb_circuit_breaker_state_set(self.producer_id, state.metric_value());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{CircuitBreaker, CircuitBreakerConfig},
+        std::time::Duration,
+    };
+
+    #[tokio::test(start_paused = true)]
+    async fn breaker_trips_then_probes_for_recovery() {
+        let breaker = CircuitBreaker::new(
+            [0],
+            CircuitBreakerConfig {
+                window: 4,
+                error_rate: 0.5,
+                open_duration: Duration::from_secs(1),
+            },
+        );
+        for failed in [false, true, false] {
+            breaker.acquire().await;
+            breaker.record(failed);
+        }
+        assert!(!breaker.is_open());
+        breaker.acquire().await;
+        breaker.record(true);
+        assert!(breaker.is_open());
+
+        // The probe waits for the open duration and reopens the breaker on failure.
+        breaker.acquire().await;
+        assert!(!breaker.is_open());
+        breaker.record(true);
+        assert!(breaker.is_open());
+
+        breaker.acquire().await;
+        breaker.record(false);
+        assert!(!breaker.is_open());
+        // The error rate starts over once closed.
+        breaker.acquire().await;
+        breaker.record(true);
+        assert!(!breaker.is_open());
+    }
+}
@@ -1,9 +1,26 @@
 use {
-    super::sink::ScyllaSinkConfig,
+    super::{
+        circuit_breaker::CircuitBreakerConfig,
+        consistency_downgrade::ConsistencyDowngradeConfig,
+        error_class::ErrorClassifier,
+        sink::{
+            BufferFullPolicy, CatchUpConfig, FieldSizeLimits, OptionalColumn, PriorityPredicate,
+            ReplicationCheck, RetryPolicy, ScyllaAuth, ScyllaSinkConfig, ShardBufferConfig,
+            ShardFailurePolicy, ShardRuntime, ShardingStrategy, ShutdownFlushPolicy,
+            DEFAULT_RECOVERY_CONCURRENCY,
+        },
+        types::{NestedCodec, ShardId, ShardOffset, Slot},
+    },
     crate::config::ConfigGrpcRequest,
     serde::Deserialize,
-    serde_with::{serde_as, DurationMilliSeconds},
-    std::{net::SocketAddr, time::Duration},
+    serde_with::{serde_as, DurationMilliSeconds, DurationSeconds},
+    std::{
+        collections::{BTreeMap, BTreeSet},
+        net::{IpAddr, SocketAddr},
+        num::NonZeroUsize,
+        path::PathBuf,
+        time::Duration,
+    },
 };
 
 const fn default_batch_len_limit() -> usize {
@@ -18,6 +35,34 @@ const fn default_linger() -> Duration {
     Duration::from_millis(10)
 }
 
+const fn default_min_batch_len() -> usize {
+    1
+}
+
+const fn default_max_linger() -> Duration {
+    Duration::from_millis(100)
+}
+
+const fn default_shard_aware_port() -> bool {
+    true
+}
+
+const fn default_slot_seen_commit_jitter() -> Duration {
+    Duration::ZERO
+}
+
+const fn default_compress_min_bytes() -> usize {
+    256
+}
+
+const fn default_recovery_concurrency() -> usize {
+    DEFAULT_RECOVERY_CONCURRENCY
+}
+
+const fn default_lock_steal_grace() -> f64 {
+    2.0
+}
+
 fn default_scylla_username() -> String {
     "cassandra".into()
 }
@@ -52,6 +97,29 @@ pub struct ScyllaDbConnectionInfo {
     pub username: String,
     #[serde(default = "default_scylla_password")]
     pub password: String,
+    // How to authenticate, `username` and `password` are ignored unless it is `password`.
+    pub auth_method: ScyllaAuthMethod,
+}
+
+impl ScyllaDbConnectionInfo {
+    pub fn auth(&self) -> ScyllaAuth {
+        match self.auth_method {
+            ScyllaAuthMethod::Password => ScyllaAuth::Password {
+                username: self.username.clone(),
+                password: self.password.clone(),
+            },
+            ScyllaAuthMethod::None => ScyllaAuth::None,
+        }
+    }
+}
+
+/// Authentication available from the config file, see [`ScyllaAuth`] for the custom ones.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScyllaAuthMethod {
+    #[default]
+    Password,
+    None,
 }
 
 #[serde_as]
@@ -60,6 +128,8 @@ pub struct ConfigYellowstoneLogServer {
     pub listen: String,
     #[serde(default = "default_keyspace")]
     pub keyspace: String,
+    // Optional keyspace holding the producer metadata tables, the log table stays in `keyspace`.
+    pub control_keyspace: Option<String>,
 }
 
 #[serde_as]
@@ -74,18 +144,249 @@ pub struct ConfigGrpc2ScyllaDB {
     // Optional network interface name used to write in the producer lock table.
     pub ifname: Option<String>,
 
+    // Optional keyspace holding the producer metadata tables, the log table stays in `keyspace`.
+    pub control_keyspace: Option<String>,
+
     #[serde(default = "default_batch_len_limit")]
     pub batch_len_limit: usize,
 
     #[serde(default = "default_batch_size_kb")]
     pub batch_size_kb_limit: usize,
 
+    // Buffering limits of specific shards keyed by shard id, the other shards use the default limits.
+    #[serde(default)]
+    pub shard_buffer_overrides: BTreeMap<ShardId, ShardBufferConfig>,
+
     #[serde(default = "default_linger")]
     #[serde_as(as = "DurationMilliSeconds<u64>")]
     pub linger: Duration,
 
+    // Minimum number of buffered events before `linger` flushes a shard, until `max_linger` elapses.
+    #[serde(default = "default_min_batch_len")]
+    pub min_batch_len: usize,
+
+    #[serde(default = "default_max_linger")]
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    pub max_linger: Duration,
+
     #[serde(default = "default_keyspace")]
     pub keyspace: String,
+
+    #[serde(default)]
+    pub shard_failure_policy: ShardFailurePolicy,
+
+    // Optional cap on the kilobytes buffered across every shard, ingestion pauses once reached.
+    pub max_buffered_kb: Option<usize>,
+
+    // Random delay window before committing a new slot seen, spreads the writes across producers. None by default.
+    #[serde(default = "default_slot_seen_commit_jitter")]
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    pub slot_seen_commit_jitter: Duration,
+
+    // Only commit slots above the recorded high-water mark, costs a read and a lightweight transaction per new slot.
+    #[serde(default)]
+    pub monotonic_slot_seen: bool,
+
+    // Check that a row written to the resume partition of each shard can be read back before starting.
+    #[serde(default)]
+    pub probe_on_start: bool,
+
+    // Optional list of the optional columns to write, e.g. ["executable", "rent_epoch"], all of them if missing.
+    pub column_mask: Option<BTreeSet<OptionalColumn>>,
+
+    // Write log rows with a timestamp derived from the slot of the event instead of the coordinator clock.
+    // These timestamps are far in the past: a row deleted or written with the server clock, by hand or by
+    // reset_on_start, shadows every later write of these rows, see ScyllaSinkConfig::client_write_timestamps.
+    #[serde(default)]
+    pub client_write_timestamps: bool,
+
+    // Route vote transactions through the priority lane of the router, ahead of every other event.
+    #[serde(default)]
+    pub priority_vote_transactions: bool,
+
+    // Optional time to live of the log rows in seconds, from 1 to 630720000 (20 years), rows never expire if missing.
+    #[serde(default)]
+    #[serde_as(as = "Option<DurationSeconds<u64>>")]
+    pub row_ttl: Option<Duration>,
+
+    // Maximum number of shards whose last offset is read, or whose partition is probed, concurrently on startup.
+    #[serde(default = "default_recovery_concurrency")]
+    pub recovery_concurrency: usize,
+
+    // Start even if the producer has fewer shards than in a previous run, abandoning the extra shards.
+    #[serde(default)]
+    pub allow_shard_shrink: bool,
+
+    // Drop account updates superseded by a higher write version within the same slot before sending them.
+    #[serde(default)]
+    pub dedup_account_updates: bool,
+
+    // Optional number of transaction signatures each shard remembers to drop a transaction seen again, best effort:
+    // only duplicates routed to the same shard within the window are dropped, e.g. with the by_pubkey strategy.
+    pub tx_dedup_window: Option<NonZeroUsize>,
+
+    // Optional number of slots an event may lag behind the highest slot seen by its shard, further behind it is
+    // reported with a warning and a metric but still written, forks being legitimate.
+    pub max_slot_regression: Option<Slot>,
+
+    // Optional check of the keyspace replication settings at startup.
+    pub replication_check: Option<ReplicationCheck>,
+
+    // Optional larger batching limits while the sink is far behind the chain tip.
+    pub catch_up: Option<CatchUpConfig>,
+
+    // Compress account update data and packed transaction structures with LZ4 before writing them.
+    #[serde(default)]
+    pub compress_data: bool,
+
+    // Account update data or packed transaction structures smaller than this many bytes are stored raw.
+    #[serde(default = "default_compress_min_bytes")]
+    pub compress_min_bytes: usize,
+
+    // Optional codec packing the nested structures of transactions into one column, "bincode", "protobuf" or "json".
+    pub nested_codec: Option<NestedCodec>,
+
+    // Take over a producer lock left by a previous instance running on the same interface and ip address.
+    #[serde(default)]
+    pub steal_own_host_lock: bool,
+
+    // Optional interface name and ipv4 address written in the producer lock, e.g. `["eth0", "10.0.0.1"]`, when
+    // the network interfaces of the host can not be discovered.
+    pub lock_identity: Option<(String, IpAddr)>,
+
+    // Optional directory of the write-ahead logs of the sink and its shards, replayed on startup after a crash.
+    pub wal_dir: Option<PathBuf>,
+
+    // Sync each command appended to the write-ahead logs to disk, so they also survive a crash of the host.
+    #[serde(default)]
+    pub wal_sync: bool,
+
+    // Optional window in milliseconds during which the period commits of every shard are batched together.
+    #[serde(default)]
+    #[serde_as(as = "Option<DurationMilliSeconds<u64>>")]
+    pub period_commit_coalescing: Option<Duration>,
+
+    // Either "best_effort" or {"at_least_once": <retry policy>} for the final flush of each shard.
+    #[serde(default)]
+    pub shutdown_flush_policy: ShutdownFlushPolicy,
+
+    // Retry policy of the slot seen and period commit writes.
+    #[serde(default)]
+    pub metadata_write_retry: RetryPolicy,
+
+    // Optional circuit breaker failing events fast while most batches fail.
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+
+    // Optional downgrade of the batches to LOCAL_ONE while replicas are unavailable, weakens durability.
+    pub consistency_downgrade: Option<ConsistencyDowngradeConfig>,
+
+    // Optional size limit of a single event in kilobytes, defaults to `batch_size_kb_limit`.
+    pub max_event_kb: Option<usize>,
+
+    // Optional size limits of the account data and transaction log messages, and whether to "reject" or "truncate".
+    #[serde(default)]
+    pub field_size_limits: FieldSizeLimits,
+
+    // Either "shared" to run the shards on the main runtime or "dedicated" for one thread per shard.
+    #[serde(default)]
+    pub shard_runtime: ShardRuntime,
+
+    // How events are spread over the shards: "round_robin" (default), "by_pubkey" or "by_slot".
+    // "by_slot" keeps every event of a slot on one shard, which makes busy slots a hotspot of a single shard.
+    #[serde(default)]
+    pub sharding_strategy: ShardingStrategy,
+
+    // Optional interval in milliseconds between two rebalances of the shards, only "by_pubkey" moves keys from the
+    // busiest shards to the least loaded ones. Routing pauses while the shards losing keys flush.
+    #[serde(default)]
+    #[serde_as(as = "Option<DurationMilliSeconds<u64>>")]
+    pub rebalance_interval: Option<Duration>,
+
+    // Optional number of connections per host, defaults to one connection per shard of each host.
+    pub pool_size_per_host: Option<NonZeroUsize>,
+
+    // Optional interval in milliseconds of the keepalive requests sent on idle connections.
+    #[serde(default)]
+    #[serde_as(as = "Option<DurationMilliSeconds<u64>>")]
+    pub keepalive_interval: Option<Duration>,
+
+    // Connect to the shard-aware port of the nodes, disable it if a NAT or firewall breaks that port.
+    #[serde(default = "default_shard_aware_port")]
+    pub shard_aware_port: bool,
+
+    // Fail a shard whose next write would leave a gap in its offsets. Disabled by default, whatever the build.
+    #[serde(default)]
+    pub strict_offset_contiguity: bool,
+
+    // Also store each event as its geyser protobuf message in `raw_proto`, at the cost of roughly twice the storage.
+    #[serde(default)]
+    pub store_raw_proto: bool,
+
+    // Optional hard deadline in milliseconds of each batch write, a batch past it fails and is retried.
+    #[serde(default)]
+    #[serde_as(as = "Option<DurationMilliSeconds<u64>>")]
+    pub flush_deadline: Option<Duration>,
+
+    // DEVELOPMENT ONLY: delete every event, period commit, slot seen and heartbeat of the producer on start and
+    // restart every shard at offset 0. Refused unless confirm_reset_on_start is also set, never enable it in
+    // production.
+    #[serde(default)]
+    pub reset_on_start: bool,
+
+    // Confirms reset_on_start, acknowledging that every row of the producer is deleted.
+    #[serde(default)]
+    pub confirm_reset_on_start: bool,
+
+    // Optional number of events not yet durable on a shard above which the router waits for the shard to flush
+    // before routing it more, enables a feedback channel from the shards to the router. Must be positive.
+    pub max_shard_durability_lag: Option<ShardOffset>,
+
+    // Optional number of slots per partition of log_by_slot, also writes every event there when set. Recorded in
+    // producer_info by the first sink using it, it can not change afterwards.
+    pub slot_bucket_size: Option<Slot>,
+
+    // Advanced, optional offset at which the producer stops accepting events so it can be rolled to a new producer id.
+    pub max_offset_before_roll: Option<ShardOffset>,
+
+    // Flush every shard before writing the events of a new slot.
+    #[serde(default)]
+    pub flush_on_slot_boundary: bool,
+
+    // Optional interval in milliseconds between two rotations of the producer lock id. The lock is rotated by the
+    // task writing the heartbeat, on its own interval since it must stay below lock_ttl.
+    #[serde(default)]
+    #[serde_as(as = "Option<DurationMilliSeconds<u64>>")]
+    pub lock_rotation_interval: Option<Duration>,
+
+    // Optional time in milliseconds after which a producer lock neither acquired nor rotated is expired, requires
+    // a shorter lock_rotation_interval. A starting producer takes over a lock expired for lock_ttl * lock_steal_grace.
+    #[serde(default)]
+    #[serde_as(as = "Option<DurationMilliSeconds<u64>>")]
+    pub lock_ttl: Option<Duration>,
+
+    // Multiplier of lock_ttl before an expired lock is taken over, at least 1. A larger grace protects a holder
+    // stalled for a moment against a second writer, at the cost of a longer downtime when the holder is dead.
+    #[serde(default = "default_lock_steal_grace")]
+    pub lock_steal_grace: f64,
+
+    // Optional maximum number of tasks running the shards, shards beyond it share a task and flush one after another.
+    pub max_concurrent_shards: Option<NonZeroUsize>,
+
+    // Optional interval in milliseconds at which the producer writes its heartbeat to producer_heartbeat, even when
+    // no events flow, so liveness checks can tell an idle producer from a dead one. Disabled by default.
+    #[serde(default)]
+    #[serde_as(as = "Option<DurationMilliSeconds<u64>>")]
+    pub heartbeat_interval: Option<Duration>,
+
+    // What to do with an event for a saturated shard: "block" (default), or "drop_oldest"/"drop_newest" which lose
+    // events to keep the other shards flowing.
+    #[serde(default)]
+    pub buffer_full_policy: BufferFullPolicy,
+
+    // Commit the periods of producer_period_commit_log as soon as a batch completes them, instead of once the
+    // next period starts, so readers of the commit log see the last offset of a shard earlier. Disabled by default.
+    #[serde(default)]
+    pub commit_offsets: bool,
 }
 
 impl ConfigGrpc2ScyllaDB {
@@ -94,9 +395,70 @@ impl ConfigGrpc2ScyllaDB {
             producer_id: self.producer_id,
             batch_len_limit: self.batch_len_limit,
             batch_size_kb_limit: self.batch_size_kb_limit,
+            shard_buffer_overrides: self.shard_buffer_overrides.clone(),
             linger: self.linger,
+            min_batch_len: self.min_batch_len,
+            max_linger: self.max_linger,
             keyspace: self.keyspace.clone(),
             ifname: self.ifname.to_owned(),
+            control_keyspace: self.control_keyspace.clone(),
+            shard_failure_policy: self.shard_failure_policy,
+            max_buffered_kb: self.max_buffered_kb,
+            slot_seen_commit_jitter: self.slot_seen_commit_jitter,
+            monotonic_slot_seen: self.monotonic_slot_seen,
+            probe_on_start: self.probe_on_start,
+            column_mask: self.column_mask.clone(),
+            client_write_timestamps: self.client_write_timestamps,
+            row_ttl: self.row_ttl,
+            recovery_concurrency: self.recovery_concurrency,
+            allow_shard_shrink: self.allow_shard_shrink,
+            shard_router: self.sharding_strategy.shard_router(),
+            rebalance_interval: self.rebalance_interval,
+            priority_predicate: self
+                .priority_vote_transactions
+                .then(PriorityPredicate::vote_transactions),
+            dedup_account_updates: self.dedup_account_updates,
+            tx_dedup_window: self.tx_dedup_window,
+            max_slot_regression: self.max_slot_regression,
+            replication_check: self.replication_check.clone(),
+            catch_up: self.catch_up.clone(),
+            compress_data: self.compress_data,
+            compress_min_bytes: self.compress_min_bytes,
+            nested_codec: self.nested_codec,
+            steal_own_host_lock: self.steal_own_host_lock,
+            ack_stream: false,
+            strict_offset_contiguity: self.strict_offset_contiguity,
+            store_raw_proto: self.store_raw_proto,
+            flush_deadline: self.flush_deadline,
+            reset_on_start: self.reset_on_start,
+            confirm_reset_on_start: self.confirm_reset_on_start,
+            max_shard_durability_lag: self.max_shard_durability_lag,
+            slot_bucket_size: self.slot_bucket_size,
+            lock_identity: self.lock_identity.clone(),
+            wal_dir: self.wal_dir.clone(),
+            wal_sync: self.wal_sync,
+            period_commit_coalescing: self.period_commit_coalescing,
+            shutdown_flush_policy: self.shutdown_flush_policy,
+            metadata_write_retry: self.metadata_write_retry,
+            circuit_breaker: self.circuit_breaker,
+            consistency_downgrade: self.consistency_downgrade,
+            max_event_kb: self.max_event_kb,
+            field_size_limits: self.field_size_limits,
+            shard_runtime: self.shard_runtime,
+            pool_size_per_host: self.pool_size_per_host,
+            keepalive_interval: self.keepalive_interval,
+            shard_aware_port: self.shard_aware_port,
+            lock_rotation_interval: self.lock_rotation_interval,
+            lock_ttl: self.lock_ttl,
+            lock_steal_grace: self.lock_steal_grace,
+            max_concurrent_shards: self.max_concurrent_shards,
+            heartbeat_interval: self.heartbeat_interval,
+            buffer_full_policy: self.buffer_full_policy,
+            error_classifier: ErrorClassifier::default(),
+            offset_committer: None,
+            commit_offsets: self.commit_offsets,
+            flush_on_slot_boundary: self.flush_on_slot_boundary,
+            max_offset_before_roll: self.max_offset_before_roll,
         }
     }
 }
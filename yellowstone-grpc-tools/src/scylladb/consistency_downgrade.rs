@@ -0,0 +1,209 @@
+use {
+    super::{
+        error_class::{ErrorClass, ErrorClassifier},
+        prom::scylladb_consistency_downgrade_inc,
+        types::ProducerId,
+    },
+    scylla::{statement::Consistency, transport::errors::QueryError},
+    serde::Deserialize,
+    serde_with::{serde_as, DurationMilliSeconds},
+    std::{sync::Mutex, time::Duration},
+    tokio::time::Instant,
+    tracing::{info, warn},
+};
+
+/// Consistency of the batches written while downgraded.
+pub const DOWNGRADED_CONSISTENCY: Consistency = Consistency::LocalOne;
+
+const fn default_unavailable_threshold() -> usize {
+    3
+}
+
+const fn default_downgrade_duration() -> Duration {
+    Duration::from_secs(30)
+}
+
+/// Settings of the consistency downgrade, see [`ConsistencyDowngrade`].
+#[serde_as]
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Deserialize)]
+pub struct ConsistencyDowngradeConfig {
+    /// Number of consecutive `Unavailable` errors at the configured consistency before downgrading.
+    #[serde(default = "default_unavailable_threshold")]
+    pub unavailable_threshold: usize,
+    /// How long batches are written at `LOCAL_ONE` in milliseconds before trying the configured consistency again.
+    #[serde(default = "default_downgrade_duration")]
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    pub duration: Duration,
+}
+
+impl Default for ConsistencyDowngradeConfig {
+    fn default() -> Self {
+        ConsistencyDowngradeConfig {
+            unavailable_threshold: default_unavailable_threshold(),
+            duration: default_downgrade_duration(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum DowngradeState {
+    /// Batches are written at the configured consistency.
+    Healthy {
+        unavailable: usize,
+    },
+    Downgraded {
+        until: Instant,
+    },
+    /// The downgrade expired, the next batch tells whether the cluster recovered.
+    Probing,
+}
+
+/// Downgrade shared by the shards of a sink, writing the batches at [`DOWNGRADED_CONSISTENCY`] while the
+/// cluster lacks the replicas the configured consistency needs.
+///
+/// After `unavailable_threshold` consecutive `Unavailable` errors, batches are written at `LOCAL_ONE` for
+/// `duration`. The next batch is then written at the configured consistency again: the downgrade is lifted if it
+/// succeeds and starts over if the replicas are still missing.
+#[derive(Debug)]
+pub(crate) struct ConsistencyDowngrade {
+    producer_id: ProducerId,
+    config: ConsistencyDowngradeConfig,
+    state: Mutex<DowngradeState>,
+}
+
+impl ConsistencyDowngrade {
+    pub(crate) const fn new(producer_id: ProducerId, config: ConsistencyDowngradeConfig) -> Self {
+        ConsistencyDowngrade {
+            producer_id,
+            config,
+            state: Mutex::new(DowngradeState::Healthy { unavailable: 0 }),
+        }
+    }
+
+    /// Returns true if the next batch must be written at [`DOWNGRADED_CONSISTENCY`].
+    pub(crate) fn is_downgraded(&self) -> bool {
+        let mut state = self.state.lock().expect("poisoned");
+        match *state {
+            DowngradeState::Downgraded { until } if Instant::now() >= until => {
+                *state = DowngradeState::Probing;
+                false
+            }
+            DowngradeState::Downgraded { .. } => true,
+            DowngradeState::Healthy { .. } | DowngradeState::Probing => false,
+        }
+    }
+
+    /// Records the outcome of a batch written at the consistency given by [`ConsistencyDowngrade::is_downgraded`].
+    ///
+    /// Returns true if the batch failed because replicas are unavailable, as told by `classifier`, and should be
+    /// written again right away, at whatever consistency applies now.
+    pub(crate) fn record<T>(
+        &self,
+        result: &Result<T, QueryError>,
+        classifier: &ErrorClassifier,
+        downgraded: bool,
+    ) -> bool {
+        let unavailable = matches!(
+            result,
+            Err(e) if classifier.classify(e) == ErrorClass::Unavailable
+        );
+        if downgraded {
+            // Failures at the lowest consistency are left to the caller.
+            return false;
+        }
+        let mut state = self.state.lock().expect("poisoned");
+        match (*state, unavailable) {
+            // Any other error leaves the cluster health undecided.
+            (DowngradeState::Probing, false) if result.is_err() => false,
+            (DowngradeState::Probing, false) => {
+                info!(
+                    "producer {:?} writes are back to the configured consistency",
+                    self.producer_id
+                );
+                *state = DowngradeState::Healthy { unavailable: 0 };
+                false
+            }
+            (DowngradeState::Healthy { .. }, false) => {
+                *state = DowngradeState::Healthy { unavailable: 0 };
+                false
+            }
+            (DowngradeState::Healthy { unavailable }, true)
+                if unavailable + 1 < self.config.unavailable_threshold =>
+            {
+                *state = DowngradeState::Healthy {
+                    unavailable: unavailable + 1,
+                };
+                true
+            }
+            (DowngradeState::Healthy { .. } | DowngradeState::Probing, true) => {
+                warn!(
+                    "producer {:?} replicas are unavailable, writing at {DOWNGRADED_CONSISTENCY:?} for {:?}",
+                    self.producer_id, self.config.duration
+                );
+                scylladb_consistency_downgrade_inc(self.producer_id);
+                *state = DowngradeState::Downgraded {
+                    until: Instant::now() + self.config.duration,
+                };
+                true
+            }
+            // Another shard downgraded while this batch was in flight.
+            (DowngradeState::Downgraded { .. }, _) => unavailable,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{ConsistencyDowngrade, ConsistencyDowngradeConfig},
+        crate::scylladb::error_class::ErrorClassifier,
+        scylla::{
+            statement::Consistency,
+            transport::errors::{DbError, QueryError},
+        },
+        std::time::Duration,
+    };
+
+    fn unavailable() -> Result<(), QueryError> {
+        Err(QueryError::DbError(
+            DbError::Unavailable {
+                consistency: Consistency::LocalQuorum,
+                required: 2,
+                alive: 1,
+            },
+            "unavailable".to_owned(),
+        ))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn downgrade_after_repeated_unavailable_then_recover() {
+        let classifier = ErrorClassifier::default();
+        let downgrade = ConsistencyDowngrade::new(
+            [0],
+            ConsistencyDowngradeConfig {
+                unavailable_threshold: 2,
+                duration: Duration::from_secs(1),
+            },
+        );
+        assert!(downgrade.record(&unavailable(), &classifier, false));
+        assert!(!downgrade.is_downgraded());
+        assert!(downgrade.record(&unavailable(), &classifier, false));
+        assert!(downgrade.is_downgraded());
+        // Failures at the lowest consistency are not retried.
+        assert!(!downgrade.record(&unavailable(), &classifier, true));
+
+        // Once expired, a single unavailable error downgrades again.
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(!downgrade.is_downgraded());
+        assert!(downgrade.record(&unavailable(), &classifier, false));
+        assert!(downgrade.is_downgraded());
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(!downgrade.is_downgraded());
+        assert!(!downgrade.record(&Ok(()), &classifier, false));
+        assert!(!downgrade.is_downgraded());
+        // The count of unavailable errors starts over once recovered.
+        assert!(downgrade.record(&unavailable(), &classifier, false));
+        assert!(!downgrade.is_downgraded());
+    }
+}
@@ -6,8 +6,8 @@ use {
     crate::scylladb::{
         sink,
         types::{
-            BlockchainEventType, ProducerId, ProducerInfo, ShardId, ShardOffset, MAX_PRODUCER,
-            MIN_PROCUDER,
+            BlockchainEvent, BlockchainEventType, ProducerId, ProducerInfo, ShardId, ShardOffset,
+            MAX_PRODUCER, MIN_PROCUDER,
         },
     },
     chrono::{DateTime, TimeDelta, Utc},
@@ -146,7 +146,9 @@ pub const INSERT_CONSUMER_PRODUCER_MAPPING: &str = r###"
 pub const GET_PRODUCER_INFO_BY_ID_OR_ANY: &str = r###"
     SELECT
         producer_id,
-        num_shards
+        num_shards,
+        partitions_per_period,
+        slot_bucket_size
     FROM producer_info
     WHERE producer_id >= ? and producer_id <= ?
     LIMIT 1
@@ -198,9 +200,15 @@ pub async fn get_producer_id_for_consumer(
 ///
 /// Returns a list of producer that has a lock
 ///
-async fn list_producers_with_lock_held(session: Arc<Session>) -> anyhow::Result<Vec<ProducerId>> {
+async fn list_producers_with_lock_held(
+    session: Arc<Session>,
+    control_keyspace: Option<&str>,
+) -> anyhow::Result<Vec<ProducerId>> {
     session
-        .query(LIST_PRODUCERS_WITH_LOCK, &[])
+        .query(
+            sink::in_control_keyspace(LIST_PRODUCERS_WITH_LOCK, control_keyspace),
+            &[],
+        )
         .await?
         .rows_typed::<(ProducerId,)>()?
         .map(|result| result.map(|row| row.0))
@@ -211,6 +219,7 @@ async fn list_producers_with_lock_held(session: Arc<Session>) -> anyhow::Result<
 async fn list_producers_heartbeat(
     session: Arc<Session>,
     heartbeat_time_dt: Duration,
+    control_keyspace: Option<&str>,
 ) -> anyhow::Result<Vec<ProducerId>> {
     let utc_now = Utc::now();
     let min_last_heartbeat = utc_now
@@ -218,7 +227,10 @@ async fn list_producers_heartbeat(
         .ok_or(anyhow::anyhow!("Invalid heartbeat time delta"))?;
 
     let producer_id_with_last_hb_datetime_pairs = session
-        .query(LIST_PRODUCER_LAST_HEARBEAT, &[])
+        .query(
+            sink::in_control_keyspace(LIST_PRODUCER_LAST_HEARBEAT, control_keyspace),
+            &[],
+        )
         .await?
         .rows_typed::<(ProducerId, DateTime<Utc>)>()?
         //.map(|result| result.map(|row| row.0))
@@ -237,12 +249,19 @@ async fn list_producers_heartbeat(
 ///
 async fn get_producer_id_with_least_assigned_consumer(
     session: Arc<Session>,
+    control_keyspace: Option<&str>,
 ) -> anyhow::Result<ProducerId> {
-    let locked_producers = list_producers_with_lock_held(Arc::clone(&session)).await?;
+    let locked_producers =
+        list_producers_with_lock_held(Arc::clone(&session), control_keyspace).await?;
 
     info!("{} producer lock(s) detected", locked_producers.len());
     let recently_active_producers = BTreeSet::from_iter(
-        list_producers_heartbeat(Arc::clone(&session), DEFAULT_LAST_HEARTBEAT_TIME_DELTA).await?,
+        list_producers_heartbeat(
+            Arc::clone(&session),
+            DEFAULT_LAST_HEARTBEAT_TIME_DELTA,
+            control_keyspace,
+        )
+        .await?,
     );
 
     info!(
@@ -278,13 +297,15 @@ async fn get_producer_id_with_least_assigned_consumer(
 
 ///
 /// Returns a specific producer information by id or return a random producer_info if `producer_id` is None.
+/// `producer_info` is read from `control_keyspace` if any, the session keyspace otherwise.
 pub async fn get_producer_info_by_id_or_any(
     session: Arc<Session>,
     producer_id: Option<ProducerId>,
+    control_keyspace: Option<&str>,
 ) -> anyhow::Result<Option<ProducerInfo>> {
     let qr = session
         .query(
-            GET_PRODUCER_INFO_BY_ID_OR_ANY,
+            sink::in_control_keyspace(GET_PRODUCER_INFO_BY_ID_OR_ANY, control_keyspace),
             (
                 producer_id.unwrap_or(MIN_PROCUDER),
                 producer_id.unwrap_or(MAX_PRODUCER),
@@ -309,16 +330,34 @@ fn get_blockchain_event_types(
             BlockchainEventType::AccountUpdate,
             BlockchainEventType::NewTransaction,
         ],
+        EventSubscriptionPolicy::BlockMetaOnly => vec![BlockchainEventType::BlockMeta],
+        EventSubscriptionPolicy::All => vec![
+            BlockchainEventType::AccountUpdate,
+            BlockchainEventType::NewTransaction,
+            BlockchainEventType::BlockMeta,
+        ],
     }
 }
 
+/// Converts an event read from the log into the geyser update streamed to consumers.
+fn into_update_oneof(event: BlockchainEvent) -> anyhow::Result<UpdateOneof> {
+    Ok(match event.event_type {
+        BlockchainEventType::AccountUpdate => UpdateOneof::Account(event.try_into()?),
+        BlockchainEventType::NewTransaction => UpdateOneof::Transaction(event.try_into()?),
+        BlockchainEventType::BlockMeta => UpdateOneof::BlockMeta(event.try_into()?),
+    })
+}
+
 async fn register_new_consumer(
     session: Arc<Session>,
     consumer_id: impl AsRef<str>,
     initial_offset_policy: InitialOffsetPolicy,
     event_sub_policy: EventSubscriptionPolicy,
+    control_keyspace: Option<&str>,
 ) -> anyhow::Result<ConsumerInfo> {
-    let producer_id = get_producer_id_with_least_assigned_consumer(Arc::clone(&session)).await?;
+    let producer_id =
+        get_producer_id_with_least_assigned_consumer(Arc::clone(&session), control_keyspace)
+            .await?;
 
     let insert_consumer_mapping_ps = session.prepare(INSERT_CONSUMER_PRODUCER_MAPPING).await?;
     session
@@ -339,6 +378,7 @@ async fn register_new_consumer(
         producer_id,
         initial_offset_policy,
         event_sub_policy,
+        control_keyspace,
     )
     .await?;
     let cs = ConsumerInfo {
@@ -359,6 +399,7 @@ async fn get_or_register_consumer(
     consumer_id: impl AsRef<str>,
     initial_offset_policy: InitialOffsetPolicy,
     event_sub_policy: EventSubscriptionPolicy,
+    control_keyspace: Option<&str>,
 ) -> anyhow::Result<ConsumerInfo> {
     let maybe_producer_id =
         get_producer_id_for_consumer(Arc::clone(&session), consumer_id.as_ref()).await?;
@@ -396,6 +437,7 @@ async fn get_or_register_consumer(
             consumer_id,
             initial_offset_policy,
             event_sub_policy,
+            control_keyspace,
         )
         .await
     }
@@ -411,11 +453,17 @@ async fn set_initial_consumer_shard_offsets(
     producer_id: ProducerId,
     initial_offset_policy: InitialOffsetPolicy,
     event_sub_policy: EventSubscriptionPolicy,
+    control_keyspace: Option<&str>,
 ) -> anyhow::Result<Vec<(ShardId, BlockchainEventType, ShardOffset)>> {
     // Create all the shards counter
-    let producer_info = get_producer_info_by_id_or_any(Arc::clone(&session), Some(producer_id))
-        .await?
-        .unwrap_or_else(|| panic!("Producer Info `{:?}` must exists", producer_id));
+    let producer_info =
+        get_producer_info_by_id_or_any(Arc::clone(&session), Some(producer_id), control_keyspace)
+            .await?
+            .unwrap_or_else(|| panic!("Producer Info `{:?}` must exists", producer_id));
+    anyhow::ensure!(
+        producer_info.partitions_per_period() == 1,
+        "producer {producer_id:?} splits its periods into several partitions, which consumers can not read yet"
+    );
 
     let num_shards = producer_info.num_shards;
 
@@ -423,8 +471,8 @@ async fn set_initial_consumer_shard_offsets(
         InitialOffsetPolicy::Latest => {
             sink::get_max_shard_offsets_for_producer(
                 Arc::clone(&session),
-                producer_id,
-                num_shards as usize,
+                &producer_info,
+                control_keyspace,
             )
             .await?
         }
@@ -485,11 +533,21 @@ async fn set_initial_consumer_shard_offsets(
 
 pub struct ScyllaYsLog {
     session: Arc<Session>,
+    control_keyspace: Option<String>,
 }
 
 impl ScyllaYsLog {
     pub fn new(session: Arc<Session>) -> Self {
-        ScyllaYsLog { session }
+        ScyllaYsLog {
+            session,
+            control_keyspace: None,
+        }
+    }
+
+    /// Reads the producer metadata tables from `control_keyspace`, the keyspace of the session by default.
+    pub fn with_control_keyspace(mut self, control_keyspace: Option<String>) -> Self {
+        self.control_keyspace = control_keyspace;
+        self
     }
 }
 
@@ -538,6 +596,7 @@ impl YellowstoneLog for ScyllaYsLog {
             tx_event_filter,
             buffer_capacity: None,
             offset_commit_interval: None,
+            control_keyspace: self.control_keyspace.clone(),
         };
 
         let rx = spawn_grpc_consumer(
@@ -572,6 +631,8 @@ pub struct SpawnGrpcConsumerReq {
     pub tx_event_filter: Option<yellowstone_grpc_proto::yellowstone::log::TransactionEventFilter>,
     pub buffer_capacity: Option<usize>,
     pub offset_commit_interval: Option<Duration>,
+    // Optional keyspace of the producer metadata tables, the keyspace of the session if missing.
+    pub control_keyspace: Option<String>,
 }
 
 type GrpcConsumerReceiver = mpsc::Receiver<Result<SubscribeUpdate, tonic::Status>>;
@@ -587,6 +648,7 @@ pub async fn spawn_grpc_consumer(
         req.consumer_id.as_str(),
         initial_offset_policy,
         event_subscription_policy,
+        req.control_keyspace.as_deref(),
     )
     .await
     .map_err(|e| {
@@ -633,6 +695,7 @@ pub async fn spawn_grpc_consumer(
                 // The ev_type will dictate if shard iterator streams account update or transaction.
                 ev_type,
                 Some(shard_filter),
+                req.control_keyspace.as_deref(),
             )
         },
     ))
@@ -808,17 +871,11 @@ impl GrpcConsumerSource {
                         max_seen_slot = block_chain_event.slot;
                         num_event_between_two_slots = 0;
                     }
-                    let geyser_event = match block_chain_event.event_type {
-                        BlockchainEventType::AccountUpdate => {
-                            UpdateOneof::Account(block_chain_event.try_into()?)
-                        }
-                        BlockchainEventType::NewTransaction => {
-                            UpdateOneof::Transaction(block_chain_event.try_into()?)
-                        }
-                    };
+                    let geyser_event = into_update_oneof(block_chain_event)?;
                     let subscribe_update = SubscribeUpdate {
                         filters: Default::default(),
                         update_oneof: Some(geyser_event),
+                        timestamp: None,
                     };
                     let t_send = Instant::now();
 
@@ -863,3 +920,43 @@ impl GrpcConsumerSource {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{get_blockchain_event_types, into_update_oneof},
+        crate::scylladb::types::{BlockMeta, BlockchainEventType},
+        yellowstone_grpc_proto::{
+            geyser::subscribe_update::UpdateOneof, yellowstone::log::EventSubscriptionPolicy,
+        },
+    };
+
+    #[test]
+    fn block_meta_subscription_streams_the_block_metas_of_the_log() {
+        assert_eq!(
+            get_blockchain_event_types(EventSubscriptionPolicy::BlockMetaOnly),
+            vec![BlockchainEventType::BlockMeta]
+        );
+        assert!(get_blockchain_event_types(EventSubscriptionPolicy::All)
+            .contains(&BlockchainEventType::BlockMeta));
+
+        let expected = BlockMeta {
+            slot: 42,
+            blockhash: "5VYF3GMPbqbsyMVGFcg8mK3dKBnCsNVrcXNE5xFDkKyA".to_owned(),
+            parent_slot: 41,
+            parent_blockhash: "8PGeYCp6VRzAqyWHXgAW7pszbLWKCZN4y8xKRjHdUNdz".to_owned(),
+            block_height: Some(40),
+            block_time: Some(1_700_000_000),
+            rewards: vec![],
+            executed_transaction_count: 3,
+            entries_count: 2,
+            ingested_at: None,
+            raw_proto: None,
+        };
+        let event = expected.clone().as_blockchain_event(0, [0], 7);
+        let Ok(UpdateOneof::BlockMeta(msg)) = into_update_oneof(event) else {
+            panic!("a block meta event must be streamed as a block meta update");
+        };
+        assert_eq!(BlockMeta::try_from(msg).unwrap(), expected);
+    }
+}
@@ -1,3 +1,5 @@
 pub mod common;
 pub mod grpc;
+pub mod offset_registry;
 mod shard_iterator;
+pub mod source;
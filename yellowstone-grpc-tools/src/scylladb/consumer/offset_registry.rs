@@ -0,0 +1,69 @@
+//!
+//! Checkpoints of consumers reading the log on their own, one offset per consumer and producer shard.
+//!
+//! The registry is optional, it requires the `consumer_offset` table (see `solana.cql`):
+//!
+//! ```cql
+//! create table if not exists solana.consumer_offset (
+//!     consumer_id text,
+//!     producer_id blob,
+//!     shard_id smallint,
+//!     offset bigint,
+//!     updated_at timestamp,
+//!     PRIMARY KEY ((consumer_id, producer_id), shard_id)
+//! );
+//! ```
+//!
+//! Unlike `consumer_info`, which is owned by the gRPC consumer, nothing in this crate writes to it on its own.
+//!
+use {
+    crate::scylladb::types::{ProducerId, ShardId, ShardOffset},
+    scylla::Session,
+    std::sync::Arc,
+};
+
+const COMMIT_CONSUMER_OFFSET: &str = r###"
+    INSERT INTO consumer_offset (consumer_id, producer_id, shard_id, offset, updated_at)
+    VALUES (?, ?, ?, ?, currentTimestamp())
+"###;
+
+const GET_CONSUMER_OFFSET: &str = r###"
+    SELECT
+        offset
+    FROM consumer_offset
+    WHERE consumer_id = ? AND producer_id = ? AND shard_id = ?
+"###;
+
+/// Records `offset` as the last offset `consumer_id` has processed in a shard of `producer_id`.
+///
+/// The last commit wins, callers must commit offsets of a shard in order.
+pub async fn commit_consumer_offset(
+    session: Arc<Session>,
+    consumer_id: &str,
+    producer_id: ProducerId,
+    shard_id: ShardId,
+    offset: ShardOffset,
+) -> anyhow::Result<()> {
+    session
+        .query(
+            COMMIT_CONSUMER_OFFSET,
+            (consumer_id, producer_id, shard_id, offset),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Returns the last offset committed by `consumer_id` in a shard of `producer_id`, `None` if it never committed.
+pub async fn get_consumer_offset(
+    session: Arc<Session>,
+    consumer_id: &str,
+    producer_id: ProducerId,
+    shard_id: ShardId,
+) -> anyhow::Result<Option<ShardOffset>> {
+    let offset = session
+        .query(GET_CONSUMER_OFFSET, (consumer_id, producer_id, shard_id))
+        .await?
+        .maybe_first_row_typed::<(ShardOffset,)>()?
+        .map(|(offset,)| offset);
+    Ok(offset)
+}
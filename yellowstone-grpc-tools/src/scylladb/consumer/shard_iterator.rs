@@ -1,10 +1,16 @@
 use {
-    crate::scylladb::types::{
-        BlockchainEvent, BlockchainEventType, ProducerId, ShardId, ShardOffset, ShardPeriod,
-        SHARD_OFFSET_MODULO,
+    crate::scylladb::{
+        sink::in_control_keyspace,
+        types::{
+            BlockchainEvent, BlockchainEventType, ProducerId, ShardId, ShardOffset, ShardPeriod,
+            SHARD_OFFSET_MODULO,
+        },
     },
     core::fmt,
-    scylla::{prepared_statement::PreparedStatement, Session},
+    scylla::{
+        cql_to_rust::FromRowError, frame::response::result::Row,
+        prepared_statement::PreparedStatement, FromRow, Session,
+    },
     std::{collections::VecDeque, sync::Arc},
     tokio::sync::oneshot::{self, error::TryRecvError},
     tracing::warn,
@@ -28,6 +34,7 @@ pub const GET_NEW_TRANSACTION_EVENT: &str = r###"
         rent_epoch,
         write_version,
         data,
+        data_codec,
         txn_signature,
 
         signature,
@@ -42,7 +49,22 @@ pub const GET_NEW_TRANSACTION_EVENT: &str = r###"
         address_table_lookups,
         meta,
         is_vote,
-        tx_index
+        tx_index,
+        ingested_at,
+        truncated,
+        tx_nested,
+        tx_nested_codec,
+        tx_nested_compression,
+        tx_success,
+        tx_err,
+        blockhash,
+        parent_slot,
+        parent_blockhash,
+        block_height,
+        block_time,
+        rewards,
+        executed_transaction_count,
+        entries_count
     FROM log
     WHERE producer_id = ? and shard_id = ? and offset > ? and period = ?
     and event_type = 1
@@ -50,7 +72,7 @@ pub const GET_NEW_TRANSACTION_EVENT: &str = r###"
     ALLOW FILTERING
 "###;
 
-const GET_LAST_SHARD_PERIOD_COMMIT: &str = r###"
+pub(crate) const GET_LAST_SHARD_PERIOD_COMMIT: &str = r###"
     SELECT
         period
     FROM producer_period_commit_log
@@ -71,7 +93,10 @@ enum ShardIteratorState {
     Empty(ShardOffset),
 
     /// The iterator is in the process of loading blockchain events from the shard.
-    Loading(ShardOffset, oneshot::Receiver<VecDeque<BlockchainEvent>>),
+    Loading(
+        ShardOffset,
+        oneshot::Receiver<anyhow::Result<VecDeque<BlockchainEvent>>>,
+    ),
 
     /// The iterator has loaded blockchain events and is ready for retrieval.
     Loaded(ShardOffset, VecDeque<BlockchainEvent>),
@@ -148,6 +173,7 @@ pub(crate) struct ShardIterator {
 /// Represents an iterator for fetching and processing blockchain events from a specific shard.
 /// The iterator fetch "micro batch" at a time.
 impl ShardIterator {
+    /// The period commits are read from `control_keyspace` if any, the session keyspace otherwise.
     pub(crate) async fn new(
         session: Arc<Session>,
         producer_id: ProducerId,
@@ -155,15 +181,28 @@ impl ShardIterator {
         offset: ShardOffset,
         event_type: BlockchainEventType,
         filter: Option<ShardFilter>,
+        control_keyspace: Option<&str>,
     ) -> anyhow::Result<Self> {
-        let get_events_ps = if event_type == BlockchainEventType::AccountUpdate {
-            let query_str = forge_account_upadate_event_query(filter.clone().unwrap_or_default());
-            session.prepare(query_str).await?
-        } else {
-            session.prepare(GET_NEW_TRANSACTION_EVENT).await?
+        let get_events_ps = match event_type {
+            BlockchainEventType::AccountUpdate => {
+                let query_str =
+                    forge_account_upadate_event_query(filter.clone().unwrap_or_default());
+                session.prepare(query_str).await?
+            }
+            BlockchainEventType::NewTransaction => {
+                session.prepare(GET_NEW_TRANSACTION_EVENT).await?
+            }
+            BlockchainEventType::BlockMeta => {
+                session.prepare(forge_block_meta_event_query()).await?
+            }
         };
 
-        let get_last_shard_period_commit = session.prepare(GET_LAST_SHARD_PERIOD_COMMIT).await?;
+        let get_last_shard_period_commit = session
+            .prepare(in_control_keyspace(
+                GET_LAST_SHARD_PERIOD_COMMIT,
+                control_keyspace,
+            ))
+            .await?;
 
         Ok(ShardIterator {
             session,
@@ -189,7 +228,7 @@ impl ShardIterator {
         }
         let last_offset = self.inner.last_offset();
 
-        let micro_batch = self.fetch_micro_batch(last_offset).await?;
+        let micro_batch = self.fetch_micro_batch(last_offset).await??;
         let new_state = ShardIteratorState::AvailableData(last_offset, micro_batch);
         self.inner = new_state;
         Ok(())
@@ -223,10 +262,12 @@ impl ShardIterator {
     }
 
     /// Fetches a micro batch of blockchain events starting from the given last offset.
+    ///
+    /// A failed query or an event which can not be decompressed or decoded fails the micro batch.
     fn fetch_micro_batch(
         &self,
         last_offset: ShardOffset,
-    ) -> oneshot::Receiver<VecDeque<BlockchainEvent>> {
+    ) -> oneshot::Receiver<anyhow::Result<VecDeque<BlockchainEvent>>> {
         let period = (last_offset + 1) / SHARD_OFFSET_MODULO;
         let producer_id = self.producer_id;
         let ps = self.get_events_prepared_stmt.clone();
@@ -234,13 +275,25 @@ impl ShardIterator {
         let session = Arc::clone(&self.session);
         let (sender, receiver) = oneshot::channel();
         tokio::spawn(async move {
-            let micro_batch = session
-                .execute(&ps, (producer_id, shard_id, last_offset, period))
-                .await
-                .expect("failed to fetch micro batch from scylladb")
-                .rows_typed_or_empty::<BlockchainEvent>()
-                .collect::<Result<VecDeque<_>, _>>()
-                .expect("failed to typed scylladb rows");
+            let micro_batch = async {
+                session
+                    .execute(&ps, (producer_id, shard_id, last_offset, period))
+                    .await?
+                    .rows_typed_or_empty::<LogRow>()
+                    .map(|row| {
+                        let LogRow(mut event) = row?;
+                        event.decompress_data()?;
+                        event.decode_nested()?;
+                        Ok(event)
+                    })
+                    .collect::<anyhow::Result<VecDeque<_>>>()
+            }
+            .await
+            .map_err(|e| {
+                e.context(format!(
+                    "shard iterator {shard_id} failed to fetch the micro batch after offset {last_offset}"
+                ))
+            });
             if sender.send(micro_batch).is_err() {
                 warn!("Shard iterator {shard_id} was fetching micro batch, but client closed its stream half.")
             }
@@ -301,7 +354,9 @@ impl ShardIterator {
                         (ShardIteratorState::Loading(last_offset, receiver), None)
                     }
                     Err(TryRecvError::Closed) => anyhow::bail!("failed to receive micro batch"),
-                    Ok(micro_batch) => (ShardIteratorState::Loaded(last_offset, micro_batch), None),
+                    Ok(micro_batch) => {
+                        (ShardIteratorState::Loaded(last_offset, micro_batch?), None)
+                    }
                 }
             }
             ShardIteratorState::Loaded(last_offset, mut micro_batch) => {
@@ -388,7 +443,9 @@ const LOG_PRIMARY_KEY_CONDITION: &str = r###"
     producer_id = ? and shard_id = ? and offset > ? and period = ?
 "###;
 
-const LOG_PROJECTION: &str = r###"
+/// Columns of `log` read into a [`BlockchainEvent`], every one but `raw_proto` which readers opt in to, see
+/// [`log_projection`].
+pub(crate) const LOG_PROJECTION: &str = r###"
     shard_id,
     period,
     producer_id,
@@ -402,6 +459,7 @@ const LOG_PROJECTION: &str = r###"
     rent_epoch,
     write_version,
     data,
+    data_codec,
     txn_signature,
     signature,
     signatures,
@@ -415,9 +473,49 @@ const LOG_PROJECTION: &str = r###"
     address_table_lookups,
     meta,
     is_vote,
-    tx_index
+    tx_index,
+    ingested_at,
+    truncated,
+    tx_nested,
+    tx_nested_codec,
+    tx_nested_compression,
+    tx_success,
+    tx_err,
+    blockhash,
+    parent_slot,
+    parent_blockhash,
+    block_height,
+    block_time,
+    rewards,
+    executed_transaction_count,
+    entries_count
 "###;
 
+/// Number of columns of [`LOG_PROJECTION`].
+const LOG_PROJECTION_LEN: usize = 43;
+
+/// Returns the columns of `log` to read, [`LOG_PROJECTION`] followed by `raw_proto` if `with_raw_proto` is set.
+pub(crate) fn log_projection(with_raw_proto: bool) -> String {
+    if with_raw_proto {
+        format!("{LOG_PROJECTION}, raw_proto")
+    } else {
+        LOG_PROJECTION.to_owned()
+    }
+}
+
+/// Event read from `log`, with or without its `raw_proto` column.
+pub(crate) struct LogRow(pub(crate) BlockchainEvent);
+
+impl FromRow for LogRow {
+    fn from_row(mut row: Row) -> Result<Self, FromRowError> {
+        // raw_proto is the last field of the event, left null when it was not read.
+        if row.columns.len() == LOG_PROJECTION_LEN {
+            row.columns.push(None);
+        }
+        BlockchainEvent::from_row(row).map(LogRow)
+    }
+}
+
 fn format_as_scylla_hexstring(bytes: &[u8]) -> String {
     if bytes.is_empty() {
         panic!("byte slice is empty")
@@ -473,3 +571,36 @@ fn forge_account_upadate_event_query(filter: ShardFilter) -> String {
         batch_size = MICRO_BATCH_SIZE,
     )
 }
+
+fn forge_block_meta_event_query() -> String {
+    format!(
+        r###"
+        SELECT
+        {projection}
+        FROM log
+        WHERE {primary_key_cond}
+        AND event_type = 2
+        ORDER BY offset ASC
+        LIMIT {batch_size}
+        ALLOW FILTERING
+        "###,
+        projection = LOG_PROJECTION,
+        primary_key_cond = LOG_PRIMARY_KEY_CONDITION,
+        batch_size = MICRO_BATCH_SIZE,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{log_projection, LOG_PROJECTION, LOG_PROJECTION_LEN};
+
+    #[test]
+    fn raw_proto_is_only_read_on_demand() {
+        assert_eq!(LOG_PROJECTION.split(',').count(), LOG_PROJECTION_LEN);
+        assert!(!log_projection(false).contains("raw_proto"));
+        assert_eq!(
+            log_projection(true).rsplit(',').next().map(str::trim),
+            Some("raw_proto")
+        );
+    }
+}
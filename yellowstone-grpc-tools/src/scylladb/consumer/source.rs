@@ -0,0 +1,848 @@
+use {
+    super::{
+        grpc::get_producer_info_by_id_or_any,
+        shard_iterator::{log_projection, LogRow, GET_LAST_SHARD_PERIOD_COMMIT},
+    },
+    crate::scylladb::{
+        sink::{get_max_shard_offsets_for_producer, in_control_keyspace, slot_bucket},
+        types::{
+            log_partition, BlockchainEvent, ProducerId, ShardId, ShardOffset, ShardPeriod, Slot,
+            Transaction, SHARD_OFFSET_MODULO,
+        },
+    },
+    futures::{
+        future::{try_join_all, Future},
+        stream::{self, Stream, StreamExt, TryStreamExt},
+    },
+    scylla::{
+        prepared_statement::PreparedStatement,
+        transport::errors::{DbError, QueryError},
+        Session,
+    },
+    std::{
+        cmp::Reverse,
+        collections::{BTreeMap, BinaryHeap, VecDeque},
+        sync::Arc,
+        time::Duration,
+    },
+};
+
+/// Number of events fetched per query while replaying a shard.
+const REPLAY_PAGE_SIZE: usize = 500;
+
+/// Default interval at which [`ScyllaSource::follow`] polls a shard once it caught up with the log.
+const DEFAULT_FOLLOW_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Locates the transactions of a slot, ordered by `tx_index`.
+///
+/// Requires the `slot_transaction_mv` materialized view (see `solana.cql`):
+///
+/// ```cql
+/// create materialized view if not exists solana.slot_transaction_mv
+/// as
+/// select slot, producer_id, tx_index, shard_id, period, offset
+/// from solana.log
+/// where
+///     slot is not null
+///     and producer_id is not null
+///     and tx_index is not null
+///     and shard_id is not null
+///     and period is not null
+///     and offset is not null
+/// primary key ((slot, producer_id), tx_index, shard_id, period, offset);
+/// ```
+///
+/// Account updates have no `tx_index`, so they never show up in the view.
+pub const GET_SLOT_TRANSACTION_LOCATIONS: &str = r###"
+    SELECT
+        shard_id,
+        period,
+        offset
+    FROM slot_transaction_mv
+    WHERE slot = ? AND producer_id = ?
+    ORDER BY tx_index ASC
+"###;
+
+const SLOT_TRANSACTION_VIEW: &str = "slot_transaction_mv";
+
+fn get_log_events_by_offsets_query() -> String {
+    format!(
+        r###"
+        SELECT {projection}
+        FROM log
+        WHERE shard_id = ? AND period = ? AND producer_id = ? AND offset IN ?
+        "###,
+        projection = log_projection(false),
+    )
+}
+
+/// Returns every transaction of `slot` written by `producer_id`, ordered by `tx_index`.
+///
+/// Fails with an explicit error if the `slot_transaction_mv` materialized view has not been created.
+pub async fn get_slot_transactions(
+    session: Arc<Session>,
+    producer_id: ProducerId,
+    slot: Slot,
+) -> anyhow::Result<Vec<Transaction>> {
+    let locations = session
+        .query(GET_SLOT_TRANSACTION_LOCATIONS, (slot, producer_id))
+        .await
+        .map_err(|e| match e {
+            QueryError::DbError(DbError::Invalid, ref msg) if msg.contains(SLOT_TRANSACTION_VIEW) => {
+                anyhow::anyhow!(
+                    "materialized view {SLOT_TRANSACTION_VIEW} is missing, create it from solana.cql to query transactions by slot: {msg}"
+                )
+            }
+            e => anyhow::Error::new(e),
+        })?
+        .rows_typed_or_empty::<(ShardId, ShardPeriod, ShardOffset)>()
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Fetch the transactions partition by partition, a slot usually spans every shard.
+    let mut offsets_by_partition: BTreeMap<(ShardId, ShardPeriod), Vec<ShardOffset>> =
+        BTreeMap::new();
+    for (shard_id, period, offset) in locations {
+        offsets_by_partition
+            .entry((shard_id, period))
+            .or_default()
+            .push(offset);
+    }
+
+    let query = get_log_events_by_offsets_query();
+    let ps = session.prepare(query).await?;
+    let partitions = try_join_all(offsets_by_partition.into_iter().map(
+        |((shard_id, period), offsets)| {
+            let session = Arc::clone(&session);
+            let ps = ps.clone();
+            async move {
+                session
+                    .execute(&ps, (shard_id, period, producer_id, offsets))
+                    .await?
+                    .rows_typed_or_empty::<LogRow>()
+                    .map(|row| restore_log_event(row?.0))
+                    .collect::<anyhow::Result<Vec<_>>>()
+            }
+        },
+    ))
+    .await?;
+
+    let mut events = partitions.into_iter().flatten().collect::<Vec<_>>();
+    events.sort_by_key(|event| event.tx_index);
+    Ok(events.into_iter().map(Transaction::from).collect())
+}
+
+/// Restores the compressed and packed columns of an event read from `log`.
+fn restore_log_event(mut event: BlockchainEvent) -> anyhow::Result<BlockchainEvent> {
+    event.decompress_data()?;
+    event.decode_nested()?;
+    Ok(event)
+}
+
+const LIST_COMMITTED_PERIODS: &str = r###"
+    SELECT period
+    FROM producer_period_commit_log
+    WHERE producer_id = ? AND shard_id = ?
+    ORDER BY period ASC
+"###;
+
+/// Streams the periods of a shard committed in `producer_period_commit_log`, in ascending order.
+///
+/// The commit log is read from `control_keyspace` if any, the session keyspace otherwise. Rows are fetched
+/// page by page as the stream is polled, the stream ends on the first error.
+pub fn list_committed_periods(
+    session: Arc<Session>,
+    producer_id: ProducerId,
+    shard_id: ShardId,
+    control_keyspace: Option<&str>,
+) -> impl Stream<Item = anyhow::Result<ShardPeriod>> {
+    let query = in_control_keyspace(LIST_COMMITTED_PERIODS, control_keyspace);
+    stream::once(async move {
+        session
+            .query_iter(query, (producer_id, shard_id))
+            .await
+            .map_err(anyhow::Error::new)
+    })
+    .map_ok(|rows| {
+        rows.into_typed::<(ShardPeriod,)>()
+            .map_ok(|(period,)| period)
+            .map_err(anyhow::Error::new)
+    })
+    .try_flatten()
+}
+
+fn list_events_in_slot_bucket_query() -> String {
+    format!(
+        r###"
+        SELECT {projection}
+        FROM log_by_slot
+        WHERE producer_id = ? AND slot_bucket = ? AND shard_id = ? AND slot >= ? AND slot <= ?
+        "###,
+        projection = log_projection(false),
+    )
+}
+
+/// Merges the events of each shard, ordered by slot then offset, into a single stream ordered by slot, then
+/// shard and offset. `shards` are in shard order, the stream ends on the first error.
+async fn merge_shards_by_slot<S>(
+    mut shards: Vec<S>,
+) -> anyhow::Result<impl Stream<Item = anyhow::Result<BlockchainEvent>>>
+where
+    S: Stream<Item = anyhow::Result<BlockchainEvent>> + Unpin,
+{
+    let heads = try_join_all(shards.iter_mut().map(|shard| shard.try_next())).await?;
+    let heap = heads
+        .iter()
+        .enumerate()
+        .filter_map(|(i, head)| head.as_ref().map(|event| Reverse((event.slot, i))))
+        .collect::<BinaryHeap<_>>();
+    Ok(stream::unfold(
+        (shards, heads, heap, false),
+        |(mut shards, mut heads, mut heap, failed)| async move {
+            if failed {
+                return None;
+            }
+            let Reverse((_, i)) = heap.pop()?;
+            let head = heads[i].take()?;
+            match shards[i].try_next().await {
+                Ok(Some(event)) => {
+                    heap.push(Reverse((event.slot, i)));
+                    heads[i] = Some(event);
+                }
+                Ok(None) => (),
+                Err(e) => return Some((Err(e), (shards, heads, heap, true))),
+            }
+            Some((Ok(head), (shards, heads, heap, false)))
+        },
+    ))
+}
+
+/// Streams the events of a producer from `from_slot` up to `to_slot` included out of `log_by_slot`, ordered
+/// by slot, then shard and offset.
+///
+/// Only producers running with `slot_bucket_size` write to `log_by_slot`, the size is read from `producer_info`,
+/// in `control_keyspace` if any. Each bucket of the range is read page by page as the stream is polled, the
+/// partitions of every shard at once, and the stream ends on the first error, e.g. if the producer does not
+/// write `log_by_slot`.
+pub fn list_events_in_slot_range(
+    session: Arc<Session>,
+    producer_id: ProducerId,
+    from_slot: Slot,
+    to_slot: Slot,
+    control_keyspace: Option<&str>,
+) -> impl Stream<Item = anyhow::Result<BlockchainEvent>> {
+    let control_keyspace = control_keyspace.map(str::to_owned);
+    stream::once(async move {
+        let producer_info = get_producer_info_by_id_or_any(
+            Arc::clone(&session),
+            Some(producer_id),
+            control_keyspace.as_deref(),
+        )
+        .await?
+        .ok_or(anyhow::anyhow!("producer {producer_id:?} does not exist"))?;
+        let slot_bucket_size = producer_info
+            .slot_bucket_size
+            .filter(|size| *size > 0)
+            .ok_or(anyhow::anyhow!(
+                "producer {producer_id:?} does not write log_by_slot, it has no slot_bucket_size"
+            ))?;
+        let ps = session.prepare(list_events_in_slot_bucket_query()).await?;
+        let num_shards = producer_info.num_shards;
+        let buckets =
+            slot_bucket(from_slot, slot_bucket_size)..=slot_bucket(to_slot, slot_bucket_size);
+        anyhow::Ok(
+            stream::iter(buckets)
+                .then(move |bucket| {
+                    let session = Arc::clone(&session);
+                    let ps = ps.clone();
+                    async move {
+                        let shards = try_join_all((0..num_shards).map(|shard_id| {
+                            session.execute_iter(
+                                ps.clone(),
+                                (producer_id, bucket, shard_id, from_slot, to_slot),
+                            )
+                        }))
+                        .await?
+                        .into_iter()
+                        .map(|rows| {
+                            rows.into_typed::<LogRow>()
+                                .map_ok(|LogRow(event)| event)
+                                .map_err(anyhow::Error::new)
+                        })
+                        .collect::<Vec<_>>();
+                        merge_shards_by_slot(shards).await
+                    }
+                })
+                .try_flatten(),
+        )
+    })
+    .try_flatten()
+    .and_then(|mut event| async move {
+        event.decompress_data()?;
+        event.decode_nested()?;
+        Ok(event)
+    })
+}
+
+fn get_log_events_in_offset_range_query(with_raw_proto: bool) -> String {
+    format!(
+        r###"
+        SELECT {projection}
+        FROM log
+        WHERE shard_id = ? AND period = ? AND producer_id = ? AND offset >= ? AND offset <= ?
+        ORDER BY offset ASC
+        LIMIT {REPLAY_PAGE_SIZE}
+        "###,
+        projection = log_projection(with_raw_proto),
+    )
+}
+
+const GET_FIRST_EVENT_IN_OFFSET_RANGE: &str = r###"
+    SELECT offset, slot
+    FROM log
+    WHERE shard_id = ? AND period = ? AND producer_id = ? AND offset >= ? AND offset <= ?
+    ORDER BY offset ASC
+    LIMIT 1
+"###;
+
+/// Returns the offset and slot of the first event of a shard from `from` up to `to` included, reading one `log`
+/// partition after another until one holds an event.
+async fn get_first_event_in_offset_range(
+    session: &Session,
+    ps: &PreparedStatement,
+    producer_id: ProducerId,
+    shard_id: ShardId,
+    partitions_per_period: i16,
+    mut from: ShardOffset,
+    to: ShardOffset,
+) -> anyhow::Result<Option<(ShardOffset, Slot)>> {
+    while from <= to {
+        let partition_end = to.min(log_partition_end(from, partitions_per_period));
+        let partition = log_partition(from, partitions_per_period);
+        let row = session
+            .execute(ps, (shard_id, partition, producer_id, from, partition_end))
+            .await?
+            .maybe_first_row_typed::<(ShardOffset, Slot)>()?;
+        if row.is_some() {
+            return Ok(row);
+        }
+        from = partition_end + 1;
+    }
+    Ok(None)
+}
+
+/// Bisects the offsets of a shard up to `end_offset` for the first event of `start_slot` or later, returns
+/// `end_offset + 1` if there is none.
+///
+/// `first_event(from)` returns the offset and slot of the first event from `from` up to `end_offset`, which skips
+/// the offsets the producer never wrote or which expired. Shards are written in slot order, so the slots found
+/// never decrease with the offset.
+async fn search_replay_start_offset<F, Fut>(
+    start_slot: Slot,
+    end_offset: ShardOffset,
+    mut first_event: F,
+) -> anyhow::Result<ShardOffset>
+where
+    F: FnMut(ShardOffset) -> Fut,
+    Fut: Future<Output = anyhow::Result<Option<(ShardOffset, Slot)>>>,
+{
+    let (mut low, mut high) = (0, end_offset + 1);
+    let mut start_offset = end_offset + 1;
+    while low < high {
+        let mid = low + (high - low) / 2;
+        match first_event(mid).await? {
+            Some((offset, slot)) if slot < start_slot => low = offset + 1,
+            Some((offset, _)) => {
+                start_offset = offset;
+                high = mid;
+            }
+            None => high = mid,
+        }
+    }
+    Ok(start_offset)
+}
+
+/// Returns the last offset of the `log` partition holding `offset`, see [`log_partition`].
+const fn log_partition_end(offset: ShardOffset, partitions_per_period: i16) -> ShardOffset {
+    let partition_len = SHARD_OFFSET_MODULO / partitions_per_period as i64;
+    (log_partition(offset, partitions_per_period) + 1) * partition_len - 1
+}
+
+/// Reads a page of events of a shard, `from` and `to` must belong to the same `log` partition.
+async fn read_log_page(
+    session: &Session,
+    ps: &PreparedStatement,
+    producer_id: ProducerId,
+    shard_id: ShardId,
+    partitions_per_period: i16,
+    from: ShardOffset,
+    to: ShardOffset,
+) -> anyhow::Result<VecDeque<BlockchainEvent>> {
+    let partition = log_partition(from, partitions_per_period);
+    let mut page = session
+        .execute(ps, (shard_id, partition, producer_id, from, to))
+        .await?
+        .rows_typed_or_empty::<LogRow>()
+        .map(|row| row.map(|LogRow(event)| event))
+        .collect::<Result<VecDeque<_>, _>>()?;
+    for event in page.iter_mut() {
+        event.decompress_data()?;
+        event.decode_nested()?;
+    }
+    Ok(page)
+}
+
+/// Reads a shard log from an offset up to an end offset, one page at a time.
+struct ShardCursor {
+    shard_id: ShardId,
+    partitions_per_period: i16,
+    next_offset: ShardOffset,
+    end_offset: ShardOffset,
+    buffer: VecDeque<BlockchainEvent>,
+}
+
+impl ShardCursor {
+    async fn next(
+        &mut self,
+        session: &Session,
+        ps: &PreparedStatement,
+        producer_id: ProducerId,
+    ) -> anyhow::Result<Option<BlockchainEvent>> {
+        while self.buffer.is_empty() && self.next_offset <= self.end_offset {
+            let partition_end = self.end_offset.min(log_partition_end(
+                self.next_offset,
+                self.partitions_per_period,
+            ));
+            let page = read_log_page(
+                session,
+                ps,
+                producer_id,
+                self.shard_id,
+                self.partitions_per_period,
+                self.next_offset,
+                partition_end,
+            )
+            .await?;
+            // An empty page means the rest of the partition has nothing left to read.
+            self.next_offset = page
+                .back()
+                .map(|event| event.offset + 1)
+                .unwrap_or(partition_end + 1);
+            self.buffer = page;
+        }
+        Ok(self.buffer.pop_front())
+    }
+}
+
+/// Read side of the log written by the ScyllaDB sink.
+#[derive(Clone)]
+pub struct ScyllaSource {
+    session: Arc<Session>,
+    follow_poll_interval: Duration,
+    control_keyspace: Option<String>,
+    with_raw_proto: bool,
+}
+
+impl ScyllaSource {
+    pub fn new(session: Arc<Session>) -> Self {
+        ScyllaSource {
+            session,
+            follow_poll_interval: DEFAULT_FOLLOW_POLL_INTERVAL,
+            control_keyspace: None,
+            with_raw_proto: false,
+        }
+    }
+
+    /// Also reads the `raw_proto` column of the events followed or replayed, left out by default.
+    pub const fn with_raw_proto(mut self, with_raw_proto: bool) -> Self {
+        self.with_raw_proto = with_raw_proto;
+        self
+    }
+
+    /// Reads the producer metadata tables from `control_keyspace`, the keyspace of the session by default.
+    pub fn with_control_keyspace(mut self, control_keyspace: Option<String>) -> Self {
+        self.control_keyspace = control_keyspace;
+        self
+    }
+
+    /// Sets how often [`ScyllaSource::follow`] checks for new events once it caught up, 200ms by default.
+    pub const fn with_follow_poll_interval(mut self, interval: Duration) -> Self {
+        self.follow_poll_interval = interval;
+        self
+    }
+
+    /// Streams the events of a shard from `from_offset` onwards, waiting for new events once it caught up with the
+    /// log instead of ending, like `tail -f`.
+    ///
+    /// The stream moves to the next `log` partition once it read the last offset of the current one, or once the
+    /// period of the current partition is committed in `producer_period_commit_log` and the rest of the partition
+    /// read again, skipping the offsets the producer never wrote.
+    ///
+    /// The stream ends on the first error.
+    pub fn follow(
+        &self,
+        producer_id: ProducerId,
+        shard_id: ShardId,
+        from_offset: ShardOffset,
+    ) -> impl Stream<Item = anyhow::Result<BlockchainEvent>> {
+        let session = Arc::clone(&self.session);
+        let poll_interval = self.follow_poll_interval;
+        let control_keyspace = self.control_keyspace.clone();
+        let with_raw_proto = self.with_raw_proto;
+        stream::once(async move {
+            let control_keyspace = control_keyspace.as_deref();
+            let partitions_per_period = get_producer_info_by_id_or_any(
+                Arc::clone(&session),
+                Some(producer_id),
+                control_keyspace,
+            )
+            .await?
+            .ok_or(anyhow::anyhow!("producer {producer_id:?} does not exist"))?
+            .partitions_per_period();
+            let ps = session
+                .prepare(get_log_events_in_offset_range_query(with_raw_proto))
+                .await?;
+            let commit_ps = session
+                .prepare(in_control_keyspace(
+                    GET_LAST_SHARD_PERIOD_COMMIT,
+                    control_keyspace,
+                ))
+                .await?;
+            anyhow::Ok((session, partitions_per_period, ps, commit_ps))
+        })
+        .map_ok(move |(session, partitions_per_period, ps, commit_ps)| {
+            let state = (session, from_offset, VecDeque::new(), None);
+            stream::try_unfold(
+                state,
+                move |(session, mut next_offset, mut buffer, mut last_committed_period)| {
+                    let ps = ps.clone();
+                    let commit_ps = commit_ps.clone();
+                    async move {
+                        loop {
+                            if let Some(event) = buffer.pop_front() {
+                                return Ok(Some((
+                                    event,
+                                    (session, next_offset, buffer, last_committed_period),
+                                )));
+                            }
+                            let period = next_offset / SHARD_OFFSET_MODULO;
+                            let partition_end =
+                                log_partition_end(next_offset, partitions_per_period);
+                            buffer = read_log_page(
+                                &session,
+                                &ps,
+                                producer_id,
+                                shard_id,
+                                partitions_per_period,
+                                next_offset,
+                                partition_end,
+                            )
+                            .await?;
+                            if let Some(event) = buffer.back() {
+                                next_offset = event.offset + 1;
+                                continue;
+                            }
+                            if last_committed_period >= Some(period) {
+                                next_offset = partition_end + 1;
+                                continue;
+                            }
+                            // The period may be committed right after the page was read, the partition is read
+                            // once more after seeing the commit so the events written meanwhile are not skipped.
+                            last_committed_period = session
+                                .execute(&commit_ps, (producer_id, shard_id))
+                                .await?
+                                .maybe_first_row_typed::<(ShardPeriod,)>()?
+                                .map(|(period,)| period);
+                            if last_committed_period < Some(period) {
+                                tokio::time::sleep(poll_interval).await;
+                            }
+                        }
+                    }
+                },
+            )
+        })
+        .try_flatten()
+    }
+
+    /// Locates the first offset of each shard holding an event of `start_slot` or later.
+    ///
+    /// The offsets of every shard are bisected up to its last offset, see [`search_replay_start_offset`]. Shards
+    /// only holding earlier slots start right after their last offset.
+    async fn get_replay_start_offsets(
+        &self,
+        producer_id: ProducerId,
+        partitions_per_period: i16,
+        start_slot: Slot,
+        end_offsets: &[(ShardId, ShardOffset)],
+    ) -> anyhow::Result<BTreeMap<ShardId, ShardOffset>> {
+        let session = self.session.as_ref();
+        let ps = session.prepare(GET_FIRST_EVENT_IN_OFFSET_RANGE).await?;
+        let start_offsets = try_join_all(end_offsets.iter().map(|&(shard_id, end_offset)| {
+            let ps = &ps;
+            async move {
+                let start_offset =
+                    search_replay_start_offset(start_slot, end_offset, move |from| {
+                        get_first_event_in_offset_range(
+                            session,
+                            ps,
+                            producer_id,
+                            shard_id,
+                            partitions_per_period,
+                            from,
+                            end_offset,
+                        )
+                    })
+                    .await?;
+                anyhow::Ok((shard_id, start_offset))
+            }
+        }))
+        .await?;
+        Ok(start_offsets.into_iter().collect())
+    }
+
+    /// Replays every event of `producer_id` from `start_slot` up to the end of the log at the time of the call.
+    ///
+    /// Shards are read concurrently and merged in slot order. Events of the same slot are spread over
+    /// several shards, they are yielded by shard id then by offset.
+    /// The merge relies on each shard being written in slot order, which is how the sink routes events.
+    ///
+    /// The stream ends on the first error.
+    pub async fn replay_from_slot(
+        &self,
+        producer_id: ProducerId,
+        start_slot: Slot,
+    ) -> anyhow::Result<impl Stream<Item = anyhow::Result<BlockchainEvent>>> {
+        let producer_info = get_producer_info_by_id_or_any(
+            Arc::clone(&self.session),
+            Some(producer_id),
+            self.control_keyspace.as_deref(),
+        )
+        .await?
+        .ok_or(anyhow::anyhow!("producer {producer_id:?} does not exist"))?;
+        let end_offsets = get_max_shard_offsets_for_producer(
+            Arc::clone(&self.session),
+            &producer_info,
+            self.control_keyspace.as_deref(),
+        )
+        .await?;
+        let start_offsets = self
+            .get_replay_start_offsets(
+                producer_id,
+                producer_info.partitions_per_period(),
+                start_slot,
+                &end_offsets,
+            )
+            .await?;
+
+        let mut cursors = end_offsets
+            .into_iter()
+            .map(|(shard_id, end_offset)| ShardCursor {
+                shard_id,
+                partitions_per_period: producer_info.partitions_per_period(),
+                next_offset: start_offsets[&shard_id],
+                end_offset,
+                buffer: VecDeque::new(),
+            })
+            .collect::<Vec<_>>();
+
+        let session = Arc::clone(&self.session);
+        let ps = session
+            .prepare(get_log_events_in_offset_range_query(self.with_raw_proto))
+            .await?;
+
+        let heads = try_join_all(
+            cursors
+                .iter_mut()
+                .map(|cursor| cursor.next(&session, &ps, producer_id)),
+        )
+        .await?;
+        let heap = heads
+            .iter()
+            .enumerate()
+            .filter_map(|(i, head)| head.as_ref().map(|event| Reverse((event.slot, i))))
+            .collect::<BinaryHeap<_>>();
+
+        let state = (session, ps, cursors, heads, heap, false);
+        Ok(stream::unfold(
+            state,
+            move |(session, ps, mut cursors, mut heads, mut heap, failed)| async move {
+                if failed {
+                    return None;
+                }
+                loop {
+                    let Reverse((_, i)) = heap.pop()?;
+                    let head = heads[i].take();
+                    match cursors[i].next(&session, &ps, producer_id).await {
+                        Ok(Some(event)) => {
+                            heap.push(Reverse((event.slot, i)));
+                            heads[i] = Some(event);
+                        }
+                        Ok(None) => (),
+                        Err(e) => return Some((Err(e), (session, ps, cursors, heads, heap, true))),
+                    }
+                    if let Some(event) = head.filter(|event| event.slot >= start_slot) {
+                        return Some((Ok(event), (session, ps, cursors, heads, heap, false)));
+                    }
+                }
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{merge_shards_by_slot, restore_log_event, search_replay_start_offset},
+        crate::scylladb::types::{
+            AccountUpdate, CompiledInstr, DataCodec, NestedCodec, ShardOffset, Slot, Transaction,
+            TransactionMeta,
+        },
+        futures::{future, stream, TryStreamExt},
+        std::{cell::Cell, collections::BTreeMap},
+    };
+
+    /// Runs [`search_replay_start_offset`] on a shard holding the events of `log`, keyed by offset.
+    async fn replay_start_offset(
+        log: &BTreeMap<ShardOffset, Slot>,
+        start_slot: Slot,
+    ) -> ShardOffset {
+        let end_offset = log.keys().next_back().copied().unwrap_or(-1);
+        search_replay_start_offset(start_slot, end_offset, |from| {
+            future::ready(Ok(log
+                .range(from..=end_offset)
+                .next()
+                .map(|(offset, slot)| (*offset, *slot))))
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn slot_buckets_of_every_shard_are_merged_by_slot() {
+        // (slot, offset) of the events of each shard in the bucket.
+        let shards = [
+            vec![(10, 0), (10, 1), (12, 2)],
+            vec![],
+            vec![(9, 5), (10, 6), (13, 7)],
+        ];
+        let streams = shards
+            .iter()
+            .enumerate()
+            .map(|(shard_id, events)| {
+                stream::iter(events.iter().map(move |(slot, offset)| {
+                    let mut event = AccountUpdate::zero_account().as_blockchain_event(
+                        shard_id as i16,
+                        [0],
+                        *offset,
+                    );
+                    event.slot = *slot;
+                    anyhow::Ok(event)
+                }))
+            })
+            .collect::<Vec<_>>();
+        let merged = merge_shards_by_slot(streams)
+            .await
+            .unwrap()
+            .map_ok(|event| (event.slot, event.shard_id, event.offset))
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(
+            merged,
+            vec![
+                (9, 2, 5),
+                (10, 0, 0),
+                (10, 0, 1),
+                (10, 2, 6),
+                (12, 0, 2),
+                (13, 2, 7)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn replay_starts_at_the_first_event_of_the_start_slot_or_later() {
+        // Offsets 0 to 3 expired, 9 to 11 were never written and slots 13 and 16 have no event in this shard.
+        let log = BTreeMap::from([
+            (4, 10),
+            (5, 10),
+            (6, 12),
+            (7, 12),
+            (8, 12),
+            (12, 14),
+            (13, 15),
+            (14, 15),
+            (15, 17),
+        ]);
+        for start_slot in 0..=18 {
+            let expected = log
+                .iter()
+                .find(|(_, slot)| **slot >= start_slot)
+                .map(|(offset, _)| *offset)
+                .unwrap_or(16);
+            assert_eq!(
+                replay_start_offset(&log, start_slot).await,
+                expected,
+                "start slot {start_slot}"
+            );
+        }
+        assert_eq!(replay_start_offset(&BTreeMap::new(), 10).await, 0);
+    }
+
+    #[tokio::test]
+    async fn replay_start_offset_is_found_in_a_logarithmic_number_of_reads() {
+        let log: BTreeMap<ShardOffset, Slot> =
+            (0..100_000).map(|offset| (offset, offset / 7)).collect();
+        let reads = Cell::new(0);
+        let end_offset = 99_999;
+        let start_offset = search_replay_start_offset(5_000, end_offset, |from| {
+            reads.set(reads.get() + 1);
+            future::ready(Ok(log
+                .range(from..=end_offset)
+                .next()
+                .map(|(offset, slot)| (*offset, *slot))))
+        })
+        .await
+        .unwrap();
+        assert_eq!(start_offset, 35_000);
+        assert!(reads.get() <= 18, "{} reads", reads.get());
+    }
+
+    #[test]
+    fn compressed_transaction_is_restored_from_its_log_row() {
+        let tx = Transaction {
+            slot: 42,
+            signature: vec![1; 64],
+            signatures: vec![vec![1; 64]],
+            num_required_signatures: 1,
+            num_readonly_signed_accounts: 0,
+            num_readonly_unsigned_accounts: 1,
+            account_keys: vec![vec![2; 32], vec![3; 32]],
+            recent_blockhash: vec![4; 32],
+            instructions: vec![CompiledInstr {
+                program_id_index: 1,
+                accounts: vec![0],
+                data: vec![5; 256],
+            }],
+            versioned: false,
+            address_table_lookups: Vec::new(),
+            meta: TransactionMeta {
+                fee: 5_000,
+                log_messages: Some(vec!["Program log: hello".to_owned(); 16]),
+                ..Default::default()
+            },
+            is_vote: false,
+            tx_index: 3,
+            ingested_at: None,
+            truncated: false,
+            raw_proto: None,
+        };
+        // Written by a sink packing and compressing `tx_nested`.
+        let mut row = tx.clone().as_blockchain_event(0, [0], 7);
+        row.encode_nested(NestedCodec::Json).unwrap();
+        row.compress_data(0);
+        assert_eq!(row.tx_nested_compression, Some(DataCodec::Lz4));
+
+        let event = restore_log_event(row).unwrap();
+        assert_eq!(Transaction::from(event), tx);
+    }
+}
@@ -0,0 +1,103 @@
+use {
+    super::types::{ShardId, ShardOffset, Slot},
+    scylla::Session,
+    serde::Serialize,
+    std::{
+        net::SocketAddr,
+        sync::atomic::{AtomicI64, AtomicUsize, Ordering},
+    },
+};
+
+/// Snapshot of the configuration and runtime state of a sink, see [`super::sink::ScyllaSink::diagnostics`].
+#[derive(Debug, Clone, Serialize)]
+pub struct SinkDiagnostics {
+    pub producer_id: u8,
+    /// Effective configuration of the sink, rendered with `Debug` since it holds callbacks.
+    pub config: String,
+    /// Number of partitions of `log` each period is split into, as registered for the producer.
+    pub partitions_per_period: i16,
+    /// Lock id this sink holds, `None` for a backfill sink or once the lock is released.
+    pub lock_id: Option<String>,
+    /// Slot of the tip last reported with `set_tip_slot`, -1 if never reported.
+    pub tip_slot: Slot,
+    pub roll_required: bool,
+    /// Shard whose failure stopped the router, if any.
+    pub failed_shard: Option<ShardId>,
+    pub shards: Vec<ShardDiagnostics>,
+    pub nodes: Vec<NodeDiagnostics>,
+}
+
+/// Offsets and buffer occupancy of a shard.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ShardDiagnostics {
+    pub shard_id: ShardId,
+    /// Offset the shard assigns to its next event.
+    pub next_offset: ShardOffset,
+    /// Offset following the last event written to ScyllaDB.
+    pub committed_offset: ShardOffset,
+    pub buffered_events: usize,
+    pub buffered_bytes: usize,
+    pub paused: bool,
+}
+
+/// A node of the cluster as seen by the session of the sink.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct NodeDiagnostics {
+    pub address: SocketAddr,
+    pub datacenter: Option<String>,
+    pub rack: Option<String>,
+    pub up: bool,
+    /// Number of CPU cores of the node, `None` if the node did not negotiate shard awareness.
+    pub shard_count: Option<u16>,
+}
+
+/// Offsets and buffer occupancy a shard publishes for the diagnostics.
+///
+/// Stores and loads are relaxed, a snapshot may mix values of two consecutive events but never slows the shard down.
+#[derive(Debug, Default)]
+pub(crate) struct ShardGauges {
+    next_offset: AtomicI64,
+    committed_offset: AtomicI64,
+    buffered_events: AtomicUsize,
+    buffered_bytes: AtomicUsize,
+}
+
+impl ShardGauges {
+    pub(crate) fn set_offsets(&self, next_offset: ShardOffset, committed_offset: ShardOffset) {
+        self.next_offset.store(next_offset, Ordering::Relaxed);
+        self.committed_offset
+            .store(committed_offset, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_buffer(&self, events: usize, bytes: usize) {
+        self.buffered_events.store(events, Ordering::Relaxed);
+        self.buffered_bytes.store(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self, shard_id: ShardId, paused: bool) -> ShardDiagnostics {
+        ShardDiagnostics {
+            shard_id,
+            next_offset: self.next_offset.load(Ordering::Relaxed),
+            committed_offset: self.committed_offset.load(Ordering::Relaxed),
+            buffered_events: self.buffered_events.load(Ordering::Relaxed),
+            buffered_bytes: self.buffered_bytes.load(Ordering::Relaxed),
+            paused,
+        }
+    }
+}
+
+/// Lists the nodes known to `session`, from the cluster metadata it already holds.
+pub(crate) fn list_nodes(session: &Session) -> Vec<NodeDiagnostics> {
+    session
+        .get_cluster_data()
+        .get_nodes_info()
+        .iter()
+        .map(|node| NodeDiagnostics {
+            address: SocketAddr::new(node.address.ip(), node.address.port()),
+            datacenter: node.datacenter.clone(),
+            rack: node.rack.clone(),
+            up: !node.is_down(),
+            shard_count: node.sharder().map(|sharder| sharder.nr_shards.get()),
+        })
+        .collect()
+}
@@ -0,0 +1,121 @@
+use {
+    scylla::transport::errors::{DbError, QueryError},
+    std::sync::Arc,
+};
+
+/// How the sink handles a failed request, as decided by an [`ErrorClassifier`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ErrorClass {
+    /// Sent again: metadata writes per their retry policy, event batches while the circuit breaker lets them through.
+    Retryable,
+    /// Never sent again, the shard or the operation sending it fails right away.
+    Fatal,
+    /// Replicas are missing, counted by the consistency downgrade and otherwise handled as retryable.
+    Unavailable,
+}
+
+/// Classifies the errors of the requests sent by the sink, see [`ScyllaSinkConfig::error_classifier`].
+///
+/// [`ScyllaSinkConfig::error_classifier`]: super::sink::ScyllaSinkConfig::error_classifier
+#[derive(Clone)]
+pub struct ErrorClassifier(Arc<dyn Fn(&QueryError) -> ErrorClass + Send + Sync>);
+
+impl ErrorClassifier {
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(&QueryError) -> ErrorClass + Send + Sync + 'static,
+    {
+        ErrorClassifier(Arc::new(f))
+    }
+
+    pub fn classify(&self, error: &QueryError) -> ErrorClass {
+        (self.0)(error)
+    }
+
+    /// Classifies an error of the sink, any error not coming from a request is fatal.
+    pub(crate) fn classify_any(&self, error: &anyhow::Error) -> ErrorClass {
+        error
+            .downcast_ref::<QueryError>()
+            .map_or(ErrorClass::Fatal, |error| self.classify(error))
+    }
+}
+
+/// Default classification:
+///
+/// | Error                                                                            | Class         |
+/// |----------------------------------------------------------------------------------|---------------|
+/// | `Unavailable`                                                                    | `Unavailable` |
+/// | `SyntaxError`, `Invalid`, `AlreadyExists`, `ConfigError`, `FunctionFailure`,      | `Fatal`       |
+/// | `AuthenticationError`, `Unauthorized`, `ProtocolError`, a bad query               |               |
+/// | anything else: timeouts, `Overloaded`, `IsBootstrapping`, connection errors, ...  | `Retryable`   |
+impl Default for ErrorClassifier {
+    fn default() -> Self {
+        ErrorClassifier::new(|error| match error {
+            QueryError::DbError(DbError::Unavailable { .. }, _) => ErrorClass::Unavailable,
+            QueryError::DbError(
+                DbError::SyntaxError
+                | DbError::Invalid
+                | DbError::AlreadyExists { .. }
+                | DbError::ConfigError
+                | DbError::FunctionFailure { .. }
+                | DbError::AuthenticationError
+                | DbError::Unauthorized
+                | DbError::ProtocolError,
+                _,
+            )
+            | QueryError::BadQuery(_) => ErrorClass::Fatal,
+            _ => ErrorClass::Retryable,
+        })
+    }
+}
+
+impl std::fmt::Debug for ErrorClassifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ErrorClassifier")
+    }
+}
+
+impl PartialEq for ErrorClassifier {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{ErrorClass, ErrorClassifier},
+        scylla::{
+            statement::Consistency,
+            transport::errors::{BadQuery, DbError, QueryError},
+        },
+    };
+
+    #[test]
+    fn default_classification() {
+        let classifier = ErrorClassifier::default();
+        let unavailable = QueryError::DbError(
+            DbError::Unavailable {
+                consistency: Consistency::LocalQuorum,
+                required: 2,
+                alive: 1,
+            },
+            "unavailable".to_owned(),
+        );
+        assert_eq!(classifier.classify(&unavailable), ErrorClass::Unavailable);
+        let invalid = QueryError::DbError(DbError::Invalid, "invalid".to_owned());
+        assert_eq!(classifier.classify(&invalid), ErrorClass::Fatal);
+        let bad_query = QueryError::BadQuery(BadQuery::Other("bad".to_owned()));
+        assert_eq!(classifier.classify(&bad_query), ErrorClass::Fatal);
+        let timeout = QueryError::RequestTimeout("timeout".to_owned());
+        assert_eq!(classifier.classify(&timeout), ErrorClass::Retryable);
+        assert_eq!(
+            classifier.classify_any(&anyhow::anyhow!("not a request")),
+            ErrorClass::Fatal
+        );
+        assert_eq!(
+            classifier.classify_any(&anyhow::Error::new(timeout)),
+            ErrorClass::Retryable
+        );
+    }
+}
@@ -0,0 +1,128 @@
+use {
+    super::{
+        sink::{ClientCommand, ScyllaSink},
+        types::{AccountUpdate, BlockMeta, Transaction},
+    },
+    std::sync::{Arc, Mutex},
+};
+
+/// Destination of the events read from geyser.
+///
+/// Implemented by [`ScyllaSink`] and by [`InMemorySink`], so pipelines written against this trait can be
+/// tested without a ScyllaDB cluster.
+#[async_trait::async_trait]
+pub trait EventSink: Send {
+    async fn log_account_update(&mut self, update: AccountUpdate) -> anyhow::Result<()>;
+
+    async fn log_transaction(&mut self, tx: Transaction) -> anyhow::Result<()>;
+
+    async fn log_block_meta(&mut self, block_meta: BlockMeta) -> anyhow::Result<()>;
+
+    /// Writes what is still buffered and closes the sink.
+    async fn shutdown(self) -> anyhow::Result<()>;
+}
+
+#[async_trait::async_trait]
+impl EventSink for ScyllaSink {
+    async fn log_account_update(&mut self, update: AccountUpdate) -> anyhow::Result<()> {
+        Ok(ScyllaSink::log_account_update(self, update).await?)
+    }
+
+    async fn log_transaction(&mut self, tx: Transaction) -> anyhow::Result<()> {
+        Ok(ScyllaSink::log_transaction(self, tx).await?)
+    }
+
+    async fn log_block_meta(&mut self, block_meta: BlockMeta) -> anyhow::Result<()> {
+        Ok(ScyllaSink::log_block_meta(self, block_meta).await?)
+    }
+
+    async fn shutdown(self) -> anyhow::Result<()> {
+        ScyllaSink::shutdown(self).await
+    }
+}
+
+#[derive(Debug, Default)]
+struct InMemoryLog {
+    events: Vec<ClientCommand>,
+    shut_down: bool,
+}
+
+/// Sink keeping every event in memory, meant for tests.
+///
+/// Clones share the same events, keep one around to inspect what was logged once the sink is shut down.
+#[derive(Debug, Default, Clone)]
+pub struct InMemorySink {
+    inner: Arc<Mutex<InMemoryLog>>,
+}
+
+impl InMemorySink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the events logged so far, in the order they were logged.
+    pub fn events(&self) -> Vec<ClientCommand> {
+        self.inner.lock().expect("poisoned").events.clone()
+    }
+
+    pub fn is_shut_down(&self) -> bool {
+        self.inner.lock().expect("poisoned").shut_down
+    }
+
+    fn record(&self, cmd: ClientCommand) -> anyhow::Result<()> {
+        let mut log = self.inner.lock().expect("poisoned");
+        if log.shut_down {
+            anyhow::bail!("in-memory sink is shut down");
+        }
+        log.events.push(cmd);
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl EventSink for InMemorySink {
+    async fn log_account_update(&mut self, update: AccountUpdate) -> anyhow::Result<()> {
+        self.record(ClientCommand::InsertAccountUpdate(update))
+    }
+
+    async fn log_transaction(&mut self, tx: Transaction) -> anyhow::Result<()> {
+        self.record(ClientCommand::InsertTransaction(tx))
+    }
+
+    async fn log_block_meta(&mut self, block_meta: BlockMeta) -> anyhow::Result<()> {
+        self.record(ClientCommand::InsertBlockMeta(block_meta))
+    }
+
+    async fn shutdown(self) -> anyhow::Result<()> {
+        self.inner.lock().expect("poisoned").shut_down = true;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{EventSink, InMemorySink},
+        crate::scylladb::{sink::ClientCommand, types::AccountUpdate},
+    };
+
+    #[tokio::test]
+    async fn in_memory_sink_records_events_until_shutdown() {
+        let update = AccountUpdate {
+            slot: 7,
+            ..AccountUpdate::zero_account()
+        };
+        let mut sink = InMemorySink::new();
+        let recorder = sink.clone();
+
+        sink.log_account_update(update.clone()).await.unwrap();
+        EventSink::shutdown(sink).await.unwrap();
+
+        assert!(recorder.is_shut_down());
+        assert_eq!(
+            recorder.events(),
+            vec![ClientCommand::InsertAccountUpdate(update.clone())]
+        );
+        assert!(recorder.clone().log_account_update(update).await.is_err());
+    }
+}
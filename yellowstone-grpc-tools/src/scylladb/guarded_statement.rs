@@ -0,0 +1,212 @@
+use {
+    super::{
+        error_class::{ErrorClass, ErrorClassifier},
+        prom::{scylladb_statement_failure_inc, scylladb_statement_retry_inc},
+        sink::RetryPolicy,
+        types::ProducerId,
+    },
+    scylla::{
+        batch::Batch,
+        prepared_statement::PreparedStatement,
+        serialize::{batch::BatchValues, row::SerializeRow},
+        transport::errors::QueryError,
+        QueryResult, Session,
+    },
+    std::{future::Future, time::Duration},
+    tracing::warn,
+};
+
+/// A prepared statement executed with the same retries, deadline and metrics wherever the sink uses it.
+#[derive(Clone)]
+pub(crate) struct GuardedStatement {
+    statement: PreparedStatement,
+    guard: RequestGuard,
+}
+
+/// Retries, deadline and metrics of the executions of a [`GuardedStatement`].
+#[derive(Clone)]
+struct RequestGuard {
+    /// Names the statement in the logs and the metric labels.
+    name: &'static str,
+    producer_id: ProducerId,
+    retry_policy: RetryPolicy,
+    /// Errors classified as fatal are never retried.
+    classifier: ErrorClassifier,
+    deadline: Option<Duration>,
+}
+
+impl GuardedStatement {
+    pub(crate) const fn new(
+        statement: PreparedStatement,
+        name: &'static str,
+        producer_id: ProducerId,
+        retry_policy: RetryPolicy,
+        classifier: ErrorClassifier,
+    ) -> Self {
+        GuardedStatement {
+            statement,
+            guard: RequestGuard {
+                name,
+                producer_id,
+                retry_policy,
+                classifier,
+                deadline: None,
+            },
+        }
+    }
+
+    /// Fails every attempt that has not completed within `deadline`, if any, with a request timeout.
+    pub(crate) const fn with_deadline(mut self, deadline: Option<Duration>) -> Self {
+        self.guard.deadline = deadline;
+        self
+    }
+
+    pub(crate) const fn statement(&self) -> &PreparedStatement {
+        &self.statement
+    }
+
+    pub(crate) const fn classifier(&self) -> &ErrorClassifier {
+        &self.guard.classifier
+    }
+
+    /// Executes the statement with `values`.
+    pub(crate) async fn execute(
+        &self,
+        session: &Session,
+        values: impl SerializeRow,
+    ) -> Result<QueryResult, QueryError> {
+        self.guard
+            .run(|| session.execute(&self.statement, &values))
+            .await
+    }
+
+    /// Executes `batch`, made of this statement, with `values`.
+    pub(crate) async fn batch(
+        &self,
+        session: &Session,
+        batch: &Batch,
+        values: impl BatchValues,
+    ) -> Result<QueryResult, QueryError> {
+        self.guard.run(|| session.batch(batch, &values)).await
+    }
+}
+
+impl RequestGuard {
+    async fn run<T, F, Fut>(&self, mut request: F) -> Result<T, QueryError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, QueryError>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match with_deadline(self.deadline, request()).await {
+                Ok(result) => return Ok(result),
+                Err(e) => {
+                    let fatal = self.classifier.classify(&e) == ErrorClass::Fatal;
+                    let Some(backoff) = self.retry_policy.backoff(attempt).filter(|_| !fatal)
+                    else {
+                        scylladb_statement_failure_inc(self.producer_id, self.name);
+                        return Err(e);
+                    };
+                    scylladb_statement_retry_inc(self.producer_id, self.name);
+                    warn!(
+                        "{} failed (attempt {attempt}), retrying in {backoff:?}: {e:?}",
+                        self.name
+                    );
+                    tokio::time::sleep(backoff).await;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+/// Fails `request` with a request timeout if it has not completed within `deadline`, if any.
+async fn with_deadline<T>(
+    deadline: Option<Duration>,
+    request: impl Future<Output = Result<T, QueryError>>,
+) -> Result<T, QueryError> {
+    let Some(deadline) = deadline else {
+        return request.await;
+    };
+    tokio::time::timeout(deadline, request)
+        .await
+        .unwrap_or_else(|_| {
+            Err(QueryError::RequestTimeout(format!(
+                "no response after the deadline of {deadline:?}"
+            )))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{with_deadline, RequestGuard},
+        crate::scylladb::{
+            error_class::ErrorClassifier,
+            prom::{SCYLLADB_STATEMENT_FAILURE, SCYLLADB_STATEMENT_RETRY},
+            sink::RetryPolicy,
+        },
+        scylla::transport::errors::QueryError,
+        std::{cell::Cell, time::Duration},
+    };
+
+    #[tokio::test(start_paused = true)]
+    async fn stalled_request_fails_past_the_deadline() {
+        let stalled = std::future::pending::<Result<(), QueryError>>();
+        let result = with_deadline(Some(Duration::from_secs(5)), stalled).await;
+        assert!(matches!(result, Err(QueryError::RequestTimeout(_))));
+
+        let result = with_deadline(Some(Duration::from_secs(5)), async { Ok(()) }).await;
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stalled_attempts_are_retried_then_counted_as_failed() {
+        let name = "stalled_attempts_test";
+        let guard = RequestGuard {
+            name,
+            producer_id: [0],
+            retry_policy: RetryPolicy {
+                max_attempts: 3,
+                initial_backoff: Duration::from_secs(1),
+                max_backoff: Duration::from_secs(1),
+            },
+            classifier: ErrorClassifier::default(),
+            deadline: Some(Duration::from_secs(5)),
+        };
+        let retries = || {
+            SCYLLADB_STATEMENT_RETRY
+                .with_label_values(&["0", name])
+                .get()
+        };
+        let failures = || {
+            SCYLLADB_STATEMENT_FAILURE
+                .with_label_values(&["0", name])
+                .get()
+        };
+
+        // The first two attempts stall past the deadline, the third one completes.
+        let attempts = Cell::new(0);
+        let request = || {
+            attempts.set(attempts.get() + 1);
+            let stalled = attempts.get() < 3;
+            async move {
+                if stalled {
+                    std::future::pending::<()>().await;
+                }
+                Ok(())
+            }
+        };
+        assert!(guard.run(request).await.is_ok());
+        assert_eq!(attempts.get(), 3);
+        assert_eq!((retries(), failures()), (2, 0));
+
+        // Every attempt stalls: the request fails once its attempts are exhausted.
+        let result = guard
+            .run(std::future::pending::<Result<(), QueryError>>)
+            .await;
+        assert!(matches!(result, Err(QueryError::RequestTimeout(_))));
+        assert_eq!((retries(), failures()), (4, 1));
+    }
+}
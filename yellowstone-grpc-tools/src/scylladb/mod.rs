@@ -1,5 +1,13 @@
+pub mod circuit_breaker;
 pub mod config;
+pub mod consistency_downgrade;
 pub mod consumer;
+pub mod diagnostics;
+pub mod error_class;
+pub mod event_sink;
+mod guarded_statement;
+pub mod offset_committer;
 pub mod prom;
 pub mod sink;
 pub mod types;
+mod wal;
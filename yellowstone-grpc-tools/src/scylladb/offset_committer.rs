@@ -0,0 +1,234 @@
+use {
+    super::{
+        sink::{in_control_keyspace, SharedSession, COMMIT_SHARD_PERIOD},
+        types::{ProducerId, ProducerInfo, ShardId, ShardOffset, ShardPeriod, SHARD_OFFSET_MODULO},
+    },
+    scylla::{prepared_statement::PreparedStatement, Session},
+    std::{
+        collections::BTreeMap,
+        sync::{Arc, Mutex},
+    },
+};
+
+/// Records the offsets written by the shards of a sink, see [`ScyllaSinkConfig::offset_committer`].
+///
+/// A shard calls [`OffsetCommitter::commit`] after each batch written to `log`, before acknowledging its events,
+/// and waits for it: the commit must be durable once it returns `Ok`, or the store may end up behind offsets
+/// already acknowledged. A failed commit fails the flush and the shard, its events are in `log` already.
+///
+/// [`ScyllaSinkConfig::offset_committer`]: super::sink::ScyllaSinkConfig::offset_committer
+#[async_trait::async_trait]
+pub trait OffsetCommitter: Send + Sync {
+    /// Records that `shard_id` wrote every offset up to `max_offset`, offsets of a shard are committed in order.
+    async fn commit(
+        &self,
+        producer_id: ProducerId,
+        shard_id: ShardId,
+        max_offset: ShardOffset,
+    ) -> anyhow::Result<()>;
+}
+
+/// Committer shared by every shard of a sink.
+#[derive(Clone)]
+pub struct SharedOffsetCommitter(Arc<dyn OffsetCommitter>);
+
+impl SharedOffsetCommitter {
+    pub fn new(committer: impl OffsetCommitter + 'static) -> Self {
+        SharedOffsetCommitter(Arc::new(committer))
+    }
+
+    pub(crate) async fn commit(
+        &self,
+        producer_id: ProducerId,
+        shard_id: ShardId,
+        max_offset: ShardOffset,
+    ) -> anyhow::Result<()> {
+        self.0.commit(producer_id, shard_id, max_offset).await
+    }
+}
+
+impl std::fmt::Debug for SharedOffsetCommitter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SharedOffsetCommitter")
+    }
+}
+
+impl PartialEq for SharedOffsetCommitter {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// Period completed by a batch ending at `max_offset`, if any.
+const fn completed_period(max_offset: ShardOffset) -> Option<ShardPeriod> {
+    let period = (max_offset + 1) / SHARD_OFFSET_MODULO - 1;
+    if period < 0 {
+        None
+    } else {
+        Some(period)
+    }
+}
+
+/// Last offset of `period`, every offset up to it is in `log` once the period is committed.
+const fn last_offset_of_period(period: ShardPeriod) -> ShardOffset {
+    (period + 1) * SHARD_OFFSET_MODULO - 1
+}
+
+/// Last period committed by each shard, a batch within a period already committed commits nothing.
+#[derive(Default)]
+struct CommittedPeriods(Mutex<BTreeMap<ShardId, ShardPeriod>>);
+
+impl CommittedPeriods {
+    /// Returns the period completed by the batch of `shard_id` ending at `max_offset`, unless already committed.
+    fn to_commit(&self, shard_id: ShardId, max_offset: ShardOffset) -> Option<ShardPeriod> {
+        let period = completed_period(max_offset)?;
+        let committed = self.0.lock().unwrap().get(&shard_id).copied();
+        (committed < Some(period)).then_some(period)
+    }
+
+    fn record(&self, shard_id: ShardId, period: ShardPeriod) {
+        let mut committed = self.0.lock().unwrap();
+        let last = committed.entry(shard_id).or_insert(period);
+        *last = period.max(*last);
+    }
+}
+
+/// Commits the offsets to `producer_period_commit_log`, the log the sink recovers its shards from.
+///
+/// Offsets are committed a period at a time: a batch completing a period commits it right away, where the shard
+/// only commits it once the next period starts. Readers get the last offset of the last period committed by each
+/// shard through [`get_committed_shard_offsets`], the offsets written since are only found in `log`.
+pub struct ScyllaOffsetCommitter {
+    session: SharedSession,
+    commit_shard_period: PreparedStatement,
+    committed_periods: CommittedPeriods,
+}
+
+impl ScyllaOffsetCommitter {
+    /// Prepares the commits, the period commit log is read from `control_keyspace` if any.
+    pub async fn new(
+        session: Arc<Session>,
+        control_keyspace: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        let commit_shard_period = session
+            .prepare(in_control_keyspace(COMMIT_SHARD_PERIOD, control_keyspace))
+            .await?;
+        Ok(Self::with_statement(
+            SharedSession::new(session).1,
+            commit_shard_period,
+        ))
+    }
+
+    /// Commits through the session of a sink with the period commit statement it prepared already.
+    pub(crate) fn with_statement(
+        session: SharedSession,
+        commit_shard_period: PreparedStatement,
+    ) -> Self {
+        ScyllaOffsetCommitter {
+            session,
+            commit_shard_period,
+            committed_periods: CommittedPeriods::default(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl OffsetCommitter for ScyllaOffsetCommitter {
+    async fn commit(
+        &self,
+        producer_id: ProducerId,
+        shard_id: ShardId,
+        max_offset: ShardOffset,
+    ) -> anyhow::Result<()> {
+        let Some(period) = self.committed_periods.to_commit(shard_id, max_offset) else {
+            return Ok(());
+        };
+        self.session
+            .current()
+            .execute(&self.commit_shard_period, (producer_id, shard_id, period))
+            .await?;
+        self.committed_periods.record(shard_id, period);
+        Ok(())
+    }
+}
+
+/// Returns the last offset of the last period committed by each shard of a producer, -1 for a shard without
+/// committed periods.
+///
+/// Every offset up to the returned one is in `log`, the shard may have written more since.
+pub async fn get_committed_shard_offsets(
+    session: &Session,
+    producer: &ProducerInfo,
+    control_keyspace: Option<&str>,
+) -> anyhow::Result<Vec<(ShardId, ShardOffset)>> {
+    let cql_shard_list = (0..producer.num_shards)
+        .map(|shard_id| shard_id.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let query_last_period_commit = format!(
+        r###"
+        SELECT
+            shard_id,
+            period
+        FROM producer_period_commit_log
+        WHERE producer_id = ?
+        AND shard_id IN ({cql_shard_list})
+        ORDER BY period DESC
+        PER PARTITION LIMIT 1
+    "###
+    );
+    let committed = session
+        .query(
+            in_control_keyspace(&query_last_period_commit, control_keyspace),
+            (producer.producer_id,),
+        )
+        .await?
+        .rows_typed_or_empty::<(ShardId, ShardPeriod)>()
+        .collect::<Result<BTreeMap<_, _>, _>>()?;
+    Ok((0..producer.num_shards)
+        .map(|shard_id| {
+            let offset = committed
+                .get(&shard_id)
+                .map_or(-1, |period| last_offset_of_period(*period));
+            (shard_id, offset)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{completed_period, last_offset_of_period, CommittedPeriods},
+        crate::scylladb::types::SHARD_OFFSET_MODULO,
+    };
+
+    #[test]
+    fn batch_commits_the_period_it_completes() {
+        assert_eq!(completed_period(0), None);
+        assert_eq!(completed_period(SHARD_OFFSET_MODULO - 2), None);
+        assert_eq!(completed_period(SHARD_OFFSET_MODULO - 1), Some(0));
+        assert_eq!(completed_period(SHARD_OFFSET_MODULO), Some(0));
+        assert_eq!(completed_period(3 * SHARD_OFFSET_MODULO - 1), Some(2));
+        assert_eq!(last_offset_of_period(0), SHARD_OFFSET_MODULO - 1);
+        assert_eq!(last_offset_of_period(2), 3 * SHARD_OFFSET_MODULO - 1);
+    }
+
+    #[test]
+    fn committed_period_is_not_committed_again() {
+        let committed = CommittedPeriods::default();
+        assert_eq!(committed.to_commit(0, 10), None);
+        assert_eq!(committed.to_commit(0, SHARD_OFFSET_MODULO - 1), Some(0));
+        committed.record(0, 0);
+        assert_eq!(committed.to_commit(0, SHARD_OFFSET_MODULO + 10), None);
+        // Every shard commits its own periods.
+        assert_eq!(committed.to_commit(1, SHARD_OFFSET_MODULO + 10), Some(0));
+        // A failed commit is not recorded, the next batch commits the period again.
+        assert_eq!(committed.to_commit(0, 2 * SHARD_OFFSET_MODULO), Some(1));
+        assert_eq!(
+            committed.to_commit(0, 2 * SHARD_OFFSET_MODULO + 10),
+            Some(1)
+        );
+        committed.record(0, 1);
+        assert_eq!(committed.to_commit(0, 2 * SHARD_OFFSET_MODULO + 20), None);
+    }
+}
@@ -1,23 +1,40 @@
 use {
-    prometheus::{Histogram, HistogramOpts, IntCounter, IntGauge},
-    std::time::Duration,
+    super::{
+        sink::BufferFullPolicy,
+        types::{DataCodec, ProducerId, ShardId, ShardOffset, ShardPeriod},
+    },
+    prometheus::{
+        core::{Collector, Desc},
+        proto::MetricFamily,
+        GaugeVec, Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec,
+        Opts,
+    },
+    std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    },
 };
 
 lazy_static::lazy_static! {
-    pub(crate) static ref SCYLLADB_BATCH_DELIVERED: IntCounter = IntCounter::new(
-        "scylladb_batch_sent_total", "Total number of batch delivered"
+    pub(crate) static ref SCYLLADB_BATCH_DELIVERED: IntCounterVec = IntCounterVec::new(
+        Opts::new("scylladb_batch_sent_total", "Total number of batch delivered by producer"),
+        &["producer_id"]
     ).unwrap();
 
-    pub(crate) static ref SCYLLADB_BATCH_SIZE: Histogram = Histogram::with_opts(
-        HistogramOpts::new("scylladb_batch_size", "The batch size sent to Scylladb"),
+    pub(crate) static ref SCYLLADB_BATCH_SIZE: HistogramVec = HistogramVec::new(
+        HistogramOpts::new("scylladb_batch_size", "The batch size sent to Scylladb by producer"),
+        &["producer_id"]
     ).unwrap();
 
-    pub(crate) static ref SCYLLADB_BATCH_REQUEST_LAG: IntGauge = IntGauge::new(
-      "scylladb_batch_request_lag", "The amount of batch request not being handle by a batching task"
+    pub(crate) static ref SCYLLADB_BATCH_REQUEST_LAG: IntGaugeVec = IntGaugeVec::new(
+        Opts::new("scylladb_batch_request_lag", "The amount of batch request not being handle by a batching task by producer"),
+        &["producer_id"]
     ).unwrap();
 
-    pub(crate) static ref SCYLLADB_BATCHITEM_DELIVERED: IntCounter = IntCounter::new(
-        "scylladb_batchitem_sent_total", "Total number of batch items delivered"
+    pub(crate) static ref SCYLLADB_BATCHITEM_DELIVERED: IntCounterVec = IntCounterVec::new(
+        Opts::new("scylladb_batchitem_sent_total", "Total number of batch items delivered by producer"),
+        &["producer_id"]
     ).unwrap();
 
     pub(crate) static ref SCYLLADB_PEAK_BATCH_LINGER_SECONDS: Histogram = Histogram::with_opts(
@@ -28,18 +45,208 @@ lazy_static::lazy_static! {
       "scylladb_batch_queue_size", "The amount of batch concurrently being linger."
     ).unwrap();
 
+    pub(crate) static ref SCYLLADB_PREPARE_FAILURE: IntCounterVec = IntCounterVec::new(
+        Opts::new("scylladb_prepare_failure_total", "Total number of statements that failed to be prepared by producer"),
+        &["producer_id"]
+    ).unwrap();
+
+    pub(crate) static ref SCYLLADB_BUFFERED_BYTES: IntGaugeVec = IntGaugeVec::new(
+        Opts::new("scylladb_buffered_bytes", "Bytes buffered across every shard by producer"),
+        &["producer_id"]
+    ).unwrap();
+
+    pub(crate) static ref SCYLLADB_BUFFERED_BYTES_LIMIT: IntGaugeVec = IntGaugeVec::new(
+        Opts::new("scylladb_buffered_bytes_limit", "Maximum bytes buffered across every shard before pausing ingestion by producer"),
+        &["producer_id"]
+    ).unwrap();
+
+    pub(crate) static ref SCYLLADB_ACCOUNT_UPDATE_DEDUP: IntCounterVec = IntCounterVec::new(
+        Opts::new("scylladb_account_update_dedup_total", "Total number of superseded account updates dropped before being sent by producer"),
+        &["producer_id"]
+    ).unwrap();
+
+    pub(crate) static ref SCYLLADB_INGESTION_LATENCY: HistogramVec = HistogramVec::new(
+        HistogramOpts::new("scylladb_ingestion_latency_seconds", "Delay between the reception of an event and its write to Scylladb by producer")
+            .buckets(vec![0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0]),
+        &["producer_id"]
+    ).unwrap();
+
+    pub(crate) static ref SCYLLADB_PARTITION_WRITES: IntCounterVec = IntCounterVec::new(
+        Opts::new("scylladb_partition_writes_total", "Total number of events written in the current period partition of each shard by producer"),
+        &["producer_id", "shard_id", "period"]
+    ).unwrap();
+
+    pub(crate) static ref SCYLLADB_OVERSIZED_EVENT: IntCounterVec = IntCounterVec::new(
+        Opts::new("scylladb_oversized_event_total", "Total number of events rejected for exceeding the event size limit by producer"),
+        &["producer_id"]
+    ).unwrap();
+
+    pub(crate) static ref SCYLLADB_OVERSIZED_FIELD: IntCounterVec = IntCounterVec::new(
+        Opts::new("scylladb_oversized_field_total", "Total number of events with a field over its size limit by producer, field and action taken"),
+        &["producer_id", "field", "action"]
+    ).unwrap();
+
+    pub(crate) static ref SCYLLADB_SHARD_DURABILITY_LAG: IntGaugeVec = IntGaugeVec::new(
+        Opts::new("scylladb_shard_durability_lag", "Number of offsets assigned by a shard but not yet written to Scylladb"),
+        &["producer_id", "shard_id"]
+    ).unwrap();
+
+    pub(crate) static ref SCYLLADB_ROUTER_RESERVE_WAIT: HistogramVec = HistogramVec::new(
+        HistogramOpts::new("scylladb_router_reserve_wait_seconds", "Time the router waits for room in the mailbox of a shard by producer")
+            .buckets(vec![0.0001, 0.0005, 0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0]),
+        &["producer_id"]
+    ).unwrap();
+
+    pub(crate) static ref SCYLLADB_ROUTER_CURSOR: IntGaugeVec = IntGaugeVec::new(
+        Opts::new("scylladb_router_cursor", "Shard the round robin router last sent an event to by producer"),
+        &["producer_id"]
+    ).unwrap();
+
+    pub(crate) static ref SCYLLADB_ACTIVE_SHARDS: IntGaugeVec = IntGaugeVec::new(
+        Opts::new("scylladb_active_shards", "Number of shard daemons still running by producer"),
+        &["producer_id"]
+    ).unwrap();
+
+    pub(crate) static ref SCYLLADB_SINGLE_ITEM_BATCH_RATIO: GaugeVec = GaugeVec::new(
+        Opts::new("scylladb_single_item_batch_ratio", "Ratio of the last batches of a shard holding a single event by producer"),
+        &["producer_id", "shard_id"]
+    ).unwrap();
+
+    pub(crate) static ref SCYLLADB_PRODUCER_LOCK_HELD: IntGaugeVec = IntGaugeVec::new(
+        Opts::new("scylladb_producer_lock_held", "Whether this instance holds the lock of the producer (0 or 1)"),
+        &["producer_id"]
+    ).unwrap();
+
+    pub(crate) static ref SCYLLADB_CIRCUIT_BREAKER_STATE: IntGaugeVec = IntGaugeVec::new(
+        Opts::new("scylladb_circuit_breaker_state", "State of the circuit breaker of the sink by producer (0 closed, 1 half-open, 2 open)"),
+        &["producer_id"]
+    ).unwrap();
+
+    pub(crate) static ref SCYLLADB_CONSISTENCY_DOWNGRADE: IntCounterVec = IntCounterVec::new(
+        Opts::new("scylladb_consistency_downgrade_total", "Total number of times the batches of a producer were downgraded to LOCAL_ONE"),
+        &["producer_id"]
+    ).unwrap();
+
+    pub(crate) static ref SCYLLADB_SHARD_REBALANCE: IntCounterVec = IntCounterVec::new(
+        Opts::new("scylladb_shard_rebalance_total", "Total number of times the router moved keys between shards by producer"),
+        &["producer_id"]
+    ).unwrap();
+
+    pub(crate) static ref SCYLLADB_COMPRESSION_INPUT_BYTES: IntCounterVec = IntCounterVec::new(
+        Opts::new("scylladb_compression_input_bytes_total", "Total number of bytes handed to the compression of event data by producer, codec and payload"),
+        &["producer_id", "codec", "payload"]
+    ).unwrap();
+
+    pub(crate) static ref SCYLLADB_COMPRESSION_OUTPUT_BYTES: IntCounterVec = IntCounterVec::new(
+        Opts::new("scylladb_compression_output_bytes_total", "Total number of bytes of event data stored after compression by producer, codec and payload"),
+        &["producer_id", "codec", "payload"]
+    ).unwrap();
+
+    pub(crate) static ref SCYLLADB_STATEMENT_RETRY: IntCounterVec = IntCounterVec::new(
+        Opts::new("scylladb_statement_retry_total", "Total number of retried statement executions by producer and statement"),
+        &["producer_id", "statement"]
+    ).unwrap();
+
+    pub(crate) static ref SCYLLADB_STATEMENT_FAILURE: IntCounterVec = IntCounterVec::new(
+        Opts::new("scylladb_statement_failure_total", "Total number of statement executions failing after every attempt by producer and statement"),
+        &["producer_id", "statement"]
+    ).unwrap();
+
+    pub(crate) static ref SCYLLADB_LOCK_ACQUISITION_SECONDS: HistogramVec = HistogramVec::new(
+        HistogramOpts::new("scylladb_lock_acquisition_seconds", "Time taken to try to acquire the producer lock by producer")
+            .buckets(vec![0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0]),
+        &["producer_id"]
+    ).unwrap();
+
+    pub(crate) static ref SCYLLADB_LOCK_ACQUISITION: IntCounterVec = IntCounterVec::new(
+        Opts::new("scylladb_lock_acquisition_total", "Total number of producer lock acquisition attempts by producer and outcome (acquired, contended, error)"),
+        &["producer_id", "outcome"]
+    ).unwrap();
+
+    pub(crate) static ref SCYLLADB_TRANSACTION_DEDUP: IntCounterVec = IntCounterVec::new(
+        Opts::new("scylladb_transaction_dedup_total", "Total number of transactions dropped because their signature was seen within the dedup window by producer"),
+        &["producer_id"]
+    ).unwrap();
+
+    pub(crate) static ref SCYLLADB_BUFFER_FULL_DROP: IntCounterVec = IntCounterVec::new(
+        Opts::new("scylladb_buffer_full_drop_total", "Total number of events dropped by the router because their shard was saturated by producer and buffer full policy"),
+        &["producer_id", "policy"]
+    ).unwrap();
+
+    pub(crate) static ref SCYLLADB_SLOT_REGRESSION: IntCounterVec = IntCounterVec::new(
+        Opts::new("scylladb_slot_regression_total", "Total number of events whose slot lagged too far behind the highest slot seen by their shard"),
+        &["producer_id", "shard_id"]
+    ).unwrap();
+
+    pub(crate) static ref SCYLLADB_OLDEST_BUFFERED_EVENT_AGE: OldestBufferedEventAge = OldestBufferedEventAge::new();
+
+}
+
+/// (producer_id, shard_id) labels of a shard.
+type ShardLabels = (String, String);
+
+/// Age of the oldest event buffered by each shard, computed when the metrics are scraped.
+///
+/// A steadily growing age means the shard does not flush, usually because it is stuck writing its batch.
+#[derive(Clone)]
+pub(crate) struct OldestBufferedEventAge {
+    gauge: GaugeVec,
+    /// When the first event of the current buffer of each shard was pushed.
+    buffered_since: Arc<Mutex<HashMap<ShardLabels, Option<Instant>>>>,
+}
+
+impl OldestBufferedEventAge {
+    fn new() -> Self {
+        Self {
+            gauge: GaugeVec::new(
+                Opts::new("scylladb_oldest_buffered_event_age_seconds", "Age of the oldest event buffered by a shard and not yet written by producer, 0 if the buffer is empty"),
+                &["producer_id", "shard_id"],
+            ).unwrap(),
+            buffered_since: Arc::default(),
+        }
+    }
+}
+
+impl Collector for OldestBufferedEventAge {
+    fn desc(&self) -> Vec<&Desc> {
+        self.gauge.desc()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let buffered_since = self.buffered_since.lock().expect("poisoned");
+        self.gauge.reset();
+        for ((producer_id, shard_id), since) in buffered_since.iter() {
+            let age = since
+                .map(|since| since.elapsed().as_secs_f64())
+                .unwrap_or(0.0);
+            self.gauge
+                .with_label_values(&[producer_id, shard_id])
+                .set(age);
+        }
+        self.gauge.collect()
+    }
 }
 
-pub fn scylladb_batch_sent_inc() {
-    SCYLLADB_BATCH_DELIVERED.inc()
+fn producer_label(producer_id: ProducerId) -> String {
+    producer_id[0].to_string()
 }
 
-pub fn scylladb_batchitem_sent_inc_by(amount: u64) {
-    SCYLLADB_BATCHITEM_DELIVERED.inc_by(amount)
+pub fn scylladb_batch_sent_inc(producer_id: ProducerId) {
+    SCYLLADB_BATCH_DELIVERED
+        .with_label_values(&[&producer_label(producer_id)])
+        .inc()
 }
 
-pub fn scylladb_batch_size_observe(batch_size: usize) {
-    SCYLLADB_BATCH_SIZE.observe(batch_size as f64)
+pub fn scylladb_batchitem_sent_inc_by(producer_id: ProducerId, amount: u64) {
+    SCYLLADB_BATCHITEM_DELIVERED
+        .with_label_values(&[&producer_label(producer_id)])
+        .inc_by(amount)
+}
+
+pub fn scylladb_batch_size_observe(producer_id: ProducerId, batch_size: usize) {
+    SCYLLADB_BATCH_SIZE
+        .with_label_values(&[&producer_label(producer_id)])
+        .observe(batch_size as f64)
 }
 
 pub fn scylladb_peak_batch_linger_observe(batch_linger: Duration) {
@@ -54,10 +261,248 @@ pub fn scylladb_batch_queue_dec() {
     SCYLLADB_BATCH_QUEUE.dec()
 }
 
-pub fn scylladb_batch_request_lag_inc() {
-    SCYLLADB_BATCH_REQUEST_LAG.inc()
+pub fn scylladb_batch_request_lag_inc(producer_id: ProducerId) {
+    SCYLLADB_BATCH_REQUEST_LAG
+        .with_label_values(&[&producer_label(producer_id)])
+        .inc()
+}
+
+pub fn scylladb_batch_request_lag_sub(producer_id: ProducerId, amount: i64) {
+    SCYLLADB_BATCH_REQUEST_LAG
+        .with_label_values(&[&producer_label(producer_id)])
+        .sub(amount)
+}
+
+pub fn scylladb_account_update_dedup_inc(producer_id: ProducerId) {
+    SCYLLADB_ACCOUNT_UPDATE_DEDUP
+        .with_label_values(&[&producer_label(producer_id)])
+        .inc()
+}
+
+pub fn scylladb_prepare_failure_inc(producer_id: ProducerId) {
+    SCYLLADB_PREPARE_FAILURE
+        .with_label_values(&[&producer_label(producer_id)])
+        .inc()
+}
+
+pub fn scylladb_buffered_bytes_set(producer_id: ProducerId, bytes: usize) {
+    SCYLLADB_BUFFERED_BYTES
+        .with_label_values(&[&producer_label(producer_id)])
+        .set(bytes as i64)
+}
+
+pub fn scylladb_buffered_bytes_limit_set(producer_id: ProducerId, bytes: usize) {
+    SCYLLADB_BUFFERED_BYTES_LIMIT
+        .with_label_values(&[&producer_label(producer_id)])
+        .set(bytes as i64)
+}
+
+pub fn scylladb_shard_durability_lag_set(
+    producer_id: ProducerId,
+    shard_id: ShardId,
+    lag: ShardOffset,
+) {
+    SCYLLADB_SHARD_DURABILITY_LAG
+        .with_label_values(&[&producer_label(producer_id), &shard_id.to_string()])
+        .set(lag)
+}
+
+pub fn scylladb_single_item_batch_ratio_set(
+    producer_id: ProducerId,
+    shard_id: ShardId,
+    ratio: f64,
+) {
+    SCYLLADB_SINGLE_ITEM_BATCH_RATIO
+        .with_label_values(&[&producer_label(producer_id), &shard_id.to_string()])
+        .set(ratio)
+}
+
+/// Records when the first event of the current buffer of a shard was pushed, `None` once the buffer is empty.
+pub fn scylladb_oldest_buffered_event_set(
+    producer_id: ProducerId,
+    shard_id: ShardId,
+    since: Option<Instant>,
+) {
+    SCYLLADB_OLDEST_BUFFERED_EVENT_AGE
+        .buffered_since
+        .lock()
+        .expect("poisoned")
+        .insert((producer_label(producer_id), shard_id.to_string()), since);
+}
+
+pub fn scylladb_oldest_buffered_event_remove(producer_id: ProducerId, shard_id: ShardId) {
+    SCYLLADB_OLDEST_BUFFERED_EVENT_AGE
+        .buffered_since
+        .lock()
+        .expect("poisoned")
+        .remove(&(producer_label(producer_id), shard_id.to_string()));
+}
+
+pub fn scylladb_transaction_dedup_inc(producer_id: ProducerId) {
+    SCYLLADB_TRANSACTION_DEDUP
+        .with_label_values(&[&producer_label(producer_id)])
+        .inc()
+}
+
+pub fn scylladb_buffer_full_drop_inc(producer_id: ProducerId, policy: BufferFullPolicy) {
+    let policy = match policy {
+        BufferFullPolicy::Block => "block",
+        BufferFullPolicy::DropOldest => "drop_oldest",
+        BufferFullPolicy::DropNewest => "drop_newest",
+    };
+    SCYLLADB_BUFFER_FULL_DROP
+        .with_label_values(&[&producer_label(producer_id), policy])
+        .inc()
+}
+
+pub fn scylladb_slot_regression_inc(producer_id: ProducerId, shard_id: ShardId) {
+    SCYLLADB_SLOT_REGRESSION
+        .with_label_values(&[&producer_label(producer_id), &shard_id.to_string()])
+        .inc()
+}
+
+pub fn scylladb_ingestion_latency_observe(producer_id: ProducerId, latency_millis: i64) {
+    SCYLLADB_INGESTION_LATENCY
+        .with_label_values(&[&producer_label(producer_id)])
+        .observe(latency_millis.max(0) as f64 / 1000.0)
+}
+
+pub fn scylladb_partition_writes_inc_by(
+    producer_id: ProducerId,
+    shard_id: ShardId,
+    period: ShardPeriod,
+    amount: u64,
+) {
+    SCYLLADB_PARTITION_WRITES
+        .with_label_values(&[
+            &producer_label(producer_id),
+            &shard_id.to_string(),
+            &period.to_string(),
+        ])
+        .inc_by(amount)
+}
+
+/// Drops the series of a period partition a shard no longer writes to, keeping the cardinality bounded.
+pub fn scylladb_partition_writes_remove(
+    producer_id: ProducerId,
+    shard_id: ShardId,
+    period: ShardPeriod,
+) {
+    let _ = SCYLLADB_PARTITION_WRITES.remove_label_values(&[
+        &producer_label(producer_id),
+        &shard_id.to_string(),
+        &period.to_string(),
+    ]);
+}
+
+pub fn scylladb_oversized_field_inc(producer_id: ProducerId, field: &str, action: &str) {
+    SCYLLADB_OVERSIZED_FIELD
+        .with_label_values(&[&producer_label(producer_id), field, action])
+        .inc()
+}
+
+pub fn scylladb_router_reserve_wait_observe(producer_id: ProducerId, wait: Duration) {
+    SCYLLADB_ROUTER_RESERVE_WAIT
+        .with_label_values(&[&producer_label(producer_id)])
+        .observe(wait.as_secs_f64())
+}
+
+pub fn scylladb_router_cursor_set(producer_id: ProducerId, shard_id: ShardId) {
+    SCYLLADB_ROUTER_CURSOR
+        .with_label_values(&[&producer_label(producer_id)])
+        .set(shard_id.into())
+}
+
+pub fn scylladb_active_shards_inc(producer_id: ProducerId) {
+    SCYLLADB_ACTIVE_SHARDS
+        .with_label_values(&[&producer_label(producer_id)])
+        .inc()
+}
+
+pub fn scylladb_active_shards_dec(producer_id: ProducerId) {
+    SCYLLADB_ACTIVE_SHARDS
+        .with_label_values(&[&producer_label(producer_id)])
+        .dec()
+}
+
+pub fn scylladb_producer_lock_held_set(producer_id: ProducerId, held: bool) {
+    SCYLLADB_PRODUCER_LOCK_HELD
+        .with_label_values(&[&producer_label(producer_id)])
+        .set(held as i64)
+}
+
+pub fn scylladb_circuit_breaker_state_set(producer_id: ProducerId, state: i64) {
+    SCYLLADB_CIRCUIT_BREAKER_STATE
+        .with_label_values(&[&producer_label(producer_id)])
+        .set(state)
+}
+
+pub fn scylladb_consistency_downgrade_inc(producer_id: ProducerId) {
+    SCYLLADB_CONSISTENCY_DOWNGRADE
+        .with_label_values(&[&producer_label(producer_id)])
+        .inc()
+}
+
+pub fn scylladb_shard_rebalance_inc(producer_id: ProducerId) {
+    SCYLLADB_SHARD_REBALANCE
+        .with_label_values(&[&producer_label(producer_id)])
+        .inc()
+}
+
+/// Counts the bytes of a payload column, `data` or `tx_nested`, before and after compression, payloads left raw count
+/// as their own output.
+pub fn scylladb_compression_bytes_inc_by(
+    producer_id: ProducerId,
+    codec: DataCodec,
+    payload: &str,
+    input_bytes: usize,
+    output_bytes: usize,
+) {
+    let producer_id = producer_label(producer_id);
+    let labels = [producer_id.as_str(), codec_label(codec), payload];
+    SCYLLADB_COMPRESSION_INPUT_BYTES
+        .with_label_values(&labels)
+        .inc_by(input_bytes as u64);
+    SCYLLADB_COMPRESSION_OUTPUT_BYTES
+        .with_label_values(&labels)
+        .inc_by(output_bytes as u64);
+}
+
+const fn codec_label(codec: DataCodec) -> &'static str {
+    match codec {
+        DataCodec::Raw => "raw",
+        DataCodec::Lz4 => "lz4",
+    }
+}
+
+pub fn scylladb_oversized_event_inc(producer_id: ProducerId) {
+    SCYLLADB_OVERSIZED_EVENT
+        .with_label_values(&[&producer_label(producer_id)])
+        .inc()
+}
+
+pub fn scylladb_lock_acquisition_observe(
+    producer_id: ProducerId,
+    outcome: &str,
+    elapsed: Duration,
+) {
+    let producer_id = producer_label(producer_id);
+    SCYLLADB_LOCK_ACQUISITION_SECONDS
+        .with_label_values(&[&producer_id])
+        .observe(elapsed.as_secs_f64());
+    SCYLLADB_LOCK_ACQUISITION
+        .with_label_values(&[&producer_id, outcome])
+        .inc()
+}
+
+pub fn scylladb_statement_retry_inc(producer_id: ProducerId, statement: &str) {
+    SCYLLADB_STATEMENT_RETRY
+        .with_label_values(&[&producer_label(producer_id), statement])
+        .inc()
 }
 
-pub fn scylladb_batch_request_lag_sub(amount: i64) {
-    SCYLLADB_BATCH_REQUEST_LAG.sub(amount)
+pub fn scylladb_statement_failure_inc(producer_id: ProducerId, statement: &str) {
+    SCYLLADB_STATEMENT_FAILURE
+        .with_label_values(&[&producer_label(producer_id), statement])
+        .inc()
 }
@@ -1,33 +1,103 @@
 use {
     super::{
+        circuit_breaker::{CircuitBreaker, CircuitBreakerConfig, CircuitOpen},
+        consistency_downgrade::{
+            ConsistencyDowngrade, ConsistencyDowngradeConfig, DOWNGRADED_CONSISTENCY,
+        },
+        diagnostics::{list_nodes, ShardGauges, SinkDiagnostics},
+        error_class::{ErrorClass, ErrorClassifier},
+        guarded_statement::GuardedStatement,
+        offset_committer::{ScyllaOffsetCommitter, SharedOffsetCommitter},
         prom::{
-            scylladb_batch_request_lag_inc, scylladb_batch_request_lag_sub,
-            scylladb_batch_sent_inc, scylladb_batch_size_observe, scylladb_batchitem_sent_inc_by,
+            scylladb_account_update_dedup_inc, scylladb_active_shards_dec,
+            scylladb_active_shards_inc, scylladb_batch_request_lag_inc,
+            scylladb_batch_request_lag_sub, scylladb_batch_sent_inc, scylladb_batch_size_observe,
+            scylladb_batchitem_sent_inc_by, scylladb_buffer_full_drop_inc,
+            scylladb_buffered_bytes_limit_set, scylladb_buffered_bytes_set,
+            scylladb_compression_bytes_inc_by, scylladb_ingestion_latency_observe,
+            scylladb_lock_acquisition_observe, scylladb_oldest_buffered_event_remove,
+            scylladb_oldest_buffered_event_set, scylladb_oversized_event_inc,
+            scylladb_oversized_field_inc, scylladb_partition_writes_inc_by,
+            scylladb_partition_writes_remove, scylladb_prepare_failure_inc,
+            scylladb_producer_lock_held_set, scylladb_router_cursor_set,
+            scylladb_router_reserve_wait_observe, scylladb_shard_durability_lag_set,
+            scylladb_shard_rebalance_inc, scylladb_single_item_batch_ratio_set,
+            scylladb_slot_regression_inc, scylladb_transaction_dedup_inc,
         },
         types::{
-            AccountUpdate, BlockchainEvent, ProducerId, ProducerInfo, ShardId, ShardOffset,
-            ShardPeriod, Transaction, SHARD_OFFSET_MODULO,
+            ingestion_timestamp, log_partition, AccountUpdate, BlockMeta, BlockchainEvent,
+            BlockchainEventType, DataCodec, NestedCodec, ProducerId, ProducerInfo, ShardId,
+            ShardOffset, ShardPeriod, Slot, Transaction, SHARD_OFFSET_MODULO,
         },
+        wal::{RouterWal, ShardWal, WalSeq},
     },
+    chrono::{DateTime, Utc},
     deepsize::DeepSizeOf,
-    futures::future,
+    futures::{future, stream, StreamExt, TryStreamExt},
     local_ip_address::{list_afinet_netifas, local_ip},
+    rand::Rng,
     scylla::{
+        authentication::AuthenticatorProvider,
         batch::{Batch, BatchType},
         cql_to_rust::{FromCqlVal, FromCqlValError, FromRowError},
-        frame::Compression,
+        frame::{response::result::ColumnType, Compression},
+        prepared_statement::PreparedStatement,
+        query::Query,
+        serialize::{
+            row::{RowSerializationContext, SerializeRow},
+            value::SerializeCql,
+            writers::{CellWriter, RowWriter, WrittenCellProof},
+            SerializationError,
+        },
+        statement::Consistency,
+        transport::session::PoolSize,
         FromRow, Session, SessionBuilder,
     },
-    std::{collections::BTreeMap, net::IpAddr, sync::Arc, time::Duration},
-    tokio::{task::JoinHandle, time::Instant},
+    serde::{Deserialize, Serialize},
+    serde_with::{serde_as, DurationMilliSeconds},
+    std::{
+        cmp::Reverse,
+        collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+        future::Future,
+        net::IpAddr,
+        num::NonZeroUsize,
+        ops::ControlFlow,
+        path::PathBuf,
+        sync::{
+            atomic::{AtomicBool, AtomicI64, AtomicUsize, Ordering},
+            Arc, Mutex, OnceLock,
+        },
+        task::Poll,
+        time::Duration,
+    },
+    tokio::{
+        sync::{mpsc, oneshot, watch},
+        task::JoinHandle,
+        time::{Instant, MissedTickBehavior},
+    },
     tracing::{error, info, warn},
     uuid::Uuid,
 };
 
 const WARNING_SCYLLADB_LATENCY_THRESHOLD: Duration = Duration::from_millis(1000);
 
+/// Delay before writing a batch again after replicas were reported unavailable.
+const UNAVAILABLE_RETRY_DELAY: Duration = Duration::from_millis(100);
+
 const DEFAULT_SHARD_MAX_BUFFER_CAPACITY: usize = 15;
 
+/// How often the router hands the events held for saturated shards over, see [`BufferFullPolicy`].
+const OVERFLOW_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Number of flushes over which a shard computes its ratio of single item batches.
+const SINGLE_ITEM_BATCH_WINDOW: usize = 100;
+
+/// Ratio of single item batches above which a shard warns that batching is ineffective.
+const SINGLE_ITEM_BATCH_WARN_RATIO: f64 = 0.8;
+
+/// Number of shards whose last offset is read concurrently when recovering the shard offsets.
+pub(crate) const DEFAULT_RECOVERY_CONCURRENCY: usize = 32;
+
 /// Untyped API in scylla will soon be deprecated, this is why we need to implement our own deser logic to
 /// only read the first column returned by a light weight transaction.
 struct LwtSuccess(bool);
@@ -57,645 +127,7393 @@ const INSERT_PRODUCER_SLOT: &str = r###"
     VALUES (?, ?, currentTimestamp())
 "###;
 
+const INSERT_PRODUCER_HEARTBEAT: &str = r###"
+    INSERT INTO producer_heartbeat (producer_id, heartbeat_at)
+    VALUES (?, currentTimestamp())
+"###;
+
+const GET_PRODUCER_HEARTBEAT: &str = r###"
+    SELECT heartbeat_at
+    FROM producer_heartbeat
+    WHERE producer_id = ?
+"###;
+
+const DELETE_LOG_PARTITION: &str = r###"
+    DELETE FROM log
+    WHERE shard_id = ? AND period = ? AND producer_id = ?
+"###;
+
+const GET_LOG_PARTITION_SLOT_RANGE: &str = r###"
+    SELECT min(slot), max(slot)
+    FROM log
+    WHERE shard_id = ? AND period = ? AND producer_id = ?
+"###;
+
+const DELETE_LOG_BY_SLOT_PARTITION: &str = r###"
+    DELETE FROM log_by_slot
+    WHERE producer_id = ? AND slot_bucket = ? AND shard_id = ?
+"###;
+
+const DELETE_SHARD_PERIOD_COMMITS: &str = r###"
+    DELETE FROM producer_period_commit_log
+    WHERE producer_id = ? AND shard_id = ?
+"###;
+
+const DELETE_PRODUCER_SLOTS: &str = r###"
+    DELETE FROM producer_slot_seen
+    WHERE producer_id = ?
+"###;
+
+const DELETE_PRODUCER_HEARTBEAT: &str = r###"
+    DELETE FROM producer_heartbeat
+    WHERE producer_id = ?
+"###;
+
+/// Rows of a producer deleted by [`reset_producer`] once its events are gone, each keyed by the producer id.
+const DELETE_PRODUCER_METADATA: [&str; 2] = [DELETE_PRODUCER_SLOTS, DELETE_PRODUCER_HEARTBEAT];
+
+const GET_PROBE_EVENT: &str = r###"
+    SELECT offset
+    FROM log
+    WHERE shard_id = ? AND period = ? AND producer_id = ? AND offset = ?
+"###;
+
+const DELETE_PROBE_EVENT: &str = r###"
+    DELETE FROM log
+    WHERE shard_id = ? AND period = ? AND producer_id = ? AND offset = ?
+"###;
+
+/// Reads the high-water mark of a producer, `max_slot` is a static column of `producer_slot_seen`.
+const GET_PRODUCER_MAX_SLOT: &str = r###"
+    SELECT max_slot
+    FROM producer_slot_seen
+    WHERE producer_id = ?
+    LIMIT 1
+"###;
+
+/// Raises the high-water mark of a producer.
+const RAISE_PRODUCER_MAX_SLOT: &str = r###"
+    UPDATE producer_slot_seen
+    SET max_slot = ?
+    WHERE producer_id = ?
+    IF max_slot < ?
+"###;
+
+/// Sets the first high-water mark of a producer, a null `max_slot` never satisfies [`RAISE_PRODUCER_MAX_SLOT`].
+const INIT_PRODUCER_MAX_SLOT: &str = r###"
+    UPDATE producer_slot_seen
+    SET max_slot = ?
+    WHERE producer_id = ?
+    IF max_slot = null
+"###;
+
+const GET_KEYSPACE_REPLICATION: &str = r###"
+    SELECT replication
+    FROM system_schema.keyspaces
+    WHERE keyspace_name = ?
+"###;
+
+const GET_CLUSTERING_ORDER: &str = r###"
+    SELECT clustering_order
+    FROM system_schema.columns
+    WHERE keyspace_name = ? AND table_name = ? AND column_name = ?
+"###;
+
+/// Clustering columns the offset recovery queries read in descending order, as created by `solana.cql`.
+///
+/// The boolean tells whether the table lives in the control keyspace.
+const RECOVERY_CLUSTERING_ORDER: [(&str, &str, bool); 2] = [
+    ("producer_period_commit_log", "period", true),
+    ("log", "offset", false),
+];
+
 const DROP_PRODUCER_LOCK: &str = r###"
     DELETE FROM producer_lock
     WHERE producer_id = ?
     IF lock_id = ?
 "###;
 
+const ROTATE_PRODUCER_LOCK: &str = r###"
+    UPDATE producer_lock
+    SET lock_id = ?
+    WHERE producer_id = ?
+    IF lock_id = ?
+"###;
+
 const TRY_ACQUIRE_PRODUCER_LOCK: &str = r###"
     INSERT INTO producer_lock (producer_id, lock_id, ifname, ipv4, created_at)
     VALUES (?, ?, ?, ?, currentTimestamp())
     IF NOT EXISTS
 "###;
 
-const GET_PRODUCER_INFO_BY_ID: &str = r###"
+const GET_PRODUCER_LOCK: &str = r###"
     SELECT
-        producer_id,
-        num_shards
-    FROM producer_info
+        lock_id,
+        ifname,
+        ipv4,
+        created_at,
+        writetime(lock_id)
+    FROM producer_lock
     WHERE producer_id = ?
 "###;
 
-const COMMIT_SHARD_PERIOD: &str = r###"
-    INSERT INTO producer_period_commit_log (producer_id, shard_id, period, created_at)
-    VALUES (?, ?, ?, currentTimestamp())
+const LIST_PRODUCERS: &str = r###"
+    SELECT
+        producer_id,
+        num_shards,
+        partitions_per_period,
+        slot_bucket_size
+    FROM producer_info
 "###;
 
-const INSERT_BLOCKCHAIN_EVENT: &str = r###"
-    INSERT INTO log (
-        shard_id, 
-        period,
+const LIST_SHARDS_WITH_COMMITTED_PERIODS: &str = r###"
+    SELECT DISTINCT
         producer_id,
-        offset,
-        slot,
-        event_type,
-        pubkey, 
-        lamports, 
-        owner, 
-        executable, 
-        rent_epoch, 
-        write_version, 
-        data, 
-        txn_signature,
-        signature,
-        signatures,
-        num_readonly_signed_accounts, 
-        num_readonly_unsigned_accounts,
-        num_required_signatures,
-        account_keys, 
-        recent_blockhash, 
-        instructions, 
-        versioned,
-        address_table_lookups, 
-        meta,
-        is_vote,
-        tx_index,
-        created_at
-    )
-    VALUES (?,?,?, ?,?,?,  ?,?,?, ?,?,?, ?,?,?, ?,?,?, ?,?,?, ?,?,?, ?,?,?, currentTimestamp())
+        shard_id
+    FROM producer_period_commit_log
+    WHERE producer_id = ? AND shard_id IN ?
 "###;
 
-#[derive(Clone, PartialEq, Debug)]
-pub struct ScyllaSinkConfig {
-    pub producer_id: u8,
-    pub batch_len_limit: usize,
-    pub batch_size_kb_limit: usize,
-    pub linger: Duration,
-    pub keyspace: String,
-    pub ifname: Option<String>,
-}
-
-#[allow(clippy::large_enum_variant)]
-#[derive(Debug, Clone, PartialEq)]
-enum ClientCommand {
-    Shutdown,
-    // Add other action if necessary...
-    InsertAccountUpdate(AccountUpdate),
-    InsertTransaction(Transaction),
-}
-
-/// Represents a shard responsible for processing and batching `ClientCommand` messages
-/// before committing them to the database in a background daemon.
-///
-/// This struct encapsulates the state and behavior required to manage message buffering,
-/// batching, and period-based commitment for a specific shard within a distributed system.
-struct Shard {
-    /// Arc-wrapped database session for executing queries.
-    session: Arc<Session>,
+/// Number of shards above the ones of a producer checked for committed periods by [`list_orphaned_shards`].
+const ORPHANED_SHARD_SCAN_LEN: ShardId = 256;
 
-    /// Unique identifier for the shard.
-    shard_id: ShardId,
+/// Shards restricted by a single query of [`list_orphaned_shards`], ScyllaDB caps it to 100 partitions by default.
+const ORPHANED_SHARD_SCAN_CHUNK: usize = 64;
 
-    /// Unique identifier for the producer associated with this shard.
-    producer_id: ProducerId,
+const REGISTER_PRODUCER: &str = r###"
+    INSERT INTO producer_info (producer_id, num_shards, partitions_per_period, created_at, updated_at)
+    VALUES (?, ?, ?, currentTimestamp(), currentTimestamp())
+    IF NOT EXISTS
+"###;
 
-    /// The next offset to be assigned for incoming client commands.
-    next_offset: ShardOffset,
+const GET_PRODUCER_INFO_BY_ID: &str = r###"
+    SELECT
+        producer_id,
+        num_shards,
+        partitions_per_period,
+        slot_bucket_size
+    FROM producer_info
+    WHERE producer_id = ?
+"###;
 
-    /// Buffer to store sharded client commands before batching.
-    buffer: Vec<BlockchainEvent>,
+const SET_PRODUCER_SLOT_BUCKET_SIZE: &str = r###"
+    UPDATE producer_info
+    SET slot_bucket_size = ?, updated_at = currentTimestamp()
+    WHERE producer_id = ?
+    IF slot_bucket_size = null
+"###;
 
-    /// Maximum capacity of the buffer (number of commands it can hold).
-    max_buffer_capacity: usize,
+pub(crate) const COMMIT_SHARD_PERIOD: &str = r###"
+    INSERT INTO producer_period_commit_log (producer_id, shard_id, period, created_at)
+    VALUES (?, ?, ?, currentTimestamp())
+"###;
 
-    /// Maximum byte size of the buffer (sum of sizes of commands it can hold).
-    max_buffer_byte_size: usize,
+/// Columns written by the sink in `log`, `created_at` is set by ScyllaDB.
+const LOG_INSERT_COLUMNS: [&str; 44] = [
+    "shard_id",
+    "period",
+    "producer_id",
+    "offset",
+    "slot",
+    "event_type",
+    "pubkey",
+    "lamports",
+    "owner",
+    "executable",
+    "rent_epoch",
+    "write_version",
+    "data",
+    "data_codec",
+    "txn_signature",
+    "signature",
+    "signatures",
+    "num_readonly_signed_accounts",
+    "num_readonly_unsigned_accounts",
+    "num_required_signatures",
+    "account_keys",
+    "recent_blockhash",
+    "instructions",
+    "versioned",
+    "address_table_lookups",
+    "meta",
+    "is_vote",
+    "tx_index",
+    "ingested_at",
+    "truncated",
+    "tx_nested",
+    "tx_nested_codec",
+    "tx_nested_compression",
+    "tx_success",
+    "tx_err",
+    "blockhash",
+    "parent_slot",
+    "parent_blockhash",
+    "block_height",
+    "block_time",
+    "rewards",
+    "executed_transaction_count",
+    "entries_count",
+    "raw_proto",
+];
 
-    /// Batch for executing database statements in bulk.
-    scylla_batch: Batch,
+/// Returns true if `offset` is the first offset of a period following another one.
+const fn starts_new_period(offset: ShardOffset) -> bool {
+    offset > 0 && offset % SHARD_OFFSET_MODULO == 0
+}
 
-    /// Current byte size of the batch being constructed.
-    curr_batch_byte_size: usize,
+/// Returns true if `offset` is the first offset of a partition of `log` following another one, see [`log_partition`].
+const fn starts_new_partition(offset: ShardOffset, partitions_per_period: i16) -> bool {
+    offset > 0
+        && log_partition(offset, partitions_per_period)
+            != log_partition(offset - 1, partitions_per_period)
+}
 
-    /// Duration to linger before flushing the buffer.
-    buffer_linger: Duration,
+/// What a shard does before buffering the event of a newly reserved offset, see [`Shard::reserve_offset`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum OffsetBoundary {
+    /// The offset belongs to the partition of the buffered events.
+    None,
+    /// The offset starts a new partition, the buffered events are flushed first.
+    Partition,
+    /// The offset starts a new period, the buffered events are flushed then the previous period is committed.
+    Period,
 }
 
-impl Shard {
-    fn new(
-        session: Arc<Session>,
-        shard_id: ShardId,
-        producer_id: ProducerId,
-        next_offset: ShardOffset,
-        max_buffer_capacity: usize,
-        max_buffer_byte_size: usize,
-        buffer_linger: Duration,
-    ) -> Self {
-        if next_offset < 0 {
-            panic!("next offset can not be negative");
-        }
-        Shard {
-            session,
-            shard_id,
-            producer_id,
-            next_offset,
-            buffer: Vec::with_capacity(max_buffer_capacity),
-            max_buffer_capacity,
-            max_buffer_byte_size,
-            // Since each shard will only batch into a single partition at a time, we can safely disable batch logging
-            // without losing atomicity guarantee provided by scylla.
-            scylla_batch: Batch::new(BatchType::Unlogged),
-            buffer_linger,
-            curr_batch_byte_size: 0,
+impl OffsetBoundary {
+    const fn of(offset: ShardOffset, partitions_per_period: i16) -> Self {
+        if starts_new_period(offset) {
+            OffsetBoundary::Period
+        } else if starts_new_partition(offset, partitions_per_period) {
+            OffsetBoundary::Partition
+        } else {
+            OffsetBoundary::None
         }
     }
 
-    fn clear_buffer(&mut self) {
-        self.buffer.clear();
-        self.curr_batch_byte_size = 0;
-        self.scylla_batch.statements.clear();
+    const fn requires_flush(self) -> bool {
+        !matches!(self, OffsetBoundary::None)
     }
+}
 
-    async fn flush(&mut self) -> anyhow::Result<()> {
-        let buffer_len = self.buffer.len();
-        if buffer_len > 0 {
-            let before = Instant::now();
-            // We must wait for the batch success to guarantee monotonicity in the shard's timeline.
-            self.session.batch(&self.scylla_batch, &self.buffer).await?;
-            scylladb_batch_request_lag_sub(buffer_len as i64);
-            scylladb_batch_sent_inc();
-            scylladb_batch_size_observe(buffer_len);
-            scylladb_batchitem_sent_inc_by(buffer_len as u64);
-            if before.elapsed() >= WARNING_SCYLLADB_LATENCY_THRESHOLD {
-                warn!("sent {} elements in {:?}", buffer_len, before.elapsed());
+/// Returns the expected and actual offsets of the first event of `events` not following `first_offset` contiguously.
+fn offset_gap(
+    first_offset: ShardOffset,
+    events: &[BlockchainEvent],
+) -> Option<(ShardOffset, ShardOffset)> {
+    (first_offset..)
+        .zip(events)
+        .find(|(expected, event)| event.offset != *expected)
+        .map(|(expected, event)| (expected, event.offset))
+}
+
+/// Compresses a copy of `events` for the rows of a batch.
+///
+/// Returns the copy along with the bytes of the `data` and `tx_nested` payloads before and after compression,
+/// leaving out the events below `metered_offset` that a previous attempt to flush them already counted.
+fn compress_rows(
+    events: &[BlockchainEvent],
+    min_bytes: usize,
+    metered_offset: ShardOffset,
+) -> (Vec<BlockchainEvent>, [(&'static str, (usize, usize)); 2]) {
+    let mut compressed = events.to_vec();
+    let (mut data, mut tx_nested) = ((0, 0), (0, 0));
+    for event in compressed.iter_mut() {
+        let sizes = event.compress_data(min_bytes);
+        if event.offset < metered_offset {
+            continue;
+        }
+        for (total, size) in [(&mut data, sizes.data), (&mut tx_nested, sizes.tx_nested)] {
+            if let Some((input, output)) = size {
+                total.0 += input;
+                total.1 += output;
             }
         }
-        self.clear_buffer();
-        Ok(())
     }
+    (compressed, [("data", data), ("tx_nested", tx_nested)])
+}
 
-    /// Converts the current `Shard` instance into a background daemon for processing and batching `ClientCommand` messages.
-    ///
-    /// This method spawns an asynchronous task (`tokio::spawn`) to continuously receive messages from a channel (`receiver`),
-    /// batch process them, and commit periods to the database. It handles message buffering
-    /// and period commitment based on the configured buffer settings and period boundaries.
-    ///
-    /// # Returns
-    /// Returns a `Sender` channel (`tokio::sync::mpsc::Sender<ClientCommand>`) that can be used to send `ClientCommand` messages
-    /// to the background daemon for processing and batching.
-    fn into_daemon(
-        mut self,
-    ) -> (
-        tokio::sync::mpsc::Sender<ClientCommand>,
-        JoinHandle<anyhow::Result<()>>,
-    ) {
-        let (sender, mut receiver) = tokio::sync::mpsc::channel::<ClientCommand>(16);
+/// Name ScyllaDB gives to the bind marker of `USING TIMESTAMP`.
+const WRITE_TIMESTAMP_MARKER: &str = "[timestamp]";
 
-        let handle: JoinHandle<anyhow::Result<()>> = tokio::spawn(async move {
-            let insert_event_ps = self.session.prepare(INSERT_BLOCKCHAIN_EVENT).await?;
-            let commit_period_ps = self.session.prepare(COMMIT_SHARD_PERIOD).await?;
+/// Bits of the client write timestamp holding the position of an event within its slot.
+const WRITE_TIMESTAMP_SLOT_SHIFT: u32 = 20;
 
-            let mut buffering_timeout = Instant::now() + self.buffer_linger;
-            loop {
-                let shard_id = self.shard_id;
-                let producer_id = self.producer_id;
-                let offset = self.next_offset;
-                let curr_period = offset / SHARD_OFFSET_MODULO;
-
-                // If we started a new period
-                if offset % SHARD_OFFSET_MODULO == 0 && offset > 0 {
-                    // Make sure the last period is committed
-                    let t = Instant::now();
-                    self.session
-                        .execute(&commit_period_ps, (producer_id, shard_id, curr_period - 1))
-                        .await?;
-                    info!(
-                        shard = shard_id,
-                        producer_id = ?self.producer_id,
-                        committed_period = curr_period,
-                        time_to_commit = ?t.elapsed()
-                    );
-                }
+/// Write timestamp of an event derived from its slot, then the index of a transaction in its slot.
+///
+/// Account updates of a slot share a timestamp, the sink orders them by offset and write versions do not fit in
+/// the low bits. Indexes past 2^20 are capped rather than wrapped, a later slot always wins.
+fn client_write_timestamp(event: &BlockchainEvent) -> i64 {
+    let position = event
+        .tx_index
+        .unwrap_or(0)
+        .clamp(0, (1 << WRITE_TIMESTAMP_SLOT_SHIFT) - 1);
+    (event.slot << WRITE_TIMESTAMP_SLOT_SHIFT) | position
+}
 
-                self.next_offset += 1;
-                let msg = receiver
-                    .recv()
-                    .await
-                    .ok_or(anyhow::anyhow!("Shard mailbox closed"))?;
+/// Largest TTL accepted by ScyllaDB, 20 years.
+const MAX_ROW_TTL: Duration = Duration::from_secs(630_720_000);
 
-                let maybe_blockchain_event = match msg {
-                    ClientCommand::Shutdown => None,
-                    ClientCommand::InsertAccountUpdate(acc_update) => {
-                        Some(acc_update.as_blockchain_event(shard_id, producer_id, offset))
-                    }
-                    ClientCommand::InsertTransaction(new_tx) => {
-                        Some(new_tx.as_blockchain_event(shard_id, producer_id, offset))
-                    }
-                };
+/// Refuses a `row_ttl` below a second, it would be rounded down to `TTL 0` which never expires the rows, or
+/// above [`MAX_ROW_TTL`], every insert would be rejected.
+fn check_row_ttl(row_ttl: Option<Duration>) -> anyhow::Result<()> {
+    if let Some(row_ttl) = row_ttl {
+        anyhow::ensure!(
+            row_ttl.as_secs() > 0,
+            "row_ttl must be at least 1 second, got {row_ttl:?}"
+        );
+        anyhow::ensure!(
+            row_ttl.as_secs() <= MAX_ROW_TTL.as_secs(),
+            "row_ttl must be at most {} seconds, got {row_ttl:?}",
+            MAX_ROW_TTL.as_secs()
+        );
+    }
+    Ok(())
+}
+
+/// Builds the insert of an event into `log`, leaving out the optional columns missing from `column_mask`.
+///
+/// Every optional column is written if `column_mask` is `None`. The write timestamp is bound along the
+/// columns if `client_write_timestamps` is set, see [`ScyllaSinkConfig::client_write_timestamps`].
+/// Rows expire after `row_ttl`, rounded down to the second, if any. `raw_proto` is only written if
+/// `store_raw_proto` is set, see [`ScyllaSinkConfig::store_raw_proto`].
+fn insert_blockchain_event_query(
+    column_mask: Option<&BTreeSet<OptionalColumn>>,
+    client_write_timestamps: bool,
+    row_ttl: Option<Duration>,
+    store_raw_proto: bool,
+) -> String {
+    let columns = LOG_INSERT_COLUMNS
+        .into_iter()
+        .filter(|column| store_raw_proto || *column != "raw_proto")
+        .filter(
+            |column| match (column_mask, OptionalColumn::from_name(column)) {
+                (Some(column_mask), Some(optional)) => column_mask.contains(&optional),
+                _ => true,
+            },
+        )
+        .collect::<Vec<_>>();
+    let bind_markers = vec!["?"; columns.len()].join(", ");
+    let mut using = Vec::new();
+    if client_write_timestamps {
+        using.push("TIMESTAMP ?".to_owned());
+    }
+    if let Some(row_ttl) = row_ttl {
+        using.push(format!("TTL {}", row_ttl.as_secs()));
+    }
+    let using = if using.is_empty() {
+        String::new()
+    } else {
+        format!(" USING {}", using.join(" AND "))
+    };
+    format!(
+        "INSERT INTO log ({}, created_at) VALUES ({bind_markers}, currentTimestamp()){using}",
+        columns.join(", ")
+    )
+}
 
-                if let Some(blockchain_event) = maybe_blockchain_event {
-                    let msg_byte_size = blockchain_event.deep_size_of();
+/// Turns the insert into `log` built by [`insert_blockchain_event_query`] into the insert of the same row into
+/// `log_by_slot`, its bucket bound first, see [`ScyllaSinkConfig::slot_bucket_size`].
+fn insert_log_by_slot_query(insert_log: &str) -> String {
+    insert_log
+        .replacen(
+            "INSERT INTO log (",
+            "INSERT INTO log_by_slot (slot_bucket, ",
+            1,
+        )
+        .replacen("VALUES (", "VALUES (?, ", 1)
+}
 
-                    let need_flush = self.buffer.len() >= self.max_buffer_capacity
-                        || self.curr_batch_byte_size + msg_byte_size >= self.max_buffer_byte_size
-                        || buffering_timeout.elapsed() > Duration::ZERO;
+/// Bucket of `log_by_slot` holding the events of `slot`.
+pub const fn slot_bucket(slot: Slot, slot_bucket_size: Slot) -> Slot {
+    slot.div_euclid(slot_bucket_size)
+}
 
-                    if need_flush {
-                        self.flush().await?;
-                        buffering_timeout = Instant::now() + self.buffer_linger;
-                    }
+/// Metadata tables of the sink, they may live in a control keyspace apart from the `log` table.
+const CONTROL_TABLES: [&str; 5] = [
+    "producer_heartbeat",
+    "producer_info",
+    "producer_lock",
+    "producer_period_commit_log",
+    "producer_slot_seen",
+];
 
-                    self.buffer.push(blockchain_event);
-                    self.scylla_batch.append_statement(insert_event_ps.clone());
-                    self.curr_batch_byte_size += msg_byte_size;
-                } else {
-                    warn!("Shard {} received shutdown command.", shard_id);
-                    self.flush().await?;
-                    warn!("shard {} finished shutdown procedure", shard_id);
-                    return Ok(());
-                }
+/// Qualifies the metadata tables referenced by `query` with `control_keyspace`.
+///
+/// The query is left untouched when there is no control keyspace, every table then lives in the session keyspace.
+pub(crate) fn in_control_keyspace(query: &str, control_keyspace: Option<&str>) -> String {
+    let Some(keyspace) = control_keyspace else {
+        return query.to_owned();
+    };
+    query
+        .split_inclusive(char::is_whitespace)
+        .map(|token| {
+            if CONTROL_TABLES.contains(&token.trim_end()) {
+                format!("{keyspace}.{token}")
+            } else {
+                token.to_owned()
             }
-        });
-        (sender, handle)
-    }
+        })
+        .collect()
 }
 
-pub struct ScyllaSink {
-    router_sender: tokio::sync::mpsc::Sender<ClientCommand>,
-    router_handle: JoinHandle<anyhow::Result<()>>,
-    shard_handles: Vec<JoinHandle<anyhow::Result<()>>>,
-    producer_lock: ProducerLock,
+/// Statements prepared once when the sink starts and shared by the router and every shard.
+#[derive(Clone)]
+struct SinkStatements {
+    insert_blockchain_event: GuardedStatement,
+    /// Columns bound by `insert_blockchain_event`.
+    log_columns: Vec<ColumnSlot>,
+    /// Appended to the event batches along `insert_blockchain_event` when the slot layout is enabled.
+    insert_log_by_slot: Option<PreparedStatement>,
+    /// Columns bound by `insert_log_by_slot`, empty without the slot layout.
+    log_by_slot_columns: Vec<ColumnSlot>,
+    commit_shard_period: GuardedStatement,
+    insert_producer_slot: GuardedStatement,
+    max_slot_seen: Option<MaxSlotStatements>,
+    /// Set if the producer keepalive writes a heartbeat, see [`ScyllaSinkConfig::heartbeat_interval`].
+    insert_producer_heartbeat: Option<GuardedStatement>,
 }
 
-#[derive(Debug)]
-pub enum ScyllaSinkError {
-    SinkClose,
+/// Lightweight transactions keeping the `producer_slot_seen` high-water mark from going backward.
+#[derive(Clone)]
+struct MaxSlotStatements {
+    get: PreparedStatement,
+    raise: PreparedStatement,
+    init: PreparedStatement,
 }
 
-/// Retrieves the latest shard offsets for a specific producer from the `shard_max_offset_mv` materialized view.
-///
-/// This asynchronous function queries the database session to fetch the latest shard offsets associated with
-/// a given `producer_id` from the `shard_max_offset_mv` materialized view. It constructs and executes a SELECT
-/// query to retrieve the shard IDs and corresponding offsets ordered by offset and period.
+impl SinkStatements {
+    /// Prepares every statement used by the sink, failing on the first one ScyllaDB rejects.
+    ///
+    /// Event batches are not retried here, the circuit breaker and the consistency downgrade decide when to
+    /// send them again, but they fail past the flush deadline. Metadata writes are idempotent and retried.
+    async fn prepare(session: &Session, config: &ScyllaSinkConfig) -> anyhow::Result<Self> {
+        let producer_id = [config.producer_id];
+        let control_keyspace = config.control_keyspace.as_deref();
+        let prepare = |query: &str| {
+            let query = in_control_keyspace(query, control_keyspace);
+            async move {
+                session.prepare(query.as_str()).await.map_err(|e| {
+                    scylladb_prepare_failure_inc(producer_id);
+                    anyhow::anyhow!("failed to prepare statement {query}: {e}")
+                })
+            }
+        };
+        let max_slot_seen = if config.monotonic_slot_seen {
+            Some(MaxSlotStatements {
+                get: prepare(GET_PRODUCER_MAX_SLOT).await?,
+                raise: prepare(RAISE_PRODUCER_MAX_SLOT).await?,
+                init: prepare(INIT_PRODUCER_MAX_SLOT).await?,
+            })
+        } else {
+            None
+        };
+        let insert_blockchain_event_query = insert_blockchain_event_query(
+            config.column_mask.as_ref(),
+            config.client_write_timestamps,
+            config.row_ttl,
+            config.store_raw_proto,
+        );
+        let insert_blockchain_event = prepare(&insert_blockchain_event_query).await?;
+        let log_columns = ColumnSlot::resolve_all(&insert_blockchain_event)?;
+        let insert_log_by_slot = if config.slot_bucket_size.is_some() {
+            Some(prepare(&insert_log_by_slot_query(&insert_blockchain_event_query)).await?)
+        } else {
+            None
+        };
+        let log_by_slot_columns = insert_log_by_slot
+            .as_ref()
+            .map(ColumnSlot::resolve_all)
+            .transpose()?
+            .unwrap_or_default();
+        let no_retry = RetryPolicy {
+            max_attempts: 1,
+            ..Default::default()
+        };
+        Ok(SinkStatements {
+            insert_blockchain_event: GuardedStatement::new(
+                insert_blockchain_event,
+                "event batch",
+                producer_id,
+                no_retry,
+                config.error_classifier.clone(),
+            )
+            .with_deadline(config.flush_deadline),
+            log_columns,
+            insert_log_by_slot,
+            log_by_slot_columns,
+            commit_shard_period: GuardedStatement::new(
+                prepare(COMMIT_SHARD_PERIOD).await?,
+                "period commit",
+                producer_id,
+                config.metadata_write_retry,
+                config.error_classifier.clone(),
+            ),
+            insert_producer_slot: GuardedStatement::new(
+                prepare(INSERT_PRODUCER_SLOT).await?,
+                "slot seen commit",
+                producer_id,
+                config.metadata_write_retry,
+                config.error_classifier.clone(),
+            ),
+            max_slot_seen,
+            insert_producer_heartbeat: match config.heartbeat_interval {
+                Some(_) => Some(GuardedStatement::new(
+                    prepare(INSERT_PRODUCER_HEARTBEAT).await?,
+                    "heartbeat",
+                    producer_id,
+                    config.metadata_write_retry,
+                    config.error_classifier.clone(),
+                )),
+                None => None,
+            },
+        })
+    }
+}
+
+/// Columns of `log` a producer may leave out to save storage, see [`ScyllaSinkConfig::column_mask`].
 ///
-/// # Parameters
-/// - `session`: An Arc-wrapped database session (`Arc<Session>`) for executing database queries.
-/// - `producer_id`: The unique identifier (`ProducerId`) of the producer whose shard offsets are being retrieved.
-/// - `num_shards` : number of shard assigned to producer.
+/// Events read back from rows without them get the default value of the column.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OptionalColumn {
+    Executable,
+    RentEpoch,
+    TxnSignature,
+    RecentBlockhash,
+    IngestedAt,
+}
+
+impl OptionalColumn {
+    const ALL: [OptionalColumn; 5] = [
+        OptionalColumn::Executable,
+        OptionalColumn::RentEpoch,
+        OptionalColumn::TxnSignature,
+        OptionalColumn::RecentBlockhash,
+        OptionalColumn::IngestedAt,
+    ];
+
+    const fn name(self) -> &'static str {
+        match self {
+            OptionalColumn::Executable => "executable",
+            OptionalColumn::RentEpoch => "rent_epoch",
+            OptionalColumn::TxnSignature => "txn_signature",
+            OptionalColumn::RecentBlockhash => "recent_blockhash",
+            OptionalColumn::IngestedAt => "ingested_at",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|column| column.name() == name)
+    }
+}
+
+/// Serializes the fields of an event bound by the insert statement.
 ///
-/// # Returns
-/// - `Ok(None)`: If no shard offsets are found for the specified producer.
-/// - `Ok(Some(rows))`: If shard offsets are found, returns a vector of tuples containing shard IDs and offsets.
-///                      Each tuple represents a shard's latest offset for the producer.
-/// - `Err`: If an error occurs during database query execution or result parsing, returns an `anyhow::Result`.
-pub(crate) async fn get_max_shard_offsets_for_producer(
-    session: Arc<Session>,
-    producer_id: ProducerId,
-    num_shards: usize,
-) -> anyhow::Result<Vec<(ShardId, ShardOffset)>> {
-    let cql_shard_list = (0..num_shards)
-        .map(|shard_id| format!("{shard_id}"))
-        .collect::<Vec<_>>()
-        .join(", ");
+/// The derived implementation of [`BlockchainEvent`] requires every field to be bound, which does not hold
+/// once optional columns are masked. The slot bucket is only bound by the insert into `log_by_slot`, the columns
+/// are the ones resolved for the statement the row is bound to.
+struct BoundColumns<'a>(&'a BlockchainEvent, Option<Slot>, &'a [ColumnSlot]);
 
-    let query_last_period_commit = format!(
-        r###"
-        SELECT
+#[derive(Debug)]
+struct UnknownLogColumn(String);
+
+impl std::fmt::Display for UnknownLogColumn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "no field of the event matches column {}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownLogColumn {}
+
+/// Serializes a field of an event into a cell.
+type SerializeField = for<'b> fn(
+    &BlockchainEvent,
+    &ColumnType,
+    CellWriter<'b>,
+) -> Result<WrittenCellProof<'b>, SerializationError>;
+
+/// Value bound to a column of an insert into the log, resolved from the column name once the statement is prepared
+/// so the rows are serialized without looking the names up.
+#[derive(Clone, Copy)]
+enum ColumnSlot {
+    WriteTimestamp,
+    SlotBucket,
+    Field(SerializeField),
+}
+
+impl ColumnSlot {
+    fn resolve(name: &str) -> Result<Self, UnknownLogColumn> {
+        macro_rules! resolve_fields {
+            ($($field:ident),+) => {
+                match name {
+                    WRITE_TIMESTAMP_MARKER => ColumnSlot::WriteTimestamp,
+                    "slot_bucket" => ColumnSlot::SlotBucket,
+                    $(stringify!($field) => ColumnSlot::Field(|event, typ, writer| {
+                        SerializeCql::serialize(&event.$field, typ, writer)
+                    }),)+
+                    name => return Err(UnknownLogColumn(name.to_owned())),
+                }
+            };
+        }
+        Ok(resolve_fields!(
             shard_id,
-            period
-        FROM producer_period_commit_log
-        where producer_id = ?
-        AND shard_id IN ({cql_shard_list})
-        ORDER BY period DESC
-        PER PARTITION LIMIT 1
-    "###
-    );
+            period,
+            producer_id,
+            offset,
+            slot,
+            event_type,
+            pubkey,
+            lamports,
+            owner,
+            executable,
+            rent_epoch,
+            write_version,
+            data,
+            data_codec,
+            txn_signature,
+            signature,
+            signatures,
+            num_readonly_signed_accounts,
+            num_readonly_unsigned_accounts,
+            num_required_signatures,
+            account_keys,
+            recent_blockhash,
+            instructions,
+            versioned,
+            address_table_lookups,
+            meta,
+            is_vote,
+            tx_index,
+            ingested_at,
+            truncated,
+            tx_nested,
+            tx_nested_codec,
+            tx_nested_compression,
+            tx_success,
+            tx_err,
+            blockhash,
+            parent_slot,
+            parent_blockhash,
+            block_height,
+            block_time,
+            rewards,
+            executed_transaction_count,
+            entries_count,
+            raw_proto
+        ))
+    }
+
+    /// Resolves the columns bound by a prepared insert into the log, in the order of its bind markers.
+    fn resolve_all(statement: &PreparedStatement) -> Result<Vec<Self>, UnknownLogColumn> {
+        statement
+            .get_variable_col_specs()
+            .iter()
+            .map(|spec| ColumnSlot::resolve(&spec.name))
+            .collect()
+    }
+}
+
+impl SerializeRow for BoundColumns<'_> {
+    fn serialize(
+        &self,
+        ctx: &RowSerializationContext<'_>,
+        writer: &mut RowWriter,
+    ) -> Result<(), SerializationError> {
+        debug_assert_eq!(ctx.columns().len(), self.2.len());
+        for (spec, slot) in ctx.columns().iter().zip(self.2) {
+            let cell = writer.make_cell_writer();
+            match slot {
+                ColumnSlot::WriteTimestamp => {
+                    SerializeCql::serialize(&client_write_timestamp(self.0), &spec.typ, cell)?
+                }
+                ColumnSlot::SlotBucket => SerializeCql::serialize(&self.1, &spec.typ, cell)?,
+                ColumnSlot::Field(serialize) => serialize(self.0, &spec.typ, cell)?,
+            };
+        }
+        Ok(())
+    }
+
+    fn is_empty(&self) -> bool {
+        false
+    }
+}
+
+/// Order in which the consumers read back the events of a sink, relative to the order they were logged in.
+///
+/// Guarantees are ordered from the weakest to the strongest, so a required guarantee can be checked with
+/// `sink.guarantees() >= required`.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum OrderingGuarantee {
+    /// Only events routed to the same shard keep their order, and which events share a shard is arbitrary.
+    Unordered,
+    /// Events of the same pubkey always go to the same shard, so they keep their order.
+    ///
+    /// When the router rebalances, the events of a pubkey already routed are durable on their old shard before
+    /// any later event is written to the new one. Readers merging the shards by slot keep the order, a reader
+    /// following a single shard sees the pubkey move away.
+    PerPubkey,
+    /// The sink has a single shard, every event keeps its order.
+    Total,
+}
+
+/// Picks the shard each event is written to.
+///
+/// The router calls [`ShardRouter::select`] once per event, in the order the events are logged. The replay
+/// of the log relies on each shard being written in slot order, which holds as long as events are logged in
+/// slot order, whatever shard they are routed to.
+pub trait ShardRouter: Send {
+    /// Returns the index of the shard `cmd` is written to, taken modulo `num_shards`.
+    fn select(&mut self, cmd: &ClientCommand, num_shards: usize) -> usize;
+
+    /// Ordering kept by this routing, reported by [`ScyllaSink::guarantees`].
+    ///
+    /// Only claim a guarantee the router actually provides, callers rely on it to refuse to start otherwise.
+    fn ordering_guarantee(&self) -> OrderingGuarantee {
+        OrderingGuarantee::Unordered
+    }
+
+    /// Called every [`ScyllaSinkConfig::rebalance_interval`] to even out the load of the shards, the routing
+    /// never changes by default.
+    ///
+    /// Returns the shards losing events to other shards, or `None` to keep the current routing. The router
+    /// then waits for these shards to write every event routed to them and calls
+    /// [`ShardRouter::finish_rebalance`], or [`ShardRouter::abort_rebalance`] if one of them did not, no event
+    /// is routed in between.
+    fn prepare_rebalance(&mut self, _num_shards: usize) -> Option<Vec<usize>> {
+        None
+    }
+
+    /// Switches to the routing prepared by the last [`ShardRouter::prepare_rebalance`].
+    fn finish_rebalance(&mut self) {}
+
+    /// Drops the routing prepared by the last [`ShardRouter::prepare_rebalance`], the current routing stays.
+    fn abort_rebalance(&mut self) {}
+}
+
+/// Default [`ShardRouter`], cycles through the shards one event at a time.
+#[derive(Debug, Default)]
+pub struct RoundRobinRouter {
+    next: usize,
+}
+
+impl ShardRouter for RoundRobinRouter {
+    fn select(&mut self, _cmd: &ClientCommand, num_shards: usize) -> usize {
+        let i = self.next % num_shards;
+        self.next = i + 1;
+        i
+    }
+}
+
+/// Number of buckets the keys are hashed into by [`PubkeyRouter`], the unit moved between shards.
+const PUBKEY_ROUTER_BUCKETS: usize = 1024;
+
+/// [`ShardRouter`] sending every event of a pubkey to the same shard, account updates are routed by pubkey
+/// and transactions by signature.
+///
+/// Keys are hashed into buckets spread over the shards. With [`ScyllaSinkConfig::rebalance_interval`] set,
+/// once a shard received more than `max_imbalance` above the average number of events since the last
+/// rebalance, the busiest buckets of the overloaded shards are moved to the least loaded ones.
+#[derive(Debug)]
+pub struct PubkeyRouter {
+    max_imbalance: f64,
+    /// Shard of each bucket, assigned on the first event.
+    assignment: Vec<usize>,
+    /// Events routed to each bucket since the last rebalance.
+    load: Vec<u64>,
+    /// Assignment waiting for the shards losing buckets to write their events.
+    pending: Option<Vec<usize>>,
+}
+
+impl Default for PubkeyRouter {
+    fn default() -> Self {
+        Self::new(0.25)
+    }
+}
+
+impl PubkeyRouter {
+    /// `max_imbalance` is the ratio above the average load of the shards tolerated before rebalancing.
+    pub fn new(max_imbalance: f64) -> Self {
+        PubkeyRouter {
+            max_imbalance,
+            assignment: Vec::new(),
+            load: vec![0; PUBKEY_ROUTER_BUCKETS],
+            pending: None,
+        }
+    }
+
+    fn bucket(cmd: &ClientCommand) -> usize {
+        let slot;
+        let key = match cmd {
+            ClientCommand::InsertAccountUpdate(update) => &update.pubkey[..],
+            ClientCommand::InsertTransaction(tx) => &tx.signature[..],
+            // A single block meta per slot, spreading them by slot is enough.
+            ClientCommand::InsertBlockMeta(block_meta) => {
+                slot = block_meta.slot.to_le_bytes();
+                &slot[..]
+            }
+        };
+        // Pubkeys and signatures are uniformly distributed, their leading bytes make a good hash.
+        let mut prefix = [0; 8];
+        let len = key.len().min(prefix.len());
+        prefix[..len].copy_from_slice(&key[..len]);
+        (u64::from_le_bytes(prefix) % PUBKEY_ROUTER_BUCKETS as u64) as usize
+    }
+}
+
+impl ShardRouter for PubkeyRouter {
+    fn select(&mut self, cmd: &ClientCommand, num_shards: usize) -> usize {
+        if self.assignment.is_empty() {
+            self.assignment = (0..PUBKEY_ROUTER_BUCKETS)
+                .map(|bucket| bucket % num_shards)
+                .collect();
+        }
+        let bucket = Self::bucket(cmd);
+        self.load[bucket] += 1;
+        self.assignment[bucket]
+    }
+
+    fn ordering_guarantee(&self) -> OrderingGuarantee {
+        OrderingGuarantee::PerPubkey
+    }
+
+    fn prepare_rebalance(&mut self, num_shards: usize) -> Option<Vec<usize>> {
+        let load = std::mem::replace(&mut self.load, vec![0; PUBKEY_ROUTER_BUCKETS]);
+        if self.assignment.is_empty() {
+            return None;
+        }
+        let mut shard_load = vec![0; num_shards];
+        for (bucket, events) in load.iter().enumerate() {
+            shard_load[self.assignment[bucket] % num_shards] += events;
+        }
+        let average = shard_load.iter().sum::<u64>() as f64 / num_shards as f64;
+        let limit = average * (1.0 + self.max_imbalance);
+
+        let mut assignment = self.assignment.clone();
+        let mut drained = BTreeSet::new();
+        let mut buckets = (0..PUBKEY_ROUTER_BUCKETS).collect::<Vec<_>>();
+        buckets.sort_by_key(|bucket| Reverse(load[*bucket]));
+        for bucket in buckets.into_iter().take_while(|bucket| load[*bucket] > 0) {
+            let from = assignment[bucket] % num_shards;
+            if shard_load[from] as f64 <= limit {
+                continue;
+            }
+            let to = (0..num_shards).min_by_key(|shard| shard_load[*shard])?;
+            // A bucket too heavy to lower the load of its shard stays where it is.
+            if shard_load[to] + load[bucket] >= shard_load[from] {
+                continue;
+            }
+            shard_load[from] -= load[bucket];
+            shard_load[to] += load[bucket];
+            assignment[bucket] = to;
+            drained.insert(from);
+        }
+        if drained.is_empty() {
+            return None;
+        }
+        self.pending = Some(assignment);
+        Some(drained.into_iter().collect())
+    }
+
+    fn finish_rebalance(&mut self) {
+        if let Some(assignment) = self.pending.take() {
+            self.assignment = assignment;
+        }
+    }
+
+    fn abort_rebalance(&mut self) {
+        self.pending = None;
+    }
+}
+
+/// [`ShardRouter`] sending every event of a slot to the same shard, `slot % num_shards`.
+///
+/// A slot can then be read back from a single shard, at the cost of the load balance: every event of a busy
+/// slot is written by one shard while the others idle. Prefer it only if consumers read whole slots.
+#[derive(Debug, Default)]
+pub struct SlotRouter;
+
+impl ShardRouter for SlotRouter {
+    fn select(&mut self, cmd: &ClientCommand, num_shards: usize) -> usize {
+        cmd.slot().rem_euclid(num_shards as Slot) as usize
+    }
+}
+
+/// Routers selectable from the config file, see [`ShardRouter`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShardingStrategy {
+    /// Spreads the events evenly, see [`RoundRobinRouter`].
+    #[default]
+    RoundRobin,
+    /// Keeps every event of a pubkey on one shard, see [`PubkeyRouter`].
+    ByPubkey,
+    /// Keeps every event of a slot on one shard, see [`SlotRouter`].
+    BySlot,
+}
+
+impl ShardingStrategy {
+    /// Returns the factory of the router, `None` for the default round-robin routing.
+    pub fn shard_router(self) -> Option<ShardRouterFactory> {
+        match self {
+            ShardingStrategy::RoundRobin => None,
+            ShardingStrategy::ByPubkey => {
+                Some(ShardRouterFactory::new(|| Box::<PubkeyRouter>::default()))
+            }
+            ShardingStrategy::BySlot => {
+                Some(ShardRouterFactory::new(|| Box::<SlotRouter>::default()))
+            }
+        }
+    }
+}
+
+type BoxedShardRouter = Box<dyn ShardRouter>;
+
+/// Builds the [`ShardRouter`] of a sink.
+///
+/// The config can be cloned, so it holds a factory rather than the router itself: every sink started from
+/// it gets a fresh router.
+#[derive(Clone)]
+pub struct ShardRouterFactory(Arc<dyn Fn() -> BoxedShardRouter + Send + Sync>);
+
+impl ShardRouterFactory {
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn() -> BoxedShardRouter + Send + Sync + 'static,
+    {
+        ShardRouterFactory(Arc::new(f))
+    }
+
+    fn build(&self) -> BoxedShardRouter {
+        (self.0)()
+    }
+}
+
+impl std::fmt::Debug for ShardRouterFactory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ShardRouterFactory")
+    }
+}
+
+impl PartialEq for ShardRouterFactory {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// How the sessions of the sink authenticate to the cluster, see [`ScyllaSink::new_with_auth`].
+///
+/// Managed services relying on another mechanism, like the SigV4 authentication of AWS Keyspaces, plug their
+/// own [`AuthenticatorProvider`] in with [`ScyllaAuth::Custom`].
+#[derive(Clone)]
+pub enum ScyllaAuth {
+    /// The cluster accepts unauthenticated connections.
+    None,
+    /// Plain text username and password, the authentication of a self-hosted ScyllaDB.
+    Password {
+        username: String,
+        password: String,
+    },
+    Custom(Arc<dyn AuthenticatorProvider>),
+}
+
+impl ScyllaAuth {
+    /// Sets the authentication of the sessions built by `session_builder`.
+    pub fn apply(self, session_builder: SessionBuilder) -> SessionBuilder {
+        match self {
+            ScyllaAuth::None => session_builder,
+            ScyllaAuth::Password { username, password } => session_builder.user(username, password),
+            ScyllaAuth::Custom(provider) => session_builder.authenticator_provider(provider),
+        }
+    }
+}
+
+impl std::fmt::Debug for ScyllaAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScyllaAuth::None => f.write_str("None"),
+            ScyllaAuth::Password { username, .. } => f
+                .debug_struct("Password")
+                .field("username", username)
+                .finish_non_exhaustive(),
+            ScyllaAuth::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
+
+/// Capacity of the router mailbox of priority events, kept shallow so they never queue for long.
+const PRIORITY_CHANNEL_CAPACITY: usize = 4;
+
+/// Decides which events take the priority lane of the router, see [`ScyllaSinkConfig::priority_predicate`].
+#[derive(Clone)]
+pub struct PriorityPredicate(Arc<dyn Fn(&ClientCommand) -> bool + Send + Sync>);
+
+impl PriorityPredicate {
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(&ClientCommand) -> bool + Send + Sync + 'static,
+    {
+        PriorityPredicate(Arc::new(f))
+    }
+
+    /// Gives priority to vote transactions.
+    pub fn vote_transactions() -> Self {
+        Self::new(|cmd| matches!(cmd, ClientCommand::InsertTransaction(tx) if tx.is_vote))
+    }
+
+    fn matches(&self, cmd: &ClientCommand) -> bool {
+        (self.0)(cmd)
+    }
+}
+
+impl std::fmt::Debug for PriorityPredicate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PriorityPredicate")
+    }
+}
+
+impl PartialEq for PriorityPredicate {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// Describes how the sink reacts when a shard daemon terminates unexpectedly (error or panic).
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ShardFailurePolicy {
+    /// Stops the whole sink as soon as a shard dies, `log_*` calls will fail afterward.
+    #[default]
+    FailFast,
+    /// Respawns the dead shard from the last offset persisted in ScyllaDB.
+    ///
+    /// Events that were still buffered inside the dead shard are lost, unless the write-ahead log is enabled.
+    Restart,
+}
+
+const fn default_retry_max_attempts() -> usize {
+    3
+}
+
+const fn default_retry_initial_backoff() -> Duration {
+    Duration::from_millis(100)
+}
+
+const fn default_retry_max_backoff() -> Duration {
+    Duration::from_secs(5)
+}
+
+/// Bounded retries with an exponential backoff.
+#[serde_as]
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Deserialize)]
+pub struct RetryPolicy {
+    /// Maximum number of attempts, the first one included.
+    #[serde(default = "default_retry_max_attempts")]
+    pub max_attempts: usize,
+    /// Delay before the first retry in milliseconds, doubled after each retry.
+    #[serde(default = "default_retry_initial_backoff")]
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    pub initial_backoff: Duration,
+    /// Upper bound of the delay between two attempts in milliseconds.
+    #[serde(default = "default_retry_max_backoff")]
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: default_retry_max_attempts(),
+            initial_backoff: default_retry_initial_backoff(),
+            max_backoff: default_retry_max_backoff(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns the delay to wait after the failed `attempt` (starting at 1, 0 waits as 1), `None` once attempts are
+    /// exhausted.
+    pub fn backoff(&self, attempt: usize) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+        let factor = 1u32
+            .checked_shl((attempt as u32).saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        Some(
+            self.initial_backoff
+                .saturating_mul(factor)
+                .min(self.max_backoff),
+        )
+    }
+}
+
+/// Describes where the shard daemons run.
+///
+/// The router, the period committer and the ScyllaDB driver always run on the runtime creating the sink.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShardRuntime {
+    /// Every shard is a task of the runtime creating the sink, sharing its worker threads with everything else.
+    #[default]
+    Shared,
+    /// Every shard runs on its own thread with a single threaded runtime.
+    ///
+    /// A shard then never waits for a worker thread busy with another task, at the cost of one thread per shard.
+    /// Requests still go through the driver tasks of the runtime creating the sink.
+    Dedicated,
+}
+
+/// Spawns a shard daemon according to `shard_runtime`.
+///
+/// With a dedicated runtime, the returned handle resolves once the shard thread returns and fails if it panicked.
+fn spawn_shard_daemon<F>(
+    shard_runtime: ShardRuntime,
+    thread_name: String,
+    daemon: F,
+) -> JoinHandle<anyhow::Result<()>>
+where
+    F: Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    match shard_runtime {
+        ShardRuntime::Shared => tokio::spawn(daemon),
+        ShardRuntime::Dedicated => {
+            let (result_tx, result_rx) = oneshot::channel();
+            let spawned = std::thread::Builder::new()
+                .name(thread_name.clone())
+                .spawn(move || {
+                    let result = tokio::runtime::Builder::new_current_thread()
+                        .enable_all()
+                        .build()
+                        .map_err(anyhow::Error::new)
+                        .and_then(|runtime| runtime.block_on(daemon));
+                    let _ = result_tx.send(result);
+                });
+            tokio::spawn(async move {
+                spawned?;
+                result_rx
+                    .await
+                    .unwrap_or_else(|_| Err(anyhow::anyhow!("thread {thread_name} panicked")))
+            })
+        }
+    }
+}
+
+/// Describes how hard a shard tries to write its buffer when the sink shuts down.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShutdownFlushPolicy {
+    /// Tries the final flush once so the shutdown stays fast.
+    ///
+    /// The buffered events are lost if it fails, unless the write-ahead log replays them on the next start.
+    #[default]
+    BestEffort,
+    /// Retries the final flush with the given policy, the shutdown lasts as long as the retries.
+    ///
+    /// The buffered events are only lost once every attempt failed.
+    AtLeastOnce(RetryPolicy),
+}
+
+/// Describes what the router does with an event for a shard whose mailbox is full, see
+/// [`ScyllaSinkConfig::buffer_full_policy`].
+///
+/// With a drop policy, a shard keeps taking the commands of its mailbox while it flushes and holds as many
+/// events as it buffers (`max_buffer_capacity`) until its buffer frees up. The router likewise holds as many
+/// for a shard whose mailbox is full anyway, and hands them over as the mailbox frees up. Once either holds that
+/// many, every new event for the shard drops one. A dropped event is never written, its offset is never
+/// assigned, so readers see no gap: the loss is visible through `scylladb_buffer_full_drop_total`, and events
+/// logged with a submission id are acknowledged as [`SubmissionAck::Dropped`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BufferFullPolicy {
+    /// Waits for the shard to take the event, the backpressure reaches the `log_*` methods. Nothing is lost.
+    #[default]
+    Block,
+    /// Drops the oldest event held for the shard to make room, the sink keeps the freshest data.
+    DropOldest,
+    /// Drops the new event, the events already held keep their place.
+    DropNewest,
+}
+
+const fn default_min_replication_factor() -> usize {
+    3
+}
+
+/// Startup guardrail against a keyspace whose replication settings risk data loss.
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize)]
+pub struct ReplicationCheck {
+    /// Minimum replication factor expected in every datacenter.
+    #[serde(default = "default_min_replication_factor")]
+    pub min_replication_factor: usize,
+    /// Refuses to start the sink instead of logging a warning when the check fails.
+    #[serde(default)]
+    pub strict: bool,
+}
+
+const fn default_catch_up_min_slot_lag() -> Slot {
+    150
+}
+
+const fn default_catch_up_max_buffer_capacity() -> usize {
+    150
+}
+
+/// Larger batching limits used while the sink is far behind the chain tip, see [`ScyllaSink::set_tip_slot`].
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize)]
+pub struct CatchUpConfig {
+    /// A shard is catching up while the slot of its events is at least this many slots behind the tip.
+    #[serde(default = "default_catch_up_min_slot_lag")]
+    pub min_slot_lag: Slot,
+    /// Maximum number of events per batch while catching up.
+    #[serde(default = "default_catch_up_max_buffer_capacity")]
+    pub max_buffer_capacity: usize,
+    /// Maximum batch size while catching up, defaults to the regular batch size limit.
+    pub batch_size_kb_limit: Option<usize>,
+}
+
+/// Buffering limits of a single shard, overriding the limits shared by every shard.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Deserialize)]
+pub struct ShardBufferConfig {
+    /// Maximum number of events per batch, defaults to the limit of every other shard.
+    pub max_buffer_capacity: Option<usize>,
+    /// Maximum batch size in kilobytes, defaults to `batch_size_kb_limit`.
+    pub batch_size_kb_limit: Option<usize>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct ScyllaSinkConfig {
+    pub producer_id: u8,
+    pub batch_len_limit: usize,
+    pub batch_size_kb_limit: usize,
+    /// Buffering limits of specific shards, the other shards use the default limits.
+    ///
+    /// Lets the hottest shards of a skewed router batch more than the others. The catch-up limits still
+    /// apply to every shard while catching up.
+    pub shard_buffer_overrides: BTreeMap<ShardId, ShardBufferConfig>,
+    pub linger: Duration,
+    /// Minimum number of buffered events for `linger` to trigger a flush, 1 flushes on every linger.
+    pub min_batch_len: usize,
+    /// Maximum time a shard buffer lingers while holding less than `min_batch_len` events.
+    ///
+    /// Only matters if `min_batch_len` is above 1, a value below `linger` behaves as `linger`.
+    pub max_linger: Duration,
+    pub keyspace: String,
+    pub ifname: Option<String>,
+    /// Keyspace holding the producer metadata tables, defaults to `keyspace`.
+    ///
+    /// Only the `log` table stays in `keyspace`, so both keyspaces can be tuned independently.
+    pub control_keyspace: Option<String>,
+    pub shard_failure_policy: ShardFailurePolicy,
+    /// Maximum number of kilobytes buffered across every shard before the router stops accepting events.
+    ///
+    /// Once reached, the router flushes every shard and waits for them before routing anything else,
+    /// which pushes back on `log_*` callers. No global cap if `None`.
+    pub max_buffered_kb: Option<usize>,
+    /// Upper bound of the random delay applied before committing a newly seen slot.
+    ///
+    /// Spreads the `producer_slot_seen` writes of a fleet of producers, it should stay well below the slot time
+    /// since the router waits for the previous commit before starting the next one.
+    pub slot_seen_commit_jitter: Duration,
+    /// Only commits a slot seen above the `max_slot` high-water mark of the producer, raised with a lightweight
+    /// transaction once the slot is committed.
+    ///
+    /// Keeps `producer_slot_seen` from going backward after a restart, at the cost of a read and an LWT per new
+    /// slot.
+    /// Requires the `max_slot` static column of `producer_slot_seen` (see `solana.cql`).
+    pub monotonic_slot_seen: bool,
+    /// Writes, reads back and deletes a sentinel event in the partition each shard resumes in before starting.
+    ///
+    /// Catches a TTL or compaction misconfiguration of the `log` table before any event is written.
+    pub probe_on_start: bool,
+    /// Optional columns written to `log`, every optional column is written if `None`.
+    ///
+    /// The key columns and the columns needed to read an event back are always written.
+    pub column_mask: Option<BTreeSet<OptionalColumn>>,
+    /// Writes `log` rows with a timestamp derived from the slot of the event, then the index of a transaction in
+    /// its slot, instead of the clock of the coordinator.
+    ///
+    /// An offset written twice then keeps the event of the latest slot whatever the clocks of the coordinators.
+    /// Account updates of a slot share a timestamp, their order is the order of their offsets.
+    ///
+    /// The timestamps are far below the current time, as if written in the 1970s. Any write or delete of a `log`
+    /// row using the server clock, a manual `DELETE` for instance, shadows it for good: the rows later written at
+    /// that key with these timestamps are older and stay invisible. This is why
+    /// [`ScyllaSinkConfig::reset_on_start`] is refused with it. `WRITETIME` of the columns no longer tells when a
+    /// row was written either, only `created_at` does, which still comes from the coordinator clock.
+    pub client_write_timestamps: bool,
+    /// Time to live of the `log` rows, rounded down to the second, between a second and 20 years (the largest
+    /// TTL ScyllaDB accepts), rows never expire if `None`.
+    ///
+    /// Only `log` rows expire, the producer metadata tables are kept. If a shard writes nothing for longer than
+    /// the TTL, its current period is empty on restart and the shard resumes at the start of the next period,
+    /// committing the empty one, so offsets are never reused.
+    pub row_ttl: Option<Duration>,
+    /// Maximum number of shards whose last offset is read, or whose partition is probed, concurrently on startup.
+    pub recovery_concurrency: usize,
+    /// Starts even though `num_shards` of the producer shrank since a previous run.
+    ///
+    /// The events of the shards above `num_shards` are abandoned, the sink refuses to start otherwise.
+    pub allow_shard_shrink: bool,
+    /// Picks the shard of each event, events are spread round-robin if `None`.
+    pub shard_router: Option<ShardRouterFactory>,
+    /// How often the router asks `shard_router` to even out the load of the shards, never if `None`.
+    ///
+    /// Only routers implementing [`ShardRouter::prepare_rebalance`], like [`PubkeyRouter`], rebalance. Routing
+    /// pauses while the shards losing keys write their buffer, see [`OrderingGuarantee::PerPubkey`] for the
+    /// ordering across a rebalance.
+    pub rebalance_interval: Option<Duration>,
+    /// Events matching this predicate go through a shallow priority lane the router always empties first,
+    /// every event takes the regular lane if `None`.
+    ///
+    /// Priority events skip ahead of the bulk events waiting in the router, they are still batched by their
+    /// shard like any other event. They get reordered relative to bulk events, even within a shard, so the sink
+    /// only guarantees [`OrderingGuarantee::Unordered`] with a priority lane.
+    pub priority_predicate: Option<PriorityPredicate>,
+    /// Keeps only the highest write version of an account per slot among the buffered account updates.
+    pub dedup_account_updates: bool,
+    /// Number of transaction signatures each shard remembers to drop a transaction seen again, no deduplication
+    /// if `None`.
+    ///
+    /// Best effort: a shard only remembers the signatures of the last transactions it buffered, the least
+    /// recently seen being forgotten first, and forgets all of them when it restarts. A shard only sees the
+    /// transactions routed to it, so duplicates are only caught if the routing sends a signature to a single
+    /// shard, as the `by_pubkey` and `by_slot` strategies do, or with a single shard. A dropped duplicate is
+    /// acknowledged with the offset of the transaction seen first. Each remembered signature costs about
+    /// 200 bytes.
+    pub tx_dedup_window: Option<NonZeroUsize>,
+    /// Number of slots an event may lag behind the highest slot seen by its shard before it is reported as a
+    /// regression, unchecked if `None`.
+    ///
+    /// A regression is only reported, with a warning and `scylladb_slot_regression_total`, the event is written
+    /// as usual since forks make slots go back legitimately. A large regression points at a routing bug or a bad
+    /// replay. A shard forgets the highest slot it saw when it restarts.
+    pub max_slot_regression: Option<Slot>,
+    /// Checks the keyspace replication settings before starting, disabled if `None`.
+    pub replication_check: Option<ReplicationCheck>,
+    /// Batches more aggressively while far behind the tip, disabled if `None`.
+    pub catch_up: Option<CatchUpConfig>,
+    /// Compresses the `data` column of account updates and the `tx_nested` column of transactions with LZ4.
+    ///
+    /// Transaction `meta` is only compressed once packed into `tx_nested`, see [`ScyllaSinkConfig::nested_codec`],
+    /// its typed column is always stored as is.
+    pub compress_data: bool,
+    /// Account update `data` or transaction `tx_nested` smaller than this is stored raw, compressing it would not
+    /// pay off.
+    pub compress_min_bytes: usize,
+    /// Packs the instructions, address table lookups and meta of transactions into the `tx_nested` column
+    /// with this codec, they are written to their typed columns if `None`.
+    ///
+    /// The consumers of this crate decode every codec, other readers of `log` have to handle the codec
+    /// they are configured with, see [`NestedCodec`].
+    pub nested_codec: Option<NestedCodec>,
+    /// Takes over a producer lock held by the same interface and ip address, assuming its holder is a previous
+    /// instance of this producer which did not release it.
+    ///
+    /// Only enable this if at most one instance of the producer can run per host.
+    pub steal_own_host_lock: bool,
+    /// Interface name and ip address written in the producer lock, instead of the ones discovered from the network
+    /// interfaces of the host.
+    ///
+    /// Needed where the interfaces can not be listed, e.g. in locked-down containers. Takes precedence over
+    /// `ifname`, the address must be an ipv4 one.
+    pub lock_identity: Option<(String, IpAddr)>,
+    /// Directory of the write-ahead logs of the sink and of its shards, disabled if `None`.
+    ///
+    /// Every command accepted by a `log_*` method is appended to the log of the sink before being routed, then
+    /// to the log of its shard once the shard buffers it, which releases it from the log of the sink. The log
+    /// of a shard is truncated once its buffer is written. Commands left in the logs by a crash, including the
+    /// ones still queued in the router or the shard mailboxes, are written again on the next start, which gives
+    /// at-least-once delivery of the accepted commands. Events dropped per [`BufferFullPolicy`] are not replayed.
+    pub wal_dir: Option<PathBuf>,
+    /// Whether the write-ahead logs sync each appended command to disk, see [`ScyllaSinkConfig::wal_dir`].
+    ///
+    /// Without it the logs survive a crash of the process but not a crash of the host.
+    pub wal_sync: bool,
+    /// Window during which period commits of every shard are gathered into a single batch, disabled if `None`.
+    ///
+    /// A shard crossing a period boundary still waits for its commit before writing the next period.
+    pub period_commit_coalescing: Option<Duration>,
+    /// Retries of the final flush of each shard on shutdown.
+    pub shutdown_flush_policy: ShutdownFlushPolicy,
+    /// Retries of the `producer_slot_seen` and `producer_period_commit_log` writes.
+    ///
+    /// Both writes are idempotent, a transient failure only kills the router or the shard once every
+    /// attempt failed.
+    pub metadata_write_retry: RetryPolicy,
+    /// Stops writing and fails the `log_*` methods fast with [`ScyllaSinkError::Backpressure`] once too many
+    /// batches fail, disabled if `None`.
+    ///
+    /// With a breaker, a failed batch is sent again instead of killing its shard, once the breaker lets it
+    /// through. The final flush on shutdown ignores the breaker and follows `shutdown_flush_policy`.
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    /// Writes the batches at `LOCAL_ONE` for a while when the configured consistency keeps failing with
+    /// `Unavailable`, disabled if `None`.
+    ///
+    /// This keeps ingestion going through the loss of replicas at the cost of durability: events written while
+    /// downgraded live on a single replica until repaired. Only the batches of the `log` table are downgraded.
+    pub consistency_downgrade: Option<ConsistencyDowngradeConfig>,
+    /// Largest event accepted by the `log_*` methods in kilobytes, defaults to `batch_size_kb_limit`.
+    ///
+    /// Bigger events are rejected with [`ScyllaSinkError::EventTooLarge`] instead of failing the batch of
+    /// their shard.
+    pub max_event_kb: Option<usize>,
+    /// Size limits of the largest fields of an event, enforced by the `log_*` methods.
+    pub field_size_limits: FieldSizeLimits,
+    /// Reports the events logged with a submission id once they are durable, see [`ScyllaSink::take_ack_stream`].
+    pub ack_stream: bool,
+    /// Where the shard daemons run, see [`ShardRuntime`].
+    pub shard_runtime: ShardRuntime,
+    /// Number of connections opened to each host, the driver opens one per shard of each host if `None`.
+    pub pool_size_per_host: Option<NonZeroUsize>,
+    /// Interval of the keepalive requests sent on idle connections, the driver default applies if `None`.
+    pub keepalive_interval: Option<Duration>,
+    /// Advanced: offset at which the producer stops accepting events so it can be rolled, never rolled if `None`.
+    ///
+    /// Meant for operators partitioning the history by producer epoch. Once a shard reaches this offset, the
+    /// events already accepted are written and the `log_*` methods fail with [`ScyllaSinkError::RollRequired`];
+    /// the caller then shuts the sink down and starts a new one under another producer id. Shards may go
+    /// slightly past the offset while writing the events already queued to them.
+    pub max_offset_before_roll: Option<ShardOffset>,
+    /// Flushes every shard and waits for the writes before routing the first event of a new slot.
+    ///
+    /// Every event of a slot is then durable in `log` before any event of a later slot is written, at the cost
+    /// of smaller batches. Period commits are unaffected: the period of a flushed event is only committed once
+    /// its shard moves on to the next period.
+    pub flush_on_slot_boundary: bool,
+    /// Interval between two rotations of the `lock_id` of the producer lock, never rotated if `None`.
+    ///
+    /// The lock is rotated at every interval, whether events flow or not, by the task writing the heartbeat of the
+    /// producer. That task runs apart from the router, so a router waiting on a full shard mailbox or a slow flush
+    /// never delays a rotation. The rotation keeps its own interval rather than the heartbeat one: it must stay
+    /// below [`ScyllaSinkConfig::lock_ttl`], while the heartbeat is only read by liveness checks and may be
+    /// disabled. The sink stops if a rotation fails.
+    pub lock_rotation_interval: Option<Duration>,
+    /// Opens the connections through the shard-aware port of the nodes, each connection then lands on the
+    /// CPU core of its choice.
+    ///
+    /// Every shard of the sink writes a single partition at a time, a shard-aware connection sends its batches
+    /// straight to the core owning that partition instead of hopping through another core of the node.
+    /// The driver falls back to the regular port when the shard-aware port is unreachable, and the cluster
+    /// may not support shard awareness at all, see the startup logs.
+    pub shard_aware_port: bool,
+    /// Fails a shard about to write an event whose offset does not follow the previous one.
+    ///
+    /// Offsets of a shard are meant to be contiguous, readers rely on it to read ranges of the log.
+    pub strict_offset_contiguity: bool,
+    /// Also writes the geyser protobuf message each event was received as in the `raw_proto` column of `log`.
+    ///
+    /// The message is the `raw_proto` field of the submitted event, so it can be decoded again by newer code
+    /// whatever the schema of the decoded columns. Events submitted without it get a null `raw_proto`. It roughly
+    /// doubles the size of each row, on top of the decoded columns.
+    pub store_raw_proto: bool,
+    /// Hard deadline of each batch write, no deadline besides the request timeout of the driver if `None`.
+    ///
+    /// Guards the shards against a batch that never completes. A batch past the deadline fails like a timed
+    /// out request: it is retried while the circuit breaker allows it, otherwise the shard stops.
+    pub flush_deadline: Option<Duration>,
+    /// **Development only, deletes data.** Once the lock is held, deletes every event of the producer, in
+    /// `log_by_slot` too, its period commits, slots seen and heartbeat, then starts every shard
+    /// at offset 0.
+    ///
+    /// Meant to reuse a producer id in dev and test clusters. Only runs if
+    /// [`ScyllaSinkConfig::confirm_reset_on_start`] is also set, the sink refuses to start otherwise.
+    /// Refused with [`ScyllaSinkConfig::client_write_timestamps`], the deletes would shadow the rows written after.
+    pub reset_on_start: bool,
+    /// Confirms [`ScyllaSinkConfig::reset_on_start`], acknowledging that every row of the producer is deleted.
+    pub confirm_reset_on_start: bool,
+    /// Number of events a shard may hold without them being durable before the router holds back the next
+    /// ones routed to it, no throttling besides the mailbox capacity if `None`. Must be positive.
+    ///
+    /// Enables a feedback channel through which every shard reports its durability lag, see the
+    /// `scylladb_shard_durability_lag` metric, each time it reserves an offset or completes a flush. Once a
+    /// shard reaches the limit, the router asks it to flush and waits for the flush before routing it more
+    /// events, so routing to a shard slows down to the pace of its writes. The wait counts in
+    /// `scylladb_router_reserve_wait_seconds`.
+    pub max_shard_durability_lag: Option<ShardOffset>,
+    /// Also writes every event to `log_by_slot`, partitioned by ranges of this many slots, so readers can
+    /// scan the events of a slot range without going through the offsets of every shard. Disabled if `None`.
+    ///
+    /// `log` keeps being written and serving the offset recovery, the slot layout doubles the writes and counts
+    /// twice towards the batch size. The first sink started with a bucket size records it in `producer_info`,
+    /// where readers find it, and every later sink of the producer must use the same one. Enabling the layout
+    /// for a producer which already wrote events leaves its earlier events out of `log_by_slot`. Must be
+    /// positive.
+    pub slot_bucket_size: Option<Slot>,
+    /// Time after which a lock neither acquired nor rotated is expired, expired locks are never taken over if
+    /// `None`. Requires a [`ScyllaSinkConfig::lock_rotation_interval`] shorter than the ttl, the rotations being
+    /// the heartbeat of the holder, as seen in the write time of `lock_id`.
+    ///
+    /// A starting instance only takes over a lock expired for `lock_ttl` times
+    /// [`ScyllaSinkConfig::lock_steal_grace`]. Once its last successful rotation is older than `lock_ttl`, or as
+    /// soon as a rotation fails, the holder considers its lock lost: the router stops routing events and the
+    /// shards stop writing, a batch being retried is abandoned, then the sink fails.
+    pub lock_ttl: Option<Duration>,
+    /// Multiplier of [`ScyllaSinkConfig::lock_ttl`] a lock must stay expired for before it is taken over, at
+    /// least 1.
+    ///
+    /// A holder whose rotations stall, e.g. by a long pause of its process or a slow node, stops writing once
+    /// its lock expired, but a batch already sent to the cluster may still land. The grace past the ttl is the
+    /// time left for these writes before another instance may take over, a larger grace makes two instances
+    /// writing at once less likely, but a producer whose instance died stays down for longer. Clocks of the
+    /// nodes and of the instance must be in sync within the grace window as well.
+    pub lock_steal_grace: f64,
+    /// Maximum number of tasks running the shards, every shard runs on its own task if `None` or if the producer
+    /// has no more shards.
+    ///
+    /// Shards beyond the limit share a task with others: shard `i` runs on task `i % max_concurrent_shards`, each
+    /// keeping its own mailbox, buffer and offsets. A task runs the commands of its shards one at a time, so their
+    /// flushes are sequential: the write throughput of a task is roughly the one of a single shard, spread over
+    /// its shards, and a slow flush of one shard delays the others. Pausing a shard pauses every shard of its
+    /// task. A shard restarted by the `restart` failure policy runs on a task of its own.
+    pub max_concurrent_shards: Option<NonZeroUsize>,
+    /// Interval at which the current time is written to the `producer_heartbeat` row of the producer, no heartbeat
+    /// if `None`. The same task rotates the producer lock, see [`ScyllaSinkConfig::lock_rotation_interval`].
+    ///
+    /// The heartbeat is written whether events flow or not, so a stale heartbeat tells a dead or disconnected
+    /// producer apart from an idle one, see [`get_producer_heartbeat`]. A failed write is only logged, the next
+    /// interval tries again. Backfill sinks do not write it.
+    pub heartbeat_interval: Option<Duration>,
+    /// Decides whether a failed request is sent again, fails right away or counts towards the consistency
+    /// downgrade, see [`ErrorClassifier::default`] for the default classification.
+    ///
+    /// Metadata writes stop retrying on a [`ErrorClass::Fatal`] error whatever their retry policy, and a batch
+    /// failing with one kills its shard even behind a circuit breaker. Only [`ErrorClass::Unavailable`] errors
+    /// count towards `consistency_downgrade`.
+    pub error_classifier: ErrorClassifier,
+    /// What the router does with an event for a shard whose mailbox is full, see [`BufferFullPolicy`].
+    ///
+    /// The drop policies trade data for latency: a shard slower than its share of the events loses some of
+    /// them instead of stalling every shard behind the router. Flushes, handoffs and shutdowns still wait for
+    /// the events held for a shard to reach it.
+    pub buffer_full_policy: BufferFullPolicy,
+    /// Called by each shard with the last offset of every batch it writes, before its events are acknowledged,
+    /// nothing is committed if `None`.
+    ///
+    /// Lets deployments keep their checkpoints in another store, see [`OffsetCommitter`] for the durability it
+    /// must provide. The sink still resumes from `log` whatever the committer holds.
+    pub offset_committer: Option<SharedOffsetCommitter>,
+    /// Commits the offsets through a [`ScyllaOffsetCommitter`] when there is no `offset_committer`, ignored by
+    /// a backfill sink which commits its periods only once it shuts down.
+    pub commit_offsets: bool,
+}
+
+/// An event submitted to the sink.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ClientCommand {
+    // Add other action if necessary...
+    InsertAccountUpdate(AccountUpdate),
+    InsertTransaction(Transaction),
+    InsertBlockMeta(BlockMeta),
+}
+
+/// Identifier chosen by the caller for an event logged with an acknowledgement, see [`ScyllaSink::take_ack_stream`].
+pub type SubmissionId = u64;
+
+/// Acknowledges that the event submitted under the id is durable in ScyllaDB at this shard and offset.
+pub type DurableAck = (SubmissionId, ShardId, ShardOffset);
+
+/// Outcome of an event logged with a submission id, sent on the ack stream, see [`ScyllaSink::take_ack_stream`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SubmissionAck {
+    /// The event is durable, see [`DurableAck`].
+    Durable(DurableAck),
+    /// The event was dropped per [`BufferFullPolicy`], it is never written.
+    Dropped(SubmissionId),
+}
+
+/// Event waiting in the router, with its submission id if it must be acknowledged and its sequence number in the
+/// write-ahead log of the sink if any.
+type Submission = (ClientCommand, Option<SubmissionId>, Option<WalSeq>);
+
+/// Write-ahead log of the sink, shared with the shards releasing the commands they take over.
+type SharedRouterWal = Arc<Mutex<RouterWal>>;
+
+/// Releases a command from the write-ahead log of the sink, if it was logged in it.
+fn release_wal_seq(
+    router_wal: Option<&SharedRouterWal>,
+    wal_seq: Option<WalSeq>,
+) -> anyhow::Result<()> {
+    match router_wal.zip(wal_seq) {
+        Some((router_wal, wal_seq)) => router_wal.lock().expect("poisoned").release(wal_seq),
+        None => Ok(()),
+    }
+}
+
+/// Gives up on an event dropped per [`BufferFullPolicy`]. Its submission is acknowledged as dropped so the caller
+/// does not wait for it, and it is released from the write-ahead log of the sink: it is not replayed either.
+fn drop_submission(
+    (_, submission_id, wal_seq): Submission,
+    ack_sender: Option<&mpsc::UnboundedSender<Vec<SubmissionAck>>>,
+    router_wal: Option<&SharedRouterWal>,
+) {
+    if let Some((submission_id, ack_sender)) = submission_id.zip(ack_sender) {
+        // The receiver may be gone, acks are best effort.
+        let _ = ack_sender.send(vec![SubmissionAck::Dropped(submission_id)]);
+    }
+    if let Err(e) = release_wal_seq(router_wal, wal_seq) {
+        warn!("failed to release a dropped event from the write-ahead log: {e:?}");
+    }
+}
+
+impl ClientCommand {
+    pub const fn slot(&self) -> Slot {
+        match self {
+            ClientCommand::InsertAccountUpdate(acc_update) => acc_update.slot,
+            ClientCommand::InsertTransaction(tx) => tx.slot,
+            ClientCommand::InsertBlockMeta(block_meta) => block_meta.slot,
+        }
+    }
+
+    /// Drops the geyser protobuf message the event was received as, if any.
+    fn clear_raw_proto(&mut self) {
+        match self {
+            ClientCommand::InsertAccountUpdate(acc_update) => acc_update.raw_proto = None,
+            ClientCommand::InsertTransaction(tx) => tx.raw_proto = None,
+            ClientCommand::InsertBlockMeta(block_meta) => block_meta.raw_proto = None,
+        }
+    }
+}
+
+impl From<BlockchainEvent> for ClientCommand {
+    fn from(event: BlockchainEvent) -> Self {
+        match event.event_type {
+            BlockchainEventType::AccountUpdate => ClientCommand::InsertAccountUpdate(event.into()),
+            BlockchainEventType::NewTransaction => ClientCommand::InsertTransaction(event.into()),
+            BlockchainEventType::BlockMeta => ClientCommand::InsertBlockMeta(event.into()),
+        }
+    }
+}
+
+/// Message flowing through the router and shard mailboxes.
+#[allow(clippy::large_enum_variant)]
+enum SinkCommand {
+    Shutdown,
+    Client(ClientCommand, Option<SubmissionId>, Option<WalSeq>),
+    /// Flushes every buffered event, acknowledged once the flush is durable.
+    Flush(oneshot::Sender<()>),
+    /// Stops without flushing and sends back every event not yet written.
+    Handoff(oneshot::Sender<Vec<ClientCommand>>),
+}
+
+/// Represents a shard responsible for processing and batching `ClientCommand` messages
+/// before committing them to the database in a background daemon.
+///
+/// This struct encapsulates the state and behavior required to manage message buffering,
+/// batching, and period-based commitment for a specific shard within a distributed system.
+struct Shard {
+    /// Database session for executing queries, see [`ScyllaSink::swap_session`].
+    session: SharedSession,
+
+    /// Unique identifier for the shard.
+    shard_id: ShardId,
+
+    /// Unique identifier for the producer associated with this shard.
+    producer_id: ProducerId,
+
+    /// The next offset to be assigned for incoming client commands.
+    next_offset: ShardOffset,
+
+    /// Every offset below this one has been written to ScyllaDB.
+    committed_offset: ShardOffset,
+
+    /// Buffer to store sharded client commands before batching.
+    buffer: Vec<BlockchainEvent>,
+
+    /// Maximum capacity of the buffer (number of commands it can hold).
+    max_buffer_capacity: usize,
+
+    /// Maximum byte size of the buffer (sum of sizes of commands it can hold).
+    max_buffer_byte_size: usize,
+
+    /// Batch for executing database statements in bulk.
+    scylla_batch: Batch,
+
+    /// Current byte size of the batch being constructed.
+    curr_batch_byte_size: usize,
+
+    /// When the first event of the current buffer was pushed, `None` while the buffer is empty.
+    oldest_buffered_at: Option<Instant>,
+
+    /// Duration to linger before flushing the buffer.
+    buffer_linger: Duration,
+
+    /// Deadline after which the buffer is flushed on the next insert, if it holds at least `min_batch_len` events.
+    buffering_timeout: Instant,
+
+    /// Minimum number of buffered events for the linger to trigger a flush.
+    min_batch_len: usize,
+
+    /// Duration to linger before flushing the buffer whatever its length.
+    max_buffer_linger: Duration,
+
+    /// Deadline after which the buffer is flushed on the next insert whatever its length.
+    max_buffering_timeout: Instant,
+
+    /// Whether buffered account updates can be superseded by a higher write version of the same account and slot.
+    dedup_account_updates: bool,
+
+    /// Position in `buffer` of each buffered account update, keyed by (pubkey, slot).
+    buffered_account_updates: HashMap<([u8; 32], Slot), usize>,
+
+    /// Signatures of the last transactions, set if transactions are deduplicated.
+    seen_signatures: Option<SeenSignatures>,
+
+    /// Highest slot seen, set if slot regressions are reported.
+    slot_regression: Option<SlotRegressionCheck>,
+
+    /// Buffer limits used instead of the regular ones while catching up.
+    catch_up: Option<CatchUpLimits>,
+
+    /// Latest chain tip slot known by the sink, -1 if unknown.
+    tip_slot: Arc<AtomicI64>,
+
+    /// Whether the shard currently uses the catch-up buffer limits.
+    catching_up: bool,
+
+    /// Offset at which the producer must be rolled, see [`ScyllaSinkConfig::max_offset_before_roll`].
+    max_offset_before_roll: Option<ShardOffset>,
+
+    /// Set by the first shard reaching `max_offset_before_roll`, shared by the whole sink.
+    roll_required: Arc<AtomicBool>,
+
+    /// Minimum size of account update data to compress, no compression if `None`.
+    compress_min_bytes: Option<usize>,
+
+    /// Events below this offset are counted in the compression metrics, a retried flush does not count them again.
+    compression_metered_offset: ShardOffset,
+
+    /// Statements prepared by the sink.
+    statements: SinkStatements,
+
+    /// Bytes buffered across every shard of the sink, `curr_batch_byte_size` is accounted in it.
+    buffer_budget: BufferBudget,
+
+    /// Directory of the write-ahead log, no write-ahead log if `None`.
+    wal_dir: Option<PathBuf>,
+
+    /// Whether the write-ahead log syncs each record to disk.
+    wal_sync: bool,
+
+    /// Write-ahead log of the buffered commands, opened when the daemon starts.
+    wal: Option<ShardWal>,
+
+    /// Write-ahead log of the sink, commands are released from it once appended to `wal`.
+    router_wal: Option<SharedRouterWal>,
+
+    /// Commits periods through the shared committer instead of on its own if set.
+    period_committer: Option<mpsc::Sender<PeriodCommitRequest>>,
+
+    /// First offset written by a backfill shard, its period commits are deferred to its shutdown if set.
+    deferred_period_commits_start: Option<ShardOffset>,
+
+    /// Retries of the final flush.
+    shutdown_flush_policy: ShutdownFlushPolicy,
+
+    /// Breaker shared by every shard of the sink, failed batches kill the shard if `None`.
+    circuit_breaker: Option<Arc<CircuitBreaker>>,
+
+    /// Codec packing the nested structures of transactions into `tx_nested`, typed columns if `None`.
+    nested_codec: Option<NestedCodec>,
+
+    /// Downgrade shared by every shard of the sink, batches always use the configured consistency if `None`.
+    consistency_downgrade: Option<Arc<ConsistencyDowngrade>>,
+
+    /// Period of the partition reported by the partition write metric, older periods are dropped from it.
+    metric_period: Option<ShardPeriod>,
+
+    /// Sizes of the last flushes, a sustained stream of single item batches means batching is ineffective.
+    single_item_batches: SingleItemBatchRatio,
+
+    /// Where the daemon of the shard runs.
+    shard_runtime: ShardRuntime,
+
+    /// Receives the acks of the events logged with a submission id once their batch is written.
+    ack_sender: Option<mpsc::UnboundedSender<Vec<SubmissionAck>>>,
+
+    /// Acks of the buffered events, sent once the buffer is flushed.
+    pending_acks: Vec<SubmissionAck>,
+
+    /// Set while an operator paused the shard, see [`ScyllaSink::pause_shard`].
+    paused: Option<watch::Receiver<bool>>,
+
+    /// What the shard does with the commands arriving while it flushes, see [`BufferFullPolicy`].
+    buffer_full_policy: BufferFullPolicy,
+
+    /// Commands taken from the mailbox during the last flushes, run before the next ones of the mailbox.
+    held: HeldCommands,
+
+    /// Reports the durability lag to the router, see [`ScyllaSinkConfig::max_shard_durability_lag`].
+    durability_feedback: Option<watch::Sender<ShardOffset>>,
+
+    /// Offsets and occupancy published for [`ScyllaSink::diagnostics`].
+    gauges: Option<Arc<ShardGauges>>,
+
+    /// Whether the offsets of the buffer are checked to follow the last written offset before each flush.
+    strict_offset_contiguity: bool,
+
+    /// Whether events carry their geyser protobuf message to the `raw_proto` column.
+    store_raw_proto: bool,
+
+    /// Number of partitions of `log` each period is split into.
+    partitions_per_period: i16,
+
+    /// Number of slots of each partition of `log_by_slot`, see [`ScyllaSinkConfig::slot_bucket_size`].
+    slot_bucket_size: Option<Slot>,
+
+    /// Commits the last offset of each batch written, if set.
+    offset_committer: Option<SharedOffsetCommitter>,
+
+    /// Raised once the producer lock is lost, the shard must not write anymore then.
+    lock_lost: Option<watch::Receiver<bool>>,
+}
+
+/// Highest slot seen by a shard, see [`ScyllaSinkConfig::max_slot_regression`].
+#[derive(Debug)]
+struct SlotRegressionCheck {
+    max_regression: Slot,
+    max_slot_seen: Slot,
+    /// Highest slot seen when the last regression was logged, -1 if none was.
+    warned_at: Slot,
+}
+
+impl SlotRegressionCheck {
+    const fn new(max_regression: Slot) -> Self {
+        SlotRegressionCheck {
+            max_regression,
+            max_slot_seen: -1,
+            warned_at: -1,
+        }
+    }
+
+    /// Records `slot`, returns the highest slot seen before it if `slot` lags further behind than allowed.
+    fn record(&mut self, slot: Slot) -> Option<Slot> {
+        if self.max_slot_seen - slot > self.max_regression {
+            return Some(self.max_slot_seen);
+        }
+        self.max_slot_seen = self.max_slot_seen.max(slot);
+        None
+    }
+}
+
+/// Signatures of the last transactions of a shard, see [`ScyllaSinkConfig::tx_dedup_window`].
+///
+/// The least recently seen signature is forgotten first. Sightings are queued along a sequence number, a queued
+/// sighting older than the last one of its signature is skipped when it reaches the front.
+#[derive(Debug)]
+struct SeenSignatures {
+    capacity: usize,
+    /// Offset of the transaction and sequence number of its last sighting, by signature.
+    last_seen: HashMap<Vec<u8>, (ShardOffset, u64)>,
+    sightings: VecDeque<(Vec<u8>, u64)>,
+    seq: u64,
+}
+
+impl SeenSignatures {
+    fn new(capacity: NonZeroUsize) -> Self {
+        SeenSignatures {
+            capacity: capacity.get(),
+            last_seen: HashMap::with_capacity(capacity.get()),
+            sightings: VecDeque::with_capacity(capacity.get()),
+            seq: 0,
+        }
+    }
+
+    /// Returns the offset of the transaction with `signature` if it is remembered, refreshing it.
+    fn check(&mut self, signature: &[u8]) -> Option<ShardOffset> {
+        let (offset, seq) = self.last_seen.get_mut(signature)?;
+        self.seq += 1;
+        *seq = self.seq;
+        let offset = *offset;
+        self.sightings.push_back((signature.to_vec(), self.seq));
+        self.evict();
+        Some(offset)
+    }
+
+    /// Remembers the transaction with `signature` at `offset`.
+    fn insert(&mut self, signature: Vec<u8>, offset: ShardOffset) {
+        self.seq += 1;
+        self.sightings.push_back((signature.clone(), self.seq));
+        self.last_seen.insert(signature, (offset, self.seq));
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.last_seen.len() > self.capacity {
+            let Some((signature, seq)) = self.sightings.pop_front() else {
+                break;
+            };
+            if self.last_seen.get(&signature).map(|(_, last)| *last) == Some(seq) {
+                self.last_seen.remove(&signature);
+            }
+        }
+        // Refreshed signatures leave outdated sightings behind, drop them before they pile up.
+        if self.sightings.len() > 2 * self.capacity {
+            let last_seen = &self.last_seen;
+            self.sightings.retain(|(signature, seq)| {
+                last_seen.get(signature).map(|(_, last)| *last) == Some(*seq)
+            });
+        }
+    }
+}
+
+/// Rolling ratio of the flushes of a shard which sent a single event.
+#[derive(Debug, Default)]
+struct SingleItemBatchRatio {
+    window: VecDeque<bool>,
+    single_item: usize,
+    warned: bool,
+}
+
+impl SingleItemBatchRatio {
+    /// Records a flush of `batch_len` events, returns the ratio over the window once it is full.
+    fn record(&mut self, batch_len: usize) -> Option<f64> {
+        let single_item = batch_len == 1;
+        self.window.push_back(single_item);
+        self.single_item += usize::from(single_item);
+        if self.window.len() > SINGLE_ITEM_BATCH_WINDOW && self.window.pop_front() == Some(true) {
+            self.single_item -= 1;
+        }
+        (self.window.len() == SINGLE_ITEM_BATCH_WINDOW)
+            .then_some(self.single_item as f64 / SINGLE_ITEM_BATCH_WINDOW as f64)
+    }
+}
+
+#[derive(Clone, Debug)]
+struct CatchUpLimits {
+    min_slot_lag: Slot,
+    max_buffer_capacity: usize,
+    max_buffer_byte_size: usize,
+}
+
+/// Bytes buffered across every shard of a sink.
+#[derive(Clone, Debug)]
+struct BufferBudget {
+    producer_id: ProducerId,
+    used_bytes: Arc<AtomicUsize>,
+    max_bytes: Option<usize>,
+}
+
+impl BufferBudget {
+    fn new(producer_id: ProducerId, max_bytes: Option<usize>) -> Self {
+        if let Some(max_bytes) = max_bytes {
+            scylladb_buffered_bytes_limit_set(producer_id, max_bytes);
+        }
+        BufferBudget {
+            producer_id,
+            used_bytes: Arc::new(AtomicUsize::new(0)),
+            max_bytes,
+        }
+    }
+
+    fn add(&self, bytes: usize) {
+        let used = self.used_bytes.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        scylladb_buffered_bytes_set(self.producer_id, used);
+    }
+
+    fn sub(&self, bytes: usize) {
+        let used = self.used_bytes.fetch_sub(bytes, Ordering::Relaxed) - bytes;
+        scylladb_buffered_bytes_set(self.producer_id, used);
+    }
+
+    fn is_exceeded(&self) -> bool {
+        self.max_bytes
+            .is_some_and(|max_bytes| self.used_bytes.load(Ordering::Relaxed) >= max_bytes)
+    }
+}
+
+/// Buffering settings shared by every shard of a producer.
+#[derive(Clone, Debug)]
+struct ShardConfig {
+    max_buffer_capacity: usize,
+    max_buffer_byte_size: usize,
+    buffer_linger: Duration,
+    min_batch_len: usize,
+    max_buffer_linger: Duration,
+    dedup_account_updates: bool,
+    tx_dedup_window: Option<NonZeroUsize>,
+    max_slot_regression: Option<Slot>,
+    catch_up: Option<CatchUpLimits>,
+    tip_slot: Arc<AtomicI64>,
+    max_offset_before_roll: Option<ShardOffset>,
+    roll_required: Arc<AtomicBool>,
+    compress_min_bytes: Option<usize>,
+    buffer_budget: BufferBudget,
+    wal_dir: Option<PathBuf>,
+    wal_sync: bool,
+    router_wal: Option<SharedRouterWal>,
+    period_committer: Option<mpsc::Sender<PeriodCommitRequest>>,
+    defer_period_commits: bool,
+    shutdown_flush_policy: ShutdownFlushPolicy,
+    circuit_breaker: Option<Arc<CircuitBreaker>>,
+    nested_codec: Option<NestedCodec>,
+    consistency_downgrade: Option<Arc<ConsistencyDowngrade>>,
+    shard_runtime: ShardRuntime,
+    ack_sender: Option<mpsc::UnboundedSender<Vec<SubmissionAck>>>,
+    strict_offset_contiguity: bool,
+    store_raw_proto: bool,
+    partitions_per_period: i16,
+    slot_bucket_size: Option<Slot>,
+    offset_committer: Option<SharedOffsetCommitter>,
+    lock_lost: Option<watch::Receiver<bool>>,
+}
+
+impl Drop for Shard {
+    fn drop(&mut self) {
+        // A shard dying with a non-empty buffer must give back its share of the global budget.
+        self.buffer_budget.sub(self.curr_batch_byte_size);
+        scylladb_oldest_buffered_event_remove(self.producer_id, self.shard_id);
+    }
+}
+
+impl Shard {
+    fn new(
+        session: SharedSession,
+        shard_id: ShardId,
+        producer_id: ProducerId,
+        next_offset: ShardOffset,
+        config: ShardConfig,
+        statements: SinkStatements,
+    ) -> Self {
+        if next_offset < 0 {
+            panic!("next offset can not be negative");
+        }
+        Shard {
+            session,
+            shard_id,
+            producer_id,
+            next_offset,
+            committed_offset: next_offset,
+            buffer: Vec::with_capacity(config.max_buffer_capacity),
+            max_buffer_capacity: config.max_buffer_capacity,
+            max_buffer_byte_size: config.max_buffer_byte_size,
+            // Since each shard will only batch into a single partition at a time, we can safely disable batch logging
+            // without losing atomicity guarantee provided by scylla.
+            scylla_batch: Batch::new(BatchType::Unlogged),
+            buffer_linger: config.buffer_linger,
+            buffering_timeout: Instant::now() + config.buffer_linger,
+            min_batch_len: config.min_batch_len,
+            max_buffer_linger: config.max_buffer_linger,
+            max_buffering_timeout: Instant::now() + config.max_buffer_linger,
+            curr_batch_byte_size: 0,
+            oldest_buffered_at: None,
+            dedup_account_updates: config.dedup_account_updates,
+            buffered_account_updates: HashMap::new(),
+            seen_signatures: config.tx_dedup_window.map(SeenSignatures::new),
+            slot_regression: config.max_slot_regression.map(SlotRegressionCheck::new),
+            catch_up: config.catch_up,
+            tip_slot: config.tip_slot,
+            max_offset_before_roll: config.max_offset_before_roll,
+            roll_required: config.roll_required,
+            catching_up: false,
+            compress_min_bytes: config.compress_min_bytes,
+            compression_metered_offset: next_offset,
+            buffer_budget: config.buffer_budget,
+            wal_dir: config.wal_dir,
+            wal_sync: config.wal_sync,
+            wal: None,
+            router_wal: config.router_wal,
+            period_committer: config.period_committer,
+            deferred_period_commits_start: config.defer_period_commits.then_some(next_offset),
+            shutdown_flush_policy: config.shutdown_flush_policy,
+            circuit_breaker: config.circuit_breaker,
+            nested_codec: config.nested_codec,
+            consistency_downgrade: config.consistency_downgrade,
+            metric_period: None,
+            single_item_batches: SingleItemBatchRatio::default(),
+            shard_runtime: config.shard_runtime,
+            ack_sender: config.ack_sender,
+            pending_acks: Vec::new(),
+            paused: None,
+            buffer_full_policy: BufferFullPolicy::Block,
+            held: HeldCommands::default(),
+            durability_feedback: None,
+            gauges: None,
+            strict_offset_contiguity: config.strict_offset_contiguity,
+            store_raw_proto: config.store_raw_proto,
+            partitions_per_period: config.partitions_per_period,
+            slot_bucket_size: config.slot_bucket_size,
+            offset_committer: config.offset_committer,
+            lock_lost: config.lock_lost,
+            statements,
+        }
+    }
+
+    /// Returns the buffer capacity and byte size limits to apply for an event of the given slot.
+    ///
+    /// The catch-up limits apply while `slot` lags far enough behind the tip, then the shard relaxes
+    /// back to its configured limits.
+    fn buffer_limits(&mut self, slot: Slot) -> (usize, usize) {
+        let Some(catch_up) = self.catch_up.as_ref() else {
+            return (self.max_buffer_capacity, self.max_buffer_byte_size);
+        };
+        let tip_slot = self.tip_slot.load(Ordering::Relaxed);
+        let catching_up = tip_slot >= 0 && tip_slot - slot >= catch_up.min_slot_lag;
+        if catching_up != self.catching_up {
+            info!(
+                shard = self.shard_id,
+                producer_id = ?self.producer_id,
+                slot,
+                tip_slot,
+                catching_up
+            );
+            self.catching_up = catching_up;
+        }
+        if catching_up {
+            (catch_up.max_buffer_capacity, catch_up.max_buffer_byte_size)
+        } else {
+            (self.max_buffer_capacity, self.max_buffer_byte_size)
+        }
+    }
+
+    /// Publishes how many offsets have been assigned but not yet written.
+    fn update_durability_lag(&self) {
+        if let Some(gauges) = self.gauges.as_deref() {
+            gauges.set_offsets(self.next_offset, self.committed_offset);
+        }
+        let lag = self.next_offset - self.committed_offset;
+        scylladb_shard_durability_lag_set(self.producer_id, self.shard_id, lag);
+        if let Some(durability_feedback) = self.durability_feedback.as_ref() {
+            durability_feedback.send_replace(lag);
+        }
+    }
+
+    fn clear_buffer(&mut self) {
+        self.buffer.clear();
+        self.buffer_budget.sub(self.curr_batch_byte_size);
+        self.curr_batch_byte_size = 0;
+        self.scylla_batch.statements.clear();
+        self.buffered_account_updates.clear();
+        self.pending_acks.clear();
+        if let Some(gauges) = self.gauges.as_deref() {
+            gauges.set_buffer(0, 0);
+        }
+        if self.oldest_buffered_at.take().is_some() {
+            scylladb_oldest_buffered_event_set(self.producer_id, self.shard_id, None);
+        }
+    }
+
+    /// Reserves the next offset of the shard.
+    ///
+    /// If the reserved offset starts a new partition, the buffered events of the previous partition are flushed so
+    /// a batch never spans two partitions. If it also starts a new period, the previous period gets committed once
+    /// fully written.
+    async fn reserve_offset(&mut self) -> anyhow::Result<ShardOffset> {
+        let offset = self.next_offset;
+        let curr_period = offset / SHARD_OFFSET_MODULO;
+
+        let boundary = OffsetBoundary::of(offset, self.partitions_per_period);
+        if boundary.requires_flush() {
+            self.flush().await?;
+        }
+        if boundary == OffsetBoundary::Period && self.deferred_period_commits_start.is_none() {
+            // Make sure the last period is committed
+            let t = Instant::now();
+            if let Some(period_committer) = self.period_committer.as_ref() {
+                let (ack, ack_rx) = oneshot::channel();
+                period_committer
+                    .send(PeriodCommitRequest {
+                        shard_id: self.shard_id,
+                        period: curr_period - 1,
+                        ack,
+                    })
+                    .await
+                    .map_err(|_| anyhow::anyhow!("period committer is closed"))?;
+                ack_rx
+                    .await
+                    .map_err(|_| anyhow::anyhow!("period committer dropped the commit"))??;
+            } else {
+                self.statements
+                    .commit_shard_period
+                    .execute(
+                        &self.session.current(),
+                        (self.producer_id, self.shard_id, curr_period - 1),
+                    )
+                    .await?;
+            }
+            info!(
+                shard = self.shard_id,
+                producer_id = ?self.producer_id,
+                committed_period = curr_period,
+                time_to_commit = ?t.elapsed()
+            );
+        }
+        self.next_offset += 1;
+        self.update_durability_lag();
+        if self
+            .max_offset_before_roll
+            .is_some_and(|max_offset| self.next_offset >= max_offset)
+            && !self.roll_required.swap(true, Ordering::Relaxed)
+        {
+            warn!(
+                "shard {} of producer {:?} reached offset {}, the producer must be rolled",
+                self.shard_id, self.producer_id, self.next_offset
+            );
+        }
+        Ok(offset)
+    }
+
+    /// Deduplicates an incoming account update against the buffered ones.
+    ///
+    /// Within a slot, an account update with a higher write version supersedes the ones with a lower write version.
+    /// The superseded update is replaced in place inside the buffer, so it never reaches `flush` and the
+    /// shard offsets stay contiguous.
+    ///
+    /// Returns the offset of the buffered event that absorbed the incoming update, which must not be buffered again,
+    /// or `None` if nothing absorbed it.
+    fn try_dedup_account_update(&mut self, acc_update: &AccountUpdate) -> Option<ShardOffset> {
+        let key = (acc_update.pubkey, acc_update.slot);
+        let &idx = self.buffered_account_updates.get(&key)?;
+        let buffered = &self.buffer[idx];
+        let offset = buffered.offset;
+        if buffered.write_version < Some(acc_update.write_version) {
+            let mut new_event = acc_update.clone().as_blockchain_event(
+                self.shard_id,
+                self.producer_id,
+                buffered.offset,
+            );
+            new_event.period = buffered.period;
+            let old_size = self.batch_byte_size(buffered);
+            let new_size = self.batch_byte_size(&new_event);
+            self.curr_batch_byte_size = self.curr_batch_byte_size - old_size + new_size;
+            self.buffer_budget.sub(old_size);
+            self.buffer_budget.add(new_size);
+            self.buffer[idx] = new_event;
+        }
+        // Either the buffered update or the incoming one is superseded, we drop one of them.
+        scylladb_account_update_dedup_inc(self.producer_id);
+        Some(offset)
+    }
+
+    /// Size of the rows of `event` in the batch, it is written twice when the shard also writes `log_by_slot`.
+    fn batch_byte_size(&self, event: &BlockchainEvent) -> usize {
+        let rows = 1 + usize::from(self.slot_bucket_size.is_some());
+        event.deep_size_of() * rows
+    }
+
+    /// Remembers to acknowledge the event once the event at `offset` is written, if it has a submission id.
+    fn track_submission(&mut self, submission_id: Option<SubmissionId>, offset: ShardOffset) {
+        if let (Some(submission_id), Some(_)) = (submission_id, self.ack_sender.as_ref()) {
+            self.pending_acks.push(SubmissionAck::Durable((
+                submission_id,
+                self.shard_id,
+                offset,
+            )));
+        }
+    }
+
+    /// Buffers a client command as a new event of the shard.
+    ///
+    /// The buffer is flushed beforehand if it is full or has been lingering for too long.
+    async fn insert(
+        &mut self,
+        mut cmd: ClientCommand,
+        submission_id: Option<SubmissionId>,
+        wal_seq: Option<WalSeq>,
+    ) -> anyhow::Result<()> {
+        let shard_id = self.shard_id;
+        let producer_id = self.producer_id;
+        if let Some(check) = self.slot_regression.as_mut() {
+            let slot = cmd.slot();
+            if let Some(max_slot_seen) = check.record(slot) {
+                scylladb_slot_regression_inc(producer_id, shard_id);
+                // A bad replay would otherwise log every event, warn once per highest slot.
+                if check.warned_at != max_slot_seen {
+                    check.warned_at = max_slot_seen;
+                    warn!("shard {shard_id} of producer {producer_id:?} received slot {slot}, {} slots behind the highest slot {max_slot_seen} it has seen", max_slot_seen - slot);
+                }
+            }
+        }
+        let wal_record = self
+            .wal
+            .as_ref()
+            .map(|_| ShardWal::encode(&cmd))
+            .transpose()?;
+        if !self.store_raw_proto {
+            cmd.clear_raw_proto();
+        }
+        let mut blockchain_event = match cmd {
+            ClientCommand::InsertAccountUpdate(acc_update) => {
+                let deduped_into = self
+                    .dedup_account_updates
+                    .then(|| self.try_dedup_account_update(&acc_update))
+                    .flatten();
+                if let Some(offset) = deduped_into {
+                    self.track_submission(submission_id, offset);
+                    self.append_to_wal(wal_record, wal_seq)?;
+                    scylladb_batch_request_lag_sub(producer_id, 1);
+                    return Ok(());
+                }
+                let offset = self.reserve_offset().await?;
+                acc_update.as_blockchain_event(shard_id, producer_id, offset)
+            }
+            ClientCommand::InsertTransaction(new_tx) => {
+                let seen_at = self
+                    .seen_signatures
+                    .as_mut()
+                    .and_then(|seen| seen.check(&new_tx.signature));
+                if let Some(offset) = seen_at {
+                    scylladb_transaction_dedup_inc(producer_id);
+                    self.track_submission(submission_id, offset);
+                    // Not logged by the shard: replayed once the first one is written, it would be written twice.
+                    release_wal_seq(self.router_wal.as_ref(), wal_seq)?;
+                    scylladb_batch_request_lag_sub(producer_id, 1);
+                    return Ok(());
+                }
+                let offset = self.reserve_offset().await?;
+                if let Some(seen) = self.seen_signatures.as_mut() {
+                    seen.insert(new_tx.signature.clone(), offset);
+                }
+                let mut event = new_tx.as_blockchain_event(shard_id, producer_id, offset);
+                if let Some(codec) = self.nested_codec {
+                    event.encode_nested(codec)?;
+                }
+                event
+            }
+            ClientCommand::InsertBlockMeta(block_meta) => {
+                let offset = self.reserve_offset().await?;
+                block_meta.as_blockchain_event(shard_id, producer_id, offset)
+            }
+        };
+        blockchain_event.period =
+            log_partition(blockchain_event.offset, self.partitions_per_period);
+
+        let msg_byte_size = self.batch_byte_size(&blockchain_event);
+        let (max_buffer_capacity, max_buffer_byte_size) = self.buffer_limits(blockchain_event.slot);
+
+        let lingered_enough = self.buffering_timeout.elapsed() > Duration::ZERO
+            && (self.buffer.len() >= self.min_batch_len
+                || self.max_buffering_timeout.elapsed() > Duration::ZERO);
+        let need_flush = self.buffer.len() >= max_buffer_capacity
+            || self.curr_batch_byte_size + msg_byte_size >= max_buffer_byte_size
+            || lingered_enough;
+
+        if need_flush {
+            self.flush().await?;
+        }
+        // Logged after the flush, which truncates the write-ahead log.
+        self.append_to_wal(wal_record, wal_seq)?;
+
+        if self.dedup_account_updates
+            && blockchain_event.event_type == BlockchainEventType::AccountUpdate
+        {
+            if let Some(pubkey) = blockchain_event.pubkey {
+                self.buffered_account_updates
+                    .insert((pubkey, blockchain_event.slot), self.buffer.len());
+            }
+        }
+        if self.oldest_buffered_at.is_none() {
+            let now = Instant::now();
+            self.oldest_buffered_at = Some(now);
+            scylladb_oldest_buffered_event_set(
+                self.producer_id,
+                self.shard_id,
+                Some(now.into_std()),
+            );
+        }
+        self.track_submission(submission_id, blockchain_event.offset);
+        self.buffer.push(blockchain_event);
+        self.scylla_batch
+            .append_statement(self.statements.insert_blockchain_event.statement().clone());
+        if let Some(insert_log_by_slot) = self.statements.insert_log_by_slot.as_ref() {
+            self.scylla_batch
+                .append_statement(insert_log_by_slot.clone());
+        }
+        self.curr_batch_byte_size += msg_byte_size;
+        self.buffer_budget.add(msg_byte_size);
+        if let Some(gauges) = self.gauges.as_deref() {
+            gauges.set_buffer(self.buffer.len(), self.curr_batch_byte_size);
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> anyhow::Result<()> {
+        let buffer_len = self.buffer.len();
+        if buffer_len > 0 {
+            if self.strict_offset_contiguity {
+                if let Some((expected, found)) = offset_gap(self.committed_offset, &self.buffer) {
+                    anyhow::bail!(
+                        "shard {} of producer {:?} expected offset {expected} but is about to write offset {found}",
+                        self.shard_id,
+                        self.producer_id
+                    );
+                }
+            }
+            // Only the rows are compressed, the buffer keeps the events as they came for handoffs and retries.
+            let compressed = self.compress_min_bytes.map(|min_bytes| {
+                let (compressed, payloads) =
+                    compress_rows(&self.buffer, min_bytes, self.compression_metered_offset);
+                for (payload, (input_bytes, output_bytes)) in payloads {
+                    if input_bytes > 0 {
+                        scylladb_compression_bytes_inc_by(
+                            self.producer_id,
+                            DataCodec::Lz4,
+                            payload,
+                            input_bytes,
+                            output_bytes,
+                        );
+                    }
+                }
+                if let Some(last) = compressed.last() {
+                    self.compression_metered_offset = last.offset + 1;
+                }
+                compressed
+            });
+            let before = Instant::now();
+            // We must wait for the batch success to guarantee monotonicity in the shard's timeline.
+            let rows = compressed
+                .as_deref()
+                .unwrap_or(&self.buffer)
+                .iter()
+                .flat_map(|event| {
+                    let by_slot = self.slot_bucket_size.map(|size| {
+                        BoundColumns(
+                            event,
+                            Some(slot_bucket(event.slot, size)),
+                            &self.statements.log_by_slot_columns,
+                        )
+                    });
+                    std::iter::once(BoundColumns(event, None, &self.statements.log_columns))
+                        .chain(by_slot)
+                })
+                .collect::<Vec<_>>();
+            self.send_batch(&rows).await?;
+            if let (Some(committer), Some(last)) =
+                (self.offset_committer.as_ref(), self.buffer.last())
+            {
+                // Before acknowledging and truncating the write-ahead log, which replays the batch if it fails.
+                committer
+                    .commit(self.producer_id, self.shard_id, last.offset)
+                    .await
+                    .map_err(|e| {
+                        e.context(format!(
+                            "shard {} failed to commit offset {}",
+                            self.shard_id, last.offset
+                        ))
+                    })?;
+            }
+            if let Some(wal) = self.wal.as_mut() {
+                wal.truncate()?;
+            }
+            if let Some(last) = self.buffer.last() {
+                self.committed_offset = last.offset + 1;
+                self.update_durability_lag();
+            }
+            if let Some(ack_sender) = self.ack_sender.as_ref() {
+                if !self.pending_acks.is_empty() {
+                    // The receiver may be gone, acks are best effort.
+                    let _ = ack_sender.send(std::mem::take(&mut self.pending_acks));
+                }
+            }
+            scylladb_batch_request_lag_sub(self.producer_id, buffer_len as i64);
+            scylladb_batch_sent_inc(self.producer_id);
+            self.record_partition_writes();
+            let now = ingestion_timestamp();
+            for ingested_at in self.buffer.iter().filter_map(|event| event.ingested_at) {
+                scylladb_ingestion_latency_observe(self.producer_id, now - ingested_at);
+            }
+            scylladb_batch_size_observe(self.producer_id, buffer_len);
+            if let Some(ratio) = self.single_item_batches.record(buffer_len) {
+                scylladb_single_item_batch_ratio_set(self.producer_id, self.shard_id, ratio);
+                let ineffective = ratio >= SINGLE_ITEM_BATCH_WARN_RATIO;
+                if ineffective && !self.single_item_batches.warned {
+                    warn!(
+                        "shard {} sent a single event in {:.0}% of its last {SINGLE_ITEM_BATCH_WINDOW} batches, check linger and min_batch_len",
+                        self.shard_id,
+                        ratio * 100.0
+                    );
+                }
+                self.single_item_batches.warned = ineffective;
+            }
+            scylladb_batchitem_sent_inc_by(self.producer_id, buffer_len as u64);
+            if before.elapsed() >= WARNING_SCYLLADB_LATENCY_THRESHOLD {
+                warn!("sent {} elements in {:?}", buffer_len, before.elapsed());
+            }
+        }
+        self.clear_buffer();
+        self.reset_buffering_timeouts();
+        Ok(())
+    }
+
+    /// Sends the batch of the buffered events, giving up as soon as the producer lock is lost since another
+    /// instance may write the shard from then on.
+    async fn send_batch(&self, rows: &[BoundColumns<'_>]) -> anyhow::Result<()> {
+        let mut lock_lost = self.lock_lost.clone();
+        tokio::select! {
+            biased;
+            _ = wait_lock_lost(&mut lock_lost) => anyhow::bail!(
+                "shard {} of producer {:?} stopped writing, the producer lock is lost",
+                self.shard_id,
+                self.producer_id
+            ),
+            result = self.retry_batch(rows) => result,
+        }
+    }
+
+    /// Sends the batch of the buffered events, retrying it whenever the circuit breaker lets it through unless
+    /// the error is fatal.
+    async fn retry_batch(&self, rows: &[BoundColumns<'_>]) -> anyhow::Result<()> {
+        let Some(circuit_breaker) = self.circuit_breaker.as_deref() else {
+            return self.write_batch(rows).await;
+        };
+        loop {
+            circuit_breaker.acquire().await;
+            match self.write_batch(rows).await {
+                Ok(_) => {
+                    circuit_breaker.record(false);
+                    return Ok(());
+                }
+                Err(e) => {
+                    circuit_breaker.record(true);
+                    let insert = &self.statements.insert_blockchain_event;
+                    if insert.classifier().classify_any(&e) == ErrorClass::Fatal {
+                        return Err(e);
+                    }
+                    warn!(
+                        "shard {} failed to write a batch of {} rows, retrying: {e:?}",
+                        self.shard_id,
+                        rows.len()
+                    );
+                }
+            }
+        }
+    }
+
+    /// Writes the batch of the buffered events, at a lower consistency while the consistency downgrade is active.
+    async fn write_batch(&self, rows: &[BoundColumns<'_>]) -> anyhow::Result<()> {
+        let insert = &self.statements.insert_blockchain_event;
+        let session = self.session.current();
+        let Some(downgrade) = self.consistency_downgrade.as_deref() else {
+            insert.batch(&session, &self.scylla_batch, rows).await?;
+            return Ok(());
+        };
+        loop {
+            let downgraded = downgrade.is_downgraded();
+            let result = if downgraded {
+                let mut batch = self.scylla_batch.clone();
+                batch.set_consistency(DOWNGRADED_CONSISTENCY);
+                insert.batch(&session, &batch, rows).await
+            } else {
+                insert.batch(&session, &self.scylla_batch, rows).await
+            };
+            if !downgrade.record(&result, insert.classifier(), downgraded) {
+                result?;
+                return Ok(());
+            }
+            warn!(
+                "shard {} could not write a batch of {} rows, replicas are unavailable",
+                self.shard_id,
+                rows.len()
+            );
+            if !downgrade.is_downgraded() {
+                tokio::time::sleep(UNAVAILABLE_RETRY_DELAY).await;
+            }
+        }
+    }
+
+    /// Counts the written events of the current period partition, the series of older periods are dropped.
+    fn record_partition_writes(&mut self) {
+        let mut writes = 0;
+        for event in self.buffer.iter() {
+            if self.metric_period != Some(event.period) {
+                if let Some(period) = self.metric_period.replace(event.period) {
+                    scylladb_partition_writes_remove(self.producer_id, self.shard_id, period);
+                }
+                writes = 0;
+            }
+            writes += 1;
+        }
+        if let Some(period) = self.metric_period {
+            scylladb_partition_writes_inc_by(self.producer_id, self.shard_id, period, writes);
+        }
+    }
+
+    /// Flushes the buffer a last time, retrying according to the shutdown flush policy.
+    async fn shutdown_flush(&mut self) -> anyhow::Result<()> {
+        // An open breaker would hold the final flush until the cluster recovers.
+        self.circuit_breaker = None;
+        let retry_policy = match self.shutdown_flush_policy {
+            ShutdownFlushPolicy::BestEffort => return self.flush().await,
+            ShutdownFlushPolicy::AtLeastOnce(retry_policy) => retry_policy,
+        };
+        let mut attempt = 1;
+        loop {
+            match self.flush().await {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    let Some(backoff) = retry_policy.backoff(attempt) else {
+                        return Err(e);
+                    };
+                    warn!(
+                        "shard {} final flush of {} events failed (attempt {attempt}), retrying in {backoff:?}: {e:?}",
+                        self.shard_id,
+                        self.buffer.len()
+                    );
+                    tokio::time::sleep(backoff).await;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    /// Commits every period a backfill shard filled, the same periods a live shard would have committed
+    /// while writing them.
+    async fn commit_deferred_periods(&self) -> anyhow::Result<()> {
+        let Some(start_offset) = self.deferred_period_commits_start else {
+            return Ok(());
+        };
+        // A period is committed once the first offset of the next one is reserved.
+        let first_boundary = (start_offset.max(1) + SHARD_OFFSET_MODULO - 1) / SHARD_OFFSET_MODULO
+            * SHARD_OFFSET_MODULO;
+        let mut committed = 0;
+        for boundary in (first_boundary..self.next_offset).step_by(SHARD_OFFSET_MODULO as usize) {
+            self.statements
+                .commit_shard_period
+                .execute(
+                    &self.session.current(),
+                    (
+                        self.producer_id,
+                        self.shard_id,
+                        boundary / SHARD_OFFSET_MODULO - 1,
+                    ),
+                )
+                .await?;
+            committed += 1;
+        }
+        info!(
+            "shard {} committed {committed} deferred periods",
+            self.shard_id
+        );
+        Ok(())
+    }
+
+    fn reset_buffering_timeouts(&mut self) {
+        let now = Instant::now();
+        self.buffering_timeout = now + self.buffer_linger;
+        self.max_buffering_timeout = now + self.max_buffer_linger;
+    }
+
+    fn append_to_wal(
+        &mut self,
+        wal_record: Option<Vec<u8>>,
+        wal_seq: Option<WalSeq>,
+    ) -> anyhow::Result<()> {
+        if let (Some(wal), Some(record)) = (self.wal.as_mut(), wal_record) {
+            wal.append(&record)?;
+        }
+        // The command is in the log of the shard now, the log of the sink no longer needs it.
+        release_wal_seq(self.router_wal.as_ref(), wal_seq)
+    }
+
+    /// Opens the write-ahead log of the shard and writes the commands a previous run left in it.
+    async fn replay_wal(&mut self) -> anyhow::Result<()> {
+        let Some(wal_dir) = self.wal_dir.clone() else {
+            return Ok(());
+        };
+        let (wal, pending) = ShardWal::open(&wal_dir, self.producer_id, self.shard_id)?;
+        self.wal = Some(wal.with_sync(self.wal_sync));
+        if !pending.is_empty() {
+            warn!(
+                "shard {} replaying {} commands from its write-ahead log",
+                self.shard_id,
+                pending.len()
+            );
+        }
+        for cmd in pending {
+            scylladb_batch_request_lag_inc(self.producer_id);
+            self.insert(cmd, None, None).await?;
+        }
+        self.flush().await?;
+        if let Some(wal) = self.wal.as_ref() {
+            wal.finish_replay()?;
+        }
+        Ok(())
+    }
+
+    /// Inserts a client command like [`Shard::insert`].
+    ///
+    /// Unless the policy is [`BufferFullPolicy::Block`], the shard keeps taking the commands of its mailbox while
+    /// the insert flushes, so a slow flush does not hold back the router. It holds as many events as its buffer,
+    /// `max_buffer_capacity`, and drops the others per the policy.
+    async fn insert_holding(
+        &mut self,
+        cmd: ClientCommand,
+        submission_id: Option<SubmissionId>,
+        wal_seq: Option<WalSeq>,
+        receiver: &mut mpsc::Receiver<SinkCommand>,
+    ) -> anyhow::Result<()> {
+        if self.buffer_full_policy == BufferFullPolicy::Block {
+            return self.insert(cmd, submission_id, wal_seq).await;
+        }
+        let drop_oldest = self.buffer_full_policy == BufferFullPolicy::DropOldest;
+        let (capacity, producer_id) = (self.max_buffer_capacity, self.producer_id);
+        let ack_sender = self.ack_sender.clone();
+        let router_wal = self.router_wal.clone();
+        let mut held = std::mem::take(&mut self.held);
+        let result = {
+            let insert = self.insert(cmd, submission_id, wal_seq);
+            tokio::pin!(insert);
+            loop {
+                tokio::select! {
+                    biased;
+                    result = &mut insert => break result,
+                    Some(msg) = receiver.recv() => {
+                        if let Some(dropped) = held.hold(msg, capacity, drop_oldest, producer_id) {
+                            // Counted by the router when it handed the event over.
+                            scylladb_batch_request_lag_sub(producer_id, 1);
+                            drop_submission(dropped, ack_sender.as_ref(), router_wal.as_ref());
+                        }
+                    }
+                }
+            }
+        };
+        self.held = held;
+        result
+    }
+
+    /// Takes the next command, the held ones first, `None` once the mailbox is closed and empty.
+    async fn next_command(
+        &mut self,
+        receiver: &mut mpsc::Receiver<SinkCommand>,
+    ) -> Option<SinkCommand> {
+        match self.held.pop_front() {
+            Some(msg) => Some(msg),
+            None => receiver.recv().await,
+        }
+    }
+
+    /// Empties the buffer without writing it, returning the buffered events in offset order.
+    ///
+    /// The caller becomes responsible for the returned events, they are removed from the write-ahead log.
+    fn take_pending(&mut self) -> anyhow::Result<Vec<ClientCommand>> {
+        let pending = self
+            .buffer
+            .drain(..)
+            .map(ClientCommand::from)
+            .collect::<Vec<_>>();
+        scylladb_batch_request_lag_sub(self.producer_id, pending.len() as i64);
+        self.clear_buffer();
+        if let Some(wal) = self.wal.as_mut() {
+            wal.truncate()?;
+        }
+        Ok(pending)
+    }
+
+    /// Hands the buffered events off to `reply`, followed by the events of `rest`, the shard stops writing.
+    fn hand_off(
+        &mut self,
+        reply: oneshot::Sender<Vec<ClientCommand>>,
+        rest: Vec<SinkCommand>,
+    ) -> anyhow::Result<()> {
+        let mut pending = self.take_pending()?;
+        append_handed_off(&mut pending, rest, self.producer_id);
+        warn!(
+            "shard {} handing off {} pending events",
+            self.shard_id,
+            pending.len()
+        );
+        if reply.send(pending).is_err() {
+            error!("shard {} pending events were dropped", self.shard_id);
+        }
+        Ok(())
+    }
+
+    /// Converts the current `Shard` instance into a background daemon for processing and batching `ClientCommand` messages.
+    ///
+    /// This method spawns an asynchronous task (`tokio::spawn`) to continuously receive messages from a channel (`receiver`),
+    /// batch process them, and commit periods to the database. It handles message buffering
+    /// and period commitment based on the configured buffer settings and period boundaries.
+    ///
+    /// # Returns
+    /// Returns a `Sender` channel (`tokio::sync::mpsc::Sender<SinkCommand>`) that can be used to send `ClientCommand` messages
+    /// to the background daemon for processing and batching.
+    fn into_daemon(
+        mut self,
+    ) -> (
+        tokio::sync::mpsc::Sender<SinkCommand>,
+        JoinHandle<anyhow::Result<()>>,
+    ) {
+        let (sender, mut receiver) = tokio::sync::mpsc::channel::<SinkCommand>(16);
+
+        let shard_runtime = self.shard_runtime;
+        let thread_name = format!("shard-{}-{}", self.producer_id[0], self.shard_id);
+        let active_shard = ActiveShardGuard::new(self.producer_id);
+        let daemon = async move {
+            let _active_shard = active_shard;
+            self.replay_wal().await?;
+            self.reset_buffering_timeouts();
+            loop {
+                self.wait_while_paused().await;
+                let Some(msg) = self.next_command(&mut receiver).await else {
+                    return self.close().await;
+                };
+                if self.run_command(msg, &mut receiver).await?.is_break() {
+                    return Ok(());
+                }
+            }
+        };
+        let handle = spawn_shard_daemon(shard_runtime, thread_name, daemon);
+        (sender, handle)
+    }
+
+    /// Waits until the shard is resumed if it is paused.
+    async fn wait_while_paused(&mut self) {
+        let shard_id = self.shard_id;
+        if let Some(paused) = self.paused.as_mut() {
+            if *paused.borrow_and_update() {
+                warn!("shard {} paused, its buffer is kept as is", shard_id);
+                // An error means the sink is gone, carry on to drain the mailbox.
+                let _ = paused.wait_for(|paused| !paused).await;
+                info!("shard {} resumed", shard_id);
+            }
+        }
+    }
+
+    /// Flushes the shard once the router dropped its sender, which only happens once it is shutting down.
+    async fn close(&mut self) -> anyhow::Result<()> {
+        info!(
+            "shard {} mailbox closed, flushing before exiting",
+            self.shard_id
+        );
+        self.shutdown_flush().await?;
+        self.commit_deferred_periods().await
+    }
+
+    /// Runs a command taken from `receiver`, the mailbox of the shard, breaks once the shard stopped.
+    async fn run_command(
+        &mut self,
+        msg: SinkCommand,
+        receiver: &mut mpsc::Receiver<SinkCommand>,
+    ) -> anyhow::Result<ControlFlow<()>> {
+        let shard_id = self.shard_id;
+        match msg {
+            SinkCommand::Client(cmd, submission_id, wal_seq) => {
+                self.insert_holding(cmd, submission_id, wal_seq, receiver)
+                    .await?
+            }
+            SinkCommand::Flush(ack) => {
+                self.flush().await?;
+                let _ = ack.send(());
+            }
+            SinkCommand::Handoff(reply) => {
+                // The events held are not in the buffer yet, they come after it.
+                let held = std::iter::from_fn(|| self.held.pop_front()).collect::<Vec<_>>();
+                self.hand_off(reply, held)?;
+                return Ok(ControlFlow::Break(()));
+            }
+            SinkCommand::Shutdown => {
+                warn!("Shard {} received shutdown command.", shard_id);
+                // Commands sent right before the shutdown are still held or in the mailbox, write them too.
+                let mut drained = std::iter::from_fn(|| self.held.pop_front()).collect::<Vec<_>>();
+                drained.extend(drain_mailbox(receiver));
+                let (commands, handoff) = split_at_handoff(drained);
+                for msg in commands {
+                    match msg {
+                        SinkCommand::Client(cmd, submission_id, wal_seq) => {
+                            self.insert(cmd, submission_id, wal_seq).await?
+                        }
+                        SinkCommand::Flush(ack) => {
+                            self.flush().await?;
+                            let _ = ack.send(());
+                        }
+                        SinkCommand::Shutdown | SinkCommand::Handoff(_) => (),
+                    }
+                }
+                if let Some((reply, rest)) = handoff {
+                    // The events written so far are in `log`, only the periods they filled are left to commit.
+                    self.hand_off(reply, rest)?;
+                    self.commit_deferred_periods().await?;
+                    return Ok(ControlFlow::Break(()));
+                }
+                self.shutdown_flush().await?;
+                self.commit_deferred_periods().await?;
+                warn!("shard {} finished shutdown procedure", shard_id);
+                return Ok(ControlFlow::Break(()));
+            }
+        }
+        Ok(ControlFlow::Continue(()))
+    }
+}
+
+/// A shard running on the task of its group, see [`spawn_shard_group`].
+struct GroupedShard {
+    shard: Shard,
+    receiver: mpsc::Receiver<SinkCommand>,
+    outcome: oneshot::Sender<anyhow::Result<()>>,
+    _active_shard: ActiveShardGuard,
+}
+
+impl GroupedShard {
+    /// Reports the outcome of the shard, then closes its mailbox so the router sees it stopped.
+    fn finish(self, result: anyhow::Result<()>) {
+        let _ = self.outcome.send(result);
+    }
+}
+
+/// Runs `shards` on a single task, see [`ScyllaSinkConfig::max_concurrent_shards`].
+///
+/// Each shard keeps its own mailbox, buffer and offsets. The task takes the commands of its shards in turn and
+/// runs them one at a time, so the flushes of its shards are sequential. A shard which stops or fails is
+/// dropped from the group without stopping the others, the task ends with its last shard. While one of its
+/// shards is paused, the task takes no command from any of them.
+///
+/// Returns the mailbox of each shard and the receiver of its outcome, in the order of `shards`.
+fn spawn_shard_group(
+    shard_runtime: ShardRuntime,
+    thread_name: String,
+    shards: Vec<Shard>,
+) -> Vec<(
+    mpsc::Sender<SinkCommand>,
+    oneshot::Receiver<anyhow::Result<()>>,
+)> {
+    let mut handles = Vec::with_capacity(shards.len());
+    let mut members = Vec::with_capacity(shards.len());
+    for shard in shards {
+        let (sender, receiver) = mpsc::channel::<SinkCommand>(16);
+        let (outcome, outcome_rx) = oneshot::channel();
+        handles.push((sender, outcome_rx));
+        members.push(GroupedShard {
+            _active_shard: ActiveShardGuard::new(shard.producer_id),
+            shard,
+            receiver,
+            outcome,
+        });
+    }
+    let daemon = async move {
+        let mut started = Vec::with_capacity(members.len());
+        for mut member in members {
+            match member.shard.replay_wal().await {
+                Ok(()) => {
+                    member.shard.reset_buffering_timeouts();
+                    started.push(member);
+                }
+                Err(e) => member.finish(Err(e)),
+            }
+        }
+        let mut members = started;
+        let mut cursor = 0;
+        while !members.is_empty() {
+            for member in members.iter_mut() {
+                member.shard.wait_while_paused().await;
+            }
+            // The commands a shard held while flushing come before the ones of its mailbox.
+            let held = (0..members.len())
+                .map(|k| (cursor + k) % members.len())
+                .find(|&j| !members[j].shard.held.is_empty());
+            let (j, msg) = match held {
+                Some(j) => (j, members[j].shard.held.pop_front()),
+                None => {
+                    std::future::poll_fn(|cx| {
+                        for k in 0..members.len() {
+                            let j = (cursor + k) % members.len();
+                            if let Poll::Ready(msg) = members[j].receiver.poll_recv(cx) {
+                                return Poll::Ready((j, msg));
+                            }
+                        }
+                        Poll::Pending
+                    })
+                    .await
+                }
+            };
+            cursor = j + 1;
+            let member = &mut members[j];
+            let stopped = match msg {
+                Some(msg) => member
+                    .shard
+                    .run_command(msg, &mut member.receiver)
+                    .await
+                    .map(|flow| flow.is_break()),
+                None => member.shard.close().await.map(|()| true),
+            };
+            match stopped {
+                Ok(false) => (),
+                Ok(true) => members.swap_remove(j).finish(Ok(())),
+                Err(e) => members.swap_remove(j).finish(Err(e)),
+            }
+        }
+        Ok(())
+    };
+    // The group reports the outcome of each shard, its own handle is not needed.
+    drop(spawn_shard_daemon(shard_runtime, thread_name, daemon));
+    handles
+}
+
+/// Counts a shard daemon in `scylladb_active_shards` until it is dropped, however the daemon ends.
+struct ActiveShardGuard(ProducerId);
+
+impl ActiveShardGuard {
+    fn new(producer_id: ProducerId) -> Self {
+        scylladb_active_shards_inc(producer_id);
+        ActiveShardGuard(producer_id)
+    }
+}
+
+impl Drop for ActiveShardGuard {
+    fn drop(&mut self) {
+        scylladb_active_shards_dec(self.0);
+    }
+}
+
+/// A shard waiting for one of its periods to be committed.
+struct PeriodCommitRequest {
+    shard_id: ShardId,
+    period: ShardPeriod,
+    ack: oneshot::Sender<anyhow::Result<()>>,
+}
+
+/// Spawns the task committing the periods of every shard, gathering the requests received within `window`
+/// into a single unlogged batch.
+///
+/// The task ends once every shard has dropped its sender.
+fn spawn_period_committer(
+    session: SharedSession,
+    producer_id: ProducerId,
+    commit_shard_period: GuardedStatement,
+    window: Duration,
+) -> mpsc::Sender<PeriodCommitRequest> {
+    let (sender, mut receiver) = mpsc::channel::<PeriodCommitRequest>(16);
+    tokio::spawn(async move {
+        while let Some(first) = receiver.recv().await {
+            let deadline = Instant::now() + window;
+            let mut requests = vec![first];
+            loop {
+                tokio::select! {
+                    maybe_request = receiver.recv() => match maybe_request {
+                        Some(request) => requests.push(request),
+                        None => break,
+                    },
+                    _ = tokio::time::sleep_until(deadline) => break,
+                }
+            }
+
+            let mut batch = Batch::new(BatchType::Unlogged);
+            let values = requests
+                .iter()
+                .map(|request| {
+                    batch.append_statement(commit_shard_period.statement().clone());
+                    (producer_id, request.shard_id, request.period)
+                })
+                .collect::<Vec<_>>();
+            let t = Instant::now();
+            let result = commit_shard_period
+                .batch(&session.current(), &batch, &values)
+                .await;
+            info!(
+                producer_id = ?producer_id,
+                committed_periods = values.len(),
+                time_to_commit = ?t.elapsed()
+            );
+            for request in requests {
+                let _ = request.ack.send(
+                    result
+                        .as_ref()
+                        .map(|_| ())
+                        .map_err(|e| anyhow::anyhow!("failed to commit period: {e}")),
+                );
+            }
+        }
+    });
+    sender
+}
+
+/// Asks every shard to flush behind the events already routed to it.
+///
+/// Returns the acknowledgement of each shard that received the command.
+async fn request_flush<'a>(
+    shards: impl IntoIterator<Item = &'a mut ShardHandle>,
+    producer_id: ProducerId,
+) -> Vec<oneshot::Receiver<()>> {
+    let mut shard_acks = Vec::new();
+    for shard in shards {
+        // The events held for the shard must be flushed too.
+        shard.send_overflow(producer_id).await;
+        let (shard_ack, shard_ack_rx) = oneshot::channel();
+        if shard
+            .mailbox
+            .send(SinkCommand::Flush(shard_ack))
+            .await
+            .is_ok()
+        {
+            shard_acks.push(shard_ack_rx);
+        }
+    }
+    shard_acks
+}
+
+/// Flushes every shard of `shards` and waits for them, fails unless every shard took and acknowledged its flush.
+async fn flush_all<'a>(
+    shards: impl IntoIterator<Item = &'a mut ShardHandle>,
+    producer_id: ProducerId,
+) -> anyhow::Result<()> {
+    let shards = shards.into_iter().collect::<Vec<_>>();
+    let num_shards = shards.len();
+    let acked = future::join_all(request_flush(shards, producer_id).await)
+        .await
+        .into_iter()
+        .filter(Result::is_ok)
+        .count();
+    anyhow::ensure!(
+        acked == num_shards,
+        "only {acked} out of {num_shards} shards flushed"
+    );
+    Ok(())
+}
+
+/// Closes a shard mailbox and returns every command still queued in it, in order.
+fn drain_mailbox(receiver: &mut mpsc::Receiver<SinkCommand>) -> Vec<SinkCommand> {
+    receiver.close();
+    let mut remaining = Vec::new();
+    while let Ok(msg) = receiver.try_recv() {
+        remaining.push(msg);
+    }
+    remaining
+}
+
+/// Splits the commands drained by a shard shutting down at the first handoff, if any: the shard writes the
+/// commands before it, then hands off its buffer followed by the commands after it.
+#[allow(clippy::type_complexity)]
+fn split_at_handoff(
+    mut commands: Vec<SinkCommand>,
+) -> (
+    Vec<SinkCommand>,
+    Option<(oneshot::Sender<Vec<ClientCommand>>, Vec<SinkCommand>)>,
+) {
+    let Some(at) = commands
+        .iter()
+        .position(|msg| matches!(msg, SinkCommand::Handoff(_)))
+    else {
+        return (commands, None);
+    };
+    let mut rest = commands.split_off(at).into_iter();
+    let Some(SinkCommand::Handoff(reply)) = rest.next() else {
+        unreachable!("the handoff was found at this position");
+    };
+    (commands, Some((reply, rest.collect())))
+}
+
+/// Appends the events of `rest` to the events handed off by a shard, the other commands are dropped.
+fn append_handed_off(
+    pending: &mut Vec<ClientCommand>,
+    rest: Vec<SinkCommand>,
+    producer_id: ProducerId,
+) {
+    for msg in rest {
+        if let SinkCommand::Client(cmd, _, _) = msg {
+            scylladb_batch_request_lag_sub(producer_id, 1);
+            pending.push(cmd);
+        }
+    }
+}
+
+/// Commands a shard took from its mailbox while it was flushing, in the order they arrived, see
+/// [`BufferFullPolicy`].
+#[derive(Default)]
+struct HeldCommands {
+    commands: VecDeque<SinkCommand>,
+    /// Number of events among the commands, only events count towards the capacity and are dropped.
+    events: usize,
+}
+
+impl HeldCommands {
+    /// Holds `msg`, dropping the oldest held event if `drop_oldest`, else `msg`, once `capacity` events are held.
+    ///
+    /// Returns the dropped event, if any.
+    fn hold(
+        &mut self,
+        msg: SinkCommand,
+        capacity: usize,
+        drop_oldest: bool,
+        producer_id: ProducerId,
+    ) -> Option<Submission> {
+        let SinkCommand::Client(cmd, submission_id, wal_seq) = msg else {
+            self.commands.push_back(msg);
+            return None;
+        };
+        let mut dropped = None;
+        if self.events >= capacity {
+            if !drop_oldest {
+                scylladb_buffer_full_drop_inc(producer_id, BufferFullPolicy::DropNewest);
+                return Some((cmd, submission_id, wal_seq));
+            }
+            scylladb_buffer_full_drop_inc(producer_id, BufferFullPolicy::DropOldest);
+            let oldest = self
+                .commands
+                .iter()
+                .position(|msg| matches!(msg, SinkCommand::Client(..)));
+            if let Some(SinkCommand::Client(cmd, submission_id, wal_seq)) =
+                oldest.and_then(|i| self.commands.remove(i))
+            {
+                self.events -= 1;
+                dropped = Some((cmd, submission_id, wal_seq));
+            }
+        }
+        self.commands
+            .push_back(SinkCommand::Client(cmd, submission_id, wal_seq));
+        self.events += 1;
+        dropped
+    }
+
+    fn pop_front(&mut self) -> Option<SinkCommand> {
+        let msg = self.commands.pop_front()?;
+        if matches!(msg, SinkCommand::Client(..)) {
+            self.events -= 1;
+        }
+        Some(msg)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+}
+
+/// Task running a shard daemon.
+enum ShardTask {
+    /// The shard runs on its own task.
+    Dedicated(JoinHandle<anyhow::Result<()>>),
+    /// The shard shares the task of its group, which reports the outcome of the shard once it stopped.
+    Grouped(oneshot::Receiver<anyhow::Result<()>>),
+}
+
+/// A running shard daemon, owned by the router.
+struct ShardHandle {
+    mailbox: tokio::sync::mpsc::Sender<SinkCommand>,
+    task: ShardTask,
+    /// Last durability lag reported by the shard, if the router throttles on it.
+    durability_lag: Option<watch::Receiver<ShardOffset>>,
+    /// Events held while the mailbox is full, oldest first, see [`BufferFullPolicy`].
+    overflow: VecDeque<Submission>,
+    /// Number of events held before dropping, the buffer capacity of the shard.
+    overflow_capacity: usize,
+}
+
+impl ShardHandle {
+    /// Holds `submission` until the mailbox frees up, dropping the oldest held event if `drop_oldest`, else
+    /// `submission`, once enough are held.
+    ///
+    /// Returns the dropped event, if any.
+    fn hold(
+        &mut self,
+        submission: Submission,
+        drop_oldest: bool,
+        producer_id: ProducerId,
+    ) -> Option<Submission> {
+        let mut dropped = None;
+        if self.overflow.len() >= self.overflow_capacity {
+            if !drop_oldest {
+                scylladb_buffer_full_drop_inc(producer_id, BufferFullPolicy::DropNewest);
+                return Some(submission);
+            }
+            scylladb_buffer_full_drop_inc(producer_id, BufferFullPolicy::DropOldest);
+            dropped = self.overflow.pop_front();
+        }
+        self.overflow.push_back(submission);
+        dropped
+    }
+
+    /// Moves the held events to the mailbox as long as it has room, without waiting.
+    fn try_send_overflow(&mut self, producer_id: ProducerId) {
+        while !self.overflow.is_empty() {
+            let Ok(permit) = self.mailbox.try_reserve() else {
+                return;
+            };
+            if let Some((cmd, submission_id, wal_seq)) = self.overflow.pop_front() {
+                permit.send(SinkCommand::Client(cmd, submission_id, wal_seq));
+                scylladb_batch_request_lag_inc(producer_id);
+            }
+        }
+    }
+
+    /// Moves every held event to the mailbox, waiting for room. They stay held if the shard is gone.
+    async fn send_overflow(&mut self, producer_id: ProducerId) {
+        while !self.overflow.is_empty() {
+            let Ok(permit) = self.mailbox.reserve().await else {
+                return;
+            };
+            if let Some((cmd, submission_id, wal_seq)) = self.overflow.pop_front() {
+                permit.send(SinkCommand::Client(cmd, submission_id, wal_seq));
+                scylladb_batch_request_lag_inc(producer_id);
+            }
+        }
+    }
+
+    /// Waits for the durability lag of the shard to drop below `max_lag` if it reached it, asking the shard to
+    /// flush first. Returns right away if the router does not throttle on the lag.
+    async fn wait_durability_lag(&mut self, max_lag: Option<ShardOffset>, producer_id: ProducerId) {
+        let lagging = max_lag
+            .zip(self.durability_lag.as_ref())
+            .filter(|(max_lag, durability_lag)| *durability_lag.borrow() >= *max_lag);
+        let Some((max_lag, durability_lag)) = lagging else {
+            return;
+        };
+        let mut durability_lag = durability_lag.clone();
+        self.send_overflow(producer_id).await;
+        // A shard only flushes a lingering buffer when it receives events, ask for it.
+        let (ack, _) = oneshot::channel();
+        let _ = self.mailbox.send(SinkCommand::Flush(ack)).await;
+        // Fails once the shard is gone, which the reservation of the router reports.
+        let _ = durability_lag.wait_for(|lag| *lag < max_lag).await;
+    }
+
+    /// Returns true once the shard daemon stopped, it then no longer takes commands.
+    fn is_finished(&self) -> bool {
+        match &self.task {
+            ShardTask::Dedicated(join_handle) => join_handle.is_finished(),
+            ShardTask::Grouped(_) => self.mailbox.is_closed(),
+        }
+    }
+
+    /// Waits for a shard daemon that is no longer accepting commands and reports why it stopped.
+    async fn join(self, shard_id: ShardId) {
+        let result = match self.task {
+            ShardTask::Dedicated(join_handle) => join_handle.await,
+            ShardTask::Grouped(outcome) => match outcome.await {
+                Ok(result) => Ok(result),
+                Err(_) => {
+                    error!("shard {shard_id} group stopped without reporting, it panicked or was cancelled");
+                    return;
+                }
+            },
+        };
+        match result {
+            Ok(Ok(())) => warn!("shard {shard_id} terminated"),
+            Ok(Err(e)) => error!("shard {shard_id} error: {e:?}"),
+            Err(e) if e.is_panic() => error!("shard {shard_id} panicked: {e:?}"),
+            Err(e) => error!("shard {shard_id} was cancelled: {e:?}"),
+        }
+    }
+
+    /// Flushes the shard buffer and waits for the shard daemon to stop.
+    async fn shutdown(self, shard_id: ShardId) {
+        warn!("Shutting down shard: {}", shard_id);
+        if self.mailbox.send(SinkCommand::Shutdown).await.is_err() {
+            error!("shard {} was already closed", shard_id);
+        }
+        self.join(shard_id).await;
+    }
+
+    /// Stops the shard daemon without flushing and collects the events it still buffers.
+    async fn handoff(self, shard_id: ShardId) -> Vec<ClientCommand> {
+        let (reply, pending) = oneshot::channel();
+        let pending = if self.mailbox.send(SinkCommand::Handoff(reply)).await.is_ok() {
+            pending.await.unwrap_or_default()
+        } else {
+            error!("shard {} was already closed", shard_id);
+            Vec::new()
+        };
+        self.join(shard_id).await;
+        pending
+    }
+}
+
+/// Holds everything needed to spawn (or respawn) the shard daemons of a producer.
+#[derive(Clone)]
+struct ShardFactory {
+    session: SharedSession,
+    producer_id: ProducerId,
+    shard_config: ShardConfig,
+    shard_buffer_overrides: BTreeMap<ShardId, ShardBufferConfig>,
+    statements: SinkStatements,
+    control_keyspace: Option<String>,
+    /// Pause switch of each shard, kept across respawns.
+    shard_pauses: BTreeMap<ShardId, watch::Receiver<bool>>,
+    shard_gauges: BTreeMap<ShardId, Arc<ShardGauges>>,
+    /// Durability lag above which the router holds back the events of a shard.
+    max_shard_durability_lag: Option<ShardOffset>,
+    buffer_full_policy: BufferFullPolicy,
+}
+
+impl ShardFactory {
+    /// Spawns the shards of `start_offsets`, grouped on at most `max_concurrent_shards` tasks if there are more.
+    fn spawn_all(
+        &self,
+        start_offsets: Vec<(ShardId, ShardOffset)>,
+        max_concurrent_shards: Option<NonZeroUsize>,
+    ) -> Vec<ShardHandle> {
+        let Some(max_tasks) = max_concurrent_shards
+            .map(NonZeroUsize::get)
+            .filter(|max_tasks| *max_tasks < start_offsets.len())
+        else {
+            return start_offsets
+                .into_iter()
+                .map(|(shard_id, next_offset)| self.spawn(shard_id, next_offset))
+                .collect();
+        };
+        info!(
+            "running the {} shards of producer {:?} on {max_tasks} tasks",
+            start_offsets.len(),
+            self.producer_id
+        );
+        // Consecutive shards land on different tasks, the round-robin routing then keeps every task busy.
+        let mut groups = (0..max_tasks).map(|_| Vec::new()).collect::<Vec<_>>();
+        for (i, (shard_id, next_offset)) in start_offsets.into_iter().enumerate() {
+            let capacity = self.max_buffer_capacity(shard_id);
+            groups[i % max_tasks].push((i, capacity, self.build(shard_id, next_offset)));
+        }
+        let mut handles = Vec::new();
+        for (group_id, group) in groups.into_iter().enumerate() {
+            let (positions, shards): (Vec<_>, Vec<_>) = group
+                .into_iter()
+                .map(|(i, capacity, (shard, durability_lag))| {
+                    ((i, capacity, durability_lag), shard)
+                })
+                .unzip();
+            let thread_name = format!("shard-group-{}-{group_id}", self.producer_id[0]);
+            let spawned = spawn_shard_group(self.shard_config.shard_runtime, thread_name, shards);
+            for ((i, capacity, durability_lag), (mailbox, outcome)) in
+                positions.into_iter().zip(spawned)
+            {
+                handles.push((
+                    i,
+                    ShardHandle {
+                        mailbox,
+                        task: ShardTask::Grouped(outcome),
+                        durability_lag,
+                        overflow: VecDeque::new(),
+                        overflow_capacity: capacity,
+                    },
+                ));
+            }
+        }
+        handles.sort_by_key(|(i, _)| *i);
+        handles.into_iter().map(|(_, handle)| handle).collect()
+    }
+
+    fn spawn(&self, shard_id: ShardId, next_offset: ShardOffset) -> ShardHandle {
+        let (shard, durability_lag) = self.build(shard_id, next_offset);
+        let (mailbox, join_handle) = shard.into_daemon();
+        ShardHandle {
+            mailbox,
+            task: ShardTask::Dedicated(join_handle),
+            durability_lag,
+            overflow: VecDeque::new(),
+            overflow_capacity: self.max_buffer_capacity(shard_id),
+        }
+    }
+
+    /// Returns the buffer capacity of a shard, its override if any.
+    fn max_buffer_capacity(&self, shard_id: ShardId) -> usize {
+        self.shard_buffer_overrides
+            .get(&shard_id)
+            .and_then(|overrides| overrides.max_buffer_capacity)
+            .unwrap_or(self.shard_config.max_buffer_capacity)
+    }
+
+    /// Builds a shard, along the receiver of its durability lag if the router throttles on it.
+    fn build(
+        &self,
+        shard_id: ShardId,
+        next_offset: ShardOffset,
+    ) -> (Shard, Option<watch::Receiver<ShardOffset>>) {
+        let mut shard_config = self.shard_config.clone();
+        shard_config.max_buffer_capacity = self.max_buffer_capacity(shard_id);
+        if let Some(overrides) = self.shard_buffer_overrides.get(&shard_id) {
+            if let Some(batch_size_kb_limit) = overrides.batch_size_kb_limit {
+                shard_config.max_buffer_byte_size = batch_size_kb_limit * 1024;
+            }
+        }
+        let mut shard = Shard::new(
+            self.session.clone(),
+            shard_id,
+            self.producer_id,
+            next_offset,
+            shard_config,
+            self.statements.clone(),
+        );
+        shard.paused = self.shard_pauses.get(&shard_id).cloned();
+        shard.buffer_full_policy = self.buffer_full_policy;
+        shard.gauges = self.shard_gauges.get(&shard_id).cloned();
+        if let Some(gauges) = shard.gauges.as_deref() {
+            gauges.set_offsets(next_offset, next_offset);
+            gauges.set_buffer(0, 0);
+        }
+        let durability_lag = self.max_shard_durability_lag.map(|_| {
+            let (sender, receiver) = watch::channel(0);
+            shard.durability_feedback = Some(sender);
+            receiver
+        });
+        (shard, durability_lag)
+    }
+
+    /// Respawns a dead shard from the last offset persisted in ScyllaDB.
+    async fn respawn(&self, shard_id: ShardId) -> anyhow::Result<ShardHandle> {
+        let (_, last_offset) = get_max_shard_offsets_for_shards(
+            self.session.current(),
+            self.producer_id,
+            &[shard_id],
+            self.control_keyspace.as_deref(),
+            1,
+            self.shard_config.partitions_per_period,
+        )
+        .await?
+        .pop()
+        .ok_or(anyhow::anyhow!("could not recover shard {shard_id} offset"))?;
+        info!("restarting shard {shard_id} at offset {}", last_offset + 1);
+        Ok(self.spawn(shard_id, last_offset + 1))
+    }
+}
+
+pub struct ScyllaSink {
+    /// Session read by every component of the sink, see [`ScyllaSink::swap_session`].
+    session: watch::Sender<Arc<Session>>,
+    tip_slot: Arc<AtomicI64>,
+    roll_required: Arc<AtomicBool>,
+    router_sender: tokio::sync::mpsc::Sender<SinkCommand>,
+    /// Predicate of the priority events and the priority mailbox of the router.
+    priority_lane: Option<(PriorityPredicate, mpsc::Sender<Submission>)>,
+    /// Requests the router to shut down, bypassing the events queued in `router_sender`.
+    router_shutdown: oneshot::Sender<()>,
+    router_handle: JoinHandle<anyhow::Result<()>>,
+    producer_id: ProducerId,
+    /// Lock of the producer, backfill sinks run without it.
+    producer_lock: Option<SharedProducerLock>,
+    /// Writes the heartbeat and rotates the lock, if either is configured.
+    keepalive: Option<KeepaliveHandle>,
+    max_event_bytes: usize,
+    field_size_limits: FieldSizeLimits,
+    ordering_guarantee: OrderingGuarantee,
+    circuit_breaker: Option<Arc<CircuitBreaker>>,
+    /// Set by the router when it stops because a shard died.
+    failed_shard: Arc<OnceLock<ShardId>>,
+    /// Acks of the events logged with a submission id, until taken by the caller.
+    ack_receiver: Option<mpsc::UnboundedReceiver<Vec<SubmissionAck>>>,
+    /// Pause switch of each shard.
+    shard_pauses: BTreeMap<ShardId, watch::Sender<bool>>,
+    /// Offsets and occupancy published by each shard.
+    shard_gauges: BTreeMap<ShardId, Arc<ShardGauges>>,
+    /// Configuration the sink started with, reported by [`ScyllaSink::diagnostics`].
+    config: ScyllaSinkConfig,
+    partitions_per_period: i16,
+    /// Every command is appended to it before being routed, see [`ScyllaSinkConfig::wal_dir`].
+    router_wal: Option<SharedRouterWal>,
+}
+
+/// Returned by the `log_*` methods.
+#[derive(Debug)]
+pub enum ScyllaSinkError {
+    /// The router stopped, the sink no longer accepts events.
+    RouterClosed,
+    /// The router stopped because this shard died and the failure policy does not restart shards.
+    ShardClosed(ShardId),
+    /// The circuit breaker of the sink is open, the caller should shed load.
+    Backpressure(CircuitOpen),
+    /// The producer reached its maximum offset, the sink no longer accepts events.
+    RollRequired(ProducerRollRequired),
+    /// The event was rejected, the sink still accepts other events.
+    EventTooLarge(EventTooLarge),
+    /// The event was rejected, the sink still accepts other events.
+    FieldTooLarge(FieldTooLarge),
+    /// The event passed to [`ScyllaSink::log_blockchain_event`] can not be decoded, the sink still accepts other
+    /// events.
+    InvalidEvent(InvalidEvent),
+    /// The event could not be appended to the write-ahead log of the sink, see [`ScyllaSinkConfig::wal_dir`].
+    Wal(anyhow::Error),
+}
+
+impl std::fmt::Display for ScyllaSinkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScyllaSinkError::RouterClosed => f.write_str("router of the sink is closed"),
+            ScyllaSinkError::ShardClosed(shard_id) => {
+                write!(f, "shard {shard_id} died, the sink is closed")
+            }
+            ScyllaSinkError::Backpressure(e) => e.fmt(f),
+            ScyllaSinkError::RollRequired(e) => e.fmt(f),
+            ScyllaSinkError::EventTooLarge(e) => e.fmt(f),
+            ScyllaSinkError::FieldTooLarge(e) => e.fmt(f),
+            ScyllaSinkError::InvalidEvent(e) => e.fmt(f),
+            ScyllaSinkError::Wal(e) => write!(f, "failed to append to the write-ahead log: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ScyllaSinkError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ScyllaSinkError::RouterClosed | ScyllaSinkError::ShardClosed(_) => None,
+            ScyllaSinkError::Backpressure(e) => Some(e),
+            ScyllaSinkError::RollRequired(e) => Some(e),
+            ScyllaSinkError::EventTooLarge(e) => Some(e),
+            ScyllaSinkError::FieldTooLarge(e) => Some(e),
+            ScyllaSinkError::InvalidEvent(e) => Some(e),
+            ScyllaSinkError::Wal(e) => Some(e.as_ref()),
+        }
+    }
+}
+
+/// Returned by the `log_*` methods for an event that could never fit in a batch, see [`ScyllaSinkConfig::max_event_kb`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EventTooLarge {
+    pub size: usize,
+    pub limit: usize,
+}
+
+impl std::fmt::Display for EventTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "event of {} bytes exceeds the {} bytes limit",
+            self.size, self.limit
+        )
+    }
+}
+
+impl std::error::Error for EventTooLarge {}
+
+/// Returned by [`ScyllaSink::log_blockchain_event`] for an event missing a column or holding corrupted data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidEvent {
+    /// Offset of the event in the log it was read from.
+    pub offset: ShardOffset,
+    pub reason: String,
+}
+
+impl std::fmt::Display for InvalidEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "invalid event at offset {}: {}",
+            self.offset, self.reason
+        )
+    }
+}
+
+impl std::error::Error for InvalidEvent {}
+
+/// Returned by the `log_*` methods once the producer reached [`ScyllaSinkConfig::max_offset_before_roll`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProducerRollRequired {
+    pub producer_id: ProducerId,
+}
+
+impl std::fmt::Display for ProducerRollRequired {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "producer {:?} reached its maximum offset and must be rolled to a new producer",
+            self.producer_id
+        )
+    }
+}
+
+impl std::error::Error for ProducerRollRequired {}
+
+/// What the sink does with an event holding a field over its size limit.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OversizedFieldAction {
+    /// Rejects the event with [`ScyllaSinkError::FieldTooLarge`].
+    #[default]
+    Reject,
+    /// Cuts the field down to its limit and sets the `truncated` column of the event.
+    Truncate,
+}
+
+impl OversizedFieldAction {
+    const fn as_str(self) -> &'static str {
+        match self {
+            OversizedFieldAction::Reject => "reject",
+            OversizedFieldAction::Truncate => "truncate",
+        }
+    }
+}
+
+/// Size limits of the fields that can grow large enough to destabilize the cluster, no limit by default.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct FieldSizeLimits {
+    /// Largest account data in bytes.
+    pub max_account_data_bytes: Option<usize>,
+    /// Largest total length of the log messages of a transaction in bytes.
+    ///
+    /// Truncation keeps the leading messages that fit.
+    pub max_log_messages_bytes: Option<usize>,
+    pub oversized_field_action: OversizedFieldAction,
+}
+
+impl FieldSizeLimits {
+    /// Checks the fields of a command against the limits, truncating them if configured to.
+    ///
+    /// Returns the field over its limit, if any.
+    fn enforce(&self, cmd: &mut ClientCommand) -> Result<Option<FieldTooLarge>, FieldTooLarge> {
+        let oversized = match cmd {
+            ClientCommand::InsertAccountUpdate(update) => self
+                .max_account_data_bytes
+                .filter(|limit| update.data.len() > *limit)
+                .map(|limit| FieldTooLarge {
+                    field: "data",
+                    size: update.data.len(),
+                    limit,
+                }),
+            ClientCommand::InsertTransaction(tx) => {
+                let size = tx
+                    .meta
+                    .log_messages
+                    .iter()
+                    .flatten()
+                    .map(String::len)
+                    .sum::<usize>();
+                self.max_log_messages_bytes
+                    .filter(|limit| size > *limit)
+                    .map(|limit| FieldTooLarge {
+                        field: "log_messages",
+                        size,
+                        limit,
+                    })
+            }
+            ClientCommand::InsertBlockMeta(_) => None,
+        };
+        let Some(oversized) = oversized else {
+            return Ok(None);
+        };
+        if self.oversized_field_action == OversizedFieldAction::Reject {
+            return Err(oversized);
+        }
+        match cmd {
+            ClientCommand::InsertAccountUpdate(update) => {
+                update.data.truncate(oversized.limit);
+                update.truncated = true;
+            }
+            ClientCommand::InsertTransaction(tx) => {
+                if let Some(log_messages) = tx.meta.log_messages.as_mut() {
+                    let mut total = 0;
+                    let kept = log_messages
+                        .iter()
+                        .take_while(|message| {
+                            total += message.len();
+                            total <= oversized.limit
+                        })
+                        .count();
+                    log_messages.truncate(kept);
+                }
+                tx.truncated = true;
+            }
+            ClientCommand::InsertBlockMeta(_) => unreachable!("block metas have no size limit"),
+        }
+        Ok(Some(oversized))
+    }
+}
+
+/// Returned by the `log_*` methods for an event with a field over its limit, see [`FieldSizeLimits`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FieldTooLarge {
+    pub field: &'static str,
+    pub size: usize,
+    pub limit: usize,
+}
+
+impl std::fmt::Display for FieldTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "field {} of {} bytes exceeds the {} bytes limit",
+            self.field, self.size, self.limit
+        )
+    }
+}
+
+impl std::error::Error for FieldTooLarge {}
+
+/// Retrieves the latest shard offsets for a specific producer from the `shard_max_offset_mv` materialized view.
+///
+/// This asynchronous function queries the database session to fetch the latest shard offsets associated with
+/// a given `producer_id` from the `shard_max_offset_mv` materialized view. It constructs and executes a SELECT
+/// query to retrieve the shard IDs and corresponding offsets ordered by offset and period.
+///
+/// # Parameters
+/// - `session`: An Arc-wrapped database session (`Arc<Session>`) for executing database queries.
+/// - `producer_id`: The unique identifier (`ProducerId`) of the producer whose shard offsets are being retrieved.
+/// - `num_shards` : number of shard assigned to producer.
+/// - `control_keyspace`: Optional keyspace of the period commit log, the session keyspace if missing.
+///
+/// # Returns
+/// - `Ok(None)`: If no shard offsets are found for the specified producer.
+/// - `Ok(Some(rows))`: If shard offsets are found, returns a vector of tuples containing shard IDs and offsets.
+///                      Each tuple represents a shard's latest offset for the producer.
+/// - `Err`: If an error occurs during database query execution or result parsing, returns an `anyhow::Result`.
+///
+/// The queries assume the clustering order of `solana.cql`, see [`check_recovery_clustering_order`].
+pub(crate) async fn get_max_shard_offsets_for_producer(
+    session: Arc<Session>,
+    producer: &ProducerInfo,
+    control_keyspace: Option<&str>,
+) -> anyhow::Result<Vec<(ShardId, ShardOffset)>> {
+    let shard_ids = (0..producer.num_shards).collect::<Vec<_>>();
+    get_max_shard_offsets_for_shards(
+        session,
+        producer.producer_id,
+        &shard_ids,
+        control_keyspace,
+        DEFAULT_RECOVERY_CONCURRENCY,
+        producer.partitions_per_period(),
+    )
+    .await
+}
+
+/// Estimates the number of events stored in `log` for a producer, without scanning the table.
+///
+/// Offsets are assigned from 0 and never reused within a shard, periods only split them into partitions.
+/// The estimate is therefore the sum over every shard of its max offset plus one, as found by
+/// [`get_max_shard_offsets_for_producer`]: one partition read per shard instead of a `COUNT(*)` over the
+/// whole table.
+///
+/// This counts the offsets assigned by the producer rather than the rows: events removed from `log` since
+/// they were written, by a TTL or a manual delete, are still counted.
+pub async fn count_producer_events(
+    session: Arc<Session>,
+    producer: &ProducerInfo,
+    control_keyspace: Option<&str>,
+) -> anyhow::Result<u64> {
+    let max_offsets =
+        get_max_shard_offsets_for_producer(session, producer, control_keyspace).await?;
+    Ok(max_offsets
+        .into_iter()
+        .map(|(_, max_offset)| (max_offset + 1).max(0) as u64)
+        .sum())
+}
+
+/// Same as [`get_max_shard_offsets_for_producer`] but only for a subset of the producer's shards.
+///
+/// The period commit log is read from `control_keyspace` if any. At most `concurrency` shards are read
+/// at the same time. The partitions of the current period of a shard are read from the last one, until one
+/// holds events.
+pub(crate) async fn get_max_shard_offsets_for_shards(
+    session: Arc<Session>,
+    producer_id: ProducerId,
+    shard_ids: &[ShardId],
+    control_keyspace: Option<&str>,
+    concurrency: usize,
+    partitions_per_period: i16,
+) -> anyhow::Result<Vec<(ShardId, ShardOffset)>> {
+    recover_shard_offsets(
+        session,
+        producer_id,
+        shard_ids,
+        control_keyspace,
+        concurrency,
+        partitions_per_period,
+        |_| (),
+    )
+    .await
+}
+
+/// Same as [`get_max_shard_offsets_for_shards`], calling `on_recovered` with the number of shards recovered so far
+/// each time the offset of a shard is read.
+async fn recover_shard_offsets(
+    session: Arc<Session>,
+    producer_id: ProducerId,
+    shard_ids: &[ShardId],
+    control_keyspace: Option<&str>,
+    concurrency: usize,
+    partitions_per_period: i16,
+    mut on_recovered: impl FnMut(usize),
+) -> anyhow::Result<Vec<(ShardId, ShardOffset)>> {
+    let cql_shard_list = shard_ids
+        .iter()
+        .map(|shard_id| format!("{shard_id}"))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let query_last_period_commit = format!(
+        r###"
+        SELECT
+            shard_id,
+            period
+        FROM producer_period_commit_log
+        where producer_id = ?
+        AND shard_id IN ({cql_shard_list})
+        ORDER BY period DESC
+        PER PARTITION LIMIT 1
+    "###
+    );
+
+    let query_last_period_commit = in_control_keyspace(&query_last_period_commit, control_keyspace);
+    let mut current_period_foreach_shard = session
+        .query(query_last_period_commit, (producer_id,))
+        .await?
+        .rows_typed_or_empty::<(ShardId, ShardPeriod)>()
+        .map(|result| result.map(|(shard_id, period)| (shard_id, period + 1)))
+        .collect::<Result<BTreeMap<_, _>, _>>()?;
+
+    for shard_id in shard_ids {
+        // Put period 0 by default for each missing shard.
+        current_period_foreach_shard.entry(*shard_id).or_insert(0);
+    }
+
+    let query_max_offset_for_shard_period = r###"
+        SELECT
+            offset
+        FROM log
+        WHERE 
+            producer_id = ?
+            AND shard_id = ?
+            and period = ?
+        ORDER BY offset desc
+        PER PARTITION LIMIT 1        
+    "###;
+    let max_offset_for_shard_period_ps = session.prepare(query_max_offset_for_shard_period).await?;
+
+    //let mut js: JoinSet<anyhow::Result<(i16, i64)>> = JoinSet::new();
+    let reads = current_period_foreach_shard
+        .into_iter()
+        .map(|(shard_id, curr_period)| {
+            let ps = max_offset_for_shard_period_ps.clone();
+            let session = Arc::clone(&session);
+            async move {
+                let partitions_per_period = ShardPeriod::from(partitions_per_period);
+                let first_partition = curr_period * partitions_per_period;
+                for partition in (first_partition..first_partition + partitions_per_period).rev() {
+                    let max_offset = session
+                        .execute(&ps, (producer_id, shard_id, partition))
+                        .await?
+                        .maybe_first_row_typed::<(ShardOffset,)>()?;
+                    if let Some((max_offset,)) = max_offset {
+                        return Ok::<_, anyhow::Error>((shard_id, max_offset));
+                    }
+                }
+                // The period holds no row, either none was written since the last period commit or they all
+                // expired with row_ttl. Its offsets may have been used already, so we seek at the end of the
+                // period: the shard resumes at the start of the next one, which commits this period first.
+                Ok((shard_id, ((curr_period + 1) * SHARD_OFFSET_MODULO) - 1))
+            }
+        })
+        .collect::<Vec<_>>();
+    let mut reads = stream::iter(reads)
+        // A burst of reads across hundreds of shards can trip the concurrency limits of the cluster.
+        .buffer_unordered(concurrency.max(1));
+    let mut shard_max_offset_pairs = Vec::with_capacity(shard_ids.len());
+    while let Some(pair) = reads.try_next().await? {
+        shard_max_offset_pairs.push(pair);
+        on_recovered(shard_max_offset_pairs.len());
+    }
+
+    if shard_max_offset_pairs.len() != shard_ids.len() {
+        panic!("missing shard period commit information, make sure the period commit is initialize before computing shard offsets");
+    }
+
+    shard_max_offset_pairs.sort_by_key(|pair| pair.0);
+
+    Ok(shard_max_offset_pairs)
+}
+
+/// Spawns a round-robin dispatcher for sending `ClientCommand` messages to a list of shard daemons.
+///
+/// This function takes ownership of the running shard daemons and returns a new `Sender` that can be used
+/// to dispatch messages in a round-robin fashion to their mailboxes.
+///
+/// The dispatcher asks `router` for the shard of each event, waiting for that shard to have room
+/// in its mailbox. It increments the ScyllaDB batch request lag for monitoring purposes.
+///
+/// The dispatcher also supervises the shards: whenever a shard daemon stops (error or panic), it applies
+/// the configured [`ShardFailurePolicy`].
+///
+/// On [`SinkCommand::Handoff`], the shards are stopped without flushing and their buffered events are
+/// sent back instead.
+///
+/// Shutdown is requested through its own channel, so a full mailbox never delays it: the router stops
+/// accepting events right away, routes the ones already queued and then shuts the shards down.
+///
+/// Events of `priority_receiver` are routed before any event of the regular mailbox.
+///
+/// # Parameters
+/// - `shards`: The running shard daemons, indexed by shard id.
+/// - `router`: Picks the shard of each event.
+/// - `shard_factory`: Used to respawn a dead shard when the failure policy allows it.
+/// - `shard_failure_policy`: What to do when a shard daemon dies.
+/// - `slot_seen_commit_jitter`: Upper bound of the random delay before committing a new max slot seen.
+/// - `commit_slot_seen`: Whether new max slots are written to `producer_slot_seen`, backfill sinks do not.
+/// - `lock_lost`: Raised by the producer keepalive once the lock is lost, the router then stops routing events.
+/// - `flush_on_slot_boundary`: Whether every shard is flushed before routing the first event of a new slot.
+/// - `rebalance_interval`: How often `router` is asked to rebalance the shards, if ever.
+/// - `priority_receiver`: Mailbox of the priority lane, if any.
+/// - `failed_shard`: Set to the id of the shard whose death stopped the router.
+///
+/// # Returns
+/// A `Sender` channel that can be used to send `ClientCommand` messages to the shard mailboxes and
+/// the `Sender` requesting the router to shut down.
+#[allow(clippy::too_many_arguments)]
+fn spawn_round_robin(
+    session: SharedSession,
+    producer_id: ProducerId,
+    mut shards: Vec<ShardHandle>,
+    mut router: BoxedShardRouter,
+    shard_factory: ShardFactory,
+    shard_failure_policy: ShardFailurePolicy,
+    slot_seen_commit_jitter: Duration,
+    commit_slot_seen: bool,
+    mut lock_lost: Option<watch::Receiver<bool>>,
+    flush_on_slot_boundary: bool,
+    rebalance_interval: Option<Duration>,
+    mut priority_receiver: Option<mpsc::Receiver<Submission>>,
+    shared_failed_shard: Arc<OnceLock<ShardId>>,
+) -> (
+    tokio::sync::mpsc::Sender<SinkCommand>,
+    oneshot::Sender<()>,
+    JoinHandle<anyhow::Result<()>>,
+) {
+    let (sender, mut receiver) = tokio::sync::mpsc::channel(DEFAULT_SHARD_MAX_BUFFER_CAPACITY);
+    let (shutdown_sender, mut shutdown_receiver) = oneshot::channel::<()>();
+
+    let h: JoinHandle<anyhow::Result<()>> = tokio::spawn(async move {
+        let insert_slot_ps = shard_factory.statements.insert_producer_slot.clone();
+        let max_slot_statements = shard_factory.statements.max_slot_seen.clone();
+        let buffer_budget = shard_factory.shard_config.buffer_budget.clone();
+        let roll_required = Arc::clone(&shard_factory.shard_config.roll_required);
+        let router_wal = shard_factory.shard_config.router_wal.clone();
+        let ack_sender = shard_factory.shard_config.ack_sender.clone();
+        let max_shard_durability_lag = shard_factory.max_shard_durability_lag;
+        let mut closing = false;
+
+        //session.execute(&insert_slot_ps, (producer_id,)).await?;
+
+        info!("Started round robin router");
+        let mut msg_between_slot = 0;
+        let mut max_slot_seen = -1;
+        let mut time_since_new_max_slot = Instant::now();
+        let mut current_slot = -1;
+        let mut background_commit_max_slot_seen =
+            tokio::spawn(future::ready(Ok::<(), anyhow::Error>(())));
+        let mut failed_shard = None;
+        let mut lock_failure = false;
+        let mut handoff = None;
+        let mut last_rebalance = Instant::now();
+        let buffer_full_policy = shard_factory.buffer_full_policy;
+        let mut overflow_retry = tokio::time::interval(OVERFLOW_RETRY_INTERVAL);
+        overflow_retry.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        'router: loop {
+            if is_lock_lost(&lock_lost) {
+                lock_failure = true;
+                break;
+            }
+            if !closing && roll_required.load(Ordering::Relaxed) {
+                // Route the events already accepted, then shut down as if the sink was closed.
+                warn!("producer {producer_id:?} must be rolled, no longer accepting events");
+                close_router_mailboxes(&mut receiver, &mut priority_receiver);
+                closing = true;
+            }
+            if rebalance_interval.is_some_and(|interval| last_rebalance.elapsed() >= interval) {
+                last_rebalance = Instant::now();
+                if let Some(drained) = router.prepare_rebalance(shards.len()) {
+                    info!("rebalancing producer {producer_id:?}, draining shards {drained:?}");
+                    // Events of the moved keys must be durable before later ones reach their new shard.
+                    let drained_shards = shards
+                        .iter_mut()
+                        .enumerate()
+                        .filter_map(|(i, shard)| drained.contains(&i).then_some(shard))
+                        .collect::<Vec<_>>();
+                    match flush_all(drained_shards, producer_id).await {
+                        Ok(()) => {
+                            router.finish_rebalance();
+                            scylladb_shard_rebalance_inc(producer_id);
+                        }
+                        Err(e) => {
+                            // Moved keys could reach their new shard before their events are durable.
+                            warn!("aborting the rebalance of producer {producer_id:?}: {e:?}");
+                            router.abort_rebalance();
+                        }
+                    }
+                }
+            }
+            let maybe_msg = tokio::select! {
+                biased;
+                _ = wait_lock_lost(&mut lock_lost) => {
+                    lock_failure = true;
+                    break 'router;
+                }
+                // Also fires if the sink is dropped without being shut down.
+                _ = &mut shutdown_receiver, if !closing => {
+                    // Route the events already accepted, the mailbox then yields `None`.
+                    close_router_mailboxes(&mut receiver, &mut priority_receiver);
+                    closing = true;
+                    continue;
+                }
+                Some((msg, submission_id, wal_seq)) = recv_priority(&mut priority_receiver) => {
+                    Some(SinkCommand::Client(msg, submission_id, wal_seq))
+                }
+                _ = overflow_retry.tick(), if shards.iter().any(|shard| !shard.overflow.is_empty()) => {
+                    for shard in shards.iter_mut() {
+                        shard.try_send_overflow(producer_id);
+                    }
+                    continue;
+                }
+                maybe_msg = receiver.recv() => maybe_msg,
+            };
+            let (msg, submission_id, wal_seq) = match maybe_msg {
+                Some(SinkCommand::Client(msg, submission_id, wal_seq)) => {
+                    (msg, submission_id, wal_seq)
+                }
+                Some(SinkCommand::Flush(ack)) => {
+                    // The flush travels behind the events already routed to each shard, so acks
+                    // are only received once every event routed before it is durable.
+                    let shard_acks = request_flush(&mut shards, producer_id).await;
+                    let num_shards = shards.len();
+                    // Wait for the acks in the background to keep routing events meanwhile.
+                    tokio::spawn(async move {
+                        let acked = future::join_all(shard_acks)
+                            .await
+                            .into_iter()
+                            .filter(Result::is_ok)
+                            .count();
+                        if acked == num_shards {
+                            let _ = ack.send(());
+                        } else {
+                            error!("only {acked} out of {num_shards} shards flushed");
+                        }
+                    });
+                    continue;
+                }
+                Some(SinkCommand::Handoff(reply)) => {
+                    handoff = Some(reply);
+                    break;
+                }
+                Some(SinkCommand::Shutdown) | None => {
+                    if !closing {
+                        warn!("round robin router's mailbox closed unexpectly.");
+                    }
+                    break;
+                }
+            };
+            let i = router.select(&msg, shards.len()) % shards.len();
+            if buffer_budget.is_exceeded() {
+                // Stop accepting events until the shards have written what they hold.
+                warn!("shards buffer budget exceeded, flushing every shard before routing more events");
+                future::join_all(request_flush(&mut shards, producer_id).await).await;
+            }
+            let slot = msg.slot();
+            if flush_on_slot_boundary && current_slot < slot {
+                if current_slot >= 0 {
+                    // Every event of the previous slots must be durable before this slot is written.
+                    flush_all(&mut shards, producer_id).await.map_err(|e| {
+                        e.context(format!(
+                            "failed to flush slot {current_slot} before slot {slot}"
+                        ))
+                    })?;
+                }
+                current_slot = slot;
+            }
+            if commit_slot_seen && max_slot_seen < slot {
+                max_slot_seen = slot;
+                let time_elapsed_between_last_max_slot = time_since_new_max_slot.elapsed();
+                // We only commit every 3 slot number
+
+                let t = Instant::now();
+                background_commit_max_slot_seen.await??;
+
+                let session = session.current();
+                let insert_slot_ps = insert_slot_ps.clone();
+                let max_slot_statements = max_slot_statements.clone();
+                let jitter = if slot_seen_commit_jitter.is_zero() {
+                    Duration::ZERO
+                } else {
+                    rand::thread_rng().gen_range(Duration::ZERO..slot_seen_commit_jitter)
+                };
+                background_commit_max_slot_seen = tokio::spawn(async move {
+                    // Avoid every producer of the fleet writing at the same slot boundary.
+                    tokio::time::sleep(jitter).await;
+                    if let Some(statements) = max_slot_statements.as_ref() {
+                        let max_slot = get_max_slot_seen(&session, statements, producer_id).await?;
+                        if max_slot.is_some_and(|max_slot| max_slot >= slot) {
+                            warn!("slot {slot} is not above the high-water mark of producer {producer_id:?}, skipping its commit");
+                            return Ok(());
+                        }
+                    }
+                    insert_slot_ps
+                        .execute(&session, (producer_id, slot))
+                        .await?;
+                    // Only raised once the slot is committed, a failed commit is not skipped by the next one.
+                    if let Some(statements) = max_slot_statements.as_ref() {
+                        raise_max_slot_seen(&session, statements, producer_id, slot).await?;
+                    }
+
+                    let time_to_commit_slot = t.elapsed();
+                    info!(
+                        "New slot: {} after {time_elapsed_between_last_max_slot:?}, events in between: {}, max_slot_approx committed in {time_to_commit_slot:?}",
+                        slot, msg_between_slot
+                    );
+                    Ok(())
+                });
+                time_since_new_max_slot = Instant::now();
+                msg_between_slot = 0;
+            }
+            msg_between_slot += 1;
+            loop {
+                let shard = &mut shards[i];
+                if !shard.is_finished() {
+                    let t = Instant::now();
+                    shard
+                        .wait_durability_lag(max_shard_durability_lag, producer_id)
+                        .await;
+                    if buffer_full_policy != BufferFullPolicy::Block {
+                        shard.try_send_overflow(producer_id);
+                        let saturated = !shard.overflow.is_empty() || shard.mailbox.capacity() == 0;
+                        // A closed mailbox is left to the reservation below, which reports the dead shard.
+                        if saturated && !shard.mailbox.is_closed() {
+                            let dropped = shard.hold(
+                                (msg, submission_id, wal_seq),
+                                buffer_full_policy == BufferFullPolicy::DropOldest,
+                                producer_id,
+                            );
+                            if let Some(dropped) = dropped {
+                                drop_submission(dropped, ack_sender.as_ref(), router_wal.as_ref());
+                            }
+                            scylladb_router_cursor_set(producer_id, i as ShardId);
+                            break;
+                        }
+                    }
+                    let reserved = shard.mailbox.reserve().await;
+                    scylladb_router_reserve_wait_observe(producer_id, t.elapsed());
+                    // The reservation may wait for long enough that the lock was lost meanwhile.
+                    if is_lock_lost(&lock_lost) {
+                        lock_failure = true;
+                        break 'router;
+                    }
+                    if let Ok(permit) = reserved {
+                        permit.send(SinkCommand::Client(msg, submission_id, wal_seq));
+                        scylladb_batch_request_lag_inc(producer_id);
+                        scylladb_router_cursor_set(producer_id, i as ShardId);
+                        break;
+                    }
+                }
+
+                // A shard stops once the lock is lost, it must not be replaced then.
+                if is_lock_lost(&lock_lost) {
+                    lock_failure = true;
+                    break 'router;
+                }
+                // The shard daemon is gone, find out why and apply the failure policy.
+                let shard_id = i as ShardId;
+                let replaced = replace_dead_shard(
+                    &mut shards[i],
+                    shard_id,
+                    shard_failure_policy,
+                    || shard_factory.respawn(shard_id),
+                    &shared_failed_shard,
+                )
+                .await;
+                if !replaced {
+                    failed_shard = Some(shard_id);
+                    break 'router;
+                }
+            }
+        }
+        // The events held for the shards are routed before they stop, unless the lock may be held by another
+        // instance by now.
+        if !lock_failure {
+            for shard in shards.iter_mut() {
+                shard.send_overflow(producer_id).await;
+            }
+        }
+        if let Some(reply) = handoff {
+            let mut pending = Vec::new();
+            for (i, shard) in shards.into_iter().enumerate() {
+                pending.extend(shard.handoff(i as ShardId).await);
+            }
+            if reply.send(pending).is_err() {
+                error!("handoff requester went away, pending events were dropped");
+            }
+        } else {
+            // Send shutdown to all shards
+            for (i, shard) in shards.into_iter().enumerate() {
+                shard.shutdown(i as ShardId).await;
+            }
+        }
+
+        warn!("End of round robin router");
+        if let Some(shard_id) = failed_shard {
+            anyhow::bail!("shard {shard_id} died, stopping the router");
+        }
+        if lock_failure {
+            anyhow::bail!("lost the lock of producer {producer_id:?}, stopping the router");
+        }
+        Ok(())
+    });
+    (sender, shutdown_sender, h)
+}
+
+/// Applies `policy` to the dead shard `shard_id`, returns false if it is not replaced.
+///
+/// A replacement built by `respawn` takes over the events held for the dead shard. Otherwise `failed_shard` is
+/// set, the router must stop.
+async fn replace_dead_shard<F>(
+    shard: &mut ShardHandle,
+    shard_id: ShardId,
+    policy: ShardFailurePolicy,
+    respawn: impl FnOnce() -> F,
+    failed_shard: &OnceLock<ShardId>,
+) -> bool
+where
+    F: Future<Output = anyhow::Result<ShardHandle>>,
+{
+    let new_shard = match policy {
+        ShardFailurePolicy::FailFast => None,
+        ShardFailurePolicy::Restart => match respawn().await {
+            Ok(new_shard) => Some(new_shard),
+            Err(e) => {
+                error!("failed to restart shard {shard_id}: {e:?}");
+                None
+            }
+        },
+    };
+    let Some(mut new_shard) = new_shard else {
+        let _ = failed_shard.set(shard_id);
+        return false;
+    };
+    new_shard.overflow = std::mem::take(&mut shard.overflow);
+    std::mem::replace(shard, new_shard).join(shard_id).await;
+    true
+}
+
+/// Waits for the next tick of `interval`, never yields one if there is no interval.
+async fn tick(interval: &mut Option<tokio::time::Interval>) -> Option<Instant> {
+    match interval {
+        Some(interval) => Some(interval.tick().await),
+        None => None,
+    }
+}
+
+/// Receives the next priority event, never yields one if there is no priority lane.
+async fn recv_priority(
+    priority_receiver: &mut Option<mpsc::Receiver<Submission>>,
+) -> Option<Submission> {
+    match priority_receiver {
+        Some(priority_receiver) => priority_receiver.recv().await,
+        None => None,
+    }
+}
+
+/// Returns whether the producer lock is known to be lost, see [`spawn_producer_keepalive`].
+fn is_lock_lost(lock_lost: &Option<watch::Receiver<bool>>) -> bool {
+    lock_lost.as_ref().is_some_and(|lost| *lost.borrow())
+}
+
+/// Resolves once the producer lock is lost, never if the lock is not watched or the keepalive stopped holding it.
+async fn wait_lock_lost(lock_lost: &mut Option<watch::Receiver<bool>>) {
+    if let Some(lost) = lock_lost {
+        if lost.wait_for(|lost| *lost).await.is_ok() {
+            return;
+        }
+    }
+    future::pending().await
+}
+
+/// Waits until `deadline`, never resolves if there is none.
+async fn sleep_until(deadline: Option<Instant>) -> Option<()> {
+    match deadline {
+        Some(deadline) => {
+            tokio::time::sleep_until(deadline).await;
+            Some(())
+        }
+        None => None,
+    }
+}
+
+/// Stops accepting events in the router, the events already queued can still be received.
+fn close_router_mailboxes(
+    receiver: &mut mpsc::Receiver<SinkCommand>,
+    priority_receiver: &mut Option<mpsc::Receiver<Submission>>,
+) {
+    receiver.close();
+    if let Some(priority_receiver) = priority_receiver {
+        priority_receiver.close();
+    }
+}
+
+/// Returns the high-water mark of a producer, `None` until its first slot is committed.
+async fn get_max_slot_seen(
+    session: &Session,
+    statements: &MaxSlotStatements,
+    producer_id: ProducerId,
+) -> anyhow::Result<Option<Slot>> {
+    let max_slot = session
+        .execute(&statements.get, (producer_id,))
+        .await?
+        .maybe_first_row_typed::<(Option<Slot>,)>()?
+        .and_then(|(max_slot,)| max_slot);
+    Ok(max_slot)
+}
+
+/// Moves the high-water mark of a producer up to `slot`, returns false if it already is at `slot` or above.
+async fn raise_max_slot_seen(
+    session: &Session,
+    statements: &MaxSlotStatements,
+    producer_id: ProducerId,
+    slot: Slot,
+) -> anyhow::Result<bool> {
+    let LwtSuccess(raised) = session
+        .execute(&statements.raise, (slot, producer_id, slot))
+        .await?
+        .single_row_typed::<LwtSuccess>()?;
+    if raised {
+        return Ok(true);
+    }
+    let LwtSuccess(initialized) = session
+        .execute(&statements.init, (slot, producer_id))
+        .await?
+        .single_row_typed::<LwtSuccess>()?;
+    Ok(initialized)
+}
+
+/// Lists what is wrong with a keyspace replication map, as found in `system_schema.keyspaces`.
+///
+/// `SimpleStrategy` is rejected as soon as the cluster spans several datacenters since it ignores topology,
+/// otherwise the replication factor of every datacenter must be at least `min_replication_factor`.
+fn keyspace_replication_issues(
+    replication: &HashMap<String, String>,
+    num_datacenters: usize,
+    min_replication_factor: usize,
+) -> Vec<String> {
+    let mut issues = Vec::new();
+    let class = replication
+        .get("class")
+        .map(String::as_str)
+        .unwrap_or_default();
+    if class.ends_with("SimpleStrategy") && num_datacenters > 1 {
+        issues.push(format!(
+            "SimpleStrategy is used in a cluster with {num_datacenters} datacenters"
+        ));
+    }
+    for (key, value) in replication {
+        if key == "class" {
+            continue;
+        }
+        match value.parse::<usize>() {
+            Ok(rf) if rf < min_replication_factor => issues.push(format!(
+                "replication factor of {key} is {rf}, expected at least {min_replication_factor}"
+            )),
+            Ok(_) => (),
+            Err(_) => issues.push(format!("unexpected replication factor for {key}: {value}")),
+        }
+    }
+    issues
+}
+
+/// Verifies the replication settings of `keyspace`, warns on a misconfiguration or fails if the check is strict.
+async fn check_keyspace_replication(
+    session: Arc<Session>,
+    keyspace: &str,
+    check: &ReplicationCheck,
+) -> anyhow::Result<()> {
+    let (replication,) = session
+        .query(GET_KEYSPACE_REPLICATION, (keyspace,))
+        .await?
+        .maybe_first_row_typed::<(HashMap<String, String>,)>()?
+        .ok_or(anyhow::anyhow!("keyspace {keyspace} does not exist"))?;
+    let num_datacenters = session.get_cluster_data().get_datacenters_info().len();
+    let issues =
+        keyspace_replication_issues(&replication, num_datacenters, check.min_replication_factor);
+    if issues.is_empty() {
+        info!("keyspace {keyspace} replication settings: {replication:?}");
+        return Ok(());
+    }
+    for issue in issues.iter() {
+        warn!("keyspace {keyspace} replication: {issue}");
+    }
+    if check.strict {
+        anyhow::bail!(
+            "keyspace {keyspace} replication check failed: {}",
+            issues.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Verifies the tables read by the offset recovery are clustered in descending order.
+///
+/// The recovery relies on `PER PARTITION LIMIT 1` returning the latest period and offset, a table created with
+/// another clustering order would silently make the sink resume from a wrong offset.
+async fn check_recovery_clustering_order(
+    session: Arc<Session>,
+    keyspace: &str,
+    control_keyspace: Option<&str>,
+) -> anyhow::Result<()> {
+    for (table, column, in_control) in RECOVERY_CLUSTERING_ORDER {
+        let table_keyspace = if in_control {
+            control_keyspace.unwrap_or(keyspace)
+        } else {
+            keyspace
+        };
+        let clustering_order = session
+            .query(GET_CLUSTERING_ORDER, (table_keyspace, table, column))
+            .await?
+            .maybe_first_row_typed::<(String,)>()?
+            .map(|(clustering_order,)| clustering_order)
+            .ok_or(anyhow::anyhow!(
+                "column {column} of {table_keyspace}.{table} does not exist"
+            ))?;
+        if !clustering_order.eq_ignore_ascii_case("desc") {
+            anyhow::bail!(
+                "{table_keyspace}.{table} must be created with clustering order by ({column} desc) as in solana.cql, found {clustering_order}"
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Deletes every event, period commit, slot seen and heartbeat of a producer, see
+/// [`ScyllaSinkConfig::reset_on_start`]. Only its registration and its lock are kept.
+///
+/// `last_offsets` holds the last offset of each shard, every partition of `log` up to it is deleted. The log
+/// goes first so a reset interrupted midway still finds the same last offsets when run again. The partitions of
+/// `log_by_slot` are found from the slots of each partition of `log`, and deleted right before it.
+async fn reset_producer(
+    session: &Session,
+    producer_id: ProducerId,
+    last_offsets: &[(ShardId, ShardOffset)],
+    control_keyspace: Option<&str>,
+    partitions_per_period: i16,
+    slot_bucket_size: Option<Slot>,
+) -> anyhow::Result<()> {
+    warn!("Resetting producer {producer_id:?}, deleting all of its events");
+    let delete_log_partition = session.prepare(DELETE_LOG_PARTITION).await?;
+    // log_by_slot only exists in keyspaces of producers writing it.
+    let log_by_slot = match slot_bucket_size {
+        Some(slot_bucket_size) => Some((
+            slot_bucket_size,
+            session.prepare(GET_LOG_PARTITION_SLOT_RANGE).await?,
+            session.prepare(DELETE_LOG_BY_SLOT_PARTITION).await?,
+        )),
+        None => None,
+    };
+    for (shard_id, last_offset) in last_offsets.iter().copied() {
+        for partition in 0..=log_partition(last_offset.max(0), partitions_per_period) {
+            if let Some((slot_bucket_size, get_slot_range, delete_log_by_slot_partition)) =
+                log_by_slot.as_ref()
+            {
+                let slot_range = session
+                    .execute(get_slot_range, (shard_id, partition, producer_id))
+                    .await?
+                    .single_row_typed::<(Option<Slot>, Option<Slot>)>()?;
+                if let (Some(min_slot), Some(max_slot)) = slot_range {
+                    let first_bucket = slot_bucket(min_slot, *slot_bucket_size);
+                    for bucket in first_bucket..=slot_bucket(max_slot, *slot_bucket_size) {
+                        session
+                            .execute(
+                                delete_log_by_slot_partition,
+                                (producer_id, bucket, shard_id),
+                            )
+                            .await?;
+                    }
+                }
+            }
+            session
+                .execute(&delete_log_partition, (shard_id, partition, producer_id))
+                .await?;
+        }
+    }
+    let delete_shard_period_commits = session
+        .prepare(in_control_keyspace(
+            DELETE_SHARD_PERIOD_COMMITS,
+            control_keyspace,
+        ))
+        .await?;
+    for (shard_id, _) in last_offsets {
+        session
+            .execute(&delete_shard_period_commits, (producer_id, shard_id))
+            .await?;
+    }
+    for delete_producer_metadata in DELETE_PRODUCER_METADATA {
+        session
+            .query(
+                in_control_keyspace(delete_producer_metadata, control_keyspace),
+                (producer_id,),
+            )
+            .await?;
+    }
+    warn!("Producer {producer_id:?} reset, every shard starts at offset 0");
+    Ok(())
+}
+
+/// Partition and offset of the sentinel probing the partition of `log` a shard resuming at `next_offset` writes.
+///
+/// The sentinel uses the offset right before the partition: no event of the partition can use it, and the offset
+/// recovery resumes right after it, in the same partition, if a crash leaves it behind.
+const fn probe_sentinel(
+    next_offset: ShardOffset,
+    partitions_per_period: i16,
+) -> (ShardPeriod, ShardOffset) {
+    let period = log_partition(next_offset, partitions_per_period);
+    (
+        period,
+        period * (SHARD_OFFSET_MODULO / partitions_per_period as i64) - 1,
+    )
+}
+
+/// Writes a sentinel event in the partition of `log` a shard resumes in, reads it back and deletes it, see
+/// [`probe_sentinel`].
+///
+/// A sentinel left behind by a crash is readable by consumers until [`delete_probe_sentinels`] runs on the next
+/// start. Fails if the sentinel is gone right after being written, which happens with a misconfigured TTL.
+async fn probe_shard_partition(
+    session: &Session,
+    statements: &SinkStatements,
+    producer_id: ProducerId,
+    shard_id: ShardId,
+    next_offset: ShardOffset,
+    partitions_per_period: i16,
+) -> anyhow::Result<()> {
+    let (period, offset) = probe_sentinel(next_offset, partitions_per_period);
+    let mut sentinel =
+        AccountUpdate::zero_account().as_blockchain_event(shard_id, producer_id, offset);
+    sentinel.period = period;
+    statements
+        .insert_blockchain_event
+        .execute(
+            session,
+            BoundColumns(&sentinel, None, &statements.log_columns),
+        )
+        .await?;
+
+    let found = session
+        .query(GET_PROBE_EVENT, (shard_id, period, producer_id, offset))
+        .await?
+        .maybe_first_row_typed::<(ShardOffset,)>()?
+        .is_some();
+    if !found {
+        anyhow::bail!(
+            "probe event of shard {shard_id} vanished from period {period} right after being written, check the TTL and compaction settings of the log table"
+        );
+    }
+    session
+        .query(DELETE_PROBE_EVENT, (shard_id, period, producer_id, offset))
+        .await?;
+    Ok(())
+}
+
+/// Probes the resume partition of every shard, at most `concurrency` at a time, see [`probe_shard_partition`].
+async fn probe_shard_partitions(
+    session: &Session,
+    statements: &SinkStatements,
+    producer_id: ProducerId,
+    start_offsets: &[(ShardId, ShardOffset)],
+    concurrency: usize,
+    partitions_per_period: i16,
+    mut on_probed: impl FnMut(usize),
+) -> anyhow::Result<()> {
+    let mut probes = stream::iter(start_offsets.iter().map(|(shard_id, next_offset)| {
+        probe_shard_partition(
+            session,
+            statements,
+            producer_id,
+            *shard_id,
+            *next_offset,
+            partitions_per_period,
+        )
+    }))
+    .buffer_unordered(concurrency.max(1));
+    let mut probed = 0;
+    while probes.try_next().await?.is_some() {
+        probed += 1;
+        on_probed(probed);
+    }
+    info!(
+        "probed the resume partition of {} shards",
+        start_offsets.len()
+    );
+    Ok(())
+}
+
+/// Deletes the sentinel a probe interrupted by a crash may have left in the resume partition of every shard, at
+/// most `concurrency` shards at a time, see [`probe_shard_partition`].
+async fn delete_probe_sentinels(
+    session: &Session,
+    producer_id: ProducerId,
+    start_offsets: &[(ShardId, ShardOffset)],
+    concurrency: usize,
+    partitions_per_period: i16,
+) -> anyhow::Result<()> {
+    let delete_probe_event = session.prepare(DELETE_PROBE_EVENT).await?;
+    stream::iter(start_offsets.iter().map(|(shard_id, next_offset)| {
+        let (period, offset) = probe_sentinel(*next_offset, partitions_per_period);
+        session.execute(
+            &delete_probe_event,
+            (*shard_id, period, producer_id, offset),
+        )
+    }))
+    .buffer_unordered(concurrency.max(1))
+    .try_collect::<Vec<_>>()
+    .await?;
+    Ok(())
+}
+
+/// Shards checked by [`list_orphaned_shards`] for a producer with `num_shards` shards, one query per chunk.
+fn orphaned_shard_candidates(num_shards: ShardId) -> Vec<Vec<ShardId>> {
+    (num_shards..num_shards.saturating_add(ORPHANED_SHARD_SCAN_LEN))
+        .collect::<Vec<_>>()
+        .chunks(ORPHANED_SHARD_SCAN_CHUNK)
+        .map(<[ShardId]>::to_vec)
+        .collect()
+}
+
+/// Lists the shards of a producer at or above `num_shards` that committed at least one period.
+///
+/// These shards were written by a run with more shards and their events are no longer reachable by the sink.
+/// A shard that never completed a period is not detected, nor one [`ORPHANED_SHARD_SCAN_LEN`] or more shards
+/// above `num_shards`.
+async fn list_orphaned_shards(
+    session: &Session,
+    producer_id: ProducerId,
+    num_shards: ShardId,
+    control_keyspace: Option<&str>,
+) -> anyhow::Result<Vec<ShardId>> {
+    let query = in_control_keyspace(LIST_SHARDS_WITH_COMMITTED_PERIODS, control_keyspace);
+    let mut orphaned_shards = Vec::new();
+    for shard_ids in orphaned_shard_candidates(num_shards) {
+        let rows = session
+            .query(query.as_str(), (producer_id, shard_ids))
+            .await?
+            .rows_typed_or_empty::<(ProducerId, ShardId)>();
+        for row in rows {
+            let (_, shard_id) = row?;
+            orphaned_shards.push(shard_id);
+        }
+    }
+    orphaned_shards.sort_unstable();
+    Ok(orphaned_shards)
+}
+
+/// Returns the producer registered under `producer_id` if any, see [`list_producers`] to list them all.
+pub async fn get_producer_info_by_id(
+    session: Arc<Session>,
+    producer_id: ProducerId,
+    control_keyspace: Option<&str>,
+) -> anyhow::Result<Option<ProducerInfo>> {
+    session
+        .query(
+            in_control_keyspace(GET_PRODUCER_INFO_BY_ID, control_keyspace),
+            (producer_id,),
+        )
+        .await?
+        .maybe_first_row_typed::<ProducerInfo>()
+        .map_err(anyhow::Error::new)
+}
+
+/// Registers `producer_id` in `producer_info` with `num_shards` shards, so a [`ScyllaSink`] can be created for it.
+///
+/// Each period of the producer is split into `partitions_per_period` partitions of `log`, see [`log_partition`].
+/// More partitions keep the partitions of a high-throughput producer small, at the cost of readers having to read
+/// every partition of a period. It must divide [`SHARD_OFFSET_MODULO`].
+///
+/// Registering an already registered producer is a no-op as long as it has the same number of shards and
+/// partitions per period, neither can be changed. Returns true if the producer has been registered by this call.
+pub async fn register_producer(
+    session: Arc<Session>,
+    producer_id: ProducerId,
+    num_shards: ShardId,
+    partitions_per_period: i16,
+    control_keyspace: Option<&str>,
+) -> anyhow::Result<bool> {
+    anyhow::ensure!(num_shards > 0, "a producer needs at least one shard");
+    anyhow::ensure!(
+        partitions_per_period > 0 && SHARD_OFFSET_MODULO % partitions_per_period as i64 == 0,
+        "partitions per period must divide {SHARD_OFFSET_MODULO}, got {partitions_per_period}"
+    );
+    let qr = session
+        .query(
+            in_control_keyspace(REGISTER_PRODUCER, control_keyspace),
+            (producer_id, num_shards, partitions_per_period),
+        )
+        .await?;
+    let LwtSuccess(applied) = qr.single_row_typed::<LwtSuccess>()?;
+    if applied {
+        info!(
+            "registered producer {producer_id:?} with {num_shards} shards and {partitions_per_period} partitions per period"
+        );
+        return Ok(true);
+    }
+    let producer_info = get_producer_info_by_id(session, producer_id, control_keyspace)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("producer {producer_id:?} vanished while registering"))?;
+    anyhow::ensure!(
+        producer_info.num_shards == num_shards,
+        "producer {producer_id:?} is already registered with {} shards, not {num_shards}",
+        producer_info.num_shards
+    );
+    anyhow::ensure!(
+        producer_info.partitions_per_period() == partitions_per_period,
+        "producer {producer_id:?} is already registered with {} partitions per period, not {partitions_per_period}",
+        producer_info.partitions_per_period()
+    );
+    Ok(false)
+}
+
+/// Returns every producer registered in `producer_info`, ordered by producer id.
+pub async fn list_producers(
+    session: Arc<Session>,
+    control_keyspace: Option<&str>,
+) -> anyhow::Result<Vec<ProducerInfo>> {
+    let mut producers = session
+        .query(in_control_keyspace(LIST_PRODUCERS, control_keyspace), &[])
+        .await?
+        .rows_typed_or_empty::<ProducerInfo>()
+        .collect::<Result<Vec<_>, _>>()?;
+    producers.sort_by_key(|producer| producer.producer_id);
+    Ok(producers)
+}
+
+/// Returns when the producer last wrote its heartbeat, `None` if it never did.
+///
+/// Only producers running with a [`ScyllaSinkConfig::heartbeat_interval`] write one. A heartbeat older than a few
+/// intervals means the producer stopped or can not reach the cluster, even if it has no events to write.
+pub async fn get_producer_heartbeat(
+    session: Arc<Session>,
+    producer_id: ProducerId,
+    control_keyspace: Option<&str>,
+) -> anyhow::Result<Option<DateTime<Utc>>> {
+    let row = session
+        .query(
+            in_control_keyspace(GET_PRODUCER_HEARTBEAT, control_keyspace),
+            (producer_id,),
+        )
+        .await?
+        .maybe_first_row_typed::<(Option<DateTime<Utc>>,)>()?;
+    Ok(row.and_then(|(heartbeat_at,)| heartbeat_at))
+}
+
+/// Instance holding the lock of a producer, as recorded in `producer_lock`.
+#[derive(FromRow, Debug, Clone, PartialEq, Eq)]
+pub struct LockHolder {
+    pub lock_id: String,
+    pub ifname: Option<String>,
+    pub ipv4: Option<String>,
+    pub created_at: Option<DateTime<Utc>>,
+    /// Write time of `lock_id` in microseconds since the epoch, when the lock was acquired or last rotated.
+    pub lock_written_at: Option<i64>,
+}
+
+impl LockHolder {
+    /// Returns true if the lock was neither acquired nor rotated within `expiry` before `now_micros`.
+    fn is_expired(&self, expiry: Duration, now_micros: i64) -> bool {
+        self.lock_written_at
+            .is_some_and(|written_at| now_micros - written_at >= expiry.as_micros() as i64)
+    }
+}
+
+/// Returns the holder of the lock of `producer_id`, `None` if the lock is free.
+///
+/// Only reads `producer_lock`, the lock is left untouched.
+pub async fn check_producer_lock(
+    session: Arc<Session>,
+    producer_id: ProducerId,
+    control_keyspace: Option<&str>,
+) -> anyhow::Result<Option<LockHolder>> {
+    session
+        .query(
+            in_control_keyspace(GET_PRODUCER_LOCK, control_keyspace),
+            (producer_id,),
+        )
+        .await?
+        .maybe_first_row_typed::<LockHolder>()
+        .map_err(anyhow::Error::new)
+}
+
+struct ProducerLock {
+    session: SharedSession,
+    lock_id: String,
+    producer_id: ProducerId,
+    control_keyspace: Option<String>,
+}
+
+impl ProducerLock {
+    /// Drops the lock if it is still held by this instance.
+    ///
+    /// A lock already gone or taken over by another instance is only reported with a warning.
+    async fn release(self) -> anyhow::Result<()> {
+        let ProducerLock {
+            session,
+            lock_id,
+            producer_id,
+            control_keyspace,
+        } = self;
+        scylladb_producer_lock_held_set(producer_id, false);
+        let LwtSuccess(released) = session
+            .current()
+            .query(
+                in_control_keyspace(DROP_PRODUCER_LOCK, control_keyspace.as_deref()),
+                (producer_id, &lock_id),
+            )
+            .await
+            .map_err(anyhow::Error::new)
+            .and_then(|qr| {
+                qr.single_row_typed::<LwtSuccess>()
+                    .map_err(anyhow::Error::new)
+            })
+            .map_err(|e| {
+                e.context(format!(
+                    "failed to release lock {lock_id} of producer {producer_id:?}"
+                ))
+            })?;
+        if !released {
+            warn!("lock {lock_id} of producer {producer_id:?} was already released or is held by another instance");
+        }
+        Ok(())
+    }
+
+    /// Swaps the `lock_id` of the lock for a new one with a single lightweight transaction, the lock stays
+    /// held throughout.
+    ///
+    /// A failed rotation means the lock is lost: either another instance took it over or the outcome of the
+    /// transaction is unknown. The sink must abort rather than keep writing without the lock.
+    async fn rotate(&mut self) -> anyhow::Result<()> {
+        let new_lock_id = Uuid::new_v4().to_string();
+        let LwtSuccess(rotated) = self
+            .session
+            .current()
+            .query(
+                in_control_keyspace(ROTATE_PRODUCER_LOCK, self.control_keyspace.as_deref()),
+                (&new_lock_id, self.producer_id, &self.lock_id),
+            )
+            .await?
+            .single_row_typed::<LwtSuccess>()?;
+        if !rotated {
+            anyhow::bail!(
+                "lock {} of producer {:?} was lost, it is no longer held by this instance",
+                self.lock_id,
+                self.producer_id
+            );
+        }
+        info!(
+            "rotated lock {} of producer {:?} to {new_lock_id}",
+            self.lock_id, self.producer_id
+        );
+        self.lock_id = new_lock_id;
+        Ok(())
+    }
+}
+
+/// Session shared by every component of the sink, each one reads the current session at every use so
+/// [`ScyllaSink::swap_session`] reaches all of them at once.
+pub(crate) struct SharedSession<S = Session>(watch::Receiver<Arc<S>>);
+
+// Not derived, the derive would require the session itself to be `Clone`.
+impl<S> Clone for SharedSession<S> {
+    fn clone(&self) -> Self {
+        SharedSession(self.0.clone())
+    }
+}
+
+impl<S> SharedSession<S> {
+    pub(crate) fn new(session: Arc<S>) -> (watch::Sender<Arc<S>>, Self) {
+        let (sender, receiver) = watch::channel(session);
+        (sender, SharedSession(receiver))
+    }
+
+    /// Returns the session the next query goes through, a query in flight completes on the session it started on.
+    pub(crate) fn current(&self) -> Arc<S> {
+        Arc::clone(&self.0.borrow())
+    }
+}
+
+/// Producer lock shared by the sink and the keepalive rotating it, `None` once released.
+type SharedProducerLock = Arc<tokio::sync::Mutex<Option<ProducerLock>>>;
+
+/// Rotates the producer lock, a no-op once it is released.
+type RotateLock = Box<dyn FnMut() -> future::BoxFuture<'static, anyhow::Result<()>> + Send>;
+
+/// Writes the heartbeat of the producer, failures are only logged.
+type WriteHeartbeat = Box<dyn Fn() -> future::BoxFuture<'static, ()> + Send>;
+
+fn heartbeat_writer(
+    session: SharedSession,
+    insert_heartbeat: GuardedStatement,
+    producer_id: ProducerId,
+) -> WriteHeartbeat {
+    Box::new(move || -> future::BoxFuture<'static, ()> {
+        let session = session.current();
+        let insert_heartbeat = insert_heartbeat.clone();
+        Box::pin(async move {
+            if let Err(e) = insert_heartbeat.execute(&session, (producer_id,)).await {
+                warn!("failed to write the heartbeat of producer {producer_id:?}: {e:?}");
+            }
+        })
+    })
+}
+
+/// Rotation of the producer lock by the keepalive, see [`ScyllaSinkConfig::lock_rotation_interval`].
+struct LockRotation {
+    rotate: RotateLock,
+    interval: Duration,
+    /// The lock is lost once the last successful rotation is older than the ttl, if any.
+    ttl: Option<Duration>,
+}
+
+impl LockRotation {
+    fn new<F>(
+        mut rotate: impl FnMut() -> F + Send + 'static,
+        interval: Duration,
+        ttl: Option<Duration>,
+    ) -> Self
+    where
+        F: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        LockRotation {
+            rotate: Box::new(move || -> future::BoxFuture<'static, anyhow::Result<()>> {
+                Box::pin(rotate())
+            }),
+            interval,
+            ttl,
+        }
+    }
+
+    /// Rotates `lock` at every interval.
+    fn of_lock(lock: SharedProducerLock, interval: Duration, ttl: Option<Duration>) -> Self {
+        let rotate = move || {
+            let lock = Arc::clone(&lock);
+            async move {
+                match lock.lock().await.as_mut() {
+                    Some(lock) => lock.rotate().await,
+                    None => Ok(()),
+                }
+            }
+        };
+        LockRotation::new(rotate, interval, ttl)
+    }
+}
+
+/// What the producer keepalive does, see [`spawn_producer_keepalive`].
+struct ProducerKeepalive {
+    producer_id: ProducerId,
+    heartbeat: Option<(WriteHeartbeat, Duration)>,
+    lock_rotation: Option<LockRotation>,
+}
+
+/// Stops the producer keepalive.
+struct KeepaliveHandle {
+    stop: oneshot::Sender<()>,
+    handle: JoinHandle<anyhow::Result<()>>,
+}
+
+impl KeepaliveHandle {
+    /// Stops the keepalive, the lock is no longer rotated once this returns.
+    async fn stop(self) {
+        let _ = self.stop.send(());
+        match self.handle.await {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => error!("Producer keepalive error: {e:?}"),
+            Err(e) => error!("Producer keepalive terminated abruptly: {e:?}"),
+        }
+    }
+}
+
+/// Spawns the task writing the heartbeat of the producer and rotating its lock, whether events flow or not.
+///
+/// The task runs apart from the router, so a router waiting on a full shard mailbox or a slow flush never delays
+/// a rotation. A rotation still in progress when the lock expires is abandoned. Once a rotation fails or the
+/// last successful one is older than the ttl, `lock_lost` is raised and the task stops: the router stops routing
+/// and the shards stop writing, another instance may hold the lock by then.
+fn spawn_producer_keepalive(
+    keepalive: ProducerKeepalive,
+    lock_lost: watch::Sender<bool>,
+) -> KeepaliveHandle {
+    let (stop, mut stop_receiver) = oneshot::channel::<()>();
+    let handle = tokio::spawn(async move {
+        let ProducerKeepalive {
+            producer_id,
+            heartbeat,
+            lock_rotation,
+        } = keepalive;
+        let (write_heartbeat, mut heartbeat_interval) = match heartbeat {
+            Some((write_heartbeat, period)) => {
+                let mut interval = tokio::time::interval(period);
+                interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                (Some(write_heartbeat), Some(interval))
+            }
+            None => (None, None),
+        };
+        let mut heartbeat_write: Option<JoinHandle<()>> = None;
+        let (mut rotate, ttl, mut rotation_interval) = match lock_rotation {
+            Some(LockRotation {
+                rotate,
+                interval: period,
+                ttl,
+            }) => {
+                // The lock has just been acquired, the first rotation is due one period from now.
+                let mut interval = tokio::time::interval_at(Instant::now() + period, period);
+                interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                (Some(rotate), ttl, Some(interval))
+            }
+            None => (None, None, None),
+        };
+        let mut last_rotation = Instant::now();
+        let result = loop {
+            let expires_at = ttl.map(|ttl| last_rotation + ttl);
+            let expired = move || {
+                anyhow::anyhow!(
+                    "lock of producer {producer_id:?} expired, last rotated {:?} ago with a ttl of {:?}",
+                    last_rotation.elapsed(),
+                    ttl.unwrap_or_default()
+                )
+            };
+            tokio::select! {
+                biased;
+                // Also fires if the sink is dropped without being shut down.
+                _ = &mut stop_receiver => break Ok(()),
+                Some(_) = sleep_until(expires_at) => break Err(expired()),
+                Some(_) = tick(&mut rotation_interval) => {
+                    let Some(rotate) = rotate.as_mut() else {
+                        continue;
+                    };
+                    let started_at = Instant::now();
+                    let rotated = match expires_at {
+                        Some(expires_at) => tokio::time::timeout_at(expires_at, rotate())
+                            .await
+                            .unwrap_or_else(|_| Err(expired())),
+                        None => rotate().await,
+                    };
+                    if let Err(e) = rotated {
+                        break Err(e.context("failed to rotate the producer lock"));
+                    }
+                    last_rotation = started_at;
+                }
+                Some(_) = tick(&mut heartbeat_interval) => {
+                    // A heartbeat still being written is enough, the next tick catches up.
+                    if heartbeat_write.as_ref().map_or(true, JoinHandle::is_finished) {
+                        if let Some(write_heartbeat) = write_heartbeat.as_ref() {
+                            heartbeat_write = Some(tokio::spawn(write_heartbeat()));
+                        }
+                    }
+                }
+            }
+        };
+        if let Err(e) = result.as_ref() {
+            error!("producer {producer_id:?} lost its lock, stopping every shard: {e:?}");
+            lock_lost.send_replace(true);
+        }
+        result
+    });
+    KeepaliveHandle { stop, handle }
+}
+
+async fn try_insert_lock(
+    session: &Session,
+    producer_id: ProducerId,
+    lock_id: &str,
+    ifname: &str,
+    ipaddr: &str,
+    control_keyspace: Option<&str>,
+) -> anyhow::Result<bool> {
+    let qr = session
+        .query(
+            in_control_keyspace(TRY_ACQUIRE_PRODUCER_LOCK, control_keyspace),
+            (producer_id, lock_id, ifname, ipaddr),
+        )
+        .await?;
+    let LwtSuccess(applied) = qr.single_row_typed::<LwtSuccess>()?;
+    Ok(applied)
+}
+
+/// Drops the lock of `producer_id` if it is held from the same interface and ip address.
+///
+/// Returns true if the lock has been dropped.
+async fn try_drop_own_host_lock(
+    session: &Arc<Session>,
+    producer_id: ProducerId,
+    ifname: &str,
+    ipaddr: &str,
+    control_keyspace: Option<&str>,
+) -> anyhow::Result<bool> {
+    let holder = check_producer_lock(Arc::clone(session), producer_id, control_keyspace).await?;
+
+    let Some(LockHolder {
+        lock_id: held_lock_id,
+        ifname: held_ifname,
+        ipv4: held_ipv4,
+        ..
+    }) = holder
+    else {
+        // The lock has been released in the meantime.
+        return Ok(true);
+    };
+    if held_ifname.as_deref() != Some(ifname) || held_ipv4.as_deref() != Some(ipaddr) {
+        return Ok(false);
+    }
+
+    warn!(
+        "producer {producer_id:?} lock {held_lock_id} is held by this host ({ifname}, {ipaddr}), taking it over"
+    );
+    let qr = session
+        .query(
+            in_control_keyspace(DROP_PRODUCER_LOCK, control_keyspace),
+            (producer_id, held_lock_id),
+        )
+        .await?;
+    let LwtSuccess(applied) = qr.single_row_typed::<LwtSuccess>()?;
+    Ok(applied)
+}
+
+/// Drops the lock of `producer_id` if it was neither acquired nor rotated within `expiry`.
+///
+/// The holder is read again with a serial read right before the delete, so a rotation committed in the
+/// meantime keeps the lock in place. Returns true if the lock has been dropped.
+async fn try_drop_expired_lock(
+    session: &Arc<Session>,
+    producer_id: ProducerId,
+    expiry: Duration,
+    control_keyspace: Option<&str>,
+) -> anyhow::Result<bool> {
+    let holder = check_producer_lock(Arc::clone(session), producer_id, control_keyspace).await?;
+    let Some(holder) = holder else {
+        // The lock has been released in the meantime.
+        return Ok(true);
+    };
+    if !holder.is_expired(expiry, Utc::now().timestamp_micros()) {
+        return Ok(false);
+    }
+
+    let mut recheck = Query::new(in_control_keyspace(GET_PRODUCER_LOCK, control_keyspace));
+    recheck.set_consistency(Consistency::Serial);
+    let latest = session
+        .query(recheck, (producer_id,))
+        .await?
+        .maybe_first_row_typed::<LockHolder>()?;
+    let Some(latest) = latest else {
+        return Ok(true);
+    };
+    if latest.lock_id != holder.lock_id || !latest.is_expired(expiry, Utc::now().timestamp_micros())
+    {
+        info!(
+            "producer {producer_id:?} lock {} is alive again, leaving it in place",
+            holder.lock_id
+        );
+        return Ok(false);
+    }
+
+    warn!(
+        "producer {producer_id:?} lock {} was not rotated for {expiry:?}, taking it over",
+        latest.lock_id
+    );
+    let qr = session
+        .query(
+            in_control_keyspace(DROP_PRODUCER_LOCK, control_keyspace),
+            (producer_id, &latest.lock_id),
+        )
+        .await?;
+    let LwtSuccess(applied) = qr.single_row_typed::<LwtSuccess>()?;
+    Ok(applied)
+}
+
+async fn try_acquire_lock(
+    session: Arc<Session>,
+    producer_id: ProducerId,
+    ifname: Option<String>,
+    control_keyspace: Option<String>,
+    steal_own_host_lock: bool,
+    lock_identity: Option<(String, IpAddr)>,
+    expired_lock_steal: Option<Duration>,
+) -> anyhow::Result<ProducerLock> {
+    let t = Instant::now();
+    let result = insert_producer_lock(
+        session,
+        producer_id,
+        ifname,
+        control_keyspace,
+        steal_own_host_lock,
+        lock_identity,
+        expired_lock_steal,
+    )
+    .await;
+    let outcome = match &result {
+        Ok(Some(_)) => "acquired",
+        Ok(None) => "contended",
+        Err(_) => "error",
+    };
+    scylladb_lock_acquisition_observe(producer_id, outcome, t.elapsed());
+    result?.ok_or_else(|| {
+        anyhow::anyhow!(
+            "Failed to lock producer {:?}, you may need to release it manually",
+            producer_id
+        )
+    })
+}
+
+/// Inserts the lock of the producer, `None` if another instance holds it.
+///
+/// A lock neither acquired nor rotated within `expired_lock_steal`, if any, is taken over.
+async fn insert_producer_lock(
+    session: Arc<Session>,
+    producer_id: ProducerId,
+    ifname: Option<String>,
+    control_keyspace: Option<String>,
+    steal_own_host_lock: bool,
+    lock_identity: Option<(String, IpAddr)>,
+    expired_lock_steal: Option<Duration>,
+) -> anyhow::Result<Option<ProducerLock>> {
+    let (ifname, ipaddr) = if let Some((ifname, ipaddr)) = lock_identity {
+        if !ipaddr.is_ipv4() {
+            anyhow::bail!("ipv6 not support for producer lock info.");
+        }
+        (ifname, ipaddr.to_string())
+    } else if let Some(ifname) = ifname {
+        let network_interfaces = list_afinet_netifas()?;
+        if let Some((_, ipaddr)) = network_interfaces
+            .iter()
+            .find(|(name, ipaddr)| *name == ifname && matches!(ipaddr, IpAddr::V4(_)))
+        {
+            (ifname, ipaddr.to_string())
+        } else {
+            anyhow::bail!("Found not interface named {}", ifname);
+        }
+    } else {
+        let ipaddr = local_ip()?;
+        if !ipaddr.is_ipv4() {
+            anyhow::bail!("ipv6 not support for producer lock info.");
+        }
+        let network_interfaces = list_afinet_netifas()?;
+        if let Some((ifname, _)) = network_interfaces
+            .iter()
+            .find(|(_, ipaddr2)| ipaddr == *ipaddr2)
+        {
+            (ifname.to_owned(), ipaddr.to_string())
+        } else {
+            anyhow::bail!("Found not interface matching ip {}", ipaddr);
+        }
+    };
+
+    let lock_id = Uuid::new_v4().to_string();
+    let control_keyspace_ref = control_keyspace.as_deref();
+    let mut acquired = try_insert_lock(
+        &session,
+        producer_id,
+        &lock_id,
+        &ifname,
+        &ipaddr,
+        control_keyspace_ref,
+    )
+    .await?;
+
+    if !acquired
+        && steal_own_host_lock
+        && try_drop_own_host_lock(
+            &session,
+            producer_id,
+            &ifname,
+            &ipaddr,
+            control_keyspace_ref,
+        )
+        .await?
+    {
+        acquired = try_insert_lock(
+            &session,
+            producer_id,
+            &lock_id,
+            &ifname,
+            &ipaddr,
+            control_keyspace_ref,
+        )
+        .await?;
+    }
+
+    if let Some(expiry) = expired_lock_steal.filter(|_| !acquired) {
+        if try_drop_expired_lock(&session, producer_id, expiry, control_keyspace_ref).await? {
+            acquired = try_insert_lock(
+                &session,
+                producer_id,
+                &lock_id,
+                &ifname,
+                &ipaddr,
+                control_keyspace_ref,
+            )
+            .await?;
+        }
+    }
+
+    // The sink moves the lock onto its shared session once it starts.
+    Ok(acquired.then(|| ProducerLock {
+        session: SharedSession::new(Arc::clone(&session)).1,
+        lock_id,
+        producer_id,
+        control_keyspace,
+    }))
+}
+
+/// Logs how many nodes negotiated shard awareness with the driver.
+///
+/// Nodes without shard awareness, like Cassandra nodes, still work but requests only target the node.
+fn log_shard_awareness(session: &Session) {
+    let cluster_data = session.get_cluster_data();
+    let nodes = cluster_data.get_nodes_info();
+    let shard_aware_nodes = nodes.iter().filter(|node| node.sharder().is_some()).count();
+    if shard_aware_nodes == 0 {
+        warn!(
+            "none of the {} nodes negotiated shard awareness, requests will not target the core owning their partition",
+            nodes.len()
+        );
+    } else {
+        info!(
+            "{shard_aware_nodes} out of {} nodes negotiated shard awareness",
+            nodes.len()
+        );
+    }
+}
+
+/// Step reached by a sink starting up, see [`ScyllaSink::new_with_progress`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum StartupProgress {
+    /// The connection pool to the cluster is ready.
+    Connected,
+    /// Statements are prepared and the schema and the producer checked.
+    Prepared { num_shards: usize },
+    /// The producer lock has been acquired, or stolen once expired.
+    LockAcquired,
+    /// The last offset of `recovered` out of `total` shards has been read.
+    OffsetsRecovered { recovered: usize, total: usize },
+    /// The resume partition of `probed` out of `total` shards has been probed, only with `probe_on_start`.
+    ShardsProbed { probed: usize, total: usize },
+    /// Shards and router are running, the sink accepts events.
+    Started,
+}
+
+impl ScyllaSink {
+    pub async fn new(
+        config: ScyllaSinkConfig,
+        hostname: impl AsRef<str>,
+        username: impl Into<String>,
+        password: impl Into<String>,
+    ) -> anyhow::Result<Self> {
+        let auth = ScyllaAuth::Password {
+            username: username.into(),
+            password: password.into(),
+        };
+        Self::new_with_auth(config, hostname, auth).await
+    }
+
+    /// Same as [`ScyllaSink::new`], authenticating to the cluster with `auth`.
+    pub async fn new_with_auth(
+        config: ScyllaSinkConfig,
+        hostname: impl AsRef<str>,
+        auth: ScyllaAuth,
+    ) -> anyhow::Result<Self> {
+        Self::init(config, hostname, auth, None).await
+    }
+
+    /// Same as [`ScyllaSink::new_with_auth`], sending each step of the startup to `progress` as it is reached.
+    ///
+    /// Offsets are recovered and shards probed `recovery_concurrency` at a time, a step is sent for every shard
+    /// done, in no particular shard order. The sink starts even if the receiver is gone.
+    pub async fn new_with_progress(
+        config: ScyllaSinkConfig,
+        hostname: impl AsRef<str>,
+        auth: ScyllaAuth,
+        progress: mpsc::UnboundedSender<StartupProgress>,
+    ) -> anyhow::Result<Self> {
+        Self::init(config, hostname, auth, Some(progress)).await
+    }
+
+    async fn init(
+        config: ScyllaSinkConfig,
+        hostname: impl AsRef<str>,
+        auth: ScyllaAuth,
+        progress: Option<mpsc::UnboundedSender<StartupProgress>>,
+    ) -> anyhow::Result<Self> {
+        let report = |step| {
+            if let Some(progress) = progress.as_ref() {
+                // Progress is informative, a receiver gone does not stop the startup.
+                let _ = progress.send(step);
+            }
+        };
+        let producer_id = [config.producer_id];
+        anyhow::ensure!(
+            !config.reset_on_start || config.confirm_reset_on_start,
+            "reset_on_start deletes every event of producer {producer_id:?}, set confirm_reset_on_start to proceed"
+        );
+        // The deletes of the reset carry the coordinator clock, far above the slot derived timestamps: every row
+        // written after the reset would be shadowed by them.
+        anyhow::ensure!(
+            !config.reset_on_start || !config.client_write_timestamps,
+            "reset_on_start can not be combined with client_write_timestamps"
+        );
+        if let Some(lock_ttl) = config.lock_ttl {
+            anyhow::ensure!(
+                config.lock_steal_grace >= 1.0,
+                "lock_steal_grace must be at least 1, got {}",
+                config.lock_steal_grace
+            );
+            anyhow::ensure!(
+                config
+                    .lock_rotation_interval
+                    .is_some_and(|interval| interval < lock_ttl),
+                "lock_ttl requires a lock_rotation_interval shorter than the ttl, the rotations keep the lock alive"
+            );
+        }
+
+        let session_builder = SessionBuilder::new()
+            .known_node(hostname)
+            .compression(Some(Compression::Lz4))
+            .use_keyspace(config.keyspace.clone(), false);
+        let mut session_builder = auth.apply(session_builder);
+        if let Some(pool_size) = config.pool_size_per_host {
+            session_builder = session_builder.pool_size(PoolSize::PerHost(pool_size));
+        }
+        if let Some(keepalive_interval) = config.keepalive_interval {
+            session_builder = session_builder.keepalive_interval(keepalive_interval);
+        }
+        session_builder = session_builder.disallow_shard_aware_port(!config.shard_aware_port);
+        let session: Session = session_builder.build().await?;
+        info!("connection pool to scylladb ready.");
+        log_shard_awareness(&session);
+        let session = Arc::new(session);
+        report(StartupProgress::Connected);
+
+        let (statements, producer_info) = Self::prepare_producer(&session, &config).await?;
+        report(StartupProgress::Prepared {
+            num_shards: producer_info.num_shards as usize,
+        });
+
+        let producer_lock = try_acquire_lock(
+            Arc::clone(&session),
+            producer_id,
+            config.ifname.to_owned(),
+            config.control_keyspace.clone(),
+            config.steal_own_host_lock,
+            config.lock_identity.clone(),
+            config
+                .lock_ttl
+                .map(|ttl| ttl.mul_f64(config.lock_steal_grace)),
+        )
+        .await?;
+
+        info!("Producer {producer_id:?} lock acquired!");
+        scylladb_producer_lock_held_set(producer_id, true);
+        report(StartupProgress::LockAcquired);
+
+        let shard_count = producer_info.num_shards as usize;
+
+        info!("init producer {producer_id:?} period commit log successful.");
+
+        let shard_ids = (0..shard_count)
+            .map(|shard_id| shard_id as ShardId)
+            .collect::<Vec<_>>();
+        let shard_offsets = recover_shard_offsets(
+            Arc::clone(&session),
+            producer_id,
+            &shard_ids,
+            config.control_keyspace.as_deref(),
+            config.recovery_concurrency,
+            producer_info.partitions_per_period(),
+            |recovered| {
+                report(StartupProgress::OffsetsRecovered {
+                    recovered,
+                    total: shard_count,
+                })
+            },
+        )
+        .await?;
+
+        info!("Got back last offsets of all {shard_count} shards");
+        let start_offsets = if config.reset_on_start {
+            reset_producer(
+                &session,
+                producer_id,
+                &shard_offsets,
+                config.control_keyspace.as_deref(),
+                producer_info.partitions_per_period(),
+                producer_info.slot_bucket_size,
+            )
+            .await?;
+            shard_ids.iter().map(|shard_id| (*shard_id, 0)).collect()
+        } else {
+            shard_offsets
+                .into_iter()
+                .map(|(shard_id, last_offset)| (shard_id, last_offset + 1))
+                .collect::<Vec<_>>()
+        };
+        // A crash during the probe of the previous start leaves its sentinel in the partition the shard resumes in.
+        delete_probe_sentinels(
+            &session,
+            producer_id,
+            &start_offsets,
+            config.recovery_concurrency,
+            producer_info.partitions_per_period(),
+        )
+        .await?;
+        if config.probe_on_start {
+            probe_shard_partitions(
+                &session,
+                &statements,
+                producer_id,
+                &start_offsets,
+                config.recovery_concurrency,
+                producer_info.partitions_per_period(),
+                |probed| {
+                    report(StartupProgress::ShardsProbed {
+                        probed,
+                        total: shard_count,
+                    })
+                },
+            )
+            .await?;
+        }
+        let sink = Self::start(
+            config,
+            session,
+            statements,
+            Some(producer_lock),
+            start_offsets,
+            producer_info.partitions_per_period(),
+        )
+        .await?;
+        report(StartupProgress::Started);
+        Ok(sink)
+    }
+
+    /// Creates a sink loading historical events into a fresh keyspace, each shard starts at the offset found
+    /// at its index in `start_offsets`. `session` must already use the keyspace of `config`.
+    ///
+    /// A backfill sink does not take the producer lock, does not commit `producer_slot_seen` and commits
+    /// the periods it filled only once it shuts down, instead of waiting for each commit before writing
+    /// the next period. A shard dying stops the whole sink since its offsets cannot be recovered before
+    /// its periods are committed.
+    ///
+    /// A backfill sink must never run concurrently with a live sink, or another backfill sink, on the same
+    /// producer: nothing prevents them from writing the same offsets.
+    pub async fn new_backfill(
+        config: ScyllaSinkConfig,
+        session: Arc<Session>,
+        start_offsets: Vec<ShardOffset>,
+    ) -> anyhow::Result<Self> {
+        let producer_id = [config.producer_id];
+        anyhow::ensure!(
+            !config.reset_on_start,
+            "a backfill sink does not hold the lock of producer {producer_id:?} and can not reset it"
+        );
+        let (statements, producer_info) = Self::prepare_producer(&session, &config).await?;
+        anyhow::ensure!(
+            start_offsets.len() == producer_info.num_shards as usize,
+            "producer {producer_id:?} has {} shards, got {} start offsets",
+            producer_info.num_shards,
+            start_offsets.len()
+        );
+        anyhow::ensure!(
+            start_offsets.iter().all(|offset| *offset >= 0),
+            "start offsets can not be negative"
+        );
+        warn!("Starting backfill of producer {producer_id:?} without the producer lock");
+
+        let start_offsets = start_offsets
+            .into_iter()
+            .enumerate()
+            .map(|(shard_id, offset)| (shard_id as ShardId, offset))
+            .collect::<Vec<_>>();
+        delete_probe_sentinels(
+            &session,
+            producer_id,
+            &start_offsets,
+            config.recovery_concurrency,
+            producer_info.partitions_per_period(),
+        )
+        .await?;
+        if config.probe_on_start {
+            probe_shard_partitions(
+                &session,
+                &statements,
+                producer_id,
+                &start_offsets,
+                config.recovery_concurrency,
+                producer_info.partitions_per_period(),
+                |_| (),
+            )
+            .await?;
+        }
+        Self::start(
+            config,
+            session,
+            statements,
+            None,
+            start_offsets,
+            producer_info.partitions_per_period(),
+        )
+        .await
+    }
+
+    /// Prepares the statements of the sink and checks the schema and the producer before starting it.
+    async fn prepare_producer(
+        session: &Arc<Session>,
+        config: &ScyllaSinkConfig,
+    ) -> anyhow::Result<(SinkStatements, ProducerInfo)> {
+        let producer_id = [config.producer_id];
+        anyhow::ensure!(
+            config.slot_bucket_size.map_or(true, |size| size > 0),
+            "slot_bucket_size must be positive"
+        );
+        // The router waits for the lag to drop below the limit, which a lag of zero never does.
+        anyhow::ensure!(
+            config.max_shard_durability_lag.map_or(true, |lag| lag > 0),
+            "max_shard_durability_lag must be positive"
+        );
+        check_row_ttl(config.row_ttl)?;
+
+        // Fail right away on schema drift instead of starting shards that cannot write.
+        let control_keyspace = config.control_keyspace.as_deref();
+        let replication_check = async {
+            match config.replication_check.as_ref() {
+                Some(replication_check) => {
+                    check_keyspace_replication(
+                        Arc::clone(session),
+                        &config.keyspace,
+                        replication_check,
+                    )
+                    .await
+                }
+                None => Ok(()),
+            }
+        };
+        // The checks only read the schema and the producer, they run along the preparation of the statements.
+        let (statements, (), (), producer_info) = future::try_join4(
+            SinkStatements::prepare(session, config),
+            replication_check,
+            check_recovery_clustering_order(
+                Arc::clone(session),
+                &config.keyspace,
+                control_keyspace,
+            ),
+            get_producer_info_by_id(Arc::clone(session), producer_id, control_keyspace),
+        )
+        .await?;
+        let mut producer_info = producer_info.ok_or_else(|| {
+            anyhow::anyhow!(
+                "producer {producer_id:?} has not yet been registered, see `register_producer`"
+            )
+        })?;
+
+        info!("Producer {producer_id:?} is registered");
+
+        // Readers find the buckets of log_by_slot from the size recorded by the first sink writing it.
+        match (producer_info.slot_bucket_size, config.slot_bucket_size) {
+            (None, Some(slot_bucket_size)) => {
+                let qr = session
+                    .query(
+                        in_control_keyspace(SET_PRODUCER_SLOT_BUCKET_SIZE, control_keyspace),
+                        (slot_bucket_size, producer_id),
+                    )
+                    .await?;
+                let LwtSuccess(applied) = qr.single_row_typed::<LwtSuccess>()?;
+                anyhow::ensure!(
+                    applied,
+                    "slot_bucket_size of producer {producer_id:?} was recorded by another sink meanwhile"
+                );
+                producer_info.slot_bucket_size = Some(slot_bucket_size);
+            }
+            (recorded, configured) => anyhow::ensure!(
+                recorded == configured,
+                "producer {producer_id:?} writes log_by_slot with slot_bucket_size {recorded:?}, not {configured:?}"
+            ),
+        }
+
+        let orphaned_shards = list_orphaned_shards(
+            session,
+            producer_id,
+            producer_info.num_shards,
+            control_keyspace,
+        )
+        .await?;
+        if !orphaned_shards.is_empty() {
+            if !config.allow_shard_shrink {
+                anyhow::bail!(
+                    "producer {producer_id:?} now has {} shards but shards {orphaned_shards:?} hold committed periods, set allow_shard_shrink to abandon them",
+                    producer_info.num_shards
+                );
+            }
+            warn!(
+                "producer {producer_id:?} shrank to {} shards, shards {orphaned_shards:?} are abandoned",
+                producer_info.num_shards
+            );
+        }
+        Ok((statements, producer_info))
+    }
+
+    /// Spawns the shards and the router, only backfill sinks run without the producer lock.
+    ///
+    /// The commands left in the write-ahead log of the sink by a previous run are logged again before returning.
+    async fn start(
+        config: ScyllaSinkConfig,
+        session: Arc<Session>,
+        statements: SinkStatements,
+        producer_lock: Option<ProducerLock>,
+        start_offsets: Vec<(ShardId, ShardOffset)>,
+        partitions_per_period: i16,
+    ) -> anyhow::Result<Self> {
+        let producer_id = [config.producer_id];
+        let (router_wal, replayed) = match config.wal_dir.as_deref() {
+            Some(wal_dir) => {
+                let (router_wal, replayed) = RouterWal::open(wal_dir, producer_id)?;
+                let router_wal = router_wal.with_sync(config.wal_sync);
+                (Some(Arc::new(Mutex::new(router_wal))), replayed)
+            }
+            None => (None, Vec::new()),
+        };
+        let effective_config = config.clone();
+        let backfill = producer_lock.is_none();
+        let (session_sender, session) = SharedSession::new(session);
+        let producer_lock = producer_lock.map(|mut lock| {
+            lock.session = session.clone();
+            Arc::new(tokio::sync::Mutex::new(Some(lock)))
+        });
+        let keepalive = ProducerKeepalive {
+            producer_id,
+            heartbeat: statements
+                .insert_producer_heartbeat
+                .clone()
+                .zip(config.heartbeat_interval)
+                .filter(|_| !backfill)
+                .map(|(statement, interval)| {
+                    (
+                        heartbeat_writer(session.clone(), statement, producer_id),
+                        interval,
+                    )
+                }),
+            lock_rotation: config
+                .lock_rotation_interval
+                .zip(producer_lock.clone())
+                .map(|(interval, lock)| LockRotation::of_lock(lock, interval, config.lock_ttl)),
+        };
+        // Only a rotation loses the lock, the router and the shards watch it only if the lock is rotated.
+        let (lock_lost_sender, lock_lost) = watch::channel(false);
+        let lock_lost = keepalive.lock_rotation.is_some().then_some(lock_lost);
+        let keepalive = (keepalive.heartbeat.is_some() || keepalive.lock_rotation.is_some())
+            .then(|| spawn_producer_keepalive(keepalive, lock_lost_sender));
+        let tip_slot = Arc::new(AtomicI64::new(-1));
+        let roll_required = Arc::new(AtomicBool::new(false));
+        let failed_shard = Arc::new(OnceLock::new());
+        let circuit_breaker = config
+            .circuit_breaker
+            .map(|breaker_config| Arc::new(CircuitBreaker::new(producer_id, breaker_config)));
+        let (ack_sender, ack_receiver) = if config.ack_stream {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            (Some(sender), Some(receiver))
+        } else {
+            (None, None)
+        };
+        let (shard_pause_senders, shard_pauses) = start_offsets
+            .iter()
+            .map(|(shard_id, _)| {
+                let (sender, receiver) = watch::channel(false);
+                ((*shard_id, sender), (*shard_id, receiver))
+            })
+            .unzip::<_, _, BTreeMap<_, _>, BTreeMap<_, _>>();
+        let shard_gauges = start_offsets
+            .iter()
+            .map(|(shard_id, _)| (*shard_id, Arc::new(ShardGauges::default())))
+            .collect::<BTreeMap<_, _>>();
+        let consistency_downgrade = config.consistency_downgrade.map(|downgrade_config| {
+            Arc::new(ConsistencyDowngrade::new(producer_id, downgrade_config))
+        });
+        let catch_up = config.catch_up.as_ref().map(|catch_up| CatchUpLimits {
+            min_slot_lag: catch_up.min_slot_lag,
+            max_buffer_capacity: catch_up.max_buffer_capacity,
+            max_buffer_byte_size: catch_up
+                .batch_size_kb_limit
+                .unwrap_or(config.batch_size_kb_limit)
+                * 1024,
+        });
+        let offset_committer = match config.offset_committer.clone() {
+            Some(committer) => Some(committer),
+            None => (config.commit_offsets && !backfill).then(|| {
+                SharedOffsetCommitter::new(ScyllaOffsetCommitter::with_statement(
+                    session.clone(),
+                    statements.commit_shard_period.statement().clone(),
+                ))
+            }),
+        };
+        let shard_factory = ShardFactory {
+            session: session.clone(),
+            producer_id,
+            shard_config: ShardConfig {
+                max_buffer_capacity: DEFAULT_SHARD_MAX_BUFFER_CAPACITY,
+                max_buffer_byte_size: config.batch_size_kb_limit * 1024,
+                buffer_linger: config.linger,
+                min_batch_len: config.min_batch_len,
+                max_buffer_linger: config.max_linger.max(config.linger),
+                dedup_account_updates: config.dedup_account_updates,
+                tx_dedup_window: config.tx_dedup_window,
+                max_slot_regression: config.max_slot_regression,
+                catch_up,
+                tip_slot: Arc::clone(&tip_slot),
+                max_offset_before_roll: config.max_offset_before_roll,
+                roll_required: Arc::clone(&roll_required),
+                compress_min_bytes: config.compress_data.then_some(config.compress_min_bytes),
+                buffer_budget: BufferBudget::new(
+                    producer_id,
+                    config.max_buffered_kb.map(|kb| kb * 1024),
+                ),
+                wal_dir: config.wal_dir.clone(),
+                wal_sync: config.wal_sync,
+                router_wal: router_wal.clone(),
+                period_committer: config.period_commit_coalescing.filter(|_| !backfill).map(
+                    |window| {
+                        spawn_period_committer(
+                            session.clone(),
+                            producer_id,
+                            statements.commit_shard_period.clone(),
+                            window,
+                        )
+                    },
+                ),
+                defer_period_commits: backfill,
+                shutdown_flush_policy: config.shutdown_flush_policy,
+                circuit_breaker: circuit_breaker.clone(),
+                nested_codec: config.nested_codec,
+                consistency_downgrade,
+                shard_runtime: config.shard_runtime,
+                ack_sender,
+                strict_offset_contiguity: config.strict_offset_contiguity,
+                store_raw_proto: config.store_raw_proto,
+                partitions_per_period,
+                slot_bucket_size: config.slot_bucket_size,
+                offset_committer,
+                lock_lost: lock_lost.clone(),
+            },
+            shard_buffer_overrides: config.shard_buffer_overrides.clone(),
+            statements,
+            control_keyspace: config.control_keyspace.clone(),
+            shard_pauses,
+            shard_gauges: shard_gauges.clone(),
+            max_shard_durability_lag: config.max_shard_durability_lag,
+            buffer_full_policy: config.buffer_full_policy,
+        };
+        for shard_id in config.shard_buffer_overrides.keys() {
+            if !start_offsets.iter().any(|(id, _)| id == shard_id) {
+                warn!("ignoring the buffer override of shard {shard_id}, the producer has no such shard");
+            }
+        }
+        let shards = shard_factory.spawn_all(start_offsets, config.max_concurrent_shards);
+
+        let shard_failure_policy = if backfill {
+            ShardFailurePolicy::FailFast
+        } else {
+            config.shard_failure_policy
+        };
+        let router = config
+            .shard_router
+            .as_ref()
+            .map(ShardRouterFactory::build)
+            .unwrap_or_else(|| Box::<RoundRobinRouter>::default());
+        let ordering_guarantee = if config.priority_predicate.is_some() {
+            OrderingGuarantee::Unordered
+        } else if shards.len() == 1 {
+            OrderingGuarantee::Total
+        } else {
+            router.ordering_guarantee()
+        };
+        let (priority_lane, priority_receiver) = match config.priority_predicate.clone() {
+            Some(predicate) => {
+                let (sender, receiver) = mpsc::channel(PRIORITY_CHANNEL_CAPACITY);
+                (Some((predicate, sender)), Some(receiver))
+            }
+            None => (None, None),
+        };
+        let (sender, router_shutdown, router_handle) = spawn_round_robin(
+            session,
+            producer_id,
+            shards,
+            router,
+            shard_factory,
+            shard_failure_policy,
+            config.slot_seen_commit_jitter,
+            !backfill,
+            lock_lost,
+            config.flush_on_slot_boundary,
+            config.rebalance_interval,
+            priority_receiver,
+            Arc::clone(&failed_shard),
+        );
+
+        let mut sink = ScyllaSink {
+            session: session_sender,
+            tip_slot,
+            roll_required,
+            router_sender: sender,
+            priority_lane,
+            router_shutdown,
+            router_handle,
+            producer_id,
+            producer_lock,
+            keepalive,
+            max_event_bytes: config
+                .max_event_kb
+                .unwrap_or(config.batch_size_kb_limit)
+                .saturating_mul(1024),
+            field_size_limits: config.field_size_limits,
+            ordering_guarantee,
+            circuit_breaker,
+            failed_shard,
+            ack_receiver,
+            shard_pauses: shard_pause_senders,
+            shard_gauges,
+            config: effective_config,
+            partitions_per_period,
+            router_wal,
+        };
+        if !replayed.is_empty() {
+            warn!(
+                "replaying {} commands from the write-ahead log of producer {producer_id:?}",
+                replayed.len()
+            );
+        }
+        for cmd in replayed {
+            sink.inner_log(cmd, None).await.map_err(|e| {
+                anyhow::Error::new(e).context("failed to replay the write-ahead log")
+            })?;
+        }
+        if let Some(router_wal) = sink.router_wal.as_ref() {
+            router_wal.lock().expect("poisoned").finish_replay()?;
+        }
+        Ok(sink)
+    }
+
+    /// Returns the order in which consumers read back the logged events, given the routing and shard count.
+    ///
+    /// Code relying on the order of events should check it on start and refuse to run with a weaker one.
+    pub const fn guarantees(&self) -> OrderingGuarantee {
+        self.ordering_guarantee
+    }
+
+    /// Returns the session backing this sink.
+    ///
+    /// This is an escape hatch for embedders that need to run ad-hoc queries (maintenance, TTL checks, etc.)
+    /// against the same connection pool as the sink instead of opening a second one.
+    /// Writing to the tables managed by the sink through this session is not supported and may break
+    /// the shard offset guarantees.
+    pub fn session(&self) -> Arc<Session> {
+        Arc::clone(&self.session.borrow())
+    }
+
+    pub async fn shutdown(self) -> anyhow::Result<()> {
+        warn!("Shutthing down scylla sink...");
+        self.resume_all_shards();
+        let roll_required = self.roll_required();
+        let router_result = self.router_shutdown.send(());
+        if router_result.is_err() && !roll_required {
+            error!("router was closed before we could gracefully shutdown all sharders. Sharder should terminate on their own...")
+        }
+        // The router owns the shards and waits for all of them to terminate.
+        match self.router_handle.await {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => error!("Router error: {e:?}"),
+            Err(e) => error!("Router terminated abruptly: {e:?}"),
+        }
+        // The shards are done writing, the lock only needs to outlive them.
+        if let Some(keepalive) = self.keepalive {
+            keepalive.stop().await;
+        }
+        if let Some(producer_lock) = self.producer_lock {
+            if let Some(producer_lock) = producer_lock.lock().await.take() {
+                producer_lock.release().await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets the latest slot of the chain, as observed by the caller.
+    ///
+    /// When catch-up is configured, shards batch more aggressively while their events lag far behind this slot.
+    /// The tip slot only moves forward.
+    pub fn set_tip_slot(&self, slot: Slot) {
+        self.tip_slot.fetch_max(slot, Ordering::Relaxed);
+    }
+
+    /// Sends every query of the sink through `session` from now on: the writes of the shards, the period and
+    /// slot commits, the heartbeat and the rotations and the release of the producer lock.
+    ///
+    /// The sink never rebuilds its session itself. A caller replacing a session whose connections are gone calls
+    /// this so the sink keeps writing through the healthy session instead of failing its next queries. Queries
+    /// in flight complete on the previous session. `session` must use the keyspace of the sink.
+    pub fn swap_session(&self, session: Arc<Session>) {
+        info!(
+            "producer {:?} now goes through a new session",
+            self.producer_id
+        );
+        self.session.send_replace(session);
+    }
+
+    /// Returns a snapshot of the configuration of the sink, the offsets and occupancy of its shards, the lock it
+    /// holds and the nodes of the cluster, meant for a debug endpoint.
+    ///
+    /// Shard figures are read from atomics the shards publish to, they may be a few events apart from each
+    /// other. Only waits for a lock rotation in progress, no query is sent to the cluster.
+    pub async fn diagnostics(&self) -> SinkDiagnostics {
+        let lock_id = match self.producer_lock.as_ref() {
+            Some(lock) => lock.lock().await.as_ref().map(|lock| lock.lock_id.clone()),
+            None => None,
+        };
+        let shards = self
+            .shard_gauges
+            .iter()
+            .map(|(shard_id, gauges)| {
+                let paused = self
+                    .shard_pauses
+                    .get(shard_id)
+                    .is_some_and(|pause| *pause.borrow());
+                gauges.snapshot(*shard_id, paused)
+            })
+            .collect();
+        SinkDiagnostics {
+            producer_id: self.config.producer_id,
+            config: format!("{:#?}", self.config),
+            partitions_per_period: self.partitions_per_period,
+            lock_id,
+            tip_slot: self.tip_slot.load(Ordering::Relaxed),
+            roll_required: self.roll_required.load(Ordering::Relaxed),
+            failed_shard: self.failed_shard.get().copied(),
+            shards,
+            nodes: list_nodes(&self.session()),
+        }
+    }
+
+    /// Pauses a shard for maintenance: the shard stops taking events from its mailbox and keeps its buffer and
+    /// offset as they are, without flushing, until [`ScyllaSink::resume_shard`].
+    ///
+    /// Events keep being routed to the paused shard until its mailbox is full, then the router waits for it and
+    /// every shard backs up behind it. This happens quickly with a deterministic routing such as per pubkey,
+    /// and with round-robin too since every shard gets its turn. [`ScyllaSink::flush_all`] waits for the paused
+    /// shard as well, while shutting down the sink resumes every shard.
+    pub fn pause_shard(&self, shard_id: ShardId) -> anyhow::Result<()> {
+        self.set_shard_paused(shard_id, true)
+    }
+
+    /// Resumes a shard paused by [`ScyllaSink::pause_shard`], it continues where it left off.
+    pub fn resume_shard(&self, shard_id: ShardId) -> anyhow::Result<()> {
+        self.set_shard_paused(shard_id, false)
+    }
+
+    fn set_shard_paused(&self, shard_id: ShardId, paused: bool) -> anyhow::Result<()> {
+        let pause = self.shard_pauses.get(&shard_id).ok_or(anyhow::anyhow!(
+            "producer {:?} has no shard {shard_id}",
+            self.producer_id
+        ))?;
+        pause.send_replace(paused);
+        Ok(())
+    }
+
+    fn resume_all_shards(&self) {
+        for pause in self.shard_pauses.values() {
+            pause.send_replace(false);
+        }
+    }
+
+    /// Flushes the events buffered in every shard and waits until they are written.
+    ///
+    /// Acts as a barrier: once this returns, every event logged before the call is durable in ScyllaDB.
+    /// The linger timer of each shard is reset by the flush, period commits are unaffected.
+    pub async fn flush_all(&self) -> anyhow::Result<()> {
+        let (ack, ack_rx) = oneshot::channel();
+        self.router_sender
+            .send(SinkCommand::Flush(ack))
+            .await
+            .map_err(|_e| anyhow::anyhow!("failed to route"))?;
+        ack_rx
+            .await
+            .map_err(|_e| anyhow::anyhow!("some shards failed to flush"))
+    }
+
+    /// Closes the sink without writing the events still buffered in the shards and returns them instead.
+    ///
+    /// Meant for failover: the caller re-submits the returned events to the instance taking over the producer,
+    /// so they are written exactly once instead of being written by both instances.
+    /// This trades durability for avoiding duplicate writes: if the returned events are not re-submitted,
+    /// they are lost.
+    ///
+    /// Events are grouped by shard, in offset order within each shard. The producer lock is released
+    /// so another instance can take over right away.
+    pub async fn close_returning_pending(self) -> Vec<ClientCommand> {
+        warn!("Closing scylla sink for handoff...");
+        self.resume_all_shards();
+        let (reply, pending) = oneshot::channel();
+        let pending = if self
+            .router_sender
+            .send(SinkCommand::Handoff(reply))
+            .await
+            .is_ok()
+        {
+            pending.await.unwrap_or_default()
+        } else {
+            error!("router was closed before handoff, pending events are lost");
+            Vec::new()
+        };
+        match self.router_handle.await {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => error!("Router error: {e:?}"),
+            Err(e) => error!("Router terminated abruptly: {e:?}"),
+        }
+        if let Some(keepalive) = self.keepalive {
+            keepalive.stop().await;
+        }
+        if let Some(producer_lock) = self.producer_lock {
+            if let Some(producer_lock) = producer_lock.lock().await.take() {
+                if let Err(e) = producer_lock.release().await {
+                    error!("failed to release producer lock: {e:?}");
+                }
+            }
+        }
+        pending
+    }
+
+    async fn inner_log(
+        &mut self,
+        mut cmd: ClientCommand,
+        submission_id: Option<SubmissionId>,
+    ) -> Result<(), ScyllaSinkError> {
+        let producer_id = self.producer_id;
+        if self
+            .circuit_breaker
+            .as_ref()
+            .is_some_and(|breaker| breaker.is_open())
+        {
+            return Err(ScyllaSinkError::Backpressure(CircuitOpen { producer_id }));
+        }
+        let action = self.field_size_limits.oversized_field_action;
+        match self.field_size_limits.enforce(&mut cmd) {
+            Ok(None) => (),
+            Ok(Some(oversized)) => {
+                scylladb_oversized_field_inc(producer_id, oversized.field, action.as_str());
+                warn!("truncating event: {oversized}");
+            }
+            Err(oversized) => {
+                scylladb_oversized_field_inc(producer_id, oversized.field, action.as_str());
+                warn!("rejecting event: {oversized}");
+                return Err(ScyllaSinkError::FieldTooLarge(oversized));
+            }
+        }
+        let size = match &cmd {
+            ClientCommand::InsertAccountUpdate(acc_update) => acc_update.deep_size_of(),
+            ClientCommand::InsertTransaction(tx) => tx.deep_size_of(),
+            ClientCommand::InsertBlockMeta(block_meta) => block_meta.deep_size_of(),
+        };
+        if size > self.max_event_bytes {
+            scylladb_oversized_event_inc(producer_id);
+            let err = EventTooLarge {
+                size,
+                limit: self.max_event_bytes,
+            };
+            warn!("rejecting event: {err}");
+            return Err(ScyllaSinkError::EventTooLarge(err));
+        }
+        let wal_seq = self
+            .router_wal
+            .as_ref()
+            .map(|router_wal| router_wal.lock().expect("poisoned").append(&cmd))
+            .transpose()
+            .map_err(ScyllaSinkError::Wal)?;
+        let routed = match self.priority_lane.as_ref() {
+            Some((predicate, priority_sender)) if predicate.matches(&cmd) => priority_sender
+                .send((cmd, submission_id, wal_seq))
+                .await
+                .map_err(|_e| ()),
+            _ => self
+                .router_sender
+                .send(SinkCommand::Client(cmd, submission_id, wal_seq))
+                .await
+                .map_err(|_e| ()),
+        };
+        routed.map_err(|()| {
+            // The caller is told the event was not accepted, it must not be replayed.
+            if let Err(e) = release_wal_seq(self.router_wal.as_ref(), wal_seq) {
+                warn!("failed to release a rejected event from the write-ahead log: {e:?}");
+            }
+            if self.roll_required() {
+                ScyllaSinkError::RollRequired(ProducerRollRequired { producer_id })
+            } else if let Some(shard_id) = self.failed_shard.get() {
+                ScyllaSinkError::ShardClosed(*shard_id)
+            } else {
+                ScyllaSinkError::RouterClosed
+            }
+        })
+    }
+
+    /// Returns true once a shard reached [`ScyllaSinkConfig::max_offset_before_roll`].
+    ///
+    /// The sink then stops accepting events, the caller should shut it down and register a new producer.
+    pub fn roll_required(&self) -> bool {
+        self.roll_required.load(Ordering::Relaxed)
+    }
+
+    /// Logs an account update, `ingested_at` is set to the current time if the caller did not set it.
+    pub async fn log_account_update(
+        &mut self,
+        mut update: AccountUpdate,
+    ) -> Result<(), ScyllaSinkError> {
+        update.ingested_at.get_or_insert_with(ingestion_timestamp);
+        let cmd = ClientCommand::InsertAccountUpdate(update);
+        self.inner_log(cmd, None).await
+    }
+
+    /// Logs a transaction, `ingested_at` is set to the current time if the caller did not set it.
+    pub async fn log_transaction(&mut self, mut tx: Transaction) -> Result<(), ScyllaSinkError> {
+        tx.ingested_at.get_or_insert_with(ingestion_timestamp);
+        let cmd = ClientCommand::InsertTransaction(tx);
+        self.inner_log(cmd, None).await
+    }
+
+    /// Logs the metadata of a block, `ingested_at` is set to the current time if the caller did not set it.
+    pub async fn log_block_meta(
+        &mut self,
+        mut block_meta: BlockMeta,
+    ) -> Result<(), ScyllaSinkError> {
+        block_meta
+            .ingested_at
+            .get_or_insert_with(ingestion_timestamp);
+        let cmd = ClientCommand::InsertBlockMeta(block_meta);
+        self.inner_log(cmd, None).await
+    }
+
+    /// Logs an account update like [`ScyllaSink::log_account_update`], acknowledged on the ack stream once durable.
+    pub async fn log_account_update_acked(
+        &mut self,
+        mut update: AccountUpdate,
+        submission_id: SubmissionId,
+    ) -> Result<(), ScyllaSinkError> {
+        update.ingested_at.get_or_insert_with(ingestion_timestamp);
+        let cmd = ClientCommand::InsertAccountUpdate(update);
+        self.inner_log(cmd, Some(submission_id)).await
+    }
+
+    /// Logs a transaction like [`ScyllaSink::log_transaction`], acknowledged on the ack stream once durable.
+    pub async fn log_transaction_acked(
+        &mut self,
+        mut tx: Transaction,
+        submission_id: SubmissionId,
+    ) -> Result<(), ScyllaSinkError> {
+        tx.ingested_at.get_or_insert_with(ingestion_timestamp);
+        let cmd = ClientCommand::InsertTransaction(tx);
+        self.inner_log(cmd, Some(submission_id)).await
+    }
+
+    /// Takes the stream acknowledging the events logged with a submission id, `None` if
+    /// [`ScyllaSinkConfig::ack_stream`] is disabled or the stream was already taken.
+    ///
+    /// Each shard sends the acks of a batch at once after writing it, so acks are correlated in bulk rather than
+    /// awaited one event at a time. Acks of a shard arrive in offset order, acks of different shards interleave.
+    /// An account update merged into a buffered one by `dedup_account_updates` is acknowledged with the offset
+    /// it was merged into.
+    ///
+    /// The channel is unbounded so a caller draining it from the task that logs events can not stall the shards,
+    /// it must be drained though. Events dropped per [`BufferFullPolicy`] are acknowledged as
+    /// [`SubmissionAck::Dropped`]. Events replayed from the write-ahead log, handed off, or lost with a dead shard
+    /// are never acknowledged.
+    pub fn take_ack_stream(&mut self) -> Option<mpsc::UnboundedReceiver<Vec<SubmissionAck>>> {
+        self.ack_receiver.take()
+    }
+
+    /// Logs an event built elsewhere, e.g. read back from the log of another producer for a replay or a migration.
+    ///
+    /// `shard_id`, `period`, `producer_id` and `offset` are overwritten with the values assigned by the shard the
+    /// event is routed to, and the payload is compressed or packed as configured for this sink. `ingested_at` is
+    /// kept, set to the current time if missing.
+    pub async fn log_blockchain_event(
+        &mut self,
+        mut event: BlockchainEvent,
+    ) -> Result<(), ScyllaSinkError> {
+        let invalid = |event: &BlockchainEvent, reason: String| {
+            ScyllaSinkError::InvalidEvent(InvalidEvent {
+                offset: event.offset,
+                reason,
+            })
+        };
+        if let Err(e) = event.decompress_data().and_then(|()| event.decode_nested()) {
+            return Err(invalid(&event, e.to_string()));
+        }
+        if let Some(field) = event.missing_field() {
+            return Err(invalid(&event, format!("{field} is not set")));
+        }
+        event.ingested_at.get_or_insert_with(ingestion_timestamp);
+        let cmd = match event.event_type {
+            BlockchainEventType::AccountUpdate => {
+                ClientCommand::InsertAccountUpdate(AccountUpdate::from(event))
+            }
+            BlockchainEventType::NewTransaction => {
+                ClientCommand::InsertTransaction(Transaction::from(event))
+            }
+            BlockchainEventType::BlockMeta => {
+                ClientCommand::InsertBlockMeta(BlockMeta::from(event))
+            }
+        };
+        self.inner_log(cmd, None).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{
+            append_handed_off, check_row_ttl, client_write_timestamp, compress_rows, drain_mailbox,
+            drop_submission, flush_all, in_control_keyspace, insert_blockchain_event_query,
+            insert_log_by_slot_query, offset_gap, orphaned_shard_candidates, probe_sentinel,
+            replace_dead_shard, slot_bucket, spawn_producer_keepalive, split_at_handoff,
+            starts_new_partition, starts_new_period, ClientCommand, ColumnSlot, FieldSizeLimits,
+            FieldTooLarge, HeldCommands, LockHolder, LockRotation, OffsetBoundary, OptionalColumn,
+            OversizedFieldAction, ProducerKeepalive, PubkeyRouter, RetryPolicy, ScyllaAuth,
+            ScyllaSink, ScyllaSinkConfig, SeenSignatures, ShardFailurePolicy, ShardHandle,
+            ShardRouter, ShardTask, SharedSession, SingleItemBatchRatio, SinkCommand,
+            SlotRegressionCheck, SlotRouter, SubmissionAck, COMMIT_SHARD_PERIOD, CONTROL_TABLES,
+            DELETE_LOG_BY_SLOT_PARTITION, DELETE_LOG_PARTITION, DELETE_PRODUCER_METADATA,
+            DELETE_SHARD_PERIOD_COMMITS, ORPHANED_SHARD_SCAN_CHUNK, ORPHANED_SHARD_SCAN_LEN,
+            SINGLE_ITEM_BATCH_WINDOW, WRITE_TIMESTAMP_MARKER,
+        },
+        crate::scylladb::{
+            config::ConfigGrpc2ScyllaDB,
+            types::{log_partition, AccountUpdate, DataCodec, ShardId, SHARD_OFFSET_MODULO},
+            wal::{RouterWal, WalSeq},
+        },
+        futures::future,
+        std::{
+            collections::BTreeSet,
+            num::NonZeroUsize,
+            sync::{
+                atomic::{AtomicUsize, Ordering},
+                Arc, Mutex, OnceLock,
+            },
+            time::Duration,
+        },
+        tokio::{
+            sync::{mpsc, oneshot, watch},
+            time::Instant,
+        },
+        yellowstone_grpc_proto::{geyser::SubscribeUpdateAccount, prost::Message},
+    };
+
+    #[tokio::test]
+    async fn reset_on_start_is_refused_with_client_write_timestamps() {
+        let config: ConfigGrpc2ScyllaDB = serde_json::from_value(serde_json::json!({
+            "endpoint": "http://127.0.0.1:10000",
+            "request": {},
+            "producer_id": 0,
+            "reset_on_start": true,
+            "confirm_reset_on_start": true,
+            "client_write_timestamps": true,
+        }))
+        .unwrap();
+        // Refused before connecting, nothing listens on that port.
+        let error = ScyllaSink::new_with_auth(
+            config.get_scylladb_sink_config(),
+            "127.0.0.1:1",
+            ScyllaAuth::None,
+        )
+        .await
+        .err()
+        .expect("the sink must refuse to start");
+        assert_eq!(
+            error.to_string(),
+            "reset_on_start can not be combined with client_write_timestamps"
+        );
+    }
+
+    /// Sink config read from a config file holding `extra` along with the required fields.
+    fn sink_config(extra: serde_json::Value) -> ScyllaSinkConfig {
+        let mut config = serde_json::json!({
+            "endpoint": "http://127.0.0.1:10000",
+            "request": {},
+            "producer_id": 0,
+        });
+        config
+            .as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        serde_json::from_value::<ConfigGrpc2ScyllaDB>(config)
+            .unwrap()
+            .get_scylladb_sink_config()
+    }
+
+    #[test]
+    fn offsets_are_committed_only_when_configured() {
+        let config = sink_config(serde_json::json!({}));
+        assert!(!config.commit_offsets);
+        assert_eq!(config.offset_committer, None);
+        assert!(sink_config(serde_json::json!({ "commit_offsets": true })).commit_offsets);
+    }
+
+    #[test]
+    fn slot_seen_commits_are_not_delayed_by_default() {
+        let config = sink_config(serde_json::json!({}));
+        assert_eq!(config.slot_seen_commit_jitter, Duration::ZERO);
+        let config = sink_config(serde_json::json!({ "slot_seen_commit_jitter": 100 }));
+        assert_eq!(config.slot_seen_commit_jitter, Duration::from_millis(100));
+    }
+
+    #[test]
+    fn offset_contiguity_is_not_enforced_by_default() {
+        assert!(!sink_config(serde_json::json!({})).strict_offset_contiguity);
+        let config = sink_config(serde_json::json!({ "strict_offset_contiguity": true }));
+        assert!(config.strict_offset_contiguity);
+    }
+
+    #[test]
+    fn shards_are_rebalanced_only_when_configured() {
+        assert_eq!(sink_config(serde_json::json!({})).rebalance_interval, None);
+        let config = sink_config(serde_json::json!({
+            "sharding_strategy": "by_pubkey",
+            "rebalance_interval": 30_000,
+        }));
+        assert_eq!(config.rebalance_interval, Some(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn reset_deletes_every_table_of_the_producer_but_its_registration_and_lock() {
+        let deleted = DELETE_PRODUCER_METADATA
+            .iter()
+            .chain(&[
+                DELETE_LOG_PARTITION,
+                DELETE_LOG_BY_SLOT_PARTITION,
+                DELETE_SHARD_PERIOD_COMMITS,
+            ])
+            .map(|query| {
+                let table = query.trim().strip_prefix("DELETE FROM ").unwrap();
+                table.split_whitespace().next().unwrap()
+            })
+            .collect::<BTreeSet<_>>();
+        let expected = CONTROL_TABLES
+            .into_iter()
+            .filter(|table| !["producer_info", "producer_lock"].contains(table))
+            .chain(["log", "log_by_slot"])
+            .collect::<BTreeSet<_>>();
+        assert_eq!(deleted, expected);
+    }
+
+    #[test]
+    fn saturated_shard_holds_its_buffer_capacity_then_drops_per_policy() {
+        let held_slots = |drop_oldest| {
+            let (mailbox, mut receiver) = mpsc::channel(1);
+            let (_, outcome) = oneshot::channel();
+            let mut shard = ShardHandle {
+                mailbox,
+                task: ShardTask::Grouped(outcome),
+                durability_lag: None,
+                overflow: Default::default(),
+                overflow_capacity: 2,
+            };
+            // Dropped events are handed back with their write-ahead log sequence number to be released.
+            let mut dropped = Vec::new();
+            for slot in 0..4 {
+                let update = AccountUpdate {
+                    slot,
+                    ..AccountUpdate::zero_account()
+                };
+                let submission = (
+                    ClientCommand::InsertAccountUpdate(update),
+                    None,
+                    Some(slot as WalSeq),
+                );
+                dropped.extend(
+                    shard
+                        .hold(submission, drop_oldest, [0])
+                        .and_then(|(_, _, seq)| seq),
+                );
+            }
+            // The mailbox has room for a single event, the other stays held.
+            shard.try_send_overflow([0]);
+            let sent = match receiver.try_recv() {
+                Ok(SinkCommand::Client(cmd, _, _)) => cmd.slot(),
+                _ => panic!("expected a held event in the mailbox"),
+            };
+            let held = shard.overflow.iter().map(|(cmd, _, _)| cmd.slot());
+            (
+                std::iter::once(sent).chain(held).collect::<Vec<_>>(),
+                dropped,
+            )
+        };
+        assert_eq!(held_slots(true), (vec![2, 3], vec![0, 1]));
+        assert_eq!(held_slots(false), (vec![0, 1], vec![2, 3]));
+    }
+
+    #[test]
+    fn flushing_shard_holds_its_mailbox_then_drops_per_policy() {
+        let held_slots = |drop_oldest| {
+            let mut held = HeldCommands::default();
+            let mut dropped = Vec::new();
+            for slot in 0..4 {
+                let update = AccountUpdate {
+                    slot,
+                    ..AccountUpdate::zero_account()
+                };
+                let msg =
+                    SinkCommand::Client(ClientCommand::InsertAccountUpdate(update), None, None);
+                let dropped_event = held.hold(msg, 2, drop_oldest, [0]);
+                dropped.extend(dropped_event.map(|(cmd, _, _)| cmd.slot()));
+                if slot == 1 {
+                    // Commands other than events are held in order, whatever the capacity.
+                    let (ack, _) = oneshot::channel();
+                    let flush = SinkCommand::Flush(ack);
+                    assert!(held.hold(flush, 2, drop_oldest, [0]).is_none());
+                }
+            }
+            let held = std::iter::from_fn(|| held.pop_front())
+                .map(|msg| match msg {
+                    SinkCommand::Client(cmd, _, _) => Some(cmd.slot()),
+                    SinkCommand::Flush(_) => None,
+                    _ => panic!("expected an event or a flush"),
+                })
+                .collect::<Vec<_>>();
+            (held, dropped)
+        };
+        assert_eq!(held_slots(true), (vec![None, Some(2), Some(3)], vec![0, 1]));
+        assert_eq!(
+            held_slots(false),
+            (vec![Some(0), Some(1), None], vec![2, 3])
+        );
+    }
+
+    #[test]
+    fn handoff_received_while_draining_gets_the_events_after_it() {
+        let event = |slot| {
+            let update = AccountUpdate {
+                slot,
+                ..AccountUpdate::zero_account()
+            };
+            SinkCommand::Client(ClientCommand::InsertAccountUpdate(update), None, None)
+        };
+        let (sender, mut receiver) = mpsc::channel(16);
+        let (reply, mut handed_off) = oneshot::channel();
+        sender.try_send(event(0)).unwrap();
+        sender.try_send(event(1)).unwrap();
+        sender.try_send(SinkCommand::Handoff(reply)).unwrap();
+        sender.try_send(event(2)).unwrap();
+
+        let (written, handoff) = split_at_handoff(drain_mailbox(&mut receiver));
+        let written = written
+            .into_iter()
+            .map(|msg| match msg {
+                SinkCommand::Client(cmd, _, _) => cmd.slot(),
+                _ => panic!("expected an event"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(written, vec![0, 1]);
+
+        // The buffer, holding the events written before the handoff, comes first.
+        let (reply, rest) = handoff.expect("the handoff must be answered");
+        let buffered = ClientCommand::InsertAccountUpdate(AccountUpdate {
+            slot: 1,
+            ..AccountUpdate::zero_account()
+        });
+        let mut pending = vec![buffered];
+        append_handed_off(&mut pending, rest, [0]);
+        reply.send(pending).unwrap();
+        let handed_off = handed_off
+            .try_recv()
+            .unwrap()
+            .iter()
+            .map(ClientCommand::slot)
+            .collect::<Vec<_>>();
+        assert_eq!(handed_off, vec![1, 2]);
+
+        let (written, handoff) = split_at_handoff(vec![event(3)]);
+        assert_eq!(written.len(), 1);
+        assert!(handoff.is_none());
+    }
+
+    #[test]
+    fn dropped_event_is_acknowledged_and_not_replayed() {
+        let dir = std::env::temp_dir().join(format!("dropped-event-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let cmd = ClientCommand::InsertAccountUpdate(AccountUpdate::zero_account());
+        let (router_wal, _) = RouterWal::open(&dir, [0]).unwrap();
+        let router_wal = Arc::new(Mutex::new(router_wal));
+        let wal_seq = router_wal.lock().unwrap().append(&cmd).unwrap();
+
+        let (ack_sender, mut acks) = mpsc::unbounded_channel();
+        drop_submission(
+            (cmd.clone(), Some(7), Some(wal_seq)),
+            Some(&ack_sender),
+            Some(&router_wal),
+        );
+        drop_submission((cmd, None, None), Some(&ack_sender), Some(&router_wal));
+        assert_eq!(acks.try_recv().unwrap(), vec![SubmissionAck::Dropped(7)]);
+        assert!(acks.try_recv().is_err());
+
+        drop(router_wal);
+        let (_, replayed) = RouterWal::open(&dir, [0]).unwrap();
+        assert!(replayed.is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn dead_shard_is_replaced_or_fails_the_router_per_policy() {
+        let shard = |task| {
+            let (mailbox, _) = mpsc::channel(1);
+            ShardHandle {
+                mailbox,
+                task,
+                durability_lag: None,
+                overflow: Default::default(),
+                overflow_capacity: 2,
+            }
+        };
+        let dying_shard = || {
+            let mut shard = shard(ShardTask::Dedicated(tokio::spawn(async {
+                anyhow::bail!("shard daemon died")
+            })));
+            let update = AccountUpdate {
+                slot: 7,
+                ..AccountUpdate::zero_account()
+            };
+            shard
+                .overflow
+                .push_back((ClientCommand::InsertAccountUpdate(update), Some(1), None));
+            shard
+        };
+        let respawned = || {
+            let (_, outcome) = oneshot::channel();
+            future::ready(Ok(shard(ShardTask::Grouped(outcome))))
+        };
 
-    let mut current_period_foreach_shard = session
-        .query(query_last_period_commit, (producer_id,))
-        .await?
-        .rows_typed_or_empty::<(ShardId, ShardPeriod)>()
-        .map(|result| result.map(|(shard_id, period)| (shard_id, period + 1)))
-        .collect::<Result<BTreeMap<_, _>, _>>()?;
+        // The replacement takes over the events held for the dead shard.
+        let failed_shard = OnceLock::new();
+        let mut dead = dying_shard();
+        let policy = ShardFailurePolicy::Restart;
+        assert!(replace_dead_shard(&mut dead, 3, policy, respawned, &failed_shard).await);
+        assert!(matches!(dead.task, ShardTask::Grouped(_)));
+        let held = dead.overflow.iter().map(|(cmd, id, _)| (cmd.slot(), *id));
+        assert_eq!(held.collect::<Vec<_>>(), vec![(7, Some(1))]);
+        assert_eq!(failed_shard.get(), None);
 
-    for shard_id in 0..num_shards {
-        // Put period 0 by default for each missing shard.
-        current_period_foreach_shard
-            .entry(shard_id as ShardId)
-            .or_insert(0);
+        // A shard failing to respawn fails the router like with FailFast.
+        let failed_respawn = || future::ready(Err(anyhow::anyhow!("cluster unreachable")));
+        let mut dead = dying_shard();
+        assert!(!replace_dead_shard(&mut dead, 3, policy, failed_respawn, &failed_shard).await);
+        assert_eq!(failed_shard.get(), Some(&3));
+
+        let failed_shard = OnceLock::new();
+        let mut dead = dying_shard();
+        let policy = ShardFailurePolicy::FailFast;
+        assert!(!replace_dead_shard(&mut dead, 5, policy, respawned, &failed_shard).await);
+        assert_eq!(failed_shard.get(), Some(&5));
     }
 
-    let query_max_offset_for_shard_period = r###"
-        SELECT
-            offset
-        FROM log
-        WHERE 
-            producer_id = ?
-            AND shard_id = ?
-            and period = ?
-        ORDER BY offset desc
-        PER PARTITION LIMIT 1        
-    "###;
-    let max_offset_for_shard_period_ps = session.prepare(query_max_offset_for_shard_period).await?;
+    #[tokio::test(start_paused = true)]
+    async fn router_waits_for_a_lagging_shard_to_flush() {
+        let (mailbox, mut receiver) = mpsc::channel(2);
+        let (_, outcome) = oneshot::channel();
+        let (lag_sender, durability_lag) = watch::channel(3);
+        let mut shard = ShardHandle {
+            mailbox,
+            task: ShardTask::Grouped(outcome),
+            durability_lag: Some(durability_lag),
+            overflow: Default::default(),
+            overflow_capacity: 2,
+        };
 
-    //let mut js: JoinSet<anyhow::Result<(i16, i64)>> = JoinSet::new();
-    let mut shard_max_offset_pairs =
-        futures::future::try_join_all(current_period_foreach_shard.iter().map(
-            |(shard_id, curr_period)| {
-                let ps = max_offset_for_shard_period_ps.clone();
-                let session = Arc::clone(&session);
-                async move {
-                    let max_offset = session
-                        .execute(&ps, (producer_id, shard_id, curr_period))
-                        .await?
-                        .maybe_first_row_typed::<(ShardOffset,)>()?
-                        .map(|tuple| tuple.0)
-                        // If row is None, it means no period has started since the last period commit.
-                        // So we seek at the end of the previous period.
-                        .unwrap_or((curr_period * SHARD_OFFSET_MODULO) - 1);
-                    Ok::<_, anyhow::Error>((*shard_id, max_offset))
-                }
-            },
-        ))
-        .await?;
+        // Not throttled below the limit nor without one.
+        shard.wait_durability_lag(Some(4), [0]).await;
+        shard.wait_durability_lag(None, [0]).await;
+        assert!(receiver.try_recv().is_err());
 
-    if shard_max_offset_pairs.len() != num_shards {
-        panic!("missing shard period commit information, make sure the period commit is initialize before computing shard offsets");
+        let wait = shard.wait_durability_lag(Some(3), [0]);
+        tokio::pin!(wait);
+        assert!(tokio::time::timeout(Duration::from_secs(60), &mut wait)
+            .await
+            .is_err());
+        assert!(matches!(receiver.try_recv(), Ok(SinkCommand::Flush(_))));
+        // The flush brings the lag back under the limit.
+        lag_sender.send(2).unwrap();
+        wait.await;
     }
 
-    shard_max_offset_pairs.sort_by_key(|pair| pair.0);
+    #[tokio::test]
+    async fn flush_fails_unless_every_shard_acknowledges_it() {
+        let shard = |mailbox| {
+            let (_, outcome) = oneshot::channel();
+            ShardHandle {
+                mailbox,
+                task: ShardTask::Grouped(outcome),
+                durability_lag: None,
+                overflow: Default::default(),
+                overflow_capacity: 1,
+            }
+        };
+        // Acknowledges its flushes, or drops them if `ack` is false.
+        let spawn_shard = |ack| {
+            let (mailbox, mut receiver) = mpsc::channel(1);
+            tokio::spawn(async move {
+                while let Some(msg) = receiver.recv().await {
+                    if let (SinkCommand::Flush(reply), true) = (msg, ack) {
+                        let _ = reply.send(());
+                    }
+                }
+            });
+            shard(mailbox)
+        };
 
-    Ok(shard_max_offset_pairs)
-}
+        let mut shards = vec![spawn_shard(true), spawn_shard(true)];
+        flush_all(&mut shards, [0]).await.unwrap();
 
-/// Spawns a round-robin dispatcher for sending `ClientCommand` messages to a list of shard mailboxes.
-///
-/// This function takes a vector of shard mailboxes (`tokio::sync::mpsc::Sender<ClientCommand>`) and returns
-/// a new `Sender` that can be used to dispatch messages in a round-robin fashion to the provided shard mailboxes.
-///
-/// The dispatcher cycles through the shard mailboxes indefinitely, ensuring each message is sent to the next
-/// available shard without waiting, or falling back to the original shard if all are busy. It increments the
-/// ScyllaDB batch request lag for monitoring purposes.
-///
-/// # Parameters
-/// - `shard_mailboxes`: A vector of `Sender` channels representing shard mailboxes to dispatch messages to.
-///
-/// # Returns
-/// A `Sender` channel that can be used to send `ClientCommand` messages to the shard mailboxes in a round-robin manner.
-fn spawn_round_robin(
-    session: Arc<Session>,
-    producer_id: ProducerId,
-    shard_mailboxes: Vec<tokio::sync::mpsc::Sender<ClientCommand>>,
-) -> (
-    tokio::sync::mpsc::Sender<ClientCommand>,
-    JoinHandle<anyhow::Result<()>>,
-) {
-    let (sender, mut receiver) = tokio::sync::mpsc::channel(DEFAULT_SHARD_MAX_BUFFER_CAPACITY);
+        shards.push(spawn_shard(false));
+        let error = flush_all(&mut shards, [0]).await.unwrap_err();
+        assert_eq!(error.to_string(), "only 2 out of 3 shards flushed");
 
-    let h: JoinHandle<anyhow::Result<()>> = tokio::spawn(async move {
-        let insert_slot_ps = session.prepare(INSERT_PRODUCER_SLOT).await?;
+        // A shard whose mailbox is closed can not flush either.
+        let (mailbox, _) = mpsc::channel(1);
+        let mut shards = vec![spawn_shard(true), shard(mailbox)];
+        let error = flush_all(&mut shards, [0]).await.unwrap_err();
+        assert_eq!(error.to_string(), "only 1 out of 2 shards flushed");
+    }
 
-        //session.execute(&insert_slot_ps, (producer_id,)).await?;
+    #[tokio::test(start_paused = true)]
+    async fn lock_is_rotated_while_the_router_waits_on_a_saturated_shard() {
+        let (mailbox, _receiver) = mpsc::channel(1);
+        mailbox.try_send(SinkCommand::Shutdown).unwrap();
+        // Stands for the router, stuck until the shard takes a command from its full mailbox.
+        let router = tokio::spawn(async move { mailbox.reserve().await.is_ok() });
 
-        let iterator = shard_mailboxes.iter().enumerate().cycle();
-        info!("Started round robin router");
-        let mut msg_between_slot = 0;
-        let mut max_slot_seen = -1;
-        let mut time_since_new_max_slot = Instant::now();
-        let mut background_commit_max_slot_seen =
-            tokio::spawn(future::ready(Ok::<(), anyhow::Error>(())));
-        for (i, shard_sender) in iterator {
-            let msg = receiver.recv().await.unwrap_or(ClientCommand::Shutdown);
-            if msg == ClientCommand::Shutdown {
-                warn!("round robin router's mailbox closed unexpectly.");
-                break;
+        let rotations = Arc::new(AtomicUsize::new(0));
+        let rotate = {
+            let rotations = Arc::clone(&rotations);
+            move || {
+                rotations.fetch_add(1, Ordering::Relaxed);
+                future::ready(Ok(()))
             }
-            let slot = match &msg {
-                ClientCommand::Shutdown => -1,
-                ClientCommand::InsertAccountUpdate(x) => x.slot,
-                ClientCommand::InsertTransaction(x) => x.slot,
-            };
-            if max_slot_seen < slot {
-                max_slot_seen = slot;
-                let time_elapsed_between_last_max_slot = time_since_new_max_slot.elapsed();
-                // We only commit every 3 slot number
+        };
+        let keepalive = ProducerKeepalive {
+            producer_id: [0],
+            heartbeat: None,
+            lock_rotation: Some(LockRotation::new(
+                rotate,
+                Duration::from_secs(1),
+                Some(Duration::from_secs(3)),
+            )),
+        };
+        let (lock_lost_sender, lock_lost) = watch::channel(false);
+        let keepalive = spawn_producer_keepalive(keepalive, lock_lost_sender);
 
-                let t = Instant::now();
-                background_commit_max_slot_seen.await??;
+        tokio::time::sleep(Duration::from_millis(10_500)).await;
+        assert!(!router.is_finished());
+        assert_eq!(rotations.load(Ordering::Relaxed), 10);
+        assert!(!*lock_lost.borrow());
+        keepalive.stop().await;
+    }
 
-                let session = Arc::clone(&session);
-                let insert_slot_ps = insert_slot_ps.clone();
-                background_commit_max_slot_seen = tokio::spawn(async move {
-                    session
-                        .execute(&insert_slot_ps, (producer_id, slot))
-                        .await?;
+    #[tokio::test(start_paused = true)]
+    async fn stalled_rotation_loses_the_lock_once_the_ttl_elapsed() {
+        let keepalive = ProducerKeepalive {
+            producer_id: [0],
+            heartbeat: None,
+            lock_rotation: Some(LockRotation::new(
+                future::pending,
+                Duration::from_secs(1),
+                Some(Duration::from_secs(3)),
+            )),
+        };
+        let (lock_lost_sender, mut lock_lost) = watch::channel(false);
+        let started_at = Instant::now();
+        let keepalive = spawn_producer_keepalive(keepalive, lock_lost_sender);
 
-                    let time_to_commit_slot = t.elapsed();
-                    info!(
-                        "New slot: {} after {time_elapsed_between_last_max_slot:?}, events in between: {}, max_slot_approx committed in {time_to_commit_slot:?}",
-                        slot, msg_between_slot
-                    );
-                    Ok(())
-                });
-                time_since_new_max_slot = Instant::now();
-                msg_between_slot = 0;
+        // The rotation started after a second is abandoned once the lock acquired at start expired.
+        lock_lost.wait_for(|lost| *lost).await.unwrap();
+        assert_eq!(started_at.elapsed(), Duration::from_secs(3));
+        let error = keepalive.handle.await.unwrap().unwrap_err();
+        assert!(format!("{error:#}").contains("expired"), "{error:#}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn swapped_session_carries_the_next_rotations_and_the_release() {
+        let (session_sender, session) = SharedSession::new(Arc::new("first"));
+        let used = Arc::new(Mutex::new(Vec::new()));
+        let rotate = {
+            let session = session.clone();
+            let used = Arc::clone(&used);
+            move || {
+                used.lock().unwrap().push(*session.current());
+                future::ready(Ok(()))
             }
-            msg_between_slot += 1;
-            let result = shard_sender.reserve().await;
-            if let Ok(permit) = result {
-                permit.send(msg);
-                scylladb_batch_request_lag_inc();
-            } else {
-                error!("shard {} seems to be closed: {:?}", i, result);
-                break;
+        };
+        let keepalive = ProducerKeepalive {
+            producer_id: [0],
+            heartbeat: None,
+            lock_rotation: Some(LockRotation::new(rotate, Duration::from_secs(1), None)),
+        };
+        let (lock_lost_sender, lock_lost) = watch::channel(false);
+        let keepalive = spawn_producer_keepalive(keepalive, lock_lost_sender);
+
+        tokio::time::sleep(Duration::from_millis(1_500)).await;
+        let in_flight = session.current();
+        session_sender.send_replace(Arc::new("second"));
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        keepalive.stop().await;
+        // The release goes through the session of the sink once the keepalive stopped.
+        used.lock().unwrap().push(*session.current());
+
+        assert_eq!(*in_flight, "first");
+        assert_eq!(*used.lock().unwrap(), ["first", "second", "second"]);
+        assert!(!*lock_lost.borrow());
+    }
+
+    #[test]
+    fn probe_sentinel_is_out_of_the_offsets_of_its_partition() {
+        for partitions_per_period in [1, 4] {
+            let partition_len = SHARD_OFFSET_MODULO / partitions_per_period as i64;
+            for next_offset in [
+                0,
+                1,
+                partition_len - 1,
+                partition_len,
+                3 * partition_len + 7,
+            ] {
+                let (period, offset) = probe_sentinel(next_offset, partitions_per_period);
+                assert_eq!(period, log_partition(next_offset, partitions_per_period));
+                // The offset recovery resumes right after a leftover sentinel, in the same partition.
+                assert_eq!(log_partition(offset + 1, partitions_per_period), period);
+                assert!(offset < next_offset);
             }
         }
-        // Send shutdown to all shards
-        for (i, shard_sender) in shard_mailboxes.iter().enumerate() {
-            warn!("Shutting down shard: {}", i);
-            shard_sender.send(ClientCommand::Shutdown).await?;
+    }
+
+    #[test]
+    fn shard_mailbox_is_drained_after_shutdown() {
+        let (sender, mut receiver) = mpsc::channel(16);
+        let updates = (0..5)
+            .map(|slot| AccountUpdate {
+                slot,
+                ..AccountUpdate::zero_account()
+            })
+            .collect::<Vec<_>>();
+
+        sender.try_send(SinkCommand::Shutdown).unwrap();
+        for update in updates.iter() {
+            sender
+                .try_send(SinkCommand::Client(
+                    ClientCommand::InsertAccountUpdate(update.clone()),
+                    None,
+                    None,
+                ))
+                .unwrap();
         }
+        assert!(matches!(receiver.try_recv(), Ok(SinkCommand::Shutdown)));
 
-        warn!("End of round robin router");
-        Ok(())
-    });
-    (sender, h)
-}
+        let remaining = drain_mailbox(&mut receiver)
+            .into_iter()
+            .map(|msg| match msg {
+                SinkCommand::Client(ClientCommand::InsertAccountUpdate(update), _, _) => update,
+                _ => panic!("unexpected command in mailbox"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(remaining, updates);
 
-async fn get_producer_info_by_id(
-    session: Arc<Session>,
-    producer_id: ProducerId,
-) -> anyhow::Result<Option<ProducerInfo>> {
-    session
-        .query(GET_PRODUCER_INFO_BY_ID, (producer_id,))
-        .await?
-        .maybe_first_row_typed::<ProducerInfo>()
-        .map_err(anyhow::Error::new)
-}
+        // The mailbox is closed, late commands are rejected instead of being silently lost.
+        assert!(sender.try_send(SinkCommand::Shutdown).is_err());
+    }
 
-struct ProducerLock {
-    session: Arc<Session>,
-    lock_id: String,
-    producer_id: ProducerId,
-}
+    #[test]
+    fn control_keyspace_only_qualifies_metadata_tables() {
+        assert_eq!(
+            in_control_keyspace(COMMIT_SHARD_PERIOD, None),
+            COMMIT_SHARD_PERIOD
+        );
+        let query = in_control_keyspace(COMMIT_SHARD_PERIOD, Some("control"));
+        assert!(query.contains("INTO control.producer_period_commit_log ("));
+        assert!(query.contains("(producer_id, shard_id, period, created_at)"));
+        let insert_blockchain_event = insert_blockchain_event_query(None, false, None, false);
+        assert_eq!(
+            in_control_keyspace(&insert_blockchain_event, Some("control")),
+            insert_blockchain_event
+        );
+    }
 
-impl ProducerLock {
-    async fn release(self) -> anyhow::Result<()> {
-        self.session
-            .query(DROP_PRODUCER_LOCK, (self.producer_id, self.lock_id))
-            .await
-            .map(|_query_result| ())
-            .map_err(anyhow::Error::new)
+    #[test]
+    fn client_write_timestamps_follow_slots_then_transaction_indexes() {
+        let event = |slot, write_version, tx_index| {
+            let mut event = AccountUpdate::zero_account().as_blockchain_event(0, [0], 0);
+            event.slot = slot;
+            event.write_version = write_version;
+            event.tx_index = tx_index;
+            client_write_timestamp(&event)
+        };
+        // Account updates of a slot share a timestamp whatever their write versions.
+        assert_eq!(event(7, Some(1 << 20), None), event(7, Some(1), None));
+        assert!(event(7, None, Some(1)) < event(7, None, Some(2)));
+        assert!(event(7, None, Some(i64::MAX)) < event(8, None, Some(0)));
+        assert!(event(7, Some(i64::MAX), None) < event(8, Some(0), None));
     }
-}
 
-async fn try_acquire_lock(
-    session: Arc<Session>,
-    producer_id: ProducerId,
-    ifname: Option<String>,
-) -> anyhow::Result<ProducerLock> {
-    let network_interfaces = list_afinet_netifas()?;
+    #[test]
+    fn masked_columns_are_left_out_of_the_insert() {
+        let query = insert_blockchain_event_query(None, false, None, false);
+        assert!(query.contains(" rent_epoch, "));
+        assert_eq!(query.matches('?').count(), 43);
+        assert!(!query.contains("raw_proto"));
+        let query = insert_blockchain_event_query(None, false, None, true);
+        assert!(query.contains(" raw_proto, "));
+        assert_eq!(query.matches('?').count(), 44);
+        let query = insert_blockchain_event_query(None, true, None, false);
+        assert!(query.ends_with("USING TIMESTAMP ?"));
+        assert_eq!(query.matches('?').count(), 44);
+        let query = insert_blockchain_event_query(
+            None,
+            true,
+            Some(Duration::from_millis(86_400_500)),
+            false,
+        );
+        assert!(query.ends_with("USING TIMESTAMP ? AND TTL 86400"));
+        // Rounded down to `TTL 0`, the rows would never expire.
+        assert!(check_row_ttl(Some(Duration::from_millis(999))).is_err());
+        assert!(check_row_ttl(Some(Duration::ZERO)).is_err());
+        assert!(check_row_ttl(Some(Duration::from_secs(1))).is_ok());
+        assert!(check_row_ttl(Some(Duration::from_millis(630_720_000_999))).is_ok());
+        assert!(check_row_ttl(Some(Duration::from_secs(630_720_001))).is_err());
+        assert!(check_row_ttl(None).is_ok());
 
-    let (ifname, ipaddr) = if let Some(ifname) = ifname {
-        if let Some((_, ipaddr)) = network_interfaces
-            .iter()
-            .find(|(name, ipaddr)| *name == ifname && matches!(ipaddr, IpAddr::V4(_)))
-        {
-            (ifname, ipaddr.to_string())
-        } else {
-            anyhow::bail!("Found not interface named {}", ifname);
-        }
-    } else {
-        let ipaddr = local_ip()?;
-        if !ipaddr.is_ipv4() {
-            anyhow::bail!("ipv6 not support for producer lock info.");
-        }
-        if let Some((ifname, _)) = network_interfaces
-            .iter()
-            .find(|(_, ipaddr2)| ipaddr == *ipaddr2)
-        {
-            (ifname.to_owned(), ipaddr.to_string())
-        } else {
-            anyhow::bail!("Found not interface matching ip {}", ipaddr);
+        let column_mask = [OptionalColumn::Executable].into_iter().collect();
+        let query = insert_blockchain_event_query(Some(&column_mask), false, None, false);
+        assert!(query.contains(" executable, "));
+        for column in [
+            "rent_epoch",
+            "txn_signature",
+            "recent_blockhash",
+            "ingested_at",
+        ] {
+            assert!(!query.contains(column), "{column} should be masked");
         }
-    };
+        assert!(query.starts_with("INSERT INTO log (shard_id, period, producer_id, offset, slot,"));
+        assert_eq!(query.matches('?').count(), 39);
+    }
 
-    let lock_id = Uuid::new_v4().to_string();
-    let qr = session
-        .query(
-            TRY_ACQUIRE_PRODUCER_LOCK,
-            (producer_id, lock_id.clone(), ifname, ipaddr),
-        )
-        .await?;
-    let lwt_success = qr.single_row_typed::<LwtSuccess>()?;
+    #[test]
+    fn lock_expires_once_not_rotated_for_the_expiry() {
+        let holder = LockHolder {
+            lock_id: "lock".to_owned(),
+            ifname: None,
+            ipv4: None,
+            created_at: None,
+            lock_written_at: Some(1_000_000),
+        };
+        let expiry = Duration::from_secs(10);
+        assert!(!holder.is_expired(expiry, 10_999_999));
+        assert!(holder.is_expired(expiry, 11_000_000));
 
-    if let LwtSuccess(true) = lwt_success {
-        let lock = ProducerLock {
-            session: Arc::clone(&session),
-            lock_id,
-            producer_id,
+        let unknown = LockHolder {
+            lock_written_at: None,
+            ..holder
         };
-        Ok(lock)
-    } else {
-        anyhow::bail!(
-            "Failed to lock producer {:?}, you may need to release it manually",
-            producer_id
+        assert!(!unknown.is_expired(expiry, i64::MAX));
+    }
+
+    #[test]
+    fn log_by_slot_insert_binds_the_bucket_first() {
+        let query = insert_log_by_slot_query(&insert_blockchain_event_query(
+            None,
+            true,
+            Some(Duration::from_secs(60)),
+            false,
+        ));
+        assert!(query.starts_with("INSERT INTO log_by_slot (slot_bucket, shard_id, period,"));
+        assert!(query.contains(" VALUES (?, ?, "));
+        assert!(query.ends_with("USING TIMESTAMP ? AND TTL 60"));
+        assert_eq!(query.matches('?').count(), 45);
+
+        assert_eq!(slot_bucket(0, 1000), 0);
+        assert_eq!(slot_bucket(999, 1000), 0);
+        assert_eq!(slot_bucket(1000, 1000), 1);
+        assert_eq!(slot_bucket(-1, 1000), -1);
+    }
+
+    #[test]
+    fn raw_proto_is_the_message_the_event_was_received_as() {
+        let msg = SubscribeUpdateAccount::try_from(AccountUpdate {
+            slot: 42,
+            lamports: 7,
+            data: vec![1; 32],
+            ..AccountUpdate::zero_account()
+        })
+        .unwrap();
+        let raw_proto = msg.encode_to_vec();
+        let mut update = AccountUpdate::try_from(msg).unwrap();
+        update.raw_proto = Some(raw_proto.clone());
+
+        let event = update.clone().as_blockchain_event(0, [0], 7);
+        assert_eq!(event.raw_proto.as_ref(), Some(&raw_proto));
+        let msg = SubscribeUpdateAccount::decode(raw_proto.as_slice()).unwrap();
+        assert_eq!(
+            AccountUpdate {
+                raw_proto: Some(raw_proto),
+                ..AccountUpdate::try_from(msg).unwrap()
+            },
+            update
         );
+
+        let mut cmd = ClientCommand::from(event);
+        cmd.clear_raw_proto();
+        let ClientCommand::InsertAccountUpdate(update) = cmd else {
+            panic!("an account update must stay an account update");
+        };
+        assert_eq!(update.raw_proto, None);
     }
-}
 
-impl ScyllaSink {
-    pub async fn new(
-        config: ScyllaSinkConfig,
-        hostname: impl AsRef<str>,
-        username: impl Into<String>,
-        password: impl Into<String>,
-    ) -> anyhow::Result<Self> {
-        let producer_id = [config.producer_id];
+    #[test]
+    fn orphaned_shards_are_looked_up_in_bounded_chunks_above_the_producer_shards() {
+        let chunks = orphaned_shard_candidates(4);
+        assert!(chunks
+            .iter()
+            .all(|chunk| chunk.len() <= ORPHANED_SHARD_SCAN_CHUNK));
+        let shard_ids = chunks.concat();
+        assert_eq!(
+            shard_ids,
+            (4..4 + ORPHANED_SHARD_SCAN_LEN).collect::<Vec<_>>()
+        );
 
-        let session: Session = SessionBuilder::new()
-            .known_node(hostname)
-            .user(username, password)
-            .compression(Some(Compression::Lz4))
-            .use_keyspace(config.keyspace.clone(), false)
-            .build()
-            .await?;
-        info!("connection pool to scylladb ready.");
-        let session = Arc::new(session);
+        // The range stops at the largest shard id.
+        let last = orphaned_shard_candidates(ShardId::MAX - 1).concat();
+        assert_eq!(last, vec![ShardId::MAX - 1]);
+    }
 
-        let producer_info = get_producer_info_by_id(Arc::clone(&session), producer_id)
-            .await?
-            .unwrap_or_else(|| panic!("producer {:?} has not yet been registered", producer_id));
+    #[test]
+    fn log_columns_resolve_to_their_slot() {
+        assert!(matches!(
+            ColumnSlot::resolve(WRITE_TIMESTAMP_MARKER),
+            Ok(ColumnSlot::WriteTimestamp)
+        ));
+        assert!(matches!(
+            ColumnSlot::resolve("slot_bucket"),
+            Ok(ColumnSlot::SlotBucket)
+        ));
+        assert!(matches!(
+            ColumnSlot::resolve("pubkey"),
+            Ok(ColumnSlot::Field(_))
+        ));
+        assert_eq!(
+            ColumnSlot::resolve("pubkeys").err().map(|e| e.to_string()),
+            Some("no field of the event matches column pubkeys".to_owned())
+        );
+    }
 
-        info!("Producer {producer_id:?} is registered");
+    #[test]
+    fn compressed_bytes_are_counted_once_per_event() {
+        let events = (0..2)
+            .map(|offset| {
+                let mut event = AccountUpdate::zero_account().as_blockchain_event(0, [0], offset);
+                event.data = Some(vec![0; 1024]);
+                event
+            })
+            .collect::<Vec<_>>();
 
-        let producer_lock =
-            try_acquire_lock(Arc::clone(&session), producer_id, config.ifname.to_owned()).await?;
+        let (compressed, [(_, data), (_, tx_nested)]) = compress_rows(&events, 0, 0);
+        assert!(compressed
+            .iter()
+            .all(|event| event.data_codec == Some(DataCodec::Lz4)));
+        assert_eq!(data.0, 2048);
+        assert!(data.1 < data.0);
+        assert_eq!(tx_nested, (0, 0));
 
-        info!("Producer {producer_id:?} lock acquired!");
+        // The retry of a flush that failed after compressing the first event only counts the second one.
+        let (compressed, [(_, retried), _]) = compress_rows(&events, 0, 1);
+        assert!(compressed
+            .iter()
+            .all(|event| event.data_codec == Some(DataCodec::Lz4)));
+        assert_eq!(retried, (data.0 / 2, data.1 / 2));
+    }
 
-        let shard_count = producer_info.num_shards as usize;
+    #[test]
+    fn offset_gap_reports_the_first_non_contiguous_event() {
+        let events = [10, 11, 13, 14]
+            .into_iter()
+            .map(|offset| AccountUpdate::zero_account().as_blockchain_event(0, [0], offset))
+            .collect::<Vec<_>>();
+        assert_eq!(offset_gap(10, &events[..2]), None);
+        assert_eq!(offset_gap(10, &events), Some((12, 13)));
+        assert_eq!(offset_gap(9, &events), Some((9, 10)));
+        assert_eq!(offset_gap(10, &[]), None);
+    }
 
-        info!("init producer {producer_id:?} period commit log successful.");
+    #[test]
+    fn periods_are_split_into_contiguous_partitions() {
+        let partition_len = SHARD_OFFSET_MODULO / 4;
+        assert_eq!(log_partition(partition_len - 1, 4), 0);
+        assert_eq!(log_partition(partition_len, 4), 1);
+        assert_eq!(log_partition(SHARD_OFFSET_MODULO, 4), 4);
+        assert_eq!(log_partition(SHARD_OFFSET_MODULO, 1), 1);
 
-        let mut sharders = vec![];
+        assert!(!starts_new_partition(0, 4));
+        assert!(!starts_new_partition(partition_len - 1, 4));
+        assert!(starts_new_partition(partition_len, 4));
+        assert!(!starts_new_partition(partition_len, 1));
+        assert!(starts_new_partition(SHARD_OFFSET_MODULO, 1));
+    }
 
-        let shard_offsets =
-            get_max_shard_offsets_for_producer(Arc::clone(&session), producer_id, shard_count)
-                .await?;
+    #[test]
+    fn buffer_is_flushed_before_the_first_offset_of_a_new_partition_or_period() {
+        for partitions_per_period in [1, 4] {
+            // Replays the steps of `Shard::reserve_offset` then buffers the offset.
+            let (mut buffer, mut batches, mut committed_periods) = (vec![], vec![], vec![]);
+            for offset in 0..SHARD_OFFSET_MODULO + 2 {
+                let boundary = OffsetBoundary::of(offset, partitions_per_period);
+                if boundary.requires_flush() {
+                    batches.push(std::mem::take(&mut buffer));
+                }
+                if boundary == OffsetBoundary::Period {
+                    assert!(buffer.is_empty(), "period committed with buffered events");
+                    committed_periods.push(offset / SHARD_OFFSET_MODULO - 1);
+                }
+                buffer.push(offset);
+            }
+            batches.push(buffer);
 
-        info!("Got back last offsets of all {shard_count} shards");
-        let mut shard_handles = Vec::with_capacity(shard_count);
-        for (shard_id, last_offset) in shard_offsets.into_iter() {
-            let session = Arc::clone(&session);
-            let shard = Shard::new(
-                session,
-                shard_id,
-                producer_id,
-                last_offset + 1,
-                DEFAULT_SHARD_MAX_BUFFER_CAPACITY,
-                config.batch_size_kb_limit * 1024,
-                config.linger,
-            );
-            let (shard_mailbox, shard_handle) = shard.into_daemon();
-            shard_handles.push(shard_handle);
-            sharders.push(shard_mailbox);
+            assert_eq!(committed_periods, vec![0]);
+            assert_eq!(batches.len(), partitions_per_period as usize + 1);
+            for batch in batches {
+                let partitions = batch
+                    .iter()
+                    .map(|offset| log_partition(*offset, partitions_per_period))
+                    .collect::<BTreeSet<_>>();
+                assert_eq!(
+                    partitions.len(),
+                    1,
+                    "batch {batch:?} spans several partitions"
+                );
+            }
         }
+    }
 
-        let (sender, router_handle) =
-            spawn_round_robin(Arc::clone(&session), producer_id, sharders);
+    #[test]
+    fn only_the_first_offset_of_a_period_starts_a_new_one() {
+        assert!(!starts_new_period(0));
+        assert!(!starts_new_period(SHARD_OFFSET_MODULO - 1));
+        assert!(starts_new_period(SHARD_OFFSET_MODULO));
+        assert!(!starts_new_period(SHARD_OFFSET_MODULO + 1));
+        assert!(starts_new_period(3 * SHARD_OFFSET_MODULO));
+    }
 
-        Ok(ScyllaSink {
-            router_sender: sender,
-            router_handle,
-            shard_handles,
-            producer_lock,
-        })
+    #[test]
+    fn slot_regression_beyond_the_threshold_is_reported() {
+        let mut check = SlotRegressionCheck::new(10);
+        assert_eq!(check.record(100), None);
+        // Forks and late events within the threshold are fine.
+        assert_eq!(check.record(90), None);
+        assert_eq!(check.record(89), Some(100));
+        // A reported slot does not lower the highest slot seen.
+        assert_eq!(check.record(95), None);
+        assert_eq!(check.record(120), None);
+        assert_eq!(check.record(100), Some(120));
     }
 
-    pub async fn shutdown(self) -> anyhow::Result<()> {
-        warn!("Shutthing down scylla sink...");
-        let router_result = self.router_sender.send(ClientCommand::Shutdown).await;
-        if router_result.is_err() {
-            error!("router was closed before we could gracefully shutdown all sharders. Sharder should terminate on their own...")
+    #[test]
+    fn least_recently_seen_signature_is_forgotten_first() {
+        let mut seen = SeenSignatures::new(NonZeroUsize::new(2).unwrap());
+        seen.insert(vec![1], 10);
+        seen.insert(vec![2], 11);
+        assert_eq!(seen.check(&[1]), Some(10));
+        seen.insert(vec![3], 12);
+        assert_eq!(seen.check(&[2]), None);
+        assert_eq!(seen.check(&[1]), Some(10));
+        assert_eq!(seen.check(&[3]), Some(12));
+
+        for _ in 0..10 {
+            seen.check(&[1]);
         }
-        if let Ok(Err(e)) = self.router_handle.await {
-            error!("Router error: {e:?}");
+        assert!(seen.sightings.len() <= 4);
+        assert_eq!(seen.last_seen.len(), 2);
+    }
+
+    #[test]
+    fn single_item_batch_ratio_rolls_over_the_last_flushes() {
+        let mut ratio = SingleItemBatchRatio::default();
+        for _ in 1..SINGLE_ITEM_BATCH_WINDOW {
+            assert_eq!(ratio.record(1), None);
         }
-        for (i, shard_handle) in self.shard_handles.into_iter().enumerate() {
-            if let Ok(Err(e)) = shard_handle.await {
-                error!("shard {i} error: {e:?}");
-            }
+        assert_eq!(ratio.record(10), Some(0.99));
+        for _ in 0..SINGLE_ITEM_BATCH_WINDOW / 2 {
+            ratio.record(10);
         }
-        self.producer_lock.release().await?;
-        Ok(())
+        assert_eq!(ratio.record(1), Some(0.49));
     }
 
-    async fn inner_log(&mut self, cmd: ClientCommand) -> anyhow::Result<()> {
-        self.router_sender
-            .send(cmd)
-            .await
-            .map_err(|_e| anyhow::anyhow!("failed to route"))
+    #[test]
+    fn retry_backoff_doubles_until_attempts_are_exhausted() {
+        let retry_policy = RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(300),
+        };
+        let backoffs = (0..=5)
+            .map(|attempt| retry_policy.backoff(attempt))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            backoffs,
+            vec![
+                Some(Duration::from_millis(100)),
+                Some(Duration::from_millis(100)),
+                Some(Duration::from_millis(200)),
+                Some(Duration::from_millis(300)),
+                Some(Duration::from_millis(300)),
+                None,
+            ]
+        );
     }
 
-    pub async fn log_account_update(&mut self, update: AccountUpdate) -> anyhow::Result<()> {
-        let cmd = ClientCommand::InsertAccountUpdate(update);
-        self.inner_log(cmd).await
+    #[test]
+    fn oversized_account_data_is_rejected_or_truncated() {
+        let update = AccountUpdate {
+            data: vec![1; 16],
+            ..AccountUpdate::zero_account()
+        };
+        let mut limits = FieldSizeLimits {
+            max_account_data_bytes: Some(10),
+            ..Default::default()
+        };
+        let oversized = FieldTooLarge {
+            field: "data",
+            size: 16,
+            limit: 10,
+        };
+
+        let mut cmd = ClientCommand::InsertAccountUpdate(update.clone());
+        assert_eq!(limits.enforce(&mut cmd), Err(oversized));
+        assert_eq!(cmd, ClientCommand::InsertAccountUpdate(update.clone()));
+
+        limits.oversized_field_action = OversizedFieldAction::Truncate;
+        assert_eq!(limits.enforce(&mut cmd), Ok(Some(oversized)));
+        let ClientCommand::InsertAccountUpdate(truncated) = cmd else {
+            panic!("expected an account update");
+        };
+        assert_eq!(truncated.data, vec![1; 10]);
+        assert!(truncated.truncated);
     }
 
-    pub async fn log_transaction(&mut self, tx: Transaction) -> anyhow::Result<()> {
-        let cmd = ClientCommand::InsertTransaction(tx);
-        self.inner_log(cmd).await
+    #[test]
+    fn pubkey_router_moves_hot_buckets_off_overloaded_shard() {
+        let update = |bucket: u16| {
+            let mut pubkey = [0; 32];
+            pubkey[..2].copy_from_slice(&bucket.to_le_bytes());
+            ClientCommand::InsertAccountUpdate(AccountUpdate {
+                pubkey,
+                ..AccountUpdate::zero_account()
+            })
+        };
+        let mut router = PubkeyRouter::default();
+        // Even buckets land on shard 0, which gets most of the events.
+        for bucket in [0, 2, 4, 1] {
+            for _ in 0..100 {
+                assert_eq!(router.select(&update(bucket), 2), bucket as usize % 2);
+            }
+        }
+        assert_eq!(router.prepare_rebalance(2), Some(vec![0]));
+        // The routing only changes once the drained shard wrote its events.
+        assert_eq!(router.select(&update(0), 2), 0);
+        router.finish_rebalance();
+        let moved = [0, 2, 4]
+            .into_iter()
+            .filter(|bucket| router.select(&update(*bucket), 2) == 1)
+            .count();
+        assert_eq!(moved, 1);
+        // Balanced within the tolerated imbalance, nothing moves.
+        assert_eq!(router.prepare_rebalance(2), None);
+
+        // An aborted rebalance keeps the routing.
+        for bucket in [1, 3] {
+            for _ in 0..150 {
+                assert_eq!(router.select(&update(bucket), 2), 1);
+            }
+        }
+        assert_eq!(router.prepare_rebalance(2), Some(vec![1]));
+        router.abort_rebalance();
+        router.finish_rebalance();
+        assert_eq!(router.select(&update(1), 2), 1);
+        assert_eq!(router.select(&update(3), 2), 1);
+    }
+
+    #[test]
+    fn slot_router_keeps_a_slot_on_one_shard() {
+        let update = |slot: i64, pubkey: u8| {
+            ClientCommand::InsertAccountUpdate(AccountUpdate {
+                slot,
+                pubkey: [pubkey; 32],
+                ..AccountUpdate::zero_account()
+            })
+        };
+        let mut router = SlotRouter;
+        for pubkey in 0..8 {
+            assert_eq!(router.select(&update(10, pubkey), 4), 2);
+            assert_eq!(router.select(&update(11, pubkey), 4), 3);
+        }
     }
 }
@@ -7,17 +7,30 @@ use {
         serialize::value::SerializeCql,
         FromRow, FromUserType, SerializeCql, SerializeRow,
     },
+    serde::{Deserialize, Serialize},
     std::iter::repeat,
     yellowstone_grpc_proto::{
+        convert_from::create_tx_error,
         geyser::{
-            SubscribeUpdateAccount, SubscribeUpdateTransaction, SubscribeUpdateTransactionInfo,
+            SubscribeUpdateAccount, SubscribeUpdateBlockMeta, SubscribeUpdateTransaction,
+            SubscribeUpdateTransactionInfo,
         },
+        prost::Message,
         solana::storage::confirmed_block::{self, CompiledInstruction},
     },
 };
 
 pub const SHARD_OFFSET_MODULO: i64 = 10000;
 
+/// Partition of `log` holding `offset` when each period is split into `partitions_per_period` partitions.
+///
+/// The `period` column of `log` holds this partition rather than the period itself, they only match with a single
+/// partition per period. Periods keep spanning [`SHARD_OFFSET_MODULO`] offsets and are still committed as a whole,
+/// the partitions of period `p` being `p * partitions_per_period` and the following ones.
+pub const fn log_partition(offset: ShardOffset, partitions_per_period: i16) -> ShardPeriod {
+    offset / (SHARD_OFFSET_MODULO / partitions_per_period as i64)
+}
+
 pub type ProgramId = [u8; 32];
 
 pub type Slot = i64;
@@ -33,6 +46,7 @@ pub const MAX_PRODUCER: ProducerId = [0xFF];
 pub enum BlockchainEventType {
     AccountUpdate = 0,
     NewTransaction = 1,
+    BlockMeta = 2,
 }
 
 impl TryFrom<i16> for BlockchainEventType {
@@ -42,6 +56,7 @@ impl TryFrom<i16> for BlockchainEventType {
         match value {
             0 => Ok(BlockchainEventType::AccountUpdate),
             1 => Ok(BlockchainEventType::NewTransaction),
+            2 => Ok(BlockchainEventType::BlockMeta),
             x => Err(anyhow!("Unknown LogEntryType equivalent for {:?}", x)),
         }
     }
@@ -52,6 +67,174 @@ impl From<BlockchainEventType> for i16 {
         match val {
             BlockchainEventType::AccountUpdate => 0,
             BlockchainEventType::NewTransaction => 1,
+            BlockchainEventType::BlockMeta => 2,
+        }
+    }
+}
+
+/// Compression of the `data` column of an account update or of the `tx_nested` column of a transaction.
+#[derive(Clone, Debug, PartialEq, Eq, Copy, DeepSizeOf)]
+pub enum DataCodec {
+    Raw = 0,
+    Lz4 = 1,
+}
+
+impl TryFrom<i16> for DataCodec {
+    type Error = anyhow::Error;
+
+    fn try_from(value: i16) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(DataCodec::Raw),
+            1 => Ok(DataCodec::Lz4),
+            x => Err(anyhow!("Unknown DataCodec equivalent for {:?}", x)),
+        }
+    }
+}
+
+impl From<DataCodec> for i16 {
+    fn from(val: DataCodec) -> Self {
+        match val {
+            DataCodec::Raw => 0,
+            DataCodec::Lz4 => 1,
+        }
+    }
+}
+
+impl SerializeCql for DataCodec {
+    fn serialize<'b>(
+        &self,
+        typ: &scylla::frame::response::result::ColumnType,
+        writer: scylla::serialize::CellWriter<'b>,
+    ) -> Result<
+        scylla::serialize::writers::WrittenCellProof<'b>,
+        scylla::serialize::SerializationError,
+    > {
+        let x: i16 = (*self).into();
+        SerializeCql::serialize(&x, typ, writer)
+    }
+}
+
+impl FromCqlVal<CqlValue> for DataCodec {
+    fn from_cql(cql_val: CqlValue) -> Result<Self, scylla::cql_to_rust::FromCqlValError> {
+        match cql_val {
+            CqlValue::SmallInt(x) => x.try_into().map_err(|_| FromCqlValError::BadVal),
+            _ => Err(FromCqlValError::BadCqlType),
+        }
+    }
+}
+
+/// Encoding of the `instructions`, `address_table_lookups` and `meta` of a transaction packed into the
+/// `tx_nested` column, which is tagged by `tx_nested_codec`.
+///
+/// Rows without codec keep them in their typed columns, readers outside of Rust have to decode `tx_nested`
+/// themselves otherwise.
+#[derive(Clone, Debug, PartialEq, Eq, Copy, DeepSizeOf, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NestedCodec {
+    /// bincode of [`TransactionNested`], only readable from Rust.
+    Bincode = 1,
+    /// `ConfirmedTransaction` message of `solana-storage.proto`, only the instructions and address table
+    /// lookups of its message are set besides its meta.
+    Protobuf = 2,
+    /// JSON object of [`TransactionNested`], byte strings are arrays of numbers.
+    Json = 3,
+}
+
+impl TryFrom<i16> for NestedCodec {
+    type Error = anyhow::Error;
+
+    fn try_from(value: i16) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(NestedCodec::Bincode),
+            2 => Ok(NestedCodec::Protobuf),
+            3 => Ok(NestedCodec::Json),
+            x => Err(anyhow!("Unknown NestedCodec equivalent for {:?}", x)),
+        }
+    }
+}
+
+impl From<NestedCodec> for i16 {
+    fn from(val: NestedCodec) -> Self {
+        match val {
+            NestedCodec::Bincode => 1,
+            NestedCodec::Protobuf => 2,
+            NestedCodec::Json => 3,
+        }
+    }
+}
+
+impl SerializeCql for NestedCodec {
+    fn serialize<'b>(
+        &self,
+        typ: &scylla::frame::response::result::ColumnType,
+        writer: scylla::serialize::CellWriter<'b>,
+    ) -> Result<
+        scylla::serialize::writers::WrittenCellProof<'b>,
+        scylla::serialize::SerializationError,
+    > {
+        let x: i16 = (*self).into();
+        SerializeCql::serialize(&x, typ, writer)
+    }
+}
+
+impl FromCqlVal<CqlValue> for NestedCodec {
+    fn from_cql(cql_val: CqlValue) -> Result<Self, scylla::cql_to_rust::FromCqlValError> {
+        match cql_val {
+            CqlValue::SmallInt(x) => x.try_into().map_err(|_| FromCqlValError::BadVal),
+            _ => Err(FromCqlValError::BadCqlType),
+        }
+    }
+}
+
+/// Nested structures of a transaction, as packed into `tx_nested` by the bincode and JSON codecs.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TransactionNested {
+    pub instructions: Vec<CompiledInstr>,
+    pub address_table_lookups: Vec<MessageAddrTableLookup>,
+    pub meta: TransactionMeta,
+}
+
+impl TransactionNested {
+    fn encode(self, codec: NestedCodec) -> anyhow::Result<Vec<u8>> {
+        match codec {
+            NestedCodec::Bincode => Ok(bincode::serialize(&self)?),
+            NestedCodec::Json => Ok(serde_json::to_vec(&self)?),
+            NestedCodec::Protobuf => {
+                let confirmed_tx = confirmed_block::ConfirmedTransaction {
+                    transaction: Some(confirmed_block::Transaction {
+                        signatures: Vec::new(),
+                        message: Some(confirmed_block::Message {
+                            instructions: try_collect(self.instructions)?,
+                            address_table_lookups: try_collect(self.address_table_lookups)?,
+                            ..Default::default()
+                        }),
+                    }),
+                    meta: Some(self.meta.try_into()?),
+                };
+                Ok(confirmed_tx.encode_to_vec())
+            }
+        }
+    }
+
+    fn decode(codec: NestedCodec, bytes: &[u8]) -> anyhow::Result<Self> {
+        match codec {
+            NestedCodec::Bincode => Ok(bincode::deserialize(bytes)?),
+            NestedCodec::Json => Ok(serde_json::from_slice(bytes)?),
+            NestedCodec::Protobuf => {
+                let confirmed_tx = confirmed_block::ConfirmedTransaction::decode(bytes)?;
+                let message = confirmed_tx
+                    .transaction
+                    .and_then(|tx| tx.message)
+                    .ok_or(anyhow!("missing message in nested transaction"))?;
+                Ok(TransactionNested {
+                    instructions: try_collect(message.instructions)?,
+                    address_table_lookups: try_collect(message.address_table_lookups)?,
+                    meta: confirmed_tx
+                        .meta
+                        .ok_or(anyhow!("missing meta in nested transaction"))?
+                        .try_into()?,
+                })
+            }
         }
     }
 }
@@ -97,6 +280,7 @@ pub struct BlockchainEvent {
     pub rent_epoch: Option<i64>,
     pub write_version: Option<i64>,
     pub data: Option<Vec<u8>>,
+    pub data_codec: Option<DataCodec>,
     pub txn_signature: Option<Vec<u8>>,
 
     // Transaction
@@ -113,11 +297,177 @@ pub struct BlockchainEvent {
     pub meta: Option<TransactionMeta>,
     pub is_vote: Option<bool>,
     pub tx_index: Option<i64>,
+
+    // Ingestion
+    pub ingested_at: Option<i64>,
+    pub truncated: Option<bool>,
+
+    // Nested transaction structures, set instead of their typed columns when packed with a codec
+    pub tx_nested: Option<Vec<u8>>,
+    pub tx_nested_codec: Option<NestedCodec>,
+    pub tx_nested_compression: Option<DataCodec>,
+
+    // Transaction outcome, extracted from `meta` when the event is built
+    pub tx_success: Option<bool>,
+    pub tx_err: Option<String>,
+
+    // BlockMeta
+    pub blockhash: Option<String>,
+    pub parent_slot: Option<i64>,
+    pub parent_blockhash: Option<String>,
+    pub block_height: Option<i64>,
+    pub block_time: Option<i64>,
+    pub rewards: Option<Vec<Reward>>,
+    pub executed_transaction_count: Option<i64>,
+    pub entries_count: Option<i64>,
+
+    // Geyser protobuf message of the event, only stored if the producer opted in
+    pub raw_proto: Option<Vec<u8>>,
+}
+
+impl BlockchainEvent {
+    /// Compresses `data`, and `tx_nested` once packed, with LZ4 if they hold at least `min_bytes` bytes.
+    ///
+    /// Smaller payloads, or payloads that LZ4 would not shrink, are stored raw. The typed `meta` column is never
+    /// compressed, transactions must be packed with a [`NestedCodec`] for their meta to be.
+    ///
+    /// Returns the size of each payload before and after compression.
+    pub fn compress_data(&mut self, min_bytes: usize) -> CompressedSizes {
+        CompressedSizes {
+            data: lz4_compress(&mut self.data, &mut self.data_codec, min_bytes),
+            tx_nested: lz4_compress(
+                &mut self.tx_nested,
+                &mut self.tx_nested_compression,
+                min_bytes,
+            ),
+        }
+    }
+
+    /// Restores the raw `data` and `tx_nested` of an event read from the log.
+    ///
+    /// Rows written before the `data_codec` or `tx_nested_compression` columns existed have no codec and are
+    /// already raw.
+    pub fn decompress_data(&mut self) -> anyhow::Result<()> {
+        lz4_decompress(&mut self.data, &mut self.data_codec)
+            .map_err(|e| anyhow!("invalid lz4 data at offset {}: {e}", self.offset))?;
+        lz4_decompress(&mut self.tx_nested, &mut self.tx_nested_compression)
+            .map_err(|e| anyhow!("invalid lz4 tx_nested at offset {}: {e}", self.offset))
+    }
+
+    /// Packs the instructions, address table lookups and meta of a transaction into `tx_nested`.
+    ///
+    /// Account updates and transactions already packed are left untouched.
+    pub fn encode_nested(&mut self, codec: NestedCodec) -> anyhow::Result<()> {
+        if self.event_type != BlockchainEventType::NewTransaction || self.tx_nested_codec.is_some()
+        {
+            return Ok(());
+        }
+        let nested = TransactionNested {
+            instructions: self.instructions.take().unwrap_or_default(),
+            address_table_lookups: self.address_table_lookups.take().unwrap_or_default(),
+            meta: self.meta.take().unwrap_or_default(),
+        };
+        self.tx_nested = Some(nested.encode(codec)?);
+        self.tx_nested_codec = Some(codec);
+        Ok(())
+    }
+
+    /// Restores the typed nested columns of a transaction read from the log.
+    ///
+    /// Rows without `tx_nested_codec` already have their typed columns set.
+    pub fn decode_nested(&mut self) -> anyhow::Result<()> {
+        let (Some(codec), Some(bytes)) = (self.tx_nested_codec, self.tx_nested.as_deref()) else {
+            return Ok(());
+        };
+        let nested = TransactionNested::decode(codec, bytes)
+            .map_err(|e| anyhow!("invalid nested transaction at offset {}: {e}", self.offset))?;
+        self.instructions = Some(nested.instructions);
+        self.address_table_lookups = Some(nested.address_table_lookups);
+        self.meta = Some(nested.meta);
+        self.tx_nested = None;
+        self.tx_nested_codec = None;
+        self.tx_nested_compression = None;
+        Ok(())
+    }
+
+    /// Returns the first column required by the event type that is not set, if any.
+    ///
+    /// The nested columns of a transaction must be decoded beforehand, see [`BlockchainEvent::decode_nested`].
+    pub fn missing_field(&self) -> Option<&'static str> {
+        let required = match self.event_type {
+            BlockchainEventType::AccountUpdate => [
+                ("pubkey", self.pubkey.is_some()),
+                ("lamports", self.lamports.is_some()),
+                ("owner", self.owner.is_some()),
+                ("write_version", self.write_version.is_some()),
+                ("data", self.data.is_some()),
+            ]
+            .to_vec(),
+            BlockchainEventType::NewTransaction => [
+                ("signature", self.signature.is_some()),
+                ("signatures", self.signatures.is_some()),
+                (
+                    "num_required_signatures",
+                    self.num_required_signatures.is_some(),
+                ),
+                (
+                    "num_readonly_signed_accounts",
+                    self.num_readonly_signed_accounts.is_some(),
+                ),
+                (
+                    "num_readonly_unsigned_accounts",
+                    self.num_readonly_unsigned_accounts.is_some(),
+                ),
+                ("account_keys", self.account_keys.is_some()),
+                ("instructions", self.instructions.is_some()),
+                ("versioned", self.versioned.is_some()),
+                (
+                    "address_table_lookups",
+                    self.address_table_lookups.is_some(),
+                ),
+                ("meta", self.meta.is_some()),
+                ("is_vote", self.is_vote.is_some()),
+                ("tx_index", self.tx_index.is_some()),
+            ]
+            .to_vec(),
+            BlockchainEventType::BlockMeta => [
+                ("blockhash", self.blockhash.is_some()),
+                ("parent_slot", self.parent_slot.is_some()),
+                ("parent_blockhash", self.parent_blockhash.is_some()),
+                ("rewards", self.rewards.is_some()),
+                (
+                    "executed_transaction_count",
+                    self.executed_transaction_count.is_some(),
+                ),
+                ("entries_count", self.entries_count.is_some()),
+            ]
+            .to_vec(),
+        };
+        required
+            .into_iter()
+            .find_map(|(field, is_set)| (!is_set).then_some(field))
+    }
 }
 
 type Pubkey = [u8; 32];
 
-#[derive(SerializeRow, Clone, Debug, DeepSizeOf, PartialEq, Eq)]
+const SIGNATURE_LEN: usize = 64;
+
+fn check_signature(name: &str, signature: &[u8]) -> anyhow::Result<()> {
+    if signature.len() != SIGNATURE_LEN {
+        return Err(anyhow!(
+            "Invalid {name}: expected {SIGNATURE_LEN} bytes, got {}",
+            signature.len()
+        ));
+    }
+    Ok(())
+}
+
+fn to_i64(name: &str, value: u64) -> anyhow::Result<i64> {
+    i64::try_from(value).map_err(|_| anyhow!("Invalid {name}: {value} overflows a bigint"))
+}
+
+#[derive(SerializeRow, Clone, Debug, DeepSizeOf, PartialEq, Eq, Serialize, Deserialize)]
 pub struct AccountUpdate {
     pub slot: i64,
     pub pubkey: Pubkey,
@@ -128,6 +478,61 @@ pub struct AccountUpdate {
     pub write_version: i64,
     pub data: Vec<u8>,
     pub txn_signature: Option<Vec<u8>>,
+    /// Unix timestamp in milliseconds at which the update was received from geyser.
+    pub ingested_at: Option<i64>,
+    /// Set when `data` was cut down to the size limit of the sink.
+    pub truncated: bool,
+    /// Geyser protobuf message the update was received as, written to `raw_proto` if the sink stores it.
+    pub raw_proto: Option<Vec<u8>>,
+}
+
+/// Returns the current unix timestamp in milliseconds, the unit of `ingested_at`.
+pub fn ingestion_timestamp() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+/// Sizes of the payloads of an event before and after [`BlockchainEvent::compress_data`], `None` if left out of LZ4.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CompressedSizes {
+    pub data: Option<(usize, usize)>,
+    /// Carries the packed instructions, address table lookups and meta of a transaction.
+    pub tx_nested: Option<(usize, usize)>,
+}
+
+/// Compresses `bytes` with LZ4 and tags them in `codec` if they hold at least `min_bytes` bytes and LZ4 shrinks them.
+///
+/// Returns the size of `bytes` before and after compression if they went through LZ4.
+fn lz4_compress(
+    bytes: &mut Option<Vec<u8>>,
+    codec: &mut Option<DataCodec>,
+    min_bytes: usize,
+) -> Option<(usize, usize)> {
+    let bytes = bytes.as_mut()?;
+    if *codec == Some(DataCodec::Lz4) || bytes.len() < min_bytes {
+        return None;
+    }
+    let input_len = bytes.len();
+    let compressed = lz4_flex::compress_prepend_size(bytes);
+    if compressed.len() < input_len {
+        *bytes = compressed;
+        *codec = Some(DataCodec::Lz4);
+    }
+    Some((input_len, bytes.len()))
+}
+
+/// Restores `bytes` compressed by [`lz4_compress`], tagging them as raw in `codec`.
+fn lz4_decompress(
+    bytes: &mut Option<Vec<u8>>,
+    codec: &mut Option<DataCodec>,
+) -> anyhow::Result<()> {
+    if *codec != Some(DataCodec::Lz4) {
+        return Ok(());
+    }
+    if let Some(bytes) = bytes.as_mut() {
+        *bytes = lz4_flex::decompress_size_prepended(bytes)?;
+    }
+    *codec = Some(DataCodec::Raw);
+    Ok(())
 }
 
 fn try_collect<U, I: IntoIterator>(it: I) -> Result<Vec<U>, <I::Item as TryInto<U>>::Error>
@@ -137,7 +542,18 @@ where
     it.into_iter().map(|item| item.try_into()).collect()
 }
 
-#[derive(Debug, SerializeCql, Clone, DeepSizeOf, FromUserType, Default, Eq, PartialEq)]
+#[derive(
+    Debug,
+    SerializeCql,
+    Clone,
+    DeepSizeOf,
+    FromUserType,
+    Default,
+    Eq,
+    PartialEq,
+    Serialize,
+    Deserialize,
+)]
 #[scylla(flavor = "match_by_name")]
 pub struct MessageAddrTableLookup {
     pub account_key: Vec<u8>,
@@ -172,7 +588,18 @@ impl From<MessageAddrTableLookup> for confirmed_block::MessageAddressTableLookup
     }
 }
 
-#[derive(Debug, SerializeCql, Clone, DeepSizeOf, FromUserType, Default, Eq, PartialEq)]
+#[derive(
+    Debug,
+    SerializeCql,
+    Clone,
+    DeepSizeOf,
+    FromUserType,
+    Default,
+    Eq,
+    PartialEq,
+    Serialize,
+    Deserialize,
+)]
 #[scylla(flavor = "match_by_name")]
 pub struct CompiledInstr {
     pub program_id_index: i64,
@@ -210,7 +637,18 @@ impl TryFrom<CompiledInstr> for confirmed_block::CompiledInstruction {
     }
 }
 
-#[derive(Debug, SerializeCql, Clone, DeepSizeOf, FromUserType, Default, Eq, PartialEq)]
+#[derive(
+    Debug,
+    SerializeCql,
+    Clone,
+    DeepSizeOf,
+    FromUserType,
+    Default,
+    Eq,
+    PartialEq,
+    Serialize,
+    Deserialize,
+)]
 #[scylla(flavor = "match_by_name")]
 pub struct InnerInstr {
     pub program_id_index: i64,
@@ -243,7 +681,18 @@ impl TryFrom<InnerInstr> for confirmed_block::InnerInstruction {
     }
 }
 
-#[derive(Debug, SerializeCql, Clone, DeepSizeOf, FromUserType, Default, Eq, PartialEq)]
+#[derive(
+    Debug,
+    SerializeCql,
+    Clone,
+    DeepSizeOf,
+    FromUserType,
+    Default,
+    Eq,
+    PartialEq,
+    Serialize,
+    Deserialize,
+)]
 #[scylla(flavor = "match_by_name")]
 pub struct InnerInstrs {
     pub index: i64,
@@ -275,7 +724,9 @@ impl TryFrom<InnerInstrs> for confirmed_block::InnerInstructions {
     }
 }
 
-#[derive(Debug, SerializeCql, Clone, DeepSizeOf, FromUserType, Default, PartialEq)]
+#[derive(
+    Debug, SerializeCql, Clone, DeepSizeOf, FromUserType, Default, PartialEq, Serialize, Deserialize,
+)]
 #[scylla(flavor = "match_by_name")]
 pub struct UiTokenAmount {
     pub ui_amount: f64,
@@ -308,7 +759,9 @@ impl TryFrom<UiTokenAmount> for confirmed_block::UiTokenAmount {
     }
 }
 
-#[derive(Debug, SerializeCql, Clone, DeepSizeOf, FromUserType, Default, PartialEq)]
+#[derive(
+    Debug, SerializeCql, Clone, DeepSizeOf, FromUserType, Default, PartialEq, Serialize, Deserialize,
+)]
 #[scylla(flavor = "match_by_name")]
 pub struct TxTokenBalance {
     pub account_index: i64,
@@ -344,7 +797,18 @@ impl TryFrom<TxTokenBalance> for confirmed_block::TokenBalance {
     }
 }
 
-#[derive(Debug, SerializeCql, Clone, DeepSizeOf, FromUserType, Default, Eq, PartialEq)]
+#[derive(
+    Debug,
+    SerializeCql,
+    Clone,
+    DeepSizeOf,
+    FromUserType,
+    Default,
+    Eq,
+    PartialEq,
+    Serialize,
+    Deserialize,
+)]
 #[scylla(flavor = "match_by_name")]
 pub struct Reward {
     pub pubkey: String,
@@ -381,7 +845,18 @@ impl TryFrom<Reward> for confirmed_block::Reward {
     }
 }
 
-#[derive(Debug, SerializeCql, Clone, DeepSizeOf, FromUserType, Default, PartialEq, Eq)]
+#[derive(
+    Debug,
+    SerializeCql,
+    Clone,
+    DeepSizeOf,
+    FromUserType,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+)]
 #[scylla(flavor = "match_by_name")]
 pub struct ReturnData {
     pub program_id: ProgramId,
@@ -410,7 +885,9 @@ impl From<ReturnData> for confirmed_block::ReturnData {
     }
 }
 
-#[derive(Debug, SerializeCql, Clone, DeepSizeOf, FromUserType, Default, PartialEq)]
+#[derive(
+    Debug, SerializeCql, Clone, DeepSizeOf, FromUserType, Default, PartialEq, Serialize, Deserialize,
+)]
 #[scylla(flavor = "match_by_name")]
 pub struct TransactionMeta {
     pub error: Option<Vec<u8>>,
@@ -475,14 +952,14 @@ impl TryFrom<confirmed_block::TransactionStatusMeta> for TransactionMeta {
             pre_balances,
             post_balances,
             inner_instructions: if status_meta.inner_instructions_none {
-                Some(inner_instructions)
-            } else {
                 None
+            } else {
+                Some(inner_instructions)
             },
             log_messages: if status_meta.log_messages_none {
-                Some(log_messages)
-            } else {
                 None
+            } else {
+                Some(log_messages)
             },
             pre_token_balances,
             post_token_balances,
@@ -539,7 +1016,7 @@ impl TryFrom<TransactionMeta> for confirmed_block::TransactionStatusMeta {
     }
 }
 
-#[derive(Debug, SerializeRow, Clone, DeepSizeOf, PartialEq)]
+#[derive(Debug, SerializeRow, Clone, DeepSizeOf, PartialEq, Serialize, Deserialize)]
 pub struct Transaction {
     pub slot: i64,
     pub signature: Vec<u8>,
@@ -555,19 +1032,26 @@ pub struct Transaction {
     pub meta: TransactionMeta,
     pub is_vote: bool,
     pub tx_index: i64,
+    /// Unix timestamp in milliseconds at which the transaction was received from geyser.
+    pub ingested_at: Option<i64>,
+    /// Set when the log messages were cut down to the size limit of the sink.
+    pub truncated: bool,
+    /// Geyser protobuf message the transaction was received as, written to `raw_proto` if the sink stores it.
+    pub raw_proto: Option<Vec<u8>>,
 }
 
 impl TryFrom<SubscribeUpdateTransaction> for Transaction {
     type Error = anyhow::Error;
 
     fn try_from(value: SubscribeUpdateTransaction) -> Result<Transaction, Self::Error> {
-        let slot: i64 = value.slot as i64;
+        let slot = to_i64("slot", value.slot)?;
 
         let val_tx = value
             .transaction
             .ok_or(anyhow!("missing transaction info object"))?;
 
         let signature = val_tx.signature;
+        check_signature("signature", &signature)?;
         let meta = val_tx
             .meta
             .ok_or(anyhow!("missing transaction status meta"))?;
@@ -601,7 +1085,10 @@ impl TryFrom<SubscribeUpdateTransaction> for Transaction {
                 .collect(),
             meta: meta.try_into()?,
             is_vote: val_tx.is_vote,
-            tx_index: val_tx.index as i64,
+            tx_index: to_i64("tx_index", val_tx.index)?,
+            ingested_at: None,
+            truncated: false,
+            raw_proto: None,
         };
 
         Ok(res)
@@ -686,6 +1173,9 @@ impl AccountUpdate {
             write_version: 0,
             data: vec![],
             txn_signature: None,
+            ingested_at: None,
+            truncated: false,
+            raw_proto: None,
         }
     }
 
@@ -709,6 +1199,7 @@ impl AccountUpdate {
             rent_epoch: Some(self.rent_epoch),
             write_version: Some(self.write_version),
             data: Some(self.data),
+            data_codec: Some(DataCodec::Raw),
             txn_signature: self.txn_signature,
             signature: Default::default(),
             signatures: Default::default(),
@@ -723,6 +1214,22 @@ impl AccountUpdate {
             meta: Default::default(),
             is_vote: Default::default(),
             tx_index: Default::default(),
+            ingested_at: self.ingested_at,
+            truncated: Some(self.truncated),
+            tx_nested: None,
+            tx_nested_codec: None,
+            tx_nested_compression: None,
+            tx_success: None,
+            tx_err: None,
+            blockhash: None,
+            parent_slot: None,
+            parent_blockhash: None,
+            block_height: None,
+            block_time: None,
+            rewards: None,
+            executed_transaction_count: None,
+            entries_count: None,
+            raw_proto: self.raw_proto,
         }
     }
 }
@@ -730,34 +1237,47 @@ impl AccountUpdate {
 impl TryFrom<SubscribeUpdateAccount> for AccountUpdate {
     type Error = anyhow::Error;
     fn try_from(value: SubscribeUpdateAccount) -> Result<Self, Self::Error> {
-        let slot = value.slot;
-        if value.account.is_none() {
-            Err(anyhow!("Missing account update."))
-        } else {
-            let acc: yellowstone_grpc_proto::prelude::SubscribeUpdateAccountInfo =
-                value.account.unwrap();
-            let pubkey: Pubkey = acc
-                .pubkey
-                .try_into()
-                .map_err(|err| anyhow!("Invalid pubkey: {:?}", err))?;
-            let owner: Pubkey = acc
-                .owner
-                .try_into()
-                .map_err(|err| anyhow!("Invalid owner: {:?}", err))?;
-
-            let ret = AccountUpdate {
-                slot: slot as i64,
-                pubkey,
-                lamports: acc.lamports as i64,
-                owner,
-                executable: acc.executable,
-                rent_epoch: acc.rent_epoch as i64,
-                write_version: acc.write_version as i64,
-                data: acc.data,
-                txn_signature: acc.txn_signature,
-            };
-            Ok(ret)
+        let slot = to_i64("slot", value.slot)?;
+        let acc = value.account.ok_or(anyhow!("Missing account update."))?;
+        let pubkey: Pubkey = acc
+            .pubkey
+            .try_into()
+            .map_err(|err| anyhow!("Invalid pubkey: {:?}", err))?;
+        let owner: Pubkey = acc
+            .owner
+            .try_into()
+            .map_err(|err| anyhow!("Invalid owner: {:?}", err))?;
+        if let Some(txn_signature) = acc.txn_signature.as_ref() {
+            check_signature("txn_signature", txn_signature)?;
         }
+
+        let ret = AccountUpdate {
+            slot,
+            pubkey,
+            lamports: to_i64("lamports", acc.lamports)?,
+            owner,
+            executable: acc.executable,
+            // Rent exempt accounts use u64::MAX, it wraps to -1 and is restored when converted back.
+            rent_epoch: acc.rent_epoch as i64,
+            write_version: to_i64("write_version", acc.write_version)?,
+            data: acc.data,
+            txn_signature: acc.txn_signature,
+            ingested_at: None,
+            truncated: false,
+            raw_proto: None,
+        };
+        Ok(ret)
+    }
+}
+
+/// Display form of a bincode serialized `TransactionError`, as stored in [`TransactionMeta::error`].
+fn transaction_error_message(error: &[u8]) -> String {
+    let error = confirmed_block::TransactionError {
+        err: error.to_vec(),
+    };
+    match create_tx_error(Some(&error)) {
+        std::result::Result::Ok(Some(error)) => error.to_string(),
+        _ => "undecodable transaction error".to_owned(),
     }
 }
 
@@ -783,6 +1303,7 @@ impl Transaction {
             rent_epoch: Default::default(),
             write_version: Default::default(),
             data: Default::default(),
+            data_codec: Default::default(),
             txn_signature: Default::default(),
 
             signature: Some(self.signature),
@@ -795,9 +1316,160 @@ impl Transaction {
             instructions: Some(self.instructions),
             versioned: Some(self.versioned),
             address_table_lookups: Some(self.address_table_lookups),
+            tx_success: Some(self.meta.error.is_none()),
+            tx_err: self.meta.error.as_deref().map(transaction_error_message),
+            blockhash: None,
+            parent_slot: None,
+            parent_blockhash: None,
+            block_height: None,
+            block_time: None,
+            rewards: None,
+            executed_transaction_count: None,
+            entries_count: None,
+            raw_proto: self.raw_proto,
             meta: Some(self.meta),
             is_vote: Some(self.is_vote),
             tx_index: Some(self.tx_index),
+            ingested_at: self.ingested_at,
+            truncated: Some(self.truncated),
+            tx_nested: None,
+            tx_nested_codec: None,
+            tx_nested_compression: None,
+        }
+    }
+}
+
+/// Metadata of a block, sent by geyser once every transaction of the block was processed.
+#[derive(Debug, Clone, DeepSizeOf, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockMeta {
+    pub slot: i64,
+    pub blockhash: String,
+    pub parent_slot: i64,
+    pub parent_blockhash: String,
+    /// Height of the block, unknown for blocks produced before it was tracked.
+    pub block_height: Option<i64>,
+    /// Estimated unix timestamp in seconds of the block, if any.
+    pub block_time: Option<i64>,
+    pub rewards: Vec<Reward>,
+    pub executed_transaction_count: i64,
+    pub entries_count: i64,
+    /// Unix timestamp in milliseconds at which the block meta was received from geyser.
+    pub ingested_at: Option<i64>,
+    /// Geyser protobuf message the block meta was received as, written to `raw_proto` if the sink stores it.
+    pub raw_proto: Option<Vec<u8>>,
+}
+
+impl TryFrom<SubscribeUpdateBlockMeta> for BlockMeta {
+    type Error = anyhow::Error;
+
+    fn try_from(value: SubscribeUpdateBlockMeta) -> Result<Self, Self::Error> {
+        Ok(BlockMeta {
+            slot: to_i64("slot", value.slot)?,
+            blockhash: value.blockhash,
+            parent_slot: to_i64("parent_slot", value.parent_slot)?,
+            parent_blockhash: value.parent_blockhash,
+            block_height: value
+                .block_height
+                .map(|block_height| to_i64("block_height", block_height.block_height))
+                .transpose()?,
+            block_time: value.block_time.map(|block_time| block_time.timestamp),
+            rewards: value
+                .rewards
+                .map(|rewards| try_collect(rewards.rewards))
+                .transpose()?
+                .unwrap_or_default(),
+            executed_transaction_count: to_i64(
+                "executed_transaction_count",
+                value.executed_transaction_count,
+            )?,
+            entries_count: to_i64("entries_count", value.entries_count)?,
+            ingested_at: None,
+            raw_proto: None,
+        })
+    }
+}
+
+impl TryFrom<BlockMeta> for SubscribeUpdateBlockMeta {
+    type Error = anyhow::Error;
+
+    fn try_from(value: BlockMeta) -> Result<Self, Self::Error> {
+        Ok(SubscribeUpdateBlockMeta {
+            slot: value.slot.try_into()?,
+            blockhash: value.blockhash,
+            rewards: Some(confirmed_block::Rewards {
+                rewards: try_collect(value.rewards)?,
+            }),
+            block_time: value
+                .block_time
+                .map(|timestamp| confirmed_block::UnixTimestamp { timestamp }),
+            block_height: value
+                .block_height
+                .map(|block_height| {
+                    Ok(confirmed_block::BlockHeight {
+                        block_height: block_height.try_into()?,
+                    })
+                })
+                .transpose()?,
+            parent_slot: value.parent_slot.try_into()?,
+            parent_blockhash: value.parent_blockhash,
+            executed_transaction_count: value.executed_transaction_count.try_into()?,
+            entries_count: value.entries_count.try_into()?,
+        })
+    }
+}
+
+impl BlockMeta {
+    pub fn as_blockchain_event(
+        self,
+        shard_id: ShardId,
+        producer_id: ProducerId,
+        offset: ShardOffset,
+    ) -> BlockchainEvent {
+        BlockchainEvent {
+            shard_id,
+            period: offset / SHARD_OFFSET_MODULO,
+            producer_id,
+            offset,
+            slot: self.slot,
+            event_type: BlockchainEventType::BlockMeta,
+            pubkey: None,
+            lamports: None,
+            owner: None,
+            executable: None,
+            rent_epoch: None,
+            write_version: None,
+            data: None,
+            data_codec: None,
+            txn_signature: None,
+            signature: None,
+            signatures: None,
+            num_required_signatures: None,
+            num_readonly_signed_accounts: None,
+            num_readonly_unsigned_accounts: None,
+            account_keys: None,
+            recent_blockhash: None,
+            instructions: None,
+            versioned: None,
+            address_table_lookups: None,
+            meta: None,
+            is_vote: None,
+            tx_index: None,
+            ingested_at: self.ingested_at,
+            truncated: None,
+            tx_nested: None,
+            tx_nested_codec: None,
+            tx_nested_compression: None,
+            tx_success: None,
+            tx_err: None,
+            blockhash: Some(self.blockhash),
+            parent_slot: Some(self.parent_slot),
+            parent_blockhash: Some(self.parent_blockhash),
+            block_height: self.block_height,
+            block_time: self.block_time,
+            rewards: Some(self.rewards),
+            executed_transaction_count: Some(self.executed_transaction_count),
+            entries_count: Some(self.entries_count),
+            raw_proto: self.raw_proto,
         }
     }
 }
@@ -862,8 +1534,8 @@ impl From<BlockchainEvent> for ShardedAccountUpdate {
             pubkey: val.pubkey.expect("pubkey is none"),
             lamports: val.lamports.expect("lamports is none"),
             owner: val.owner.expect("owner is none"),
-            executable: val.executable.expect("executable is none"),
-            rent_epoch: val.rent_epoch.expect("rent_epch is none"),
+            executable: val.executable.unwrap_or_default(),
+            rent_epoch: val.rent_epoch.unwrap_or_default(),
             write_version: val.write_version.expect("write_version is none"),
             data: val.data.expect("data is none"),
             txn_signature: val.txn_signature,
@@ -893,7 +1565,7 @@ impl From<BlockchainEvent> for ShardedTransaction {
                 .num_readonly_unsigned_accounts
                 .expect("num_readonly_unsigned_accounts is none"),
             account_keys: val.account_keys.expect("account_keys is none"),
-            recent_blockhash: val.recent_blockhash.expect("recent_blockhash is none"),
+            recent_blockhash: val.recent_blockhash.unwrap_or_default(),
             instructions: val.instructions.expect("instructions is none"),
             versioned: val.versioned.expect("versioned is none"),
             address_table_lookups: val
@@ -922,7 +1594,7 @@ impl From<BlockchainEvent> for Transaction {
                 .num_readonly_unsigned_accounts
                 .expect("num_readonly_unsigned_accounts is none"),
             account_keys: val.account_keys.expect("account_keys is none"),
-            recent_blockhash: val.recent_blockhash.expect("recent_blockhash is none"),
+            recent_blockhash: val.recent_blockhash.unwrap_or_default(),
             instructions: val.instructions.expect("instructions is none"),
             versioned: val.versioned.expect("versioned is none"),
             address_table_lookups: val
@@ -931,6 +1603,9 @@ impl From<BlockchainEvent> for Transaction {
             meta: val.meta.expect("meta is none"),
             is_vote: val.is_vote.expect("is_vote is none"),
             tx_index: val.tx_index.expect("tx_index is none"),
+            ingested_at: val.ingested_at,
+            truncated: val.truncated.unwrap_or(false),
+            raw_proto: val.raw_proto,
         }
     }
 }
@@ -942,11 +1617,34 @@ impl From<BlockchainEvent> for AccountUpdate {
             pubkey: val.pubkey.expect("pubkey is none"),
             lamports: val.lamports.expect("lamports is none"),
             owner: val.owner.expect("owner is none"),
-            executable: val.executable.expect("executable is none"),
-            rent_epoch: val.rent_epoch.expect("rent_epch is none"),
+            executable: val.executable.unwrap_or_default(),
+            rent_epoch: val.rent_epoch.unwrap_or_default(),
             write_version: val.write_version.expect("write_version is none"),
             data: val.data.expect("data is none"),
             txn_signature: val.txn_signature,
+            ingested_at: val.ingested_at,
+            truncated: val.truncated.unwrap_or(false),
+            raw_proto: val.raw_proto,
+        }
+    }
+}
+
+impl From<BlockchainEvent> for BlockMeta {
+    fn from(val: BlockchainEvent) -> Self {
+        BlockMeta {
+            slot: val.slot,
+            blockhash: val.blockhash.expect("blockhash is none"),
+            parent_slot: val.parent_slot.expect("parent_slot is none"),
+            parent_blockhash: val.parent_blockhash.expect("parent_blockhash is none"),
+            block_height: val.block_height,
+            block_time: val.block_time,
+            rewards: val.rewards.expect("rewards is none"),
+            executed_transaction_count: val
+                .executed_transaction_count
+                .expect("executed_transaction_count is none"),
+            entries_count: val.entries_count.expect("entries_count is none"),
+            ingested_at: val.ingested_at,
+            raw_proto: val.raw_proto,
         }
     }
 }
@@ -955,6 +1653,17 @@ impl From<BlockchainEvent> for AccountUpdate {
 pub struct ProducerInfo {
     pub producer_id: ProducerId,
     pub num_shards: ShardId,
+    // Number of partitions of `log` each period is split into, a single one if unset
+    pub partitions_per_period: Option<i16>,
+    // Number of slots of each partition of `log_by_slot`, unset if the producer does not write it
+    pub slot_bucket_size: Option<Slot>,
+}
+
+impl ProducerInfo {
+    /// Number of partitions of `log` each period of the producer is split into, see [`log_partition`].
+    pub fn partitions_per_period(&self) -> i16 {
+        self.partitions_per_period.unwrap_or(1)
+    }
 }
 
 impl TryFrom<AccountUpdate> for SubscribeUpdateAccount {
@@ -1009,3 +1718,215 @@ impl TryFrom<BlockchainEvent> for SubscribeUpdateTransaction {
         ret.try_into()
     }
 }
+
+impl TryFrom<BlockchainEvent> for SubscribeUpdateBlockMeta {
+    type Error = anyhow::Error;
+    fn try_from(value: BlockchainEvent) -> Result<Self, Self::Error> {
+        anyhow::ensure!(
+            value.event_type == BlockchainEventType::BlockMeta,
+            "BlockchainEvent is not a BlockMeta"
+        );
+        let ret: BlockMeta = value.into();
+        ret.try_into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{
+            transaction_error_message, AccountUpdate, BlockMeta, CompiledInstr, DataCodec,
+            MessageAddrTableLookup, NestedCodec, Reward, Transaction, TransactionMeta,
+        },
+        yellowstone_grpc_proto::{
+            geyser::{
+                SubscribeUpdateAccount, SubscribeUpdateBlockMeta, SubscribeUpdateTransaction,
+            },
+            solana::storage::confirmed_block,
+        },
+    };
+
+    fn account_update() -> AccountUpdate {
+        AccountUpdate {
+            slot: 42,
+            pubkey: [1; 32],
+            lamports: 1_000,
+            owner: [2; 32],
+            write_version: 7,
+            rent_epoch: u64::MAX as i64,
+            data: vec![3; 16],
+            txn_signature: Some(vec![4; 64]),
+            ..AccountUpdate::zero_account()
+        }
+    }
+
+    #[test]
+    fn account_update_roundtrip() {
+        let expected = account_update();
+        let msg = SubscribeUpdateAccount::try_from(expected.clone()).unwrap();
+        assert_eq!(msg.account.as_ref().unwrap().rent_epoch, u64::MAX);
+        assert_eq!(AccountUpdate::try_from(msg).unwrap(), expected);
+    }
+
+    #[test]
+    fn account_update_rejects_malformed_messages() {
+        let mut msg = SubscribeUpdateAccount::try_from(account_update()).unwrap();
+        msg.account.as_mut().unwrap().pubkey.pop();
+        assert!(AccountUpdate::try_from(msg).is_err());
+
+        let mut msg = SubscribeUpdateAccount::try_from(account_update()).unwrap();
+        msg.account.as_mut().unwrap().txn_signature = Some(vec![4; 10]);
+        assert!(AccountUpdate::try_from(msg).is_err());
+
+        let mut msg = SubscribeUpdateAccount::try_from(account_update()).unwrap();
+        msg.slot = u64::MAX;
+        assert!(AccountUpdate::try_from(msg).is_err());
+
+        let mut msg = SubscribeUpdateAccount::try_from(account_update()).unwrap();
+        msg.account = None;
+        assert!(AccountUpdate::try_from(msg).is_err());
+    }
+
+    #[test]
+    fn block_meta_roundtrip_through_the_log() {
+        let expected = BlockMeta {
+            slot: 42,
+            blockhash: "5VYF3GMPbqbsyMVGFcg8mK3dKBnCsNVrcXNE5xFDkKyA".to_owned(),
+            parent_slot: 41,
+            parent_blockhash: "8PGeYCp6VRzAqyWHXgAW7pszbLWKCZN4y8xKRjHdUNdz".to_owned(),
+            block_height: Some(40),
+            block_time: None,
+            rewards: vec![Reward {
+                pubkey: "validator".to_owned(),
+                lamports: 5_000,
+                post_balance: 1_000_000,
+                reward_type: 1,
+                commission: String::new(),
+            }],
+            executed_transaction_count: 3,
+            entries_count: 2,
+            ingested_at: Some(1_700_000_000_000),
+            raw_proto: None,
+        };
+        let msg = SubscribeUpdateBlockMeta::try_from(expected.clone()).unwrap();
+        assert_eq!(msg.block_time, None);
+        assert_eq!(BlockMeta::try_from(msg).unwrap().rewards, expected.rewards);
+
+        let event = expected.clone().as_blockchain_event(0, [0], 7);
+        assert_eq!(event.missing_field(), None);
+        assert_eq!(BlockMeta::from(event), expected);
+    }
+
+    #[test]
+    fn transaction_rejects_missing_transaction() {
+        let msg = SubscribeUpdateTransaction {
+            transaction: None,
+            slot: 42,
+        };
+        assert!(Transaction::try_from(msg).is_err());
+    }
+
+    #[test]
+    fn nested_codecs_roundtrip() {
+        let tx = Transaction {
+            slot: 42,
+            signature: vec![1; 64],
+            signatures: vec![vec![1; 64]],
+            num_required_signatures: 1,
+            num_readonly_signed_accounts: 0,
+            num_readonly_unsigned_accounts: 1,
+            account_keys: vec![vec![2; 32], vec![3; 32]],
+            recent_blockhash: vec![4; 32],
+            instructions: vec![CompiledInstr {
+                program_id_index: 1,
+                accounts: vec![0],
+                data: vec![5; 8],
+            }],
+            versioned: true,
+            address_table_lookups: vec![MessageAddrTableLookup {
+                account_key: vec![6; 32],
+                writable_indexes: vec![1],
+                readonly_indexes: vec![2, 3],
+            }],
+            meta: TransactionMeta {
+                fee: 5_000,
+                pre_balances: vec![10_000, 1],
+                post_balances: vec![5_000, 1],
+                log_messages: Some(vec!["Program log: hello".to_owned()]),
+                compute_units_consumed: Some(150),
+                ..Default::default()
+            },
+            is_vote: false,
+            tx_index: 3,
+            ingested_at: None,
+            truncated: false,
+            raw_proto: None,
+        };
+        let expected = tx.clone().as_blockchain_event(0, [0], 7);
+        assert_eq!(expected.tx_success, Some(true));
+        assert_eq!(expected.tx_err, None);
+        for codec in [
+            NestedCodec::Bincode,
+            NestedCodec::Protobuf,
+            NestedCodec::Json,
+        ] {
+            let mut event = expected.clone();
+            event.encode_nested(codec).unwrap();
+            assert!(event.instructions.is_none() && event.meta.is_none());
+            assert_eq!(event.tx_nested_codec, Some(codec));
+            event.decode_nested().unwrap();
+            assert_eq!(event, expected);
+
+            // The packed meta is compressed along with the rest of `tx_nested`.
+            let mut event = expected.clone();
+            event.encode_nested(codec).unwrap();
+            let packed_len = event.tx_nested.as_ref().map(Vec::len);
+            assert!(event.compress_data(0).tx_nested.is_some());
+            if codec == NestedCodec::Json {
+                assert_eq!(event.tx_nested_compression, Some(DataCodec::Lz4));
+                assert!(event.tx_nested.as_ref().map(Vec::len) < packed_len);
+            }
+            event.decompress_data().unwrap();
+            event.decode_nested().unwrap();
+            assert_eq!(event, expected);
+        }
+    }
+
+    #[test]
+    fn transaction_meta_keeps_missing_inner_instructions_and_log_messages_apart() {
+        let log_messages = Some(vec!["Program log: hello".to_owned()]);
+        for (inner_instructions, log_messages) in [(None, log_messages), (Some(Vec::new()), None)] {
+            let meta = TransactionMeta {
+                inner_instructions,
+                log_messages,
+                ..Default::default()
+            };
+            let status_meta =
+                confirmed_block::TransactionStatusMeta::try_from(meta.clone()).unwrap();
+            assert_eq!(
+                status_meta.inner_instructions_none,
+                meta.inner_instructions.is_none()
+            );
+            assert_eq!(status_meta.log_messages_none, meta.log_messages.is_none());
+            assert_eq!(TransactionMeta::try_from(status_meta).unwrap(), meta);
+        }
+    }
+
+    #[test]
+    fn missing_field_reports_unset_required_column() {
+        let mut event = account_update().as_blockchain_event(0, [0], 7);
+        assert_eq!(event.missing_field(), None);
+        event.owner = None;
+        assert_eq!(event.missing_field(), Some("owner"));
+    }
+
+    #[test]
+    fn transaction_error_message_decodes_bincode() {
+        // Variant 0 of TransactionError
+        assert_eq!(transaction_error_message(&[0, 0, 0, 0]), "Account in use");
+        assert_eq!(
+            transaction_error_message(&[0xff]),
+            "undecodable transaction error"
+        );
+    }
+}
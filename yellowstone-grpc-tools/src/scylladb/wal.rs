@@ -0,0 +1,459 @@
+use {
+    super::{
+        sink::ClientCommand,
+        types::{ProducerId, ShardId},
+    },
+    serde::{de::DeserializeOwned, Deserialize, Serialize},
+    std::{
+        collections::{BTreeMap, VecDeque},
+        fs::{self, File, OpenOptions},
+        io::{self, Read, Seek, Write},
+        path::{Path, PathBuf},
+    },
+    tracing::warn,
+};
+
+/// Sequence number of a command in the [`RouterWal`] of a sink.
+pub(crate) type WalSeq = u64;
+
+/// Number of commands appended to a segment of a [`RouterWal`] before the next segment is started.
+const ROUTER_WAL_SEGMENT_LEN: usize = 4096;
+
+/// Append-only log of the commands buffered by a shard and not yet written to ScyllaDB.
+///
+/// Each record is a little-endian `u32` length followed by the bincode encoding of a [`ClientCommand`].
+/// Records are written straight to the file without `fsync` unless [`ShardWal::with_sync`] is set, so they
+/// survive a crash of the process but not a crash of the host.
+pub(crate) struct ShardWal {
+    file: File,
+    replay_path: PathBuf,
+    /// Whether each record is synced to disk once appended.
+    sync: bool,
+}
+
+impl ShardWal {
+    /// Opens the log of a shard inside `dir` and returns the commands a previous run did not write.
+    ///
+    /// The previous log is moved aside until [`ShardWal::finish_replay`] is called, a crash during the replay
+    /// replays the same commands again on the next start.
+    pub(crate) fn open(
+        dir: &Path,
+        producer_id: ProducerId,
+        shard_id: ShardId,
+    ) -> anyhow::Result<(Self, Vec<ClientCommand>)> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(format!("producer-{}-shard-{shard_id}.wal", producer_id[0]));
+        let replay_path = path.with_extension("wal.replay");
+
+        if replay_path.exists() {
+            // A previous replay did not complete, the log only holds commands of the replay file.
+            if path.exists() {
+                fs::remove_file(&path)?;
+            }
+        } else if path.exists() {
+            fs::rename(&path, &replay_path)?;
+        }
+
+        let pending = if replay_path.exists() {
+            read_records(&replay_path)?
+        } else {
+            Vec::new()
+        };
+
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(&path)?;
+        Ok((
+            ShardWal {
+                file,
+                replay_path,
+                sync: false,
+            },
+            pending,
+        ))
+    }
+
+    /// Syncs each appended record to disk, so the log survives a crash of the host as well.
+    pub(crate) fn with_sync(mut self, sync: bool) -> Self {
+        self.sync = sync;
+        self
+    }
+
+    /// Removes the replayed log once its commands have been written to ScyllaDB.
+    pub(crate) fn finish_replay(&self) -> anyhow::Result<()> {
+        match fs::remove_file(&self.replay_path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Encodes a command as a record of the log.
+    pub(crate) fn encode(cmd: &ClientCommand) -> anyhow::Result<Vec<u8>> {
+        encode_record(cmd)
+    }
+
+    pub(crate) fn append(&mut self, record: &[u8]) -> anyhow::Result<()> {
+        self.file.write_all(record)?;
+        if self.sync {
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Forgets every logged command, they have all been written to ScyllaDB.
+    pub(crate) fn truncate(&mut self) -> anyhow::Result<()> {
+        self.file.set_len(0)?;
+        self.file.rewind()?;
+        Ok(())
+    }
+}
+
+/// Append-only log of the commands logged by a sink and not yet taken over by the log of their shard.
+///
+/// The sink appends each command before routing it, so the commands waiting in the mailboxes of the router and
+/// of the shards, or held by the router for a saturated shard, survive a crash of the process like the ones
+/// buffered by the shards do in their [`ShardWal`]. A command is released once its shard appended it to its
+/// own log, or once the router dropped it per the buffer full policy.
+///
+/// The log is split into segments, a segment is removed once every command of it and of the segments before it
+/// is released. Releases are recorded in the log as well, records are written without `fsync` like the ones of
+/// [`ShardWal`] unless [`RouterWal::with_sync`] is set. A crash between a shard taking a command over and its
+/// release being recorded replays the command twice.
+#[derive(Debug)]
+pub(crate) struct RouterWal {
+    dir: PathBuf,
+    producer_id: ProducerId,
+    /// Segment being appended to.
+    file: File,
+    /// Segments of this run, oldest first, the last one is `file`.
+    segments: VecDeque<RouterWalSegment>,
+    next_segment_no: u64,
+    next_seq: WalSeq,
+    /// Segments of the previous run, removed once their commands are logged again.
+    replayed: Vec<PathBuf>,
+    /// Whether each command is synced to disk once appended.
+    sync: bool,
+}
+
+#[derive(Debug)]
+struct RouterWalSegment {
+    path: PathBuf,
+    /// Sequence number of the first command of the segment.
+    first_seq: WalSeq,
+    /// Number of commands appended to the segment.
+    len: usize,
+    /// Number of commands of the segment not released yet.
+    unreleased: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+#[allow(clippy::large_enum_variant)]
+enum RouterWalRecord {
+    Command(WalSeq, ClientCommand),
+    Release(WalSeq),
+}
+
+impl RouterWal {
+    /// Opens the log of a sink inside `dir` and returns the commands a previous run did not release.
+    ///
+    /// The segments of the previous run are kept until [`RouterWal::finish_replay`] is called, a crash before
+    /// replays the same commands again on the next start.
+    pub(crate) fn open(
+        dir: &Path,
+        producer_id: ProducerId,
+    ) -> anyhow::Result<(Self, Vec<ClientCommand>)> {
+        fs::create_dir_all(dir)?;
+        let prefix = format!("producer-{}-router-", producer_id[0]);
+        let mut previous = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let segment_no = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_prefix(&prefix)?.strip_suffix(".wal"))
+                .and_then(|segment_no| segment_no.parse::<u64>().ok());
+            if let Some(segment_no) = segment_no {
+                previous.push((segment_no, path));
+            }
+        }
+        previous.sort();
+
+        let mut pending = BTreeMap::new();
+        let mut next_seq = 0;
+        for (_, path) in previous.iter() {
+            for record in read_records(path)? {
+                match record {
+                    RouterWalRecord::Command(seq, cmd) => {
+                        next_seq = next_seq.max(seq + 1);
+                        pending.insert(seq, cmd);
+                    }
+                    RouterWalRecord::Release(seq) => {
+                        pending.remove(&seq);
+                    }
+                }
+            }
+        }
+
+        let segment_no = previous.last().map_or(0, |(segment_no, _)| segment_no + 1);
+        let (path, file) = create_segment(dir, producer_id, segment_no)?;
+        let wal = RouterWal {
+            dir: dir.to_owned(),
+            producer_id,
+            file,
+            segments: VecDeque::from([RouterWalSegment {
+                path,
+                first_seq: next_seq,
+                len: 0,
+                unreleased: 0,
+            }]),
+            next_segment_no: segment_no + 1,
+            next_seq,
+            replayed: previous.into_iter().map(|(_, path)| path).collect(),
+            sync: false,
+        };
+        Ok((wal, pending.into_values().collect()))
+    }
+
+    /// Syncs each appended command to disk, so the log survives a crash of the host as well.
+    ///
+    /// Releases are not synced, a lost release replays its command twice like a crash right before it.
+    pub(crate) fn with_sync(mut self, sync: bool) -> Self {
+        self.sync = sync;
+        self
+    }
+
+    /// Removes the segments of the previous run once their commands have been logged again.
+    pub(crate) fn finish_replay(&mut self) -> anyhow::Result<()> {
+        for path in self.replayed.drain(..) {
+            match fs::remove_file(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends a command, to be released with the returned sequence number.
+    pub(crate) fn append(&mut self, cmd: &ClientCommand) -> anyhow::Result<WalSeq> {
+        let seq = self.next_seq;
+        // The record borrows the command, it encodes like a `RouterWalRecord::Command`.
+        #[derive(Serialize)]
+        enum RecordRef<'a> {
+            Command(WalSeq, &'a ClientCommand),
+        }
+        self.file
+            .write_all(&encode_record(&RecordRef::Command(seq, cmd))?)?;
+        if self.sync {
+            self.file.sync_data()?;
+        }
+        self.next_seq += 1;
+        let segment = self.segments.back_mut().expect("current segment");
+        segment.len += 1;
+        segment.unreleased += 1;
+        if segment.len >= ROUTER_WAL_SEGMENT_LEN {
+            let (path, file) = create_segment(&self.dir, self.producer_id, self.next_segment_no)?;
+            self.file = file;
+            self.segments.push_back(RouterWalSegment {
+                path,
+                first_seq: self.next_seq,
+                len: 0,
+                unreleased: 0,
+            });
+            self.next_segment_no += 1;
+        }
+        Ok(seq)
+    }
+
+    /// Releases a command, it is no longer replayed on the next start.
+    pub(crate) fn release(&mut self, seq: WalSeq) -> anyhow::Result<()> {
+        self.file
+            .write_all(&encode_record(&RouterWalRecord::Release(seq))?)?;
+        let segment = self
+            .segments
+            .iter_mut()
+            .rev()
+            .find(|segment| segment.first_seq <= seq);
+        if let Some(segment) = segment {
+            segment.unreleased = segment.unreleased.saturating_sub(1);
+        }
+        // The releases of a segment may be recorded in the next ones, segments are removed oldest first.
+        while self.segments.len() > 1 && self.segments[0].unreleased == 0 {
+            let segment = self.segments.pop_front().expect("oldest segment");
+            fs::remove_file(&segment.path)?;
+        }
+        Ok(())
+    }
+}
+
+fn create_segment(
+    dir: &Path,
+    producer_id: ProducerId,
+    segment_no: u64,
+) -> anyhow::Result<(PathBuf, File)> {
+    let path = dir.join(format!(
+        "producer-{}-router-{segment_no:020}.wal",
+        producer_id[0]
+    ));
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .open(&path)?;
+    Ok((path, file))
+}
+
+fn encode_record(record: &impl Serialize) -> anyhow::Result<Vec<u8>> {
+    let payload = bincode::serialize(record)?;
+    let len = u32::try_from(payload.len())?;
+    let mut record = Vec::with_capacity(4 + payload.len());
+    record.extend_from_slice(&len.to_le_bytes());
+    record.extend_from_slice(&payload);
+    Ok(record)
+}
+
+fn read_records<T: DeserializeOwned>(path: &Path) -> anyhow::Result<Vec<T>> {
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+
+    let mut records = Vec::new();
+    let mut rest = bytes.as_slice();
+    while rest.len() >= 4 {
+        let (len, tail) = rest.split_at(4);
+        let len = u32::from_le_bytes(len.try_into().expect("4 bytes")) as usize;
+        if tail.len() < len {
+            break;
+        }
+        let (payload, tail) = tail.split_at(len);
+        records.push(bincode::deserialize(payload)?);
+        rest = tail;
+    }
+    if !rest.is_empty() {
+        // The process died in the middle of an append, the command was never acknowledged.
+        warn!(
+            "ignoring {} trailing bytes of a torn record in {}",
+            rest.len(),
+            path.display()
+        );
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{RouterWal, ShardWal, ROUTER_WAL_SEGMENT_LEN},
+        crate::scylladb::{sink::ClientCommand, types::AccountUpdate},
+        std::{fs::OpenOptions, io::Write},
+    };
+
+    fn account_update(slot: i64) -> ClientCommand {
+        ClientCommand::InsertAccountUpdate(AccountUpdate {
+            slot,
+            pubkey: [1; 32],
+            lamports: 10,
+            owner: [2; 32],
+            executable: false,
+            rent_epoch: 0,
+            write_version: slot,
+            data: vec![3; 16],
+            txn_signature: None,
+            ingested_at: Some(slot),
+            truncated: false,
+            raw_proto: None,
+        })
+    }
+
+    #[test]
+    fn unwritten_commands_are_replayed_until_the_replay_finishes() {
+        let dir = std::env::temp_dir().join(format!("shard-wal-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let (wal, pending) = ShardWal::open(&dir, [0], 1).unwrap();
+        assert!(pending.is_empty());
+        let mut wal = wal.with_sync(true);
+        for slot in 1..=3 {
+            let record = ShardWal::encode(&account_update(slot)).unwrap();
+            wal.append(&record).unwrap();
+            if slot == 1 {
+                wal.truncate().unwrap();
+            }
+        }
+        drop(wal);
+
+        // Simulate a crash in the middle of an append.
+        OpenOptions::new()
+            .append(true)
+            .open(dir.join("producer-0-shard-1.wal"))
+            .unwrap()
+            .write_all(&[42, 0])
+            .unwrap();
+
+        let (_wal, pending) = ShardWal::open(&dir, [0], 1).unwrap();
+        assert_eq!(pending, vec![account_update(2), account_update(3)]);
+
+        // The replay did not finish, the same commands come back.
+        let (wal, pending) = ShardWal::open(&dir, [0], 1).unwrap();
+        assert_eq!(pending, vec![account_update(2), account_update(3)]);
+        wal.finish_replay().unwrap();
+
+        let (_wal, pending) = ShardWal::open(&dir, [0], 1).unwrap();
+        assert!(pending.is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn queued_commands_survive_until_their_shard_takes_them_over() {
+        let dir = std::env::temp_dir().join(format!("router-wal-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let segments = || std::fs::read_dir(&dir).unwrap().count();
+
+        // Slots 1 and 3 are taken over by their shard, 2 and 4 are still queued when the process dies.
+        let (mut wal, pending) = RouterWal::open(&dir, [0]).unwrap();
+        assert!(pending.is_empty());
+        let seqs = (1..=4)
+            .map(|slot| wal.append(&account_update(slot)).unwrap())
+            .collect::<Vec<_>>();
+        wal.release(seqs[0]).unwrap();
+        wal.release(seqs[2]).unwrap();
+        drop(wal);
+
+        let (wal, pending) = RouterWal::open(&dir, [0]).unwrap();
+        assert_eq!(pending, vec![account_update(2), account_update(4)]);
+        drop(wal);
+
+        // The replay did not finish, the same commands come back.
+        let (mut wal, pending) = RouterWal::open(&dir, [0]).unwrap();
+        assert_eq!(pending, vec![account_update(2), account_update(4)]);
+        let seqs = pending
+            .iter()
+            .map(|cmd| wal.append(cmd).unwrap())
+            .collect::<Vec<_>>();
+        wal.finish_replay().unwrap();
+        assert_eq!(segments(), 1);
+        for seq in seqs {
+            wal.release(seq).unwrap();
+        }
+        drop(wal);
+
+        // Fully released segments are removed, the last one is kept for the next commands.
+        let (mut wal, pending) = RouterWal::open(&dir, [0]).unwrap();
+        assert!(pending.is_empty());
+        wal.finish_replay().unwrap();
+        let seqs = (0..ROUTER_WAL_SEGMENT_LEN as i64 * 2)
+            .map(|slot| wal.append(&account_update(slot)).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(segments(), 3);
+        for seq in seqs.iter().take(ROUTER_WAL_SEGMENT_LEN) {
+            wal.release(*seq).unwrap();
+        }
+        assert_eq!(segments(), 2);
+        drop(wal);
+
+        let (_wal, pending) = RouterWal::open(&dir, [0]).unwrap();
+        assert_eq!(pending.len(), ROUTER_WAL_SEGMENT_LEN);
+        assert_eq!(pending[0], account_update(ROUTER_WAL_SEGMENT_LEN as i64));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}